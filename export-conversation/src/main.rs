@@ -0,0 +1,74 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::StructOpt;
+use whisperfish::store::{self, ExportFormat, Storage};
+
+/// Export a Whisperfish conversation to HTML, JSON or plain text
+#[derive(StructOpt, Debug)]
+#[structopt(name = "export-conversation")]
+struct Opt {
+    /// Whisperfish storage password
+    #[structopt(short, long)]
+    password: Option<String>,
+
+    /// Use an additional account instead of the default one
+    #[structopt(long)]
+    account: Option<String>,
+
+    /// Session to export.
+    ///
+    /// Leave out to list the sessions and their ids.
+    #[structopt(short, long)]
+    session: Option<i32>,
+
+    /// Output format: html, json or text
+    #[structopt(short, long, default_value = "html")]
+    format: ExportFormat,
+
+    /// Directory to write the conversation and its attachments to
+    #[structopt(short, long, parse(from_os_str), default_value = ".")]
+    output: PathBuf,
+}
+
+#[actix_rt::main]
+async fn main() -> Result<(), anyhow::Error> {
+    env_logger::init();
+
+    let opt = Opt::from_args();
+
+    let config = whisperfish::config::SignalConfig::read_account_from_file(opt.account.as_deref())?;
+    let config = Arc::new(config);
+    let storage = Storage::open(
+        config,
+        &store::account_location(opt.account.as_deref())?,
+        opt.password,
+    )
+    .await?;
+
+    let sid = match opt.session {
+        Some(sid) => sid,
+        None => {
+            for session in storage.fetch_sessions() {
+                let name = match &session.r#type {
+                    store::orm::SessionType::DirectMessage(recipient) => {
+                        recipient.name().into_owned()
+                    }
+                    store::orm::SessionType::GroupV1(group) => group.name.clone(),
+                    store::orm::SessionType::GroupV2(group) => group.name.clone(),
+                };
+                println!("{}\t{}", session.id, name);
+            }
+            return Ok(());
+        }
+    };
+
+    let summary = storage.export_session(sid, opt.format, &opt.output)?;
+    println!(
+        "Exported {} messages and {} attachments to {}",
+        summary.messages,
+        summary.attachments,
+        summary.path.display()
+    );
+
+    Ok(())
+}
@@ -15,6 +15,10 @@ struct Opt {
     #[structopt(short, long)]
     password: Option<String>,
 
+    /// Use an additional account instead of the default one
+    #[structopt(long)]
+    account: Option<String>,
+
     /// CDN number (normally either 0 or 2)
     #[structopt(short = "c", long)]
     cdn_number: u32,
@@ -48,14 +52,18 @@ async fn main() -> Result<(), anyhow::Error> {
 
     let mut opt = Opt::from_args();
 
-    let config = whisperfish::config::SignalConfig::read_from_file()?;
+    let config = whisperfish::config::SignalConfig::read_account_from_file(opt.account.as_deref())?;
     let config = Arc::new(config);
-    let settings = whisperfish::config::SettingsBridge::default();
+    let settings = whisperfish::config::SettingsBridge::for_account(opt.account.as_deref());
     let dir = settings.get_string("attachment_dir");
     let dest = Path::new(&dir);
 
-    let mut storage =
-        Storage::open(config.clone(), &store::default_location()?, opt.password).await?;
+    let mut storage = Storage::open(
+        config.clone(),
+        &store::account_location(opt.account.as_deref())?,
+        opt.password,
+    )
+    .await?;
 
     let key_material = hex::decode(opt.key)?;
     anyhow::ensure!(
@@ -36,26 +36,30 @@ struct Opts {
     /// Send a signal to shutdown Whisperfish
     #[structopt(long)]
     quit: bool,
+
+    /// Serve a signal-cli compatible JSON-RPC interface on this Unix socket
+    ///
+    /// Overrides `jsonrpcSocket` from the config file.
+    #[structopt(long, parse(from_os_str))]
+    jsonrpc_socket: Option<std::path::PathBuf>,
+
+    /// Run for an additional account instead of the default one
+    ///
+    /// Every account has its own storage, config and encryption password, and runs in its own
+    /// process.  The name may only contain lowercase letters, digits and underscores.
+    #[structopt(long, parse(try_from_str = parse_account))]
+    account: Option<String>,
 }
 
-fn main() {
-    // Ctrl-C --> graceful shutdown
-    if let Ok(mut signals) = Signals::new([SIGINT].iter()) {
-        thread::spawn(move || {
-            let mut terminate = false;
-            for _ in signals.forever() {
-                if !terminate {
-                    log::info!("[SIGINT] Trying to exit gracefully...");
-                    terminate = true;
-                    dbus_quit_app().ok();
-                } else {
-                    log::info!("[SIGINT] Exiting forcefully...");
-                    std::process::exit(1);
-                }
-            }
-        });
+fn parse_account(account: &str) -> Result<String, String> {
+    if store::is_valid_account_name(account) {
+        Ok(account.to_owned())
+    } else {
+        Err("use up to 32 lowercase letters, digits and underscores".into())
     }
+}
 
+fn main() {
     // Sailjail only accepts -prestart on the command line as optional argument,
     // structopt however only supports --prestart.
     // See: https://github.com/clap-rs/clap/issues/1210
@@ -71,9 +75,28 @@ fn main() {
 
     // Then, handle command line arguments and overwrite settings from config file if necessary
     let opt = Opts::from_iter(args);
+    let service_name = dbus_service_name(opt.account.as_deref());
+
+    // Ctrl-C --> graceful shutdown
+    if let Ok(mut signals) = Signals::new([SIGINT].iter()) {
+        let service_name = service_name.clone();
+        thread::spawn(move || {
+            let mut terminate = false;
+            for _ in signals.forever() {
+                if !terminate {
+                    log::info!("[SIGINT] Trying to exit gracefully...");
+                    terminate = true;
+                    dbus_quit_app(&service_name).ok();
+                } else {
+                    log::info!("[SIGINT] Exiting forcefully...");
+                    std::process::exit(1);
+                }
+            }
+        });
+    }
 
     if opt.quit {
-        if let Err(e) = dbus_quit_app() {
+        if let Err(e) = dbus_quit_app(&service_name) {
             eprintln!("{}", e);
         }
         return;
@@ -100,11 +123,17 @@ fn main() {
     };
 
     // Read config file or get a default config
-    let mut config = match config::SignalConfig::read_from_file() {
+    let mut config = match config::SignalConfig::read_account_from_file(opt.account.as_deref()) {
         Ok(x) => x,
         Err(e) => {
             eprintln!("Config file not found: {}", e);
-            config::SignalConfig::default()
+            match config::SignalConfig::for_account(opt.account.clone()) {
+                Ok(x) => x,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
         }
     };
 
@@ -125,6 +154,14 @@ fn main() {
         std::process::exit(1);
     }
 
+    let shared_dir = config.get_share_dir();
+    if !shared_dir.exists() {
+        if let Err(e) = std::fs::create_dir_all(&shared_dir) {
+            eprintln!("Could not create {}: {}", shared_dir.display(), e);
+            std::process::exit(1);
+        }
+    }
+
     if opt.verbose {
         config.verbose = true;
     }
@@ -132,8 +169,9 @@ fn main() {
         config.autostart = true;
     }
     config.override_captcha = opt.captcha;
-
-    let shared_dir = config.get_share_dir();
+    if opt.jsonrpc_socket.is_some() {
+        config.jsonrpc_socket = opt.jsonrpc_socket;
+    }
 
     let log_file_path = shared_dir.join(format!(
         "harbour-whisperfish.{}.log",
@@ -199,9 +237,13 @@ fn main() {
         store::Storage::clear_old_logs(&shared_dir, MAX_LOGFILE_COUNT, LOGFILE_REGEX);
     }
 
-    let instance_lock = SingleInstance::new("whisperfish").unwrap();
+    let instance_name = match config.account() {
+        None => "whisperfish".to_owned(),
+        Some(account) => format!("whisperfish-{}", account),
+    };
+    let instance_lock = SingleInstance::new(&instance_name).unwrap();
     if !instance_lock.is_single() {
-        if let Err(e) = dbus_show_app() {
+        if let Err(e) = dbus_show_app(&service_name) {
             log::error!("{}", e);
         }
         return;
@@ -213,12 +255,12 @@ fn main() {
     }
 }
 
-fn dbus_show_app() -> Result<(), dbus::Error> {
+fn dbus_show_app(service_name: &str) -> Result<(), dbus::Error> {
     log::info!("Calling app.show() on DBus.");
 
     let c = Connection::new_session()?;
     let proxy = c.with_proxy(
-        "be.rubdos.whisperfish",
+        service_name,
         "/be/rubdos/whisperfish/app",
         Duration::from_millis(20000),
     );
@@ -226,12 +268,12 @@ fn dbus_show_app() -> Result<(), dbus::Error> {
     proxy.method_call("be.rubdos.whisperfish.app", "show", ())
 }
 
-fn dbus_quit_app() -> Result<(), dbus::Error> {
+fn dbus_quit_app(service_name: &str) -> Result<(), dbus::Error> {
     log::info!("Calling app.quit() on DBus.");
 
     let c = Connection::new_session()?;
     let proxy = c.with_proxy(
-        "be.rubdos.whisperfish",
+        service_name,
         "/be/rubdos/whisperfish/app",
         Duration::from_millis(1000),
     );
@@ -246,7 +288,7 @@ fn run_main_app(config: config::SignalConfig) -> Result<(), anyhow::Error> {
     // Right now, we only create the attachment (and storage) directory if necessary
     // With more refactoring there should be probably more initialization here
     // Not creating the storage/attachment directory is fatal and we return here.
-    let mut settings = crate::config::SettingsBridge::default();
+    let mut settings = crate::config::SettingsBridge::for_account(config.account());
     settings.migrate_qsettings_paths();
 
     for dir in &[
@@ -265,11 +307,13 @@ fn run_main_app(config: config::SignalConfig) -> Result<(), anyhow::Error> {
     settings.set_bool("verbose", config.verbose);
     settings.set_bool("logfile", config.logfile);
 
+    let account = config.account().map(String::from);
+
     // This will panic here if feature `sailfish` is not enabled
     gui::run(config).unwrap();
 
     // ...and pull them back after execution.
-    match config::SignalConfig::read_from_file() {
+    match config::SignalConfig::read_account_from_file(account.as_deref()) {
         Ok(mut config) => {
             config.verbose = settings.get_verbose();
             config.logfile = settings.get_logfile();
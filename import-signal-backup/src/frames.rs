@@ -0,0 +1,369 @@
+//! Reader for the encrypted frame format of Signal Android backups.
+//!
+//! The file starts with the length-prefixed, plaintext `Header` frame, which carries the IV and the
+//! salt.  Every next frame is encrypted with AES-256-CTR and authenticated by a HMAC-SHA256 that is
+//! truncated to 10 bytes.  The first four bytes of the IV are a counter, that is incremented for
+//! every frame and every attachment.  Attachment data follows its frame as a separate blob.
+
+use aes::cipher::{NewCipher, StreamCipher};
+use anyhow::Context;
+use hmac::{Mac, NewMac};
+use prost::Message;
+use sha2::Digest;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+include!(concat!(env!("OUT_DIR"), "/signal.rs"));
+
+type Cipher = ctr::Ctr128BE<aes::Aes256>;
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+const MAC_LEN: usize = 10;
+/// The header only holds an IV, a salt and a version; it is read before anything is verified.
+const MAX_HEADER_LEN: usize = 1024;
+/// Frames hold single database rows, so anything larger means the passphrase was wrong.
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+const KEY_ITERATIONS: usize = 250_000;
+
+pub struct BackupReader {
+    file: BufReader<File>,
+    cipher_key: [u8; 32],
+    mac_key: [u8; 32],
+    iv: [u8; 16],
+    counter: u32,
+    version: u32,
+}
+
+impl BackupReader {
+    /// Opens a backup, with the passphrase as shown by Signal: 30 digits, optionally with spaces.
+    pub fn open(path: &Path, passphrase: &str) -> Result<Self, anyhow::Error> {
+        let mut file = BufReader::new(
+            File::open(path).with_context(|| format!("Could not open {}", path.display()))?,
+        );
+
+        let mut len = [0u8; 4];
+        file.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        anyhow::ensure!(len <= MAX_HEADER_LEN, "Not a Signal backup");
+        let mut header = vec![0u8; len];
+        file.read_exact(&mut header)?;
+        let header = BackupFrame::decode(&header[..])
+            .context("Not a Signal backup")?
+            .header
+            .context("Backup does not start with a header")?;
+
+        let mut iv = [0u8; 16];
+        anyhow::ensure!(header.iv().len() == 16, "Invalid IV in backup header");
+        iv.copy_from_slice(header.iv());
+        let counter = u32::from_be_bytes([iv[0], iv[1], iv[2], iv[3]]);
+        let version = header.version();
+        anyhow::ensure!(version <= 1, "Unsupported backup version {}", version);
+        log::info!("Opened backup of version {}", version);
+
+        let (cipher_key, mac_key) = derive_keys(passphrase, header.salt());
+
+        Ok(Self {
+            file,
+            cipher_key,
+            mac_key,
+            iv,
+            counter,
+            version,
+        })
+    }
+
+    fn next_cipher(&mut self) -> ([u8; 16], Cipher) {
+        let mut iv = self.iv;
+        iv[..4].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter = self.counter.wrapping_add(1);
+        let cipher = Cipher::new(&self.cipher_key.into(), &iv.into());
+        (iv, cipher)
+    }
+
+    fn new_mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.mac_key).expect("MAC keylength error")
+    }
+
+    fn verify(&mut self, mac: HmacSha256) -> Result<(), anyhow::Error> {
+        let mut theirs = [0u8; MAC_LEN];
+        self.file.read_exact(&mut theirs)?;
+        verify_truncated(mac, &theirs)
+    }
+
+    /// Reads and decrypts the next frame.
+    pub fn next_frame(&mut self) -> Result<BackupFrame, anyhow::Error> {
+        let (_iv, mut cipher) = self.next_cipher();
+        let mut mac = self.new_mac();
+
+        // Since version 1, the frame length is encrypted too.
+        let mut len = [0u8; 4];
+        self.file
+            .read_exact(&mut len)
+            .context("Backup is truncated")?;
+        if self.version >= 1 {
+            mac.update(&len);
+            cipher.apply_keystream(&mut len);
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        anyhow::ensure!(
+            (MAC_LEN..=MAX_FRAME_LEN).contains(&len),
+            "Invalid frame length {}; probably wrong passphrase",
+            len
+        );
+
+        let mut frame = vec![0u8; len - MAC_LEN];
+        self.file
+            .read_exact(&mut frame)
+            .context("Backup is truncated")?;
+        mac.update(&frame);
+        self.verify(mac)
+            .context("Could not verify frame; probably wrong passphrase")?;
+
+        cipher.apply_keystream(&mut frame);
+        Ok(BackupFrame::decode(&frame[..])?)
+    }
+
+    /// Reads and decrypts the data of an attachment, avatar or sticker, that follows its frame.
+    pub fn read_blob(&mut self, len: u32, out: &mut impl Write) -> Result<(), anyhow::Error> {
+        let (iv, mut cipher) = self.next_cipher();
+        let mut mac = self.new_mac();
+        mac.update(&iv);
+
+        let mut remaining = len as usize;
+        let mut buf = vec![0u8; 8192];
+        while remaining > 0 {
+            let chunk = &mut buf[..remaining.min(8192)];
+            self.file.read_exact(chunk).context("Backup is truncated")?;
+            mac.update(chunk);
+            cipher.apply_keystream(chunk);
+            out.write_all(chunk)?;
+            remaining -= chunk.len();
+        }
+
+        self.verify(mac).context("Could not verify attachment")
+    }
+
+    /// Skips over the data of an attachment, avatar or sticker.
+    pub fn skip_blob(&mut self, len: u32) -> Result<(), anyhow::Error> {
+        self.read_blob(len, &mut std::io::sink())
+    }
+}
+
+/// Derives the cipher and MAC keys like `BackupRecordInputStream` in Signal Android does.
+fn derive_keys(passphrase: &str, salt: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let input: Vec<u8> = passphrase
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .into_bytes();
+
+    let mut digest = sha2::Sha512::new();
+    digest.update(salt);
+    let mut hash = input.clone();
+    for _ in 0..KEY_ITERATIONS {
+        digest.update(&hash);
+        digest.update(&input);
+        hash = digest.finalize_reset().to_vec();
+    }
+
+    let mut derived = [0u8; 64];
+    hkdf::Hkdf::<sha2::Sha256>::new(None, &hash[..32])
+        .expand(b"Backup Export", &mut derived)
+        .expect("valid HKDF output length");
+
+    let mut cipher_key = [0u8; 32];
+    let mut mac_key = [0u8; 32];
+    cipher_key.copy_from_slice(&derived[..32]);
+    mac_key.copy_from_slice(&derived[32..]);
+    (cipher_key, mac_key)
+}
+
+fn verify_truncated(mac: HmacSha256, theirs: &[u8; MAC_LEN]) -> Result<(), anyhow::Error> {
+    let ours = mac.finalize().into_bytes();
+    let difference = ours[..MAC_LEN]
+        .iter()
+        .zip(theirs)
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+    anyhow::ensure!(difference == 0, "MAC verification failed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a version 1 backup like Signal Android does.
+    struct BackupWriter {
+        out: Vec<u8>,
+        cipher_key: [u8; 32],
+        mac_key: [u8; 32],
+        iv: [u8; 16],
+        counter: u32,
+    }
+
+    impl BackupWriter {
+        fn new(passphrase: &str) -> Self {
+            let salt = [7u8; 32];
+            let iv = [0, 0, 0, 42, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+            let header = BackupFrame {
+                header: Some(Header {
+                    iv: Some(iv.to_vec()),
+                    salt: Some(salt.to_vec()),
+                    version: Some(1),
+                }),
+                ..Default::default()
+            }
+            .encode_to_vec();
+            let mut out = (header.len() as u32).to_be_bytes().to_vec();
+            out.extend_from_slice(&header);
+
+            let (cipher_key, mac_key) = derive_keys(passphrase, &salt);
+            Self {
+                out,
+                cipher_key,
+                mac_key,
+                iv,
+                counter: 42,
+            }
+        }
+
+        fn next_cipher(&mut self) -> ([u8; 16], Cipher) {
+            let mut iv = self.iv;
+            iv[..4].copy_from_slice(&self.counter.to_be_bytes());
+            self.counter += 1;
+            (iv, Cipher::new(&self.cipher_key.into(), &iv.into()))
+        }
+
+        fn write_mac(&mut self, mac: HmacSha256) {
+            self.out
+                .extend_from_slice(&mac.finalize().into_bytes()[..MAC_LEN]);
+        }
+
+        fn frame(&mut self, frame: BackupFrame) {
+            let (_iv, mut cipher) = self.next_cipher();
+            let mut mac = HmacSha256::new_from_slice(&self.mac_key).unwrap();
+            let mut body = frame.encode_to_vec();
+            let mut len = ((body.len() + MAC_LEN) as u32).to_be_bytes();
+            cipher.apply_keystream(&mut len);
+            cipher.apply_keystream(&mut body);
+            mac.update(&len);
+            mac.update(&body);
+            self.out.extend_from_slice(&len);
+            self.out.extend_from_slice(&body);
+            self.write_mac(mac);
+        }
+
+        fn blob(&mut self, data: &[u8]) {
+            let (iv, mut cipher) = self.next_cipher();
+            let mut mac = HmacSha256::new_from_slice(&self.mac_key).unwrap();
+            mac.update(&iv);
+            let mut data = data.to_vec();
+            cipher.apply_keystream(&mut data);
+            mac.update(&data);
+            self.out.extend_from_slice(&data);
+            self.write_mac(mac);
+        }
+    }
+
+    const PASSPHRASE: &str = "12345 67890 12345 67890 12345 67890";
+
+    fn statement() -> SqlStatement {
+        SqlStatement {
+            statement: Some("INSERT INTO thread VALUES (?)".into()),
+            parameters: vec![sql_statement::SqlParameter {
+                integer_parameter: Some(1),
+                ..Default::default()
+            }],
+        }
+    }
+
+    /// A backup with a statement, an attachment, and the end frame.
+    fn backup() -> Vec<u8> {
+        let mut writer = BackupWriter::new(PASSPHRASE);
+        writer.frame(BackupFrame {
+            statement: Some(statement()),
+            ..Default::default()
+        });
+        writer.frame(BackupFrame {
+            attachment: Some(Attachment {
+                row_id: Some(3),
+                attachment_id: Some(4),
+                length: Some(5),
+            }),
+            ..Default::default()
+        });
+        writer.blob(b"hello");
+        writer.frame(BackupFrame {
+            end: Some(true),
+            ..Default::default()
+        });
+        writer.out
+    }
+
+    fn open(data: &[u8], passphrase: &str) -> Result<BackupReader, anyhow::Error> {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), data).unwrap();
+        BackupReader::open(file.path(), passphrase)
+    }
+
+    #[test]
+    fn read_backup() {
+        // Signal shows the passphrase in groups of five digits.
+        let mut reader = open(&backup(), &PASSPHRASE.replace(' ', "")).unwrap();
+
+        assert_eq!(reader.next_frame().unwrap().statement, Some(statement()));
+        let attachment = reader.next_frame().unwrap().attachment.unwrap();
+        let mut data = Vec::new();
+        reader.read_blob(attachment.length(), &mut data).unwrap();
+        assert_eq!(data, b"hello");
+        assert!(reader.next_frame().unwrap().end());
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let mut reader = open(&backup(), "000000000000000000000000000000").unwrap();
+        assert!(reader.next_frame().is_err());
+    }
+
+    #[test]
+    fn corrupt_frame() {
+        let mut data = backup();
+        let header_len = 4 + u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        data[header_len + 6] ^= 1;
+        let mut reader = open(&data, PASSPHRASE).unwrap();
+        assert!(reader.next_frame().is_err());
+    }
+
+    #[test]
+    fn truncated_backup() {
+        let data = backup();
+        let mut reader = open(&data[..data.len() - 10], PASSPHRASE).unwrap();
+        reader.next_frame().unwrap();
+        let attachment = reader.next_frame().unwrap().attachment.unwrap();
+        reader.skip_blob(attachment.length()).unwrap();
+        assert!(reader.next_frame().is_err());
+    }
+
+    #[test]
+    fn oversized_header() {
+        let mut data = backup();
+        data[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(open(&data, PASSPHRASE).is_err());
+    }
+
+    #[test]
+    fn key_derivation() {
+        let salt = [1u8; 32];
+        assert_eq!(
+            derive_keys("123451234512345", &salt),
+            derive_keys("12345 12345 12345", &salt)
+        );
+        assert_ne!(
+            derive_keys("123451234512345", &salt),
+            derive_keys("123451234512345", &[2u8; 32])
+        );
+        let (cipher_key, mac_key) = derive_keys("123451234512345", &salt);
+        assert_ne!(cipher_key, mac_key);
+    }
+}
@@ -0,0 +1,626 @@
+//! Maps the database of a Signal Android backup onto the Whisperfish storage.
+//!
+//! The SQL statements in the backup are replayed into a scratch database, from which the threads,
+//! recipients, messages, reactions and attachments are read.  Android renames columns every now
+//! and then, which is why the queries are assembled from the columns that are actually present.
+//! Only backups that have the unified `message` table (Signal Android 6.11 and later) are
+//! supported.
+
+use crate::frames::{sql_statement::SqlParameter, BackupReader, SqlStatement};
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sql_types::*;
+use libsignal_service::zkgroup::api::groups::{GroupMasterKey, GroupSecretParams};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use whisperfish::store::{
+    millis_to_naive_chrono, orm, GroupV2, NewAttachment, NewMessage, Storage, TrustLevel,
+};
+
+// Message types, from `MessageTypes` in Signal Android.
+const BASE_TYPE_MASK: i64 = 0x1F;
+const BASE_INBOX_TYPE: i64 = 20;
+const BASE_OUTBOX_TYPE: i64 = 21;
+const BASE_SENT_TYPE: i64 = 23;
+const BASE_PENDING_INSECURE_SMS_FALLBACK: i64 = 26;
+const SPECIAL_TYPES_MASK: i64 = 0x10000 | 0x20000 | 0x40000 | 0x400000 | 0x800000;
+
+const GROUP_V2_PREFIX: &str = "__signal_group__v2__!";
+
+#[derive(Debug, Default)]
+pub struct ImportStats {
+    pub recipients: usize,
+    pub sessions: usize,
+    pub messages: usize,
+    pub skipped_messages: usize,
+    pub reactions: usize,
+    pub attachments: usize,
+}
+
+#[derive(QueryableByName)]
+struct TableColumn {
+    #[diesel(sql_type = Text)]
+    name: String,
+}
+
+#[derive(QueryableByName)]
+struct AndroidRecipient {
+    #[diesel(sql_type = BigInt)]
+    id: i64,
+    #[diesel(sql_type = Nullable<Text>)]
+    aci: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    e164: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    given_name: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    family_name: Option<String>,
+}
+
+#[derive(QueryableByName)]
+struct AndroidGroup {
+    #[diesel(sql_type = BigInt)]
+    recipient_id: i64,
+    #[diesel(sql_type = Text)]
+    group_id: String,
+    #[diesel(sql_type = Nullable<Binary>)]
+    master_key: Option<Vec<u8>>,
+}
+
+#[derive(QueryableByName)]
+struct AndroidThread {
+    #[diesel(sql_type = BigInt)]
+    id: i64,
+    #[diesel(sql_type = BigInt)]
+    recipient_id: i64,
+}
+
+#[derive(QueryableByName)]
+struct AndroidMessage {
+    #[diesel(sql_type = BigInt)]
+    id: i64,
+    #[diesel(sql_type = BigInt)]
+    thread_id: i64,
+    #[diesel(sql_type = BigInt)]
+    from_recipient_id: i64,
+    #[diesel(sql_type = BigInt)]
+    date_sent: i64,
+    #[diesel(sql_type = BigInt)]
+    date_received: i64,
+    #[diesel(sql_type = BigInt)]
+    message_type: i64,
+    #[diesel(sql_type = Nullable<Text>)]
+    body: Option<String>,
+    #[diesel(sql_type = Bool)]
+    is_read: bool,
+    #[diesel(sql_type = BigInt)]
+    quote_id: i64,
+    #[diesel(sql_type = BigInt)]
+    expires_in: i64,
+    #[diesel(sql_type = Bool)]
+    view_once: bool,
+    #[diesel(sql_type = Bool)]
+    skip: bool,
+}
+
+#[derive(QueryableByName)]
+struct AndroidAttachment {
+    #[diesel(sql_type = BigInt)]
+    id: i64,
+    #[diesel(sql_type = BigInt)]
+    message_id: i64,
+    #[diesel(sql_type = Nullable<Text>)]
+    content_type: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    caption: Option<String>,
+    #[diesel(sql_type = Bool)]
+    voice_note: bool,
+    #[diesel(sql_type = Bool)]
+    borderless: bool,
+    #[diesel(sql_type = Integer)]
+    display_order: i32,
+}
+
+#[derive(QueryableByName)]
+struct AndroidReaction {
+    #[diesel(sql_type = BigInt)]
+    message_id: i64,
+    #[diesel(sql_type = BigInt)]
+    author_id: i64,
+    #[diesel(sql_type = Text)]
+    emoji: String,
+    #[diesel(sql_type = BigInt)]
+    date_sent: i64,
+}
+
+/// The scratch database, with the attachment data that came along.
+pub struct Replay {
+    db: SqliteConnection,
+    attachments: HashMap<i64, PathBuf>,
+}
+
+/// Decrypts the whole backup into `scratch`.
+pub fn replay(reader: &mut BackupReader, scratch: &Path) -> Result<Replay, anyhow::Error> {
+    let db_path = scratch.join("signal.db");
+    let mut db = SqliteConnection::establish(db_path.to_str().expect("UTF-8 scratch path"))?;
+    let mut attachments = HashMap::new();
+    let mut failed_statements = 0;
+
+    loop {
+        let frame = reader.next_frame()?;
+        if let Some(statement) = frame.statement {
+            // Android also exports its full text search tables and the like, which we don't need
+            // and which SQLCipher may not be able to create.
+            if let Err(e) = db.batch_execute(&inline_parameters(&statement)) {
+                log::debug!("Skipping statement {:?}: {}", statement.statement(), e);
+                failed_statements += 1;
+            }
+        } else if let Some(attachment) = frame.attachment {
+            let path = scratch.join(format!("attachment-{}", attachment.row_id()));
+            let mut file = std::fs::File::create(&path)?;
+            reader.read_blob(attachment.length(), &mut file)?;
+            attachments.insert(attachment.row_id() as i64, path);
+        } else if let Some(avatar) = frame.avatar {
+            // Avatars are fetched again with the profiles.
+            reader.skip_blob(avatar.length())?;
+        } else if let Some(sticker) = frame.sticker {
+            reader.skip_blob(sticker.length())?;
+        } else if let Some(version) = frame.version {
+            log::info!("Backup has database version {}", version.version());
+        } else if frame.end() {
+            break;
+        }
+    }
+
+    if failed_statements > 0 {
+        log::info!("Skipped {} statements", failed_statements);
+    }
+    Ok(Replay { db, attachments })
+}
+
+/// Inlines the parameters of a statement, because Diesel has no dynamic binding.
+fn inline_parameters(statement: &SqlStatement) -> String {
+    let mut parameters = statement.parameters.iter();
+    let mut sql = String::new();
+    let mut in_string = false;
+    for c in statement.statement().chars() {
+        match c {
+            '\'' => {
+                in_string = !in_string;
+                sql.push(c);
+            }
+            '?' if !in_string => match parameters.next() {
+                Some(parameter) => sql.push_str(&sql_literal(parameter)),
+                None => sql.push(c),
+            },
+            c => sql.push(c),
+        }
+    }
+    sql
+}
+
+fn sql_literal(parameter: &SqlParameter) -> String {
+    if let Some(s) = &parameter.string_paramter {
+        format!("'{}'", s.replace('\'', "''"))
+    } else if let Some(i) = parameter.integer_parameter {
+        // Java longs are sent as unsigned integers.
+        (i as i64).to_string()
+    } else if let Some(d) = parameter.double_parameter.filter(|d| d.is_finite()) {
+        format!("{:?}", d)
+    } else if let Some(blob) = &parameter.blob_parameter {
+        format!("X'{}'", hex::encode(blob))
+    } else {
+        "NULL".into()
+    }
+}
+
+fn columns(db: &mut SqliteConnection, table: &str) -> Result<HashSet<String>, anyhow::Error> {
+    let columns: Vec<TableColumn> =
+        diesel::sql_query(format!("PRAGMA table_info({})", table)).load(db)?;
+    Ok(columns.into_iter().map(|c| c.name).collect())
+}
+
+/// Returns the first of `candidates` that is a column, or `fallback`.
+fn pick<'a>(columns: &HashSet<String>, candidates: &[&'a str], fallback: &'a str) -> &'a str {
+    candidates
+        .iter()
+        .find(|c| columns.contains(**c))
+        .copied()
+        .unwrap_or(fallback)
+}
+
+fn extension(mime_type: &str) -> &str {
+    match mime_type {
+        "image/jpeg" | "image/jpg" => "jpg",
+        "image/png" => "png",
+        "text/plain" | "text/x-signal-plain" => "txt",
+        other => mime_guess::get_mime_extensions_str(other)
+            .and_then(|exts| exts.first())
+            .copied()
+            .unwrap_or("bin"),
+    }
+}
+
+pub struct Importer<'a> {
+    storage: &'a mut Storage,
+    attachment_dir: PathBuf,
+    replay: Replay,
+    recipients: HashMap<i64, orm::Recipient>,
+    group_sessions: HashMap<i64, i32>,
+    thread_sessions: HashMap<i64, i32>,
+    messages: HashMap<i64, i32>,
+    pub stats: ImportStats,
+}
+
+impl<'a> Importer<'a> {
+    pub fn new(storage: &'a mut Storage, attachment_dir: PathBuf, replay: Replay) -> Self {
+        Self {
+            storage,
+            attachment_dir,
+            replay,
+            recipients: HashMap::new(),
+            group_sessions: HashMap::new(),
+            thread_sessions: HashMap::new(),
+            messages: HashMap::new(),
+            stats: ImportStats::default(),
+        }
+    }
+
+    pub fn import(&mut self) -> Result<(), anyhow::Error> {
+        anyhow::ensure!(
+            !columns(&mut self.replay.db, "message")?.is_empty(),
+            "This backup was made by a Signal version that is too old; please update Signal first"
+        );
+
+        self.import_recipients()?;
+        self.import_groups()?;
+        self.import_threads()?;
+        self.import_messages()?;
+        self.import_reactions()?;
+        Ok(())
+    }
+
+    fn import_recipients(&mut self) -> Result<(), anyhow::Error> {
+        let cols = columns(&mut self.replay.db, "recipient")?;
+        let query = format!(
+            "SELECT _id AS id, {} AS aci, {} AS e164, {} AS given_name, {} AS family_name FROM recipient",
+            pick(&cols, &["aci", "uuid"], "NULL"),
+            pick(&cols, &["e164", "phone"], "NULL"),
+            pick(&cols, &["profile_given_name", "signal_profile_name"], "NULL"),
+            pick(&cols, &["profile_family_name"], "NULL"),
+        );
+        let recipients: Vec<AndroidRecipient> =
+            diesel::sql_query(query).load(&mut self.replay.db)?;
+
+        for recipient in recipients {
+            let uuid = recipient
+                .aci
+                .as_deref()
+                .and_then(|aci| uuid::Uuid::parse_str(aci).ok());
+            let e164 = recipient
+                .e164
+                .as_deref()
+                .and_then(|e164| phonenumber::parse(None, e164).ok());
+            let imported = match self.storage.merge_and_fetch_recipient(
+                e164,
+                uuid,
+                None,
+                TrustLevel::Uncertain,
+            ) {
+                Ok(imported) => imported,
+                // Neither an ACI nor a phone number.
+                Err(_) => continue,
+            };
+            let has_name = recipient
+                .given_name
+                .as_deref()
+                .map_or(false, |n| !n.is_empty());
+            if let (Some(uuid), true) = (uuid, has_name) {
+                if imported.profile_joined_name.is_none() {
+                    self.storage.update_profile_details(
+                        &uuid,
+                        &recipient.given_name,
+                        &recipient.family_name,
+                        &None,
+                        &None,
+                    );
+                }
+            }
+            self.recipients.insert(recipient.id, imported);
+            self.stats.recipients += 1;
+        }
+        Ok(())
+    }
+
+    fn import_groups(&mut self) -> Result<(), anyhow::Error> {
+        let groups: Vec<AndroidGroup> = diesel::sql_query(
+            "SELECT recipient_id, group_id, master_key FROM groups WHERE recipient_id IS NOT NULL",
+        )
+        .load(&mut self.replay.db)?;
+
+        for group in groups {
+            let master_key = match (
+                group.group_id.starts_with(GROUP_V2_PREFIX),
+                group.master_key,
+            ) {
+                (true, Some(master_key)) if master_key.len() == 32 => master_key,
+                _ => {
+                    log::warn!("Skipping legacy or broken group {}", group.group_id);
+                    continue;
+                }
+            };
+            let mut key_stack = [0u8; 32];
+            key_stack.copy_from_slice(&master_key);
+            let secret = GroupSecretParams::derive_from_master_key(GroupMasterKey::new(key_stack));
+
+            // The name and the members are filled in when the group is refreshed.
+            let session = self.storage.fetch_or_insert_session_by_group_v2(&GroupV2 {
+                secret,
+                revision: 0,
+            });
+            self.group_sessions.insert(group.recipient_id, session.id);
+        }
+        Ok(())
+    }
+
+    fn import_threads(&mut self) -> Result<(), anyhow::Error> {
+        let cols = columns(&mut self.replay.db, "thread")?;
+        let query = format!(
+            "SELECT _id AS id, {} AS recipient_id FROM thread",
+            pick(&cols, &["recipient_id", "thread_recipient_id"], "NULL")
+        );
+        let threads: Vec<AndroidThread> = diesel::sql_query(query).load(&mut self.replay.db)?;
+
+        for thread in threads {
+            let session_id = if let Some(session_id) = self.group_sessions.get(&thread.recipient_id)
+            {
+                *session_id
+            } else if let Some(recipient) = self.recipients.get(&thread.recipient_id) {
+                self.storage
+                    .fetch_or_insert_session_by_recipient_id(recipient.id)
+                    .id
+            } else {
+                log::warn!("Skipping thread {} without known recipient", thread.id);
+                continue;
+            };
+            self.thread_sessions.insert(thread.id, session_id);
+            self.stats.sessions += 1;
+        }
+        Ok(())
+    }
+
+    fn import_messages(&mut self) -> Result<(), anyhow::Error> {
+        let cols = columns(&mut self.replay.db, "message")?;
+        // Stories and remotely deleted messages are skipped altogether.
+        let skip = match (cols.contains("story_type"), cols.contains("remote_deleted")) {
+            (true, true) => "(story_type != 0 OR remote_deleted != 0)",
+            (true, false) => "(story_type != 0)",
+            (false, true) => "(remote_deleted != 0)",
+            (false, false) => "0",
+        };
+        let query = format!(
+            "SELECT _id AS id, thread_id, {} AS from_recipient_id, date_sent, {} AS date_received, \
+             \"type\" AS message_type, body, \"read\" AS is_read, {} AS quote_id, \
+             {} AS expires_in, {} AS view_once, {} AS skip \
+             FROM message ORDER BY date_sent ASC",
+            pick(&cols, &["from_recipient_id", "recipient_id"], "0"),
+            pick(&cols, &["date_received", "date"], "date_sent"),
+            pick(&cols, &["quote_id"], "0"),
+            pick(&cols, &["expires_in"], "0"),
+            pick(&cols, &["view_once"], "0"),
+            skip,
+        );
+        let messages: Vec<AndroidMessage> = diesel::sql_query(query).load(&mut self.replay.db)?;
+        let mut attachments = self.load_attachments()?;
+
+        for message in messages {
+            let base_type = message.message_type & BASE_TYPE_MASK;
+            let session_id = match self.thread_sessions.get(&message.thread_id) {
+                Some(session_id) => *session_id,
+                None => {
+                    self.stats.skipped_messages += 1;
+                    continue;
+                }
+            };
+            if message.skip
+                || message.message_type & SPECIAL_TYPES_MASK != 0
+                || !(BASE_INBOX_TYPE..=BASE_PENDING_INSECURE_SMS_FALLBACK).contains(&base_type)
+            {
+                // Calls, group updates, timer changes and the like.
+                self.stats.skipped_messages += 1;
+                continue;
+            }
+
+            let timestamp = millis_to_naive_chrono(message.date_sent as u64);
+            if let Some(existing) = self.storage.fetch_message_by_timestamp(timestamp) {
+                if existing.session_id == session_id {
+                    log::debug!("Message {} was imported before", message.id);
+                    self.messages.insert(message.id, existing.id);
+                    continue;
+                }
+            }
+
+            let outgoing = base_type >= BASE_OUTBOX_TYPE;
+            let sender = if outgoing {
+                None
+            } else {
+                self.recipients.get(&message.from_recipient_id)
+            };
+            let message_attachments = if message.view_once {
+                // Android removes view-once media once it has been opened.
+                Vec::new()
+            } else {
+                attachments.remove(&message.id).unwrap_or_default()
+            };
+
+            let imported = self.storage.create_message(&NewMessage {
+                session_id,
+                source_e164: sender.and_then(|r| r.e164.clone()),
+                source_uuid: sender.and_then(|r| r.uuid),
+                text: message.body.unwrap_or_default(),
+                timestamp,
+                sent: base_type == BASE_SENT_TYPE,
+                received: false,
+                is_read: message.is_read || outgoing,
+                flags: 0,
+                attachment: None,
+                mime_type: None,
+                has_attachment: !message_attachments.is_empty(),
+                outgoing,
+                is_unidentified: false,
+                quote_timestamp: Some(message.quote_id as u64).filter(|ts| *ts > 0),
+                expires_in: Some(message.expires_in as u64)
+                    .filter(|ms| *ms > 0)
+                    .map(std::time::Duration::from_millis),
+            });
+            if !outgoing {
+                self.storage.set_message_received_timestamp(
+                    imported.id,
+                    millis_to_naive_chrono(message.date_received as u64),
+                );
+            }
+            self.messages.insert(message.id, imported.id);
+            self.stats.messages += 1;
+
+            for attachment in message_attachments {
+                self.import_attachment(imported.id, attachment)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load_attachments(&mut self) -> Result<HashMap<i64, Vec<AndroidAttachment>>, anyhow::Error> {
+        // Signal Android renamed `part` to `attachment` in 6.37.
+        let (table, cols) = match columns(&mut self.replay.db, "attachment")? {
+            cols if !cols.is_empty() => ("attachment", cols),
+            _ => ("part", columns(&mut self.replay.db, "part")?),
+        };
+        let query = format!(
+            "SELECT _id AS id, {} AS message_id, {} AS content_type, {} AS caption, \
+             {} AS voice_note, {} AS borderless, {} AS display_order \
+             FROM {} WHERE {} = 0",
+            pick(&cols, &["message_id", "mid"], "0"),
+            pick(&cols, &["content_type", "ct"], "NULL"),
+            pick(&cols, &["caption"], "NULL"),
+            pick(&cols, &["voice_note"], "0"),
+            pick(&cols, &["borderless"], "0"),
+            pick(&cols, &["display_order"], "0"),
+            table,
+            // Quoted thumbnails are not separate attachments in Whisperfish.
+            pick(&cols, &["quote"], "0"),
+        );
+
+        let mut attachments: HashMap<i64, Vec<AndroidAttachment>> = HashMap::new();
+        let rows: Vec<AndroidAttachment> = diesel::sql_query(query).load(&mut self.replay.db)?;
+        for row in rows {
+            attachments.entry(row.message_id).or_default().push(row);
+        }
+        Ok(attachments)
+    }
+
+    fn import_attachment(
+        &mut self,
+        mid: i32,
+        attachment: AndroidAttachment,
+    ) -> Result<(), anyhow::Error> {
+        let staged = match self.replay.attachments.remove(&attachment.id) {
+            Some(staged) => staged,
+            None => {
+                log::warn!("No data in backup for attachment {}", attachment.id);
+                return Ok(());
+            }
+        };
+        let mime_type = attachment
+            .content_type
+            .unwrap_or_else(|| "application/octet-stream".into());
+
+        let mut path = self
+            .attachment_dir
+            .join(uuid::Uuid::new_v4().as_simple().to_string());
+        path.set_extension(extension(&mime_type));
+        // Sailjail mounts the directories separately, which makes a rename fail.
+        if std::fs::rename(&staged, &path).is_err() {
+            std::fs::copy(&staged, &path)?;
+            std::fs::remove_file(&staged)?;
+        }
+
+        self.storage.insert_local_attachment(
+            mid,
+            attachment.display_order,
+            &NewAttachment {
+                path: path.to_str().expect("UTF-8 attachment path").into(),
+                mime_type,
+                caption: attachment.caption.filter(|c| !c.is_empty()),
+                is_voice_note: attachment.voice_note,
+                is_borderless: attachment.borderless,
+            },
+        )?;
+        self.stats.attachments += 1;
+        Ok(())
+    }
+
+    fn import_reactions(&mut self) -> Result<(), anyhow::Error> {
+        let reactions: Vec<AndroidReaction> =
+            diesel::sql_query("SELECT message_id, author_id, emoji, date_sent FROM reaction")
+                .load(&mut self.replay.db)?;
+
+        for reaction in reactions {
+            let (mid, author) = match (
+                self.messages.get(&reaction.message_id),
+                self.recipients.get(&reaction.author_id),
+            ) {
+                (Some(mid), Some(author)) => (*mid, author.id),
+                _ => continue,
+            };
+            self.storage.save_reaction(
+                mid,
+                author,
+                reaction.emoji,
+                millis_to_naive_chrono(reaction.date_sent as u64),
+            );
+            self.stats.reactions += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameter(f: impl FnOnce(&mut SqlParameter)) -> SqlParameter {
+        let mut parameter = SqlParameter::default();
+        f(&mut parameter);
+        parameter
+    }
+
+    #[test]
+    fn inlines_parameters() {
+        let statement = SqlStatement {
+            statement: Some("INSERT INTO t VALUES (?, '?', ?, ?, ?, ?)".into()),
+            parameters: vec![
+                parameter(|p| p.string_paramter = Some("it's".into())),
+                parameter(|p| p.integer_parameter = Some(-2i64 as u64)),
+                parameter(|p| p.double_parameter = Some(1.5)),
+                parameter(|p| p.blob_parameter = Some(vec![0xCA, 0xFE])),
+                parameter(|p| p.nullparameter = Some(true)),
+            ],
+        };
+        assert_eq!(
+            inline_parameters(&statement),
+            "INSERT INTO t VALUES ('it''s', '?', -2, 1.5, X'cafe', NULL)"
+        );
+    }
+
+    #[test]
+    fn keeps_unbound_parameters() {
+        let statement = SqlStatement {
+            statement: Some("SELECT ?, ?".into()),
+            parameters: vec![parameter(|p| p.integer_parameter = Some(1))],
+        };
+        assert_eq!(inline_parameters(&statement), "SELECT 1, ?");
+        // Not a number, so not a literal
+        let nan = parameter(|p| p.double_parameter = Some(f64::NAN));
+        assert_eq!(sql_literal(&nan), "NULL");
+    }
+}
@@ -0,0 +1,73 @@
+mod frames;
+mod import;
+
+use std::{path::PathBuf, sync::Arc};
+use structopt::StructOpt;
+use whisperfish::store::{self, Storage};
+
+/// Imports the history from a Signal Android backup into Whisperfish.
+///
+/// Recipients, threads, messages, reactions, quotes and attachments are imported. Messages that
+/// were imported before are skipped, so the import can be repeated.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "import-signal-backup")]
+struct Opt {
+    /// Whisperfish storage password
+    #[structopt(short, long)]
+    password: Option<String>,
+
+    /// Use an additional account instead of the default one
+    #[structopt(long)]
+    account: Option<String>,
+
+    /// The 30 digit passphrase of the backup. Asked for when not given.
+    #[structopt(long)]
+    passphrase: Option<String>,
+
+    /// The `signal-*.backup` file
+    #[structopt(parse(from_os_str))]
+    backup: PathBuf,
+}
+
+#[actix_rt::main]
+async fn main() -> Result<(), anyhow::Error> {
+    env_logger::init();
+
+    let opt = Opt::from_args();
+
+    let config = whisperfish::config::SignalConfig::read_account_from_file(opt.account.as_deref())?;
+    let config = Arc::new(config);
+    let settings = whisperfish::config::SettingsBridge::for_account(opt.account.as_deref());
+    let attachment_dir = PathBuf::from(settings.get_string("attachment_dir"));
+
+    let mut storage = Storage::open(
+        config,
+        &store::account_location(opt.account.as_deref())?,
+        opt.password,
+    )
+    .await?;
+
+    let passphrase = match opt.passphrase {
+        Some(passphrase) => passphrase,
+        None => rpassword::read_password_from_tty(Some("Backup passphrase: "))?,
+    };
+
+    // The backup is decrypted next to the storage, so that it does not end up on a shared file
+    // system.
+    let scratch = tempfile::tempdir_in(storage.path())?;
+    let mut reader = frames::BackupReader::open(&opt.backup, &passphrase)?;
+    let replay = import::replay(&mut reader, scratch.path())?;
+
+    let mut importer = import::Importer::new(&mut storage, attachment_dir, replay);
+    importer.import()?;
+
+    let stats = &importer.stats;
+    println!("Recipients: {}", stats.recipients);
+    println!("Conversations: {}", stats.sessions);
+    println!("Messages: {}", stats.messages);
+    println!("└ skipped: {}", stats.skipped_messages);
+    println!("Reactions: {}", stats.reactions);
+    println!("Attachments: {}", stats.attachments);
+
+    Ok(())
+}
@@ -0,0 +1,9 @@
+-- Duration in milliseconds and waveform summary of voice notes
+ALTER TABLE attachments
+    ADD COLUMN audio_duration INTEGER DEFAULT NULL;
+ALTER TABLE attachments
+    ADD COLUMN audio_waveform BLOB DEFAULT NULL;
+
+-- When an incoming voice note was played (or other media viewed)
+ALTER TABLE messages
+    ADD COLUMN viewed_timestamp TIMESTAMP DEFAULT NULL;
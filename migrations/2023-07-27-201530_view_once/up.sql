@@ -0,0 +1,3 @@
+-- Media that may only be opened once
+ALTER TABLE messages
+    ADD COLUMN is_view_once BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1 @@
+DROP TABLE sender_certificates;
@@ -0,0 +1,6 @@
+-- Sealed sender certificates, kept until they expire
+CREATE TABLE sender_certificates (
+    kind TEXT PRIMARY KEY ON CONFLICT REPLACE NOT NULL,
+    certificate BLOB NOT NULL,
+    expires TIMESTAMP NOT NULL
+);
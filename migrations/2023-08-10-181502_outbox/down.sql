@@ -0,0 +1,2 @@
+DROP TABLE outbox;
+ALTER TABLE receipts DROP COLUMN sent;
@@ -0,0 +1,17 @@
+-- Outgoing messages that still have to be sent, with their retry schedule
+CREATE TABLE outbox (
+    message_id INTEGER PRIMARY KEY NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_error TEXT,
+
+    FOREIGN KEY(message_id) REFERENCES messages(id) ON DELETE CASCADE
+);
+
+-- When the message was sent to this recipient, so retries can skip them
+ALTER TABLE receipts ADD COLUMN sent TIMESTAMP;
+
+-- Messages that were on their way when Whisperfish last stopped
+INSERT INTO outbox (message_id)
+    SELECT id FROM messages
+    WHERE is_outbound AND sent_timestamp IS NULL AND NOT sending_has_failed;
@@ -0,0 +1,2 @@
+-- When sending the message to this recipient failed; cleared once it was sent after all
+ALTER TABLE receipts ADD COLUMN failed TIMESTAMP;
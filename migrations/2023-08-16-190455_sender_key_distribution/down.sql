@@ -0,0 +1,2 @@
+DROP TABLE sender_key_shared;
+DROP TABLE sender_key_distributions;
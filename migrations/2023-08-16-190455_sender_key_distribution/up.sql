@@ -0,0 +1,19 @@
+-- The distribution id of our sender key in a group; a new id means a new sender key
+CREATE TABLE sender_key_distributions (
+    group_v2_id TEXT PRIMARY KEY NOT NULL,
+    distribution_id TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY(group_v2_id) REFERENCES group_v2s(id) ON DELETE CASCADE
+);
+
+-- The devices that got our sender key for a distribution id
+CREATE TABLE sender_key_shared (
+    distribution_id TEXT NOT NULL,
+    address TEXT NOT NULL,
+    device INTEGER NOT NULL,
+    shared_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY(distribution_id, address, device),
+    FOREIGN KEY(distribution_id) REFERENCES sender_key_distributions(distribution_id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+DROP TABLE sent_content_recipients;
+DROP TABLE sent_content_log;
@@ -0,0 +1,18 @@
+-- What we sent recently, so it can be sent again when a recipient could not decrypt it
+CREATE TABLE sent_content_log (
+    id INTEGER PRIMARY KEY NOT NULL,
+    timestamp TIMESTAMP NOT NULL,
+    content BLOB NOT NULL,
+    sent_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX sent_content_log_timestamp ON sent_content_log(timestamp);
+
+-- The recipients of the logged content that did not confirm delivery yet
+CREATE TABLE sent_content_recipients (
+    sent_content_id INTEGER NOT NULL,
+    address TEXT NOT NULL,
+
+    PRIMARY KEY(sent_content_id, address),
+    FOREIGN KEY(sent_content_id) REFERENCES sent_content_log(id) ON DELETE CASCADE
+);
@@ -0,0 +1,10 @@
+DELETE FROM sent_content_log;
+DROP TABLE sent_content_recipients;
+
+CREATE TABLE sent_content_recipients (
+    sent_content_id INTEGER NOT NULL,
+    address TEXT NOT NULL,
+
+    PRIMARY KEY(sent_content_id, address),
+    FOREIGN KEY(sent_content_id) REFERENCES sent_content_log(id) ON DELETE CASCADE
+);
@@ -0,0 +1,14 @@
+-- Retry requests and delivery receipts come from a single device, so the recipients of logged
+-- content are tracked per device.  The old entries do not tell which devices got the content;
+-- the log is short-lived, so it starts over.
+DELETE FROM sent_content_log;
+DROP TABLE sent_content_recipients;
+
+CREATE TABLE sent_content_recipients (
+    sent_content_id INTEGER NOT NULL,
+    address TEXT NOT NULL,
+    device_id INTEGER NOT NULL,
+
+    PRIMARY KEY(sent_content_id, address, device_id),
+    FOREIGN KEY(sent_content_id) REFERENCES sent_content_log(id) ON DELETE CASCADE
+);
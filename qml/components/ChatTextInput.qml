@@ -35,6 +35,7 @@ Item {
                                      attachments.length > 0)
 
     signal sendMessage(var text, var attachments, var replyTo /* message id */)
+    signal sendVoiceNote(var path, var replyTo /* message id */)
     signal sendTypingNotification()
     signal sendTypingNotificationEnd()
     signal quotedMessageClicked(var messageId)
@@ -263,7 +264,7 @@ Item {
                     bottom: moreButton.top
                 }
                 width: cameraButton.width
-                height: cameraButton.height + attachButton.height + (2 * Theme.paddingSmall)
+                height: cameraButton.height + attachButton.height + voiceNoteButton.height + (3 * Theme.paddingSmall)
 
                 clip: false
 
@@ -315,6 +316,23 @@ Item {
                         pageStack.push(multiDocumentPickerDialog)
                     }
                 }
+
+                IconButton {
+                    id: voiceNoteButton
+                    anchors {
+                        top: attachButton.bottom
+                        topMargin: Theme.paddingSmall
+                        horizontalCenter: parent.horizontalCenter
+                    }
+                    icon.source: "image://theme/icon-m-mic"
+                    icon.width: enableAttachments ? Theme.iconSizeMedium : 0
+                    icon.height: icon.width
+                    visible: enableAttachments
+                    onClicked: {
+                        inputRow.toggleAttachmentButtons()
+                        pageStack.push(voiceNotePickerPage)
+                    }
+                }
             }
 
             IconButton {
@@ -364,6 +382,19 @@ Item {
                 }
             }
 
+            Component {
+                id: voiceNotePickerPage
+                SoundPickerPage {
+                    //: Voice note picker page title
+                    //% "Select a recording"
+                    title: qsTrId("whisperfish-select-voice-note-page-title")
+                    onSelectedContentPropertiesChanged: {
+                        sendVoiceNote(selectedContentProperties.filePath, quoteItem.messageId)
+                        resetQuote()
+                    }
+                }
+            }
+
             Component {
                 id: multiDocumentPickerDialog
                 MultiContentPickerDialog {
@@ -430,7 +430,7 @@ SilicaListView {
                 //: Resend message menu item
                 //% "Retry sending"
                 text: qsTrId("whisperfish-resend-message-menu")
-                visible: !!(menu.parent && menu.parent.modelData.failed)
+                visible: !!(menu.parent && (menu.parent.modelData.failed || menu.parent.modelData.queued))
                 onClicked: resendInline(menu.parent)
             }
             MenuItem {
@@ -6,5 +6,13 @@ import Sailfish.Silica 1.0
 // TODO distinguish between voice notes and attached audio files
 // TODO play audio inline
 AttachmentItemFile {
-    // this is a stub
+    property int messageId: -1
+    property bool outgoing: false
+
+    // Opening a received voice note counts as listening to it.
+    onClicked: {
+        if (_hasAttach && attach.is_voice_note && !outgoing && messageId > -1) {
+            MessageModel.markViewed(messageId)
+        }
+    }
 }
@@ -16,6 +16,8 @@ MouseArea {
     property bool _isAnimated: _hasAttach ? /\.(gif)$/i.test(attach.data) : false
     property bool _isVideo: _hasAttach ? /^video\//.test(attach.type) : false
     property bool _isAnimatedPaused: false
+    // View-once media is not shown inline, and opens only once
+    property bool _isViewOnce: message ? message.viewOnce : false
 
     Recipient {
         id: recipient
@@ -37,6 +39,10 @@ MouseArea {
     onClicked: {
         if (!_hasAttach) {
             return
+        } else if (_isViewOnce) {
+            if (!message.viewed) {
+                MessageModel.openViewOnce(message.id)
+            }
         } else if (_isAnimatedPaused && animationLoader.item) {
             _isAnimatedPaused = false
             animationLoader.item.paused = false
@@ -54,7 +60,7 @@ MouseArea {
                 'path': attach.data,
                 'isAnimated': _isAnimated,
                 'attachment': attach,
-                'isViewOnce': false, // TODO: Implement attachment can only be viewed once
+                'isViewOnce': false,
             })
         }
     }
@@ -65,10 +71,10 @@ MouseArea {
     Thumbnail {
         id: nemoThumbnail
         visible: opacity > 0.0
-        opacity: (!_isAnimated && attach.data != null && (attach.visual_hash == null || status == Thumbnail.Ready)) ? 1.0 : 0.0
+        opacity: (!_isAnimated && !_isViewOnce && attach.data != null && (attach.visual_hash == null || status == Thumbnail.Ready)) ? 1.0 : 0.0
         Behavior on opacity { NumberAnimation { duration: 250 } }
         width: parent.width; height: parent.height
-        source: (!_isAnimated && _hasAttach && attach.data != null) ? attach.data : ''
+        source: (!_isAnimated && !_isViewOnce && _hasAttach && attach.data != null) ? attach.data : ''
         sourceSize { width: width; height: height }
 
         onStatusChanged: {
@@ -91,14 +97,15 @@ MouseArea {
         id: animationLoader
         anchors.fill: parent
         asynchronous: true
-        sourceComponent: _isAnimated ? animatedComponent : null
+        sourceComponent: (_isAnimated && !_isViewOnce) ? animatedComponent : null
     }
 
     HighlightImage {
         highlighted: parent.highlighted ? true : undefined
         anchors.centerIn: parent
         width: Theme.iconSizeLarge; height: width
-        source: (_isVideo || _isAnimatedPaused) ? 'image://theme/icon-l-play' : ''
+        source: _isViewOnce ? 'image://theme/icon-l-image' :
+                (_isVideo || _isAnimatedPaused) ? 'image://theme/icon-l-play' : ''
     }
 
     Rectangle {
@@ -347,6 +347,8 @@ Loader {
         id: detail_audioComponent
         AttachmentItemAudio {
             attach: JSON.parse(detailAttachments.get(currentAttachmentIndex))
+            messageId: root.messageId
+            outgoing: !!message.outgoing
             onPressAndHold: root.pressAndHold(mouse)
         }
     }
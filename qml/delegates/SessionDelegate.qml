@@ -378,6 +378,14 @@ ListItem {
                 onClicked: delayedArchivedAction = true
             }
 
+            MenuItem {
+                //: Export the conversation to a file
+                //% "Export conversation"
+                text: qsTrId("whisperfish-session-export")
+                onClicked: pageStack.push(Qt.resolvedUrl("../pages/ExportConversationDialog.qml"),
+                                          { sessionId: model.id, name: name })
+            }
+
             MenuItem {
                 visible: !isGroup
                 enabled: !isGroup
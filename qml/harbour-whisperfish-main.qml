@@ -2,6 +2,7 @@ import QtQuick 2.2
 import Sailfish.Silica 1.0
 import Nemo.Notifications 1.0
 import Nemo.DBus 2.0
+import Nemo.Connectivity 1.0
 import org.nemomobile.contacts 1.0
 import "pages"
 
@@ -293,7 +294,7 @@ ApplicationWindow
     }
 
     DBusAdaptor {
-        service: "be.rubdos.whisperfish"
+        service: DBusServiceName
         path: "/be/rubdos/whisperfish/app"
         iface: "be.rubdos.whisperfish.app"
 
@@ -345,6 +346,85 @@ ApplicationWindow
             dbusShareClient.call("done")
         }
     }
+    // Interface for other applications and scripts, documented in doc/dbus-api.md.
+    // The actual work is done by the DBusApi object.
+    DBusAdaptor {
+        id: dbusApi
+        service: DBusServiceName
+        path: "/be/rubdos/whisperfish/api"
+        iface: "be.rubdos.whisperfish.api"
+
+        xml: '  <interface name="be.rubdos.whisperfish.api">\n' +
+             '    <method name="sendMessage">\n' +
+             '      <arg name="recipient" type="s" direction="in"/>\n' +
+             '      <arg name="message" type="s" direction="in"/>\n' +
+             '      <arg name="sessionId" type="i" direction="out"/>\n' +
+             '    </method>\n' +
+             '    <method name="sendSessionMessage">\n' +
+             '      <arg name="sessionId" type="i" direction="in"/>\n' +
+             '      <arg name="message" type="s" direction="in"/>\n' +
+             '      <arg name="queued" type="b" direction="out"/>\n' +
+             '    </method>\n' +
+             '    <method name="listSessions">\n' +
+             '      <arg name="sessions" type="s" direction="out"/>\n' +
+             '    </method>\n' +
+             '    <method name="getMessage">\n' +
+             '      <arg name="messageId" type="i" direction="in"/>\n' +
+             '      <arg name="message" type="s" direction="out"/>\n' +
+             '    </method>\n' +
+             '    <method name="markRead">\n' +
+             '      <arg name="sessionId" type="i" direction="in"/>\n' +
+             '      <arg name="found" type="b" direction="out"/>\n' +
+             '    </method>\n' +
+             '    <method name="isConnected">\n' +
+             '      <arg name="connected" type="b" direction="out"/>\n' +
+             '    </method>\n' +
+             '    <signal name="messageReceived">\n' +
+             '      <arg name="sessionId" type="i"/>\n' +
+             '      <arg name="messageId" type="i"/>\n' +
+             '      <arg name="message" type="s"/>\n' +
+             '    </signal>\n' +
+             '    <signal name="connectedChanged">\n' +
+             '      <arg name="connected" type="b"/>\n' +
+             '    </signal>\n' +
+             '  </interface>\n'
+
+        function sendMessage(recipient, message) {
+            return DBusApi.sendMessage(recipient, message)
+        }
+
+        function sendSessionMessage(sid, message) {
+            return DBusApi.sendSessionMessage(sid, message)
+        }
+
+        function listSessions() {
+            return DBusApi.listSessions()
+        }
+
+        function getMessage(mid) {
+            return DBusApi.getMessage(mid)
+        }
+
+        function markRead(sid) {
+            return DBusApi.markRead(sid)
+        }
+
+        function isConnected() {
+            return ClientWorker.connected
+        }
+    }
+
+    ConnectionHelper {
+        // Reconnects right away when the network comes back
+        onOnlineChanged: ClientWorker.set_network_available(online)
+    }
+
+    Connections {
+        target: ClientWorker
+        onMessageReceived: dbusApi.emitSignal("messageReceived", [sid, mid, DBusApi.getMessage(mid)])
+        onConnectedChanged: dbusApi.emitSignal("connectedChanged", [ClientWorker.connected])
+    }
+
     DBusInterface {
         id: dbusShareClient
         service: "be.rubdos.whisperfish.shareClient.c" + shareClientId
@@ -0,0 +1,78 @@
+import QtQuick 2.2
+import Sailfish.Silica 1.0
+
+Page {
+    id: accountsPage
+    objectName: "accountsPage"
+
+    property var accounts: AppState.accounts()
+    property string currentAccount: AppState.accountName()
+
+    SilicaListView {
+        id: listView
+        anchors.fill: parent
+        model: accounts
+
+        header: Column {
+            width: parent.width
+
+            PageHeader {
+                //: Title for the accounts page
+                //% "Accounts"
+                title: qsTrId("whisperfish-accounts-title")
+            }
+
+            TextField {
+                id: newAccountField
+                width: parent.width
+                inputMethodHints: Qt.ImhNoAutoUppercase | Qt.ImhNoPredictiveText
+                validator: RegExpValidator { regExp: /^[a-z0-9_]{1,32}$/ }
+                //: Label of the text field to add an account
+                //% "New account"
+                label: qsTrId("whisperfish-accounts-new-label")
+                //: Placeholder of the text field to add an account
+                //% "Name of a new account, e.g. work"
+                placeholderText: qsTrId("whisperfish-accounts-new-placeholder")
+                //: Description of the text field to add an account
+                //% "Lowercase letters, digits and underscores. The account is set up in a new window."
+                description: qsTrId("whisperfish-accounts-new-description")
+
+                EnterKey.enabled: acceptableInput
+                EnterKey.iconSource: "image://theme/icon-m-enter-accept"
+                EnterKey.onClicked: {
+                    if (AppState.switchAccount(text)) {
+                        text = ""
+                        accounts = AppState.accounts()
+                    }
+                }
+            }
+        }
+
+        delegate: ListItem {
+            id: delegate
+            contentHeight: Theme.itemSizeSmall
+            property string account: modelData
+
+            Label {
+                anchors {
+                    left: parent.left
+                    leftMargin: Theme.horizontalPageMargin
+                    right: parent.right
+                    rightMargin: Theme.horizontalPageMargin
+                    verticalCenter: parent.verticalCenter
+                }
+                truncationMode: TruncationMode.Fade
+                highlighted: delegate.highlighted || account === currentAccount
+                text: account !== ""
+                    ? account
+                      //: Name of the default account in the list of accounts
+                      //% "Default account"
+                    : qsTrId("whisperfish-accounts-default")
+            }
+
+            onClicked: AppState.switchAccount(account)
+        }
+
+        VerticalScrollDecorator {}
+    }
+}
@@ -0,0 +1,153 @@
+import QtQuick 2.2
+import Sailfish.Silica 1.0
+
+Page {
+    id: changeNumberPage
+    objectName: "changeNumberPage"
+
+    // A verification code was sent to the new number
+    property bool codeRequested: false
+    property bool busy: false
+
+    Connections {
+        target: ClientWorker
+        onNumberChangeCodeRequested: {
+            busy = false
+            codeRequested = true
+            statusLabel.text = ""
+        }
+        onNumberChanged: {
+            busy = false
+            codeRequested = false
+            //: Change phone number page, number changed
+            //% "Your phone number is now %1."
+            statusLabel.text = qsTrId("whisperfish-change-number-changed").arg(number)
+        }
+        onNumberChangeFailed: {
+            busy = false
+            //: Change phone number page, change failed
+            //% "The phone number could not be changed. Please check the number or code and try again."
+            statusLabel.text = qsTrId("whisperfish-change-number-failed")
+        }
+    }
+
+    SilicaFlickable {
+        anchors.fill: parent
+        contentHeight: column.height + Theme.paddingLarge
+
+        VerticalScrollDecorator {}
+
+        Column {
+            id: column
+            width: parent.width
+            spacing: Theme.paddingLarge
+
+            PageHeader {
+                //: Change phone number page title
+                //% "Change phone number"
+                title: qsTrId("whisperfish-change-number-title")
+                description: SetupWorker.phoneNumber
+            }
+
+            Label {
+                anchors {
+                    left: parent.left
+                    leftMargin: Theme.horizontalPageMargin
+                    right: parent.right
+                    rightMargin: Theme.horizontalPageMargin
+                }
+                wrapMode: Text.Wrap
+                color: Theme.secondaryHighlightColor
+                font.pixelSize: Theme.fontSizeSmall
+                //: Change phone number page explanation
+                //% "Your conversations, groups and profile move to the new number. Signal sends a verification code to the new number. Unlink your other devices first."
+                text: qsTrId("whisperfish-change-number-description")
+            }
+
+            TextField {
+                id: numberField
+                width: parent.width
+                enabled: !busy && !codeRequested
+                inputMethodHints: Qt.ImhDialableCharactersOnly
+                //: Change phone number page, new number field label
+                //% "New phone number"
+                label: qsTrId("whisperfish-change-number-number-label")
+                placeholderText: label
+                EnterKey.enabled: text.trim().length > 0
+                EnterKey.onClicked: requestButton.clicked(null)
+            }
+
+            TextSwitch {
+                id: voiceSwitch
+                enabled: !busy && !codeRequested
+                //: Change phone number page, receive the code by voice call
+                //% "Call me with the code"
+                text: qsTrId("whisperfish-change-number-voice")
+            }
+
+            Button {
+                id: requestButton
+                anchors.horizontalCenter: parent.horizontalCenter
+                visible: !codeRequested
+                enabled: !busy && numberField.text.trim().length > 0
+                //: Change phone number page, request a verification code
+                //% "Send code"
+                text: qsTrId("whisperfish-change-number-request-code")
+                onClicked: {
+                    busy = true
+                    statusLabel.text = ""
+                    ClientWorker.change_number(numberField.text.trim(), voiceSwitch.checked)
+                }
+            }
+
+            TextField {
+                id: codeField
+                width: parent.width
+                visible: codeRequested
+                enabled: !busy
+                inputMethodHints: Qt.ImhDigitsOnly
+                validator: RegExpValidator { regExp: /^[0-9]{3}-?[0-9]{3}$/ }
+                //: Change phone number page, verification code field label
+                //% "Verification code"
+                label: qsTrId("whisperfish-change-number-code-label")
+                placeholderText: label
+                EnterKey.enabled: acceptableInput
+                EnterKey.onClicked: confirmButton.clicked(null)
+            }
+
+            Button {
+                id: confirmButton
+                anchors.horizontalCenter: parent.horizontalCenter
+                visible: codeRequested
+                enabled: !busy && codeField.acceptableInput
+                //: Change phone number page, confirm the change
+                //% "Change number"
+                text: qsTrId("whisperfish-change-number-confirm")
+                onClicked: {
+                    busy = true
+                    ClientWorker.confirm_number_change(codeField.text.replace('-', ''))
+                }
+            }
+
+            BusyIndicator {
+                anchors.horizontalCenter: parent.horizontalCenter
+                size: BusyIndicatorSize.Medium
+                running: busy
+                visible: running
+            }
+
+            Label {
+                id: statusLabel
+                anchors {
+                    left: parent.left
+                    leftMargin: Theme.horizontalPageMargin
+                    right: parent.right
+                    rightMargin: Theme.horizontalPageMargin
+                }
+                visible: text.length > 0
+                wrapMode: Text.Wrap
+                color: Theme.highlightColor
+            }
+        }
+    }
+}
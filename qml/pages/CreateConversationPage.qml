@@ -11,6 +11,8 @@ Page {
     property alias sessionId: createConversation.sessionId
     property alias e164: createConversation.e164
     property alias uuid: createConversation.uuid
+    property alias username: createConversation.username
+    property bool usernameNotFound: false
 
     function attemptTransition() {
         if (sessionId != -1) {
@@ -39,6 +41,21 @@ Page {
         }
     }
 
+    Connections {
+        target: ClientWorker
+        onUsernameNotFound: {
+            if (username.toLowerCase() === createConversation.username) {
+                usernameNotFound = true
+            }
+        }
+    }
+
+    Component.onCompleted: {
+        if (createConversation.username !== "") {
+            ClientWorker.find_username(createConversation.username)
+        }
+    }
+
 
     PageHeader {
         //: Page header title when a new conversation is being created
@@ -50,7 +67,15 @@ Page {
     BusyIndicator {
         size: BusyIndicatorSize.Large
         anchors.centerIn: parent
-        running: !createConversation.invalid && !createConversation.ready
+        running: !createConversation.invalid && !createConversation.ready && !usernameNotFound
+    }
+
+    ViewPlaceholder {
+        enabled: usernameNotFound
+        //: Placeholder when starting a conversation with an unknown username
+        //% "Username not found"
+        text: qsTrId("whisperfish-creating-conversation-username-not-found")
+        hintText: createConversation.username
     }
 }
 
@@ -0,0 +1,68 @@
+import QtQuick 2.2
+import Sailfish.Silica 1.0
+
+Dialog {
+    id: exportDialog
+    objectName: "exportConversationDialog"
+
+    property int sessionId
+    property string name
+
+    onAccepted: SessionModel.exportSession(sessionId, formatCombo.currentItem.format)
+
+    Column {
+        width: parent.width
+        spacing: Theme.paddingLarge
+
+        DialogHeader {
+            //: Export conversation dialog accept button
+            //% "Export"
+            acceptText: qsTrId("whisperfish-export-conversation-accept")
+            //: Export conversation dialog title
+            //% "Export conversation"
+            title: qsTrId("whisperfish-export-conversation-title")
+        }
+
+        Label {
+            anchors {
+                left: parent.left
+                leftMargin: Theme.horizontalPageMargin
+                right: parent.right
+                rightMargin: Theme.horizontalPageMargin
+            }
+            wrapMode: Text.Wrap
+            color: Theme.highlightColor
+            //: Export conversation dialog explanation
+            //% "The conversation with %1 and its attachments will be saved in the Documents/Whisperfish folder. The export is not encrypted."
+            text: qsTrId("whisperfish-export-conversation-description").arg(name)
+        }
+
+        ComboBox {
+            id: formatCombo
+            width: parent.width
+            //: Export conversation file format
+            //% "Format"
+            label: qsTrId("whisperfish-export-conversation-format")
+            menu: ContextMenu {
+                MenuItem {
+                    property string format: "html"
+                    //: Export conversation as a web page
+                    //% "Web page (HTML)"
+                    text: qsTrId("whisperfish-export-conversation-html")
+                }
+                MenuItem {
+                    property string format: "json"
+                    //: Export conversation as JSON
+                    //% "JSON"
+                    text: qsTrId("whisperfish-export-conversation-json")
+                }
+                MenuItem {
+                    property string format: "text"
+                    //: Export conversation as plain text
+                    //% "Plain text"
+                    text: qsTrId("whisperfish-export-conversation-text")
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+import QtQuick 2.2
+import Sailfish.Silica 1.0
+
+Dialog {
+    id: findUsernameDialog
+    objectName: "findUsernameDialog"
+
+    // A username has a nickname and a numeric discriminator, e.g. alice.42
+    property var usernameFormat: /^[A-Za-z_][A-Za-z0-9_]{2,31}\.[0-9]{2,9}$/
+
+    canAccept: usernameFormat.test(usernameField.text.trim())
+    acceptDestination: Qt.resolvedUrl("CreateConversationPage.qml")
+    acceptDestinationAction: PageStackAction.Replace
+    acceptDestinationProperties: { "username": usernameField.text.trim() }
+
+    Column {
+        width: parent.width
+        spacing: Theme.paddingLarge
+
+        DialogHeader {
+            //: Find by username dialog accept button
+            //% "Find"
+            acceptText: qsTrId("whisperfish-find-username-accept")
+            //: Find by username dialog title
+            //% "Find by username"
+            title: qsTrId("whisperfish-find-username-title")
+        }
+
+        TextField {
+            id: usernameField
+            width: parent.width
+            focus: true
+            inputMethodHints: Qt.ImhNoAutoUppercase | Qt.ImhNoPredictiveText
+            //: Find by username text field label
+            //% "Username"
+            label: qsTrId("whisperfish-find-username-label")
+            placeholderText: "alice.42"
+            EnterKey.enabled: findUsernameDialog.canAccept
+            EnterKey.iconSource: "image://theme/icon-m-enter-accept"
+            EnterKey.onClicked: findUsernameDialog.accept()
+        }
+
+        Label {
+            anchors {
+                left: parent.left
+                leftMargin: Theme.horizontalPageMargin
+                right: parent.right
+                rightMargin: Theme.horizontalPageMargin
+            }
+            wrapMode: Text.Wrap
+            color: Theme.secondaryHighlightColor
+            font.pixelSize: Theme.fontSizeSmall
+            //: Find by username dialog explanation
+            //% "Enter the full username, including the number after the dot."
+            text: qsTrId("whisperfish-find-username-description")
+        }
+    }
+}
@@ -33,6 +33,31 @@ Page {
         previewBody: qsTrId("whisperfish-update-reminder-body")
     }
 
+    Notification {
+        id: exportNotification
+        appIcon: "harbour-whisperfish"
+        appName: "Whisperfish"
+        category: "harbour-whisperfish-export"
+    }
+
+    Connections {
+        target: SessionModel
+        onSessionExported: {
+            //: Notification title after a conversation was exported
+            //% "Conversation exported"
+            exportNotification.previewSummary = qsTrId("whisperfish-export-conversation-done")
+            exportNotification.previewBody = path
+            exportNotification.publish()
+        }
+        onSessionExportFailed: {
+            //: Notification title when exporting a conversation failed
+            //% "Exporting the conversation failed"
+            exportNotification.previewSummary = qsTrId("whisperfish-export-conversation-failed")
+            exportNotification.previewBody = ""
+            exportNotification.publish()
+        }
+    }
+
     Component.onCompleted: {
         var now = new Date()
         var then = Date.parse(buildDate)
@@ -19,6 +19,41 @@ Page {
         messageId: message.id
     }
 
+    Receipts {
+        id: receipts
+        app: AppState
+        messageId: message.id
+    }
+
+    function statusText(status) {
+        switch (status) {
+        case "viewed":
+            //: Delivery status of a message for one recipient: they viewed it
+            //% "Viewed"
+            return qsTrId("whisperfish-message-info-status-viewed")
+        case "read":
+            //: Delivery status of a message for one recipient: they read it
+            //% "Read"
+            return qsTrId("whisperfish-message-info-status-read")
+        case "delivered":
+            //: Delivery status of a message for one recipient: it reached their device
+            //% "Delivered"
+            return qsTrId("whisperfish-message-info-status-delivered")
+        case "sent":
+            //: Delivery status of a message for one recipient: it was sent to them
+            //% "Sent"
+            return qsTrId("whisperfish-message-info-status-sent")
+        case "failed":
+            //: Delivery status of a message for one recipient: it could not be sent to them
+            //% "Failed"
+            return qsTrId("whisperfish-message-info-status-failed")
+        default:
+            //: Delivery status of a message for one recipient: it was not sent yet
+            //% "Pending"
+            return qsTrId("whisperfish-message-info-status-pending")
+        }
+    }
+
     SilicaFlickable {
         id: silicaFlickable
         anchors.fill: parent
@@ -100,6 +135,35 @@ Page {
                 label: qsTrId("whisperfish-message-timestamp")
                 value: message.timestamp
             }
+            SectionHeader {
+                visible: outgoing && receipts.count
+                //: Section header for the delivery status of a message per recipient
+                //% "Delivery"
+                text: qsTrId("whisperfish-message-info-delivery")
+            }
+            ListView {
+                id: receiptView
+                visible: outgoing
+                width: parent.width
+                height: childrenRect.height
+                model: receipts.receipts
+                delegate: ListItem {
+                    width: parent.width
+                    height: childrenRect.height
+                    DetailItem {
+                        label: model.name !== "" ? model.name : model.e164
+                        value: statusText(model.status)
+                    }
+                }
+            }
+            Button {
+                visible: outgoing && message.failed && receipts.failedCount > 0
+                anchors.horizontalCenter: parent.horizontalCenter
+                //: Button to send a message again to the recipients it failed for
+                //% "Retry sending"
+                text: qsTrId("whisperfish-resend-message-menu")
+                onClicked: MessageModel.sendMessage(message.id)
+            }
             SectionHeader {
                 visible: reactions.count
                 //: Reactions section header
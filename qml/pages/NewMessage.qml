@@ -81,6 +81,15 @@ Page {
 
         RemorsePopup { id: remorse }
 
+        PullDownMenu {
+            MenuItem {
+                //: Menu item to start a conversation by username
+                //% "Find by username"
+                text: qsTrId("whisperfish-find-username-menu")
+                onClicked: pageStack.push(Qt.resolvedUrl("FindUsernameDialog.qml"))
+            }
+        }
+
         Column {
             id: content
             y: newMessagePage.isLandscape ? Theme.paddingMedium : 0
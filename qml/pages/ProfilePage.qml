@@ -406,6 +406,144 @@ Page {
                 }
             }
 
+            TextField {
+                visible: !isOwnProfile && text.length > 0
+                width: parent.width
+                readOnly: true
+                font.pixelSize: Theme.fontSizeMedium
+                //: Profile username field
+                //% "Username"
+                label: qsTrId("whisperfish-profile-username")
+                text: recipient.username
+            }
+
+            SectionHeader {
+                visible: isOwnProfile
+                //: Own profile, username section header
+                //% "Username"
+                text: qsTrId("whisperfish-profile-username")
+            }
+
+            Column {
+                id: usernameColumn
+                visible: isOwnProfile
+                width: parent.width
+                spacing: Theme.paddingMedium
+
+                property string reserved: ""
+                property bool busy: false
+                property bool failed: false
+
+                Connections {
+                    target: ClientWorker
+                    onUsernameReserved: {
+                        usernameColumn.busy = false
+                        usernameColumn.reserved = username
+                    }
+                    onUsernameChanged: {
+                        usernameColumn.busy = false
+                        usernameColumn.reserved = ""
+                    }
+                    onUsernameFailed: {
+                        usernameColumn.busy = false
+                        usernameColumn.failed = true
+                    }
+                }
+
+                Label {
+                    id: usernameStatus
+                    x: Theme.horizontalPageMargin
+                    width: parent.width - 2*Theme.horizontalPageMargin
+                    wrapMode: Text.Wrap
+                    color: Theme.highlightColor
+                    text: usernameColumn.failed
+                        //: Own profile, setting or deleting the username failed
+                        //% "Changing the username failed"
+                        ? qsTrId("whisperfish-profile-username-failed")
+                        : usernameColumn.reserved !== ""
+                        //: Own profile, a username is reserved but not confirmed yet
+                        //% "%1 is available. Confirm to make it yours."
+                        ? qsTrId("whisperfish-profile-username-reserved").arg(usernameColumn.reserved)
+                        : ClientWorker.username !== ""
+                        ? ClientWorker.username
+                        //: Own profile, no username set
+                        //% "You have no username. Others can find you by username without knowing your phone number."
+                        : qsTrId("whisperfish-profile-username-none")
+                }
+
+                TextField {
+                    id: nicknameField
+                    visible: usernameColumn.reserved === ""
+                    width: parent.width
+                    inputMethodHints: Qt.ImhNoAutoUppercase | Qt.ImhNoPredictiveText
+                    validator: RegExpValidator { regExp: /^[A-Za-z_][A-Za-z0-9_]{2,31}$/ }
+                    //: Own profile, nickname part of a new username
+                    //% "New username, without number"
+                    label: qsTrId("whisperfish-profile-username-nickname")
+                    placeholderText: label
+                }
+
+                Button {
+                    anchors.horizontalCenter: parent.horizontalCenter
+                    enabled: !usernameColumn.busy && (usernameColumn.reserved !== "" || nicknameField.acceptableInput)
+                    text: usernameColumn.reserved !== ""
+                        //: Own profile, confirm the reserved username button
+                        //% "Confirm username"
+                        ? qsTrId("whisperfish-profile-username-confirm")
+                        //: Own profile, reserve a username button
+                        //% "Check availability"
+                        : qsTrId("whisperfish-profile-username-reserve")
+                    onClicked: {
+                        usernameColumn.busy = true
+                        usernameColumn.failed = false
+                        if (usernameColumn.reserved !== "") {
+                            ClientWorker.confirm_username()
+                        } else {
+                            ClientWorker.reserve_username(nicknameField.text)
+                        }
+                    }
+                }
+
+                Button {
+                    anchors.horizontalCenter: parent.horizontalCenter
+                    visible: ClientWorker.username !== "" && usernameColumn.reserved === ""
+                    enabled: !usernameColumn.busy
+                    //: Own profile, delete username button
+                    //% "Delete username"
+                    text: qsTrId("whisperfish-profile-username-delete")
+                    onClicked: {
+                        //: Own profile, username deleted remorse message (past tense)
+                        //% "Username deleted"
+                        remorse.execute(qsTrId("whisperfish-profile-username-deleted"),
+                            function() {
+                                usernameColumn.busy = true
+                                usernameColumn.failed = false
+                                ClientWorker.delete_username()
+                            })
+                    }
+                }
+
+                Image {
+                    visible: ClientWorker.username !== ""
+                    anchors.horizontalCenter: parent.horizontalCenter
+                    width: Math.min(parent.width - 2*Theme.horizontalPageMargin, 2*Theme.itemSizeHuge)
+                    height: width
+                    smooth: false
+                    fillMode: Image.PreserveAspectFit
+                    source: ClientWorker.username !== "" ? ClientWorker.username_qr(ClientWorker.username) : ""
+                }
+
+                LinkedText {
+                    visible: ClientWorker.username !== ""
+                    x: Theme.horizontalPageMargin
+                    width: parent.width - 2*Theme.horizontalPageMargin
+                    wrapMode: Text.WrapAnywhere
+                    horizontalAlignment: Text.AlignHCenter
+                    font.pixelSize: Theme.fontSizeExtraSmall
+                    plainText: ClientWorker.username !== "" ? ClientWorker.username_link(ClientWorker.username) : ""
+                }
+            }
+
             Separator {
                 visible: isOwnProfile
                 horizontalAlignment: Qt.AlignHCenter
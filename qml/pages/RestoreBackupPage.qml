@@ -0,0 +1,101 @@
+import QtQuick 2.2
+import Sailfish.Silica 1.0
+import "../components"
+
+BlockingInfoPageBase {
+    id: root
+    objectName: "restoreBackupPage"
+
+    pageTitle: "" // xx("Step 1.1")
+
+    //: restore backup page title
+    //% "Restore backup"
+    mainTitle: qsTrId("whisperfish-restore-backup-title")
+
+    //: restore backup prompt
+    //% "A Whisperfish backup was found in your Downloads folder. Enter its passphrase to continue with the account in it, or skip to register."
+    mainDescription: qsTrId("whisperfish-restore-backup-prompt")
+
+    property bool _canAccept: passphraseField.text !== ''
+
+    signal accept
+    onAccept: {
+        if (!_canAccept) return
+        Prompt.backupPassphrase(passphraseField.text)
+        busy = true // restoring takes a while
+    }
+
+    signal skip
+    onSkip: {
+        Prompt.backupPassphrase('') // register instead
+        busy = true // wait for the backend to prompt the next step
+    }
+
+    signal _retry
+    on_Retry: {
+        //: restore backup prompt after a wrong passphrase
+        //% "The passphrase does not open the backup. Please try again, or skip to register."
+        mainDescription = qsTrId("whisperfish-restore-backup-retry-message")
+        passphraseField.text = ''
+        busy = false
+    }
+
+    Connections {
+        target: Prompt
+        // Asked again when the passphrase was wrong
+        onPromptBackupPassphrase: _retry()
+        onPromptRegistrationType: {
+            root.forceActiveFocus() // to close the keyboard
+            pageStack.replace(Qt.resolvedUrl("SetupRegistrationTypePage.qml"),
+                              PageStackAction.Animated)
+        }
+    }
+
+    Connections {
+        target: SetupWorker
+        onSetupComplete: {
+            if (SetupWorker.registered) {
+                showMainPage()
+            }
+        }
+    }
+
+    Column {
+        width: parent.width
+        spacing: 1.5*Theme.paddingLarge
+
+        PasswordField {
+            id: passphraseField
+            anchors.horizontalCenter: parent.horizontalCenter
+            width: parent.width - 2*Theme.horizontalPageMargin
+            inputMethodHints: Qt.ImhNoPredictiveText | Qt.ImhSensitiveData
+            //: Backup passphrase label
+            //% "Backup passphrase"
+            label: qsTrId("whisperfish-restore-backup-passphrase-label")
+            //: Backup passphrase input placeholder
+            //% "Passphrase of the backup"
+            placeholderText: qsTrId("whisperfish-restore-backup-passphrase-placeholder")
+            EnterKey.iconSource: "image://theme/icon-m-enter-accept"
+            EnterKey.onClicked: accept()
+        }
+
+        Row {
+            anchors.horizontalCenter: parent.horizontalCenter
+            spacing: Theme.paddingMedium
+            Button {
+                //: restore button label
+                //% "Restore"
+                text: qsTrId("whisperfish-restore-backup-button-label")
+                enabled: _canAccept && !busy
+                onClicked: accept()
+            }
+            Button {
+                //: skip button label
+                //% "Skip"
+                text: qsTrId("whisperfish-skip-button-label")
+                enabled: !busy
+                onClicked: skip()
+            }
+        }
+    }
+}
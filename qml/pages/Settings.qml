@@ -15,6 +15,8 @@ Page {
 
     // Cache encryption state so it's only queried once from storage
     property bool encryptedDatabase: AppState.isEncrypted()
+    property bool _exportingBackup: false
+    property bool _changingPassword: false
 
     SilicaFlickable {
         anchors.fill: parent
@@ -32,7 +34,7 @@ Page {
                 }
             }
             MenuItem {
-                visible: false // XXX: Unimplemented
+                visible: !ClientWorker.connected && ClientWorker.connectionState !== "deregistered"
                 //: Reconnect menu
                 //% "Reconnect"
                 text: qsTrId("whisperfish-settings-reconnect-menu")
@@ -40,6 +42,18 @@ Page {
                     ClientWorker.reconnect()
                 }
             }
+            MenuItem {
+                //: Change phone number menu option
+                //% "Change phone number"
+                text: qsTrId("whisperfish-settings-change-number-menu")
+                onClicked: pageStack.push(Qt.resolvedUrl("ChangeNumberPage.qml"))
+            }
+            MenuItem {
+                //: Accounts menu option
+                //% "Accounts"
+                text: qsTrId("whisperfish-settings-accounts-menu")
+                onClicked: pageStack.push(Qt.resolvedUrl("AccountsPage.qml"))
+            }
             MenuItem {
                 //: Show own profile menu
                 //% "Show my profile"
@@ -403,6 +417,153 @@ Page {
             }
             // ------ END BACKGROUND&STARTUP SETTINGS ------
 
+            // ------ BEGIN STORAGE PASSWORD SETTINGS ------
+            SectionHeader {
+                //: Settings page storage password section
+                //% "Storage password"
+                text: qsTrId("whisperfish-settings-storage-password-section")
+            }
+            PasswordField {
+                id: currentStoragePassword
+                visible: encryptedDatabase
+                anchors.horizontalCenter: parent.horizontalCenter
+                width: parent.width - 2*Theme.horizontalPageMargin
+                inputMethodHints: Qt.ImhNoPredictiveText | Qt.ImhSensitiveData
+                //: Settings page current storage password label
+                //% "Current password"
+                label: qsTrId("whisperfish-settings-current-storage-password")
+                placeholderText: label
+            }
+            PasswordField {
+                id: newStoragePassword
+                anchors.horizontalCenter: parent.horizontalCenter
+                width: parent.width - 2*Theme.horizontalPageMargin
+                inputMethodHints: Qt.ImhNoPredictiveText | Qt.ImhSensitiveData
+                validator: RegExpValidator{ regExp: encryptedDatabase ? /|.{6,}/ : /.{6,}/ }
+                //: Settings page new storage password label
+                //% "New password"
+                label: qsTrId("whisperfish-settings-new-storage-password")
+                placeholderText: label
+            }
+            Button {
+                id: changeStoragePassword
+                anchors.horizontalCenter: parent.horizontalCenter
+                width: parent.width - 2*Theme.horizontalPageMargin
+                enabled: newStoragePassword.acceptableInput
+                         && (!encryptedDatabase || currentStoragePassword.text.length > 0)
+                         && !_changingPassword
+                text: !encryptedDatabase
+                    //: Settings page button to encrypt an unencrypted storage
+                    //% "Encrypt storage"
+                    ? qsTrId("whisperfish-settings-encrypt-storage")
+                    : newStoragePassword.text.length > 0
+                    //: Settings page button to change the storage password
+                    //% "Change password"
+                    ? qsTrId("whisperfish-settings-change-storage-password")
+                    //: Settings page button to decrypt the storage
+                    //% "Remove encryption"
+                    : qsTrId("whisperfish-settings-decrypt-storage")
+                onClicked: {
+                    _changingPassword = true
+                    ClientWorker.change_storage_password(currentStoragePassword.text, newStoragePassword.text)
+                }
+            }
+            Label {
+                id: storagePasswordStatus
+                anchors.horizontalCenter: parent.horizontalCenter
+                width: parent.width - 2*Theme.horizontalPageMargin
+                wrapMode: Text.Wrap
+                font.pixelSize: Theme.fontSizeExtraSmall
+                color: Theme.secondaryHighlightColor
+                //: Settings page storage password description
+                //% "The password is not stored anywhere, you will not be able to open your data if you lose it. Leave the new password empty to remove the encryption."
+                text: qsTrId("whisperfish-settings-storage-password-description")
+            }
+            Connections {
+                target: ClientWorker
+                onStoragePasswordChanged: {
+                    _changingPassword = false
+                    encryptedDatabase = encrypted
+                    // Keep the auto unlock password working
+                    if (SettingsBridge.plaintext_password.length > 0) {
+                        SettingsBridge.plaintext_password = newStoragePassword.text
+                    }
+                    currentStoragePassword.text = ""
+                    newStoragePassword.text = ""
+                    //: Settings page status after changing the storage password
+                    //% "Storage password changed"
+                    storagePasswordStatus.text = qsTrId("whisperfish-settings-storage-password-changed")
+                }
+                onStoragePasswordFailed: {
+                    _changingPassword = false
+                    //: Settings page status after a failure to change the storage password
+                    //% "Changing the password failed. Is the current password correct?"
+                    storagePasswordStatus.text = qsTrId("whisperfish-settings-storage-password-failed")
+                }
+            }
+            // ------ END STORAGE PASSWORD SETTINGS ------
+
+            // ------ BEGIN BACKUP SETTINGS ------
+            SectionHeader {
+                //: Settings page backup section
+                //% "Backup"
+                text: qsTrId("whisperfish-settings-backup-section")
+            }
+            PasswordField {
+                id: backupPassphrase
+                anchors.horizontalCenter: parent.horizontalCenter
+                width: parent.width - 2*Theme.horizontalPageMargin
+                inputMethodHints: Qt.ImhNoPredictiveText | Qt.ImhSensitiveData
+                validator: RegExpValidator{ regExp: /.{12,}/ }
+                //: Settings page backup passphrase label
+                //% "Backup passphrase"
+                label: qsTrId("whisperfish-settings-backup-passphrase")
+                //: Settings page backup passphrase placeholder
+                //% "Passphrase of at least 12 characters"
+                placeholderText: qsTrId("whisperfish-settings-backup-passphrase-placeholder")
+            }
+            Button {
+                id: exportBackup
+                anchors.horizontalCenter: parent.horizontalCenter
+                width: parent.width - 2*Theme.horizontalPageMargin
+                enabled: backupPassphrase.acceptableInput && !_exportingBackup
+                //: Settings page 'Export backup' button: write an encrypted backup of the account to Downloads
+                //% "Export backup"
+                text: qsTrId("whisperfish-settings-export-backup")
+                onClicked: {
+                    _exportingBackup = true
+                    ClientWorker.export_backup(backupPassphrase.text)
+                    backupPassphrase.text = ""
+                }
+            }
+            Label {
+                id: backupStatus
+                anchors.horizontalCenter: parent.horizontalCenter
+                width: parent.width - 2*Theme.horizontalPageMargin
+                wrapMode: Text.Wrap
+                font.pixelSize: Theme.fontSizeExtraSmall
+                color: Theme.secondaryHighlightColor
+                //: Settings page backup description
+                //% "The backup contains your messages, keys and attachments. Put it in the Downloads folder of another device to restore it while setting up Whisperfish."
+                text: qsTrId("whisperfish-settings-backup-description")
+            }
+            Connections {
+                target: ClientWorker
+                onBackupExported: {
+                    _exportingBackup = false
+                    //: Settings page backup status after a successful export
+                    //% "Backup written to %1"
+                    backupStatus.text = qsTrId("whisperfish-settings-backup-exported").arg(path)
+                }
+                onBackupFailed: {
+                    _exportingBackup = false
+                    //: Settings page backup status after a failed export
+                    //% "Writing the backup failed"
+                    backupStatus.text = qsTrId("whisperfish-settings-backup-failed")
+                }
+            }
+            // ------ END BACKUP SETTINGS ------
+
             // ------ BEGIN ADVANCED SETTINGS ------
             SectionHeader {
                 //: Settings page advanced section
@@ -490,6 +651,18 @@ Page {
                     ClientWorker.compact_db();
                 }
             }
+            Button {
+                id: collectGarbage
+                visible: SettingsBridge.debug_mode
+                anchors.horizontalCenter: parent.horizontalCenter
+                width: parent.width - 2*Theme.horizontalPageMargin
+                //: Settings page 'Clean up storage' button: move unused attachment and avatar files to quarantine
+                //% "Clean up storage"
+                text: qsTrId("whisperfish-settings-collect-garbage")
+                onClicked: {
+                    ClientWorker.collect_garbage(true);
+                }
+            }
             Button {
                 id: testCaptcha
                 visible: SettingsBridge.debug_mode
@@ -514,13 +687,34 @@ Page {
                 //: Settings page websocket status
                 //% "Websocket Status"
                 label: qsTrId("whisperfish-settings-websocket")
-                value: ClientWorker.connected ?
-                    //: Settings page connected message
-                    //% "Connected"
-                    qsTrId("whisperfish-settings-connected") :
-                    //: Settings page disconnected message
-                    //% "Disconnected"
-                    qsTrId("whisperfish-settings-disconnected")
+                value: {
+                    switch (ClientWorker.connectionState) {
+                    case "connected":
+                        //: Settings page connected message
+                        //% "Connected"
+                        return qsTrId("whisperfish-settings-connected")
+                    case "connecting":
+                        //: Settings page connecting message
+                        //% "Connecting"
+                        return qsTrId("whisperfish-settings-connecting")
+                    case "backingOff":
+                        //: Settings page message while waiting to reconnect
+                        //% "Waiting to reconnect"
+                        return qsTrId("whisperfish-settings-backing-off")
+                    case "authFailed":
+                        //: Settings page message when the server refused the credentials
+                        //% "Authentication failed"
+                        return qsTrId("whisperfish-settings-auth-failed")
+                    case "deregistered":
+                        //: Settings page message when the device is no longer registered
+                        //% "No longer registered"
+                        return qsTrId("whisperfish-settings-deregistered")
+                    default:
+                        //: Settings page disconnected message
+                        //% "Disconnected"
+                        return qsTrId("whisperfish-settings-disconnected")
+                    }
+                }
             }
             DetailItem {
                 //: Settings page unsent messages
@@ -559,6 +753,41 @@ Page {
                     qsTrId("whisperfish-settings-encrypted-db-disabled")
             }
             // ------ END STATS ------
+
+            // ------ BEGIN STORAGE USAGE ------
+            StorageUsage {
+                id: storageUsage
+                app: AppState
+            }
+            SectionHeader {
+                //: Settings page storage usage section
+                //% "Storage usage"
+                text: qsTrId("whisperfish-settings-storage-usage-section")
+            }
+            DetailItem {
+                //: Settings page total size of the attachments
+                //% "Attachments"
+                label: qsTrId("whisperfish-settings-storage-usage-total")
+                //: Settings page size and amount of all attachments
+                //% "%1 in %n file(s)"
+                value: qsTrId("whisperfish-settings-storage-usage-total-value", storageUsage.totalAttachments)
+                    .arg(Format.formatFileSize(storageUsage.totalBytes))
+            }
+            Repeater {
+                // The five conversations that take the most space
+                model: storageUsage.sessions
+                delegate: DetailItem {
+                    visible: index < 5
+                    Session {
+                        id: usageSession
+                        app: AppState
+                        sessionId: model.sessionId
+                    }
+                    label: usageSession.isGroup ? usageSession.groupName : getRecipientName(usageSession.recipientE164, usageSession.recipientName, true)
+                    value: Format.formatFileSize(model.bytes)
+                }
+            }
+            // ------ END STORAGE USAGE ------
         }
     }
 }
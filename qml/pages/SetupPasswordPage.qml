@@ -55,6 +55,11 @@ BlockingInfoPageBase {
             pageStack.replace(Qt.resolvedUrl("SetupRegistrationTypePage.qml"),
                               PageStackAction.Animated)
         }
+        onPromptBackupPassphrase: {
+            root.forceActiveFocus() // to close the keyboard
+            pageStack.replace(Qt.resolvedUrl("RestoreBackupPage.qml"),
+                              PageStackAction.Animated)
+        }
     }
 
     Column {
@@ -147,8 +147,7 @@ Page {
         onSendMessage: {
             for (var r in sessionList.recipients) {
                 var recp = sessionList.recipients[r]
-                var firstAttachedPath = (attachments.length > 0 ? attachments[0].data : '')
-                MessageModel.createMessage(recp.id, text, firstAttachedPath, -1, true)
+                MessageModel.createMultiMessage(recp.id, text, JSON.stringify(attachments), -1)
             }
             pageStack.pop()
         }
@@ -111,9 +111,10 @@ Page {
         enablePersonalizedPlaceholder: false
         showSeparator: true
         enableAttachments: false
-        attachments: (typeof root.shareObject.resources[0] === 'string' || root.shareObject.resources[0] instanceof String)
-            ? [ { data: root.shareObject.resources[0].replace(/^file:\/\//, ''), type: root.shareObject.mimeType } ]
-            : []
+        // Shared files are sent together, as one album
+        attachments: root.shareObject.resources
+            .filter(function(res) { return typeof res === 'string' || res instanceof String })
+            .map(function(res) { return { data: res.replace(/^file:\/\//, ''), type: root.shareObject.mimeType } })
         enableSending: Object.keys(sessionList.recipients).length > 0
 
         Component.onCompleted: {
@@ -152,8 +153,7 @@ Page {
         onSendMessage: {
             for (var r in sessionList.recipients) {
                 var recp = sessionList.recipients[r]
-                var firstAttachedPath = (attachments.length > 0 ? attachments[0].data : '')
-                MessageModel.createMessage(recp.id, text, firstAttachedPath, -1, true)
+                MessageModel.createMultiMessage(recp.id, text, JSON.stringify(attachments), -1)
             }
             pageStack.pop()
         }
@@ -26,6 +26,7 @@ Requires:   sailfish-components-contacts-qt5
 Requires:   nemo-qml-plugin-contacts-qt5
 Requires:   nemo-qml-plugin-configuration-qt5
 Requires:   nemo-qml-plugin-notifications-qt5
+Requires:   nemo-qml-plugin-connectivity
 Requires:   openssl-libs
 Requires:   dbus
 
@@ -299,6 +300,9 @@ install -D %{targetdir}/harbour-whisperfish %{buildroot}%{_bindir}/harbour-whisp
 %if %{with tools}
 install -D %{targetdir}/fetch-signal-attachment %{buildroot}%{_bindir}/fetch-signal-attachment
 install -D %{targetdir}/whisperfish-migration-dry-run %{buildroot}%{_bindir}/whisperfish-migration-dry-run
+install -D %{targetdir}/import-signal-backup %{buildroot}%{_bindir}/import-signal-backup
+install -D %{targetdir}/export-conversation %{buildroot}%{_bindir}/export-conversation
+install -D %{targetdir}/wf-cli %{buildroot}%{_bindir}/wf-cli
 %endif
 %endif
 
@@ -0,0 +1,310 @@
+use actix::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+use structopt::StructOpt;
+use whisperfish::actor::{MarkSessionRead, SessionActor};
+use whisperfish::gui::StorageReady;
+use whisperfish::store::observer::{Event, Interest};
+use whisperfish::store::{self, orm, schema, Storage};
+use whisperfish::worker::{
+    ClientActor, IsConnected, LinkDevice, NoClientEvents, QueueAttachment, QueueMessage,
+};
+
+/// Command line client for Whisperfish
+///
+/// Works on the same storage as the app, so do not run both at the same time.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "wf-cli")]
+struct Opt {
+    /// Whisperfish storage password
+    #[structopt(short, long)]
+    password: Option<String>,
+
+    /// Use an additional account instead of the default one
+    #[structopt(long)]
+    account: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// List the sessions, with their ids
+    Sessions,
+
+    /// Show the last messages of a session
+    Messages {
+        #[structopt(short, long)]
+        session: i32,
+
+        /// Number of messages to show
+        #[structopt(short = "n", long, default_value = "20")]
+        limit: usize,
+    },
+
+    /// Send a message to a session
+    Send {
+        #[structopt(short, long)]
+        session: i32,
+
+        /// File to attach; can be repeated
+        #[structopt(short, long)]
+        attachment: Vec<String>,
+
+        message: String,
+    },
+
+    /// Receive and print messages until interrupted
+    Receive,
+
+    /// Mark all messages of a session as read
+    MarkRead {
+        #[structopt(short, long)]
+        session: i32,
+    },
+
+    /// Link a new device, given the `sgnl://linkdevice` or `tsdevice:` URL from its QR code
+    LinkDevice { url: String },
+
+    /// List all known recipients
+    Recipients,
+}
+
+/// How long `send` waits for the message to be delivered.
+const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The actors that the app would run, without the QML bits.
+struct Actors {
+    session: Addr<SessionActor>,
+    client: Addr<ClientActor>,
+}
+
+impl Actors {
+    async fn start(
+        config: Arc<whisperfish::config::SignalConfig>,
+        storage: &Storage,
+    ) -> Result<Self, anyhow::Error> {
+        let session = SessionActor::headless().start();
+        let client =
+            ClientActor::with_events(Box::new(NoClientEvents), session.clone(), config)?.start();
+
+        let msg = StorageReady {
+            storage: storage.clone(),
+        };
+        session.send(msg.clone()).await?;
+        client.send(msg).await?;
+
+        Ok(Self { session, client })
+    }
+
+    async fn wait_connected(&self) -> Result<(), anyhow::Error> {
+        for _ in 0..60 {
+            if self.client.send(IsConnected).await? {
+                return Ok(());
+            }
+            actix::clock::sleep(Duration::from_millis(500)).await;
+        }
+        anyhow::bail!("Could not connect to the Signal servers")
+    }
+}
+
+/// Prints every message that gets inserted in the storage.
+struct Printer {
+    storage: Storage,
+}
+
+impl Actor for Printer {
+    type Context = Context<Self>;
+}
+
+impl Handler<Event> for Printer {
+    type Result = Vec<Interest>;
+
+    fn handle(&mut self, event: Event, _ctx: &mut Self::Context) -> Self::Result {
+        if event.is_insert() {
+            if let Some(message) = event
+                .key()
+                .as_i32()
+                .and_then(|mid| self.storage.fetch_message_by_id(mid))
+            {
+                print_message(&self.storage, &message, true);
+            }
+        }
+        vec![Interest::whole_table(schema::messages::table)]
+    }
+}
+
+fn session_name(session: &orm::Session) -> String {
+    match &session.r#type {
+        orm::SessionType::DirectMessage(recipient) => recipient.name().into_owned(),
+        orm::SessionType::GroupV1(group) => group.name.clone(),
+        orm::SessionType::GroupV2(group) => group.name.clone(),
+    }
+}
+
+fn print_message(storage: &Storage, message: &orm::Message, with_session: bool) {
+    let sender = if message.is_outbound {
+        "me".into()
+    } else {
+        message
+            .sender_recipient_id
+            .and_then(|rid| storage.fetch_recipient_by_id(rid))
+            .map(|r| r.name().into_owned())
+            .unwrap_or_else(|| "?".into())
+    };
+    let prefix = if with_session {
+        format!("[{}] ", message.session_id)
+    } else {
+        String::new()
+    };
+    let attachments = storage.fetch_attachments_for_message(message.id).len();
+    println!(
+        "{}{} #{} {}: {}{}",
+        prefix,
+        message.server_timestamp.format("%Y-%m-%d %H:%M:%S"),
+        message.id,
+        sender,
+        message.text.as_deref().unwrap_or(""),
+        if attachments > 0 {
+            format!(" [{} attachment(s)]", attachments)
+        } else {
+            String::new()
+        },
+    );
+}
+
+#[actix_rt::main]
+async fn main() -> Result<(), anyhow::Error> {
+    env_logger::init();
+
+    let opt = Opt::from_args();
+
+    let config = whisperfish::config::SignalConfig::read_account_from_file(opt.account.as_deref())?;
+    let location = store::account_location(opt.account.as_deref())?;
+    let config = Arc::new(config);
+    let mut storage = Storage::open(config.clone(), &location, opt.password).await?;
+
+    match opt.command {
+        Command::Sessions => {
+            for session in storage.fetch_sessions() {
+                println!(
+                    "{}\t{}{}",
+                    session.id,
+                    session_name(&session),
+                    if session.is_archived {
+                        " (archived)"
+                    } else {
+                        ""
+                    }
+                );
+            }
+        }
+        Command::Messages { session, limit } => {
+            let messages = storage.fetch_all_messages_augmented(session);
+            // Newest first from the store, oldest first on the terminal.
+            for message in messages.iter().take(limit).rev() {
+                print_message(&storage, &message.inner, false);
+            }
+        }
+        Command::Recipients => {
+            for recipient in storage.fetch_recipients() {
+                println!(
+                    "{}\t{}\t{}\t{}{}",
+                    recipient.id,
+                    recipient.uuid.map(|u| u.to_string()).unwrap_or_default(),
+                    recipient
+                        .e164
+                        .as_ref()
+                        .map(ToString::to_string)
+                        .unwrap_or_default(),
+                    recipient.name(),
+                    if recipient.is_registered {
+                        ""
+                    } else {
+                        " (unregistered)"
+                    },
+                );
+            }
+        }
+        Command::MarkRead { session } => {
+            let actors = Actors::start(config, &storage).await?;
+            actors
+                .session
+                .send(MarkSessionRead { sid: session })
+                .await?;
+        }
+        Command::LinkDevice { url } => {
+            let actors = Actors::start(config, &storage).await?;
+            actors.client.send(LinkDevice { tsurl: url }).await?;
+        }
+        Command::Send {
+            session,
+            attachment,
+            message,
+        } => {
+            anyhow::ensure!(
+                storage.fetch_session_by_id(session).is_some(),
+                "No session with id {}",
+                session
+            );
+            for path in &attachment {
+                anyhow::ensure!(
+                    std::path::Path::new(path).is_file(),
+                    "Attachment {} is not a file",
+                    path
+                );
+            }
+            let actors = Actors::start(config, &storage).await?;
+            actors.wait_connected().await?;
+
+            let mid = actors
+                .client
+                .send(QueueMessage {
+                    session_id: session,
+                    message,
+                    attachments: attachment.into_iter().map(QueueAttachment::new).collect(),
+                    quote: -1,
+                })
+                .await??;
+
+            let deadline = std::time::Instant::now() + SEND_TIMEOUT;
+            loop {
+                let sent = storage.fetch_message_by_id(mid).expect("queued message");
+                if sent.sent_timestamp.is_some() {
+                    println!("Sent message {}", mid);
+                    break;
+                }
+                if sent.sending_has_failed {
+                    anyhow::bail!("Could not send message {}", mid);
+                }
+                anyhow::ensure!(
+                    std::time::Instant::now() < deadline,
+                    "Timed out sending message {}",
+                    mid
+                );
+                actix::clock::sleep(Duration::from_millis(250)).await;
+            }
+        }
+        Command::Receive => {
+            let printer = Printer {
+                storage: storage.clone(),
+            }
+            .start();
+            storage.register_observer(
+                vec![Interest::whole_table(schema::messages::table)],
+                printer.downgrade().recipient(),
+            );
+
+            let actors = Actors::start(config, &storage).await?;
+            actors.wait_connected().await?;
+            log::info!("Connected; receiving until interrupted");
+
+            actix_rt::signal::ctrl_c().await?;
+            // Keep the actors alive until here.
+            drop(actors);
+        }
+    }
+
+    Ok(())
+}
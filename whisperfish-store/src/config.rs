@@ -1,3 +1,3 @@
 mod signalconfig;
 
-pub use self::signalconfig::SignalConfig;
+pub use self::signalconfig::{config_dir, SignalConfig};
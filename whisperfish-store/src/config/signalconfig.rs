@@ -50,6 +50,28 @@ mod phonenumber_serde_e164 {
     }
 }
 
+/// The directory with the config files of an account, `None` being the default account.
+///
+/// Like the storage, additional accounts live in `accounts/<name>` below the default directory.
+pub fn config_dir(account: Option<&str>) -> Result<std::path::PathBuf, anyhow::Error> {
+    let root = dirs::config_dir()
+        .context("Could not get xdg config directory path")?
+        .join("be.rubdos")
+        .join("harbour-whisperfish");
+
+    Ok(match account {
+        None => root,
+        Some(account) => {
+            anyhow::ensure!(
+                crate::store::is_valid_account_name(account),
+                "Invalid account name {:?}",
+                account
+            );
+            root.join("accounts").join(account)
+        }
+    })
+}
+
 /// Global Config
 ///
 /// This struct holds the global configuration of the whisperfish app.
@@ -76,6 +98,10 @@ pub struct SignalConfig {
     // cannot set this path by a config file.
     #[serde(skip)]
     share_dir: std::path::PathBuf,
+    /// The account this config belongs to, `None` for the default account.  This is determined by
+    /// the location of the config file, so it is not serialized.
+    #[serde(skip)]
+    account: Option<String>,
     /// Verbosity of the logging messages
     pub verbose: bool,
     /// Enable writing to log file
@@ -87,6 +113,10 @@ pub struct SignalConfig {
     pub autostart: bool,
 
     pub override_captcha: Option<String>,
+
+    /// Unix socket on which to serve the signal-cli compatible JSON-RPC interface.  Disabled when
+    /// unset.
+    pub jsonrpc_socket: Option<std::path::PathBuf>,
 }
 
 impl Default for SignalConfig {
@@ -100,10 +130,12 @@ impl Default for SignalConfig {
             pni: std::sync::Mutex::new(None),
             device_id: std::sync::Mutex::new(libsignal_service::push_service::DEFAULT_DEVICE_ID),
             share_dir: path.to_path_buf(),
+            account: None,
             verbose: false,
             logfile: false,
             autostart: false,
             override_captcha: None,
+            jsonrpc_socket: None,
         }
     }
 }
@@ -147,27 +179,36 @@ impl SignalConfig {
         Ok(())
     }
 
+    /// The default config of an account, `None` being the default account.
+    pub fn for_account(account: Option<String>) -> Result<Self, anyhow::Error> {
+        let path = crate::store::account_location(account.as_deref())?;
+
+        Ok(Self {
+            share_dir: path.to_path_buf(),
+            account,
+            ..Default::default()
+        })
+    }
+
     pub fn read_from_file() -> Result<Self, anyhow::Error> {
-        let path = dirs::config_dir()
-            .context("Could not get xdg config directory path")?
-            .join("be.rubdos")
-            .join("harbour-whisperfish")
-            .join("config.yml");
+        Self::read_account_from_file(None)
+    }
+
+    pub fn read_account_from_file(account: Option<&str>) -> Result<Self, anyhow::Error> {
+        let path = config_dir(account)?.join("config.yml");
 
         let fd = std::fs::File::open(&path)
             .with_context(|| format!("Could not open config file: {}", &path.display()))?;
-        let ret = serde_yaml::from_reader(fd)
+        let mut ret: Self = serde_yaml::from_reader(fd)
             .with_context(|| format!("Could not read config file: {}", &path.display()))?;
+        ret.share_dir = crate::store::account_location(account)?.to_path_buf();
+        ret.account = account.map(Into::into);
 
         Ok(ret)
     }
 
     pub fn write_to_file(&self) -> Result<(), anyhow::Error> {
-        let path = dirs::config_dir()
-            // XXX use anyhow context here
-            .expect("No config directory found")
-            .join("be.rubdos")
-            .join("harbour-whisperfish");
+        let path = config_dir(self.account.as_deref())?;
 
         // create config directory if it does not exist
         if !path.exists() {
@@ -186,6 +227,10 @@ impl SignalConfig {
         Ok(())
     }
 
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
     pub fn get_share_dir(&self) -> std::path::PathBuf {
         self.share_dir.to_owned()
     }
@@ -195,7 +240,7 @@ impl SignalConfig {
     }
 
     pub fn attachments_regex(&self) -> regex::Regex {
-        regex::Regex::new(r"/home/[a-zA-Z][a-zA-Z0-9]*/\.local/share(/be.rubdos)?/harbour-whisperfish/storage/(attachments|camera)/([a-zA-Z0-9_-]+\.[a-zA-Z0-9]+)").unwrap()
+        regex::Regex::new(r"/home/[a-zA-Z][a-zA-Z0-9]*/\.local/share(/be.rubdos)?/harbour-whisperfish(/accounts/[a-z0-9_]+)?/storage/(attachments|camera)/([a-zA-Z0-9_-]+\.[a-zA-Z0-9]+)").unwrap()
     }
 
     pub fn default_attachment_dir(&self) -> std::path::PathBuf {
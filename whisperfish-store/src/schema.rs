@@ -35,6 +35,8 @@ diesel::table! {
         cdn_number -> Nullable<Integer>,
         caption -> Nullable<Text>,
         pointer -> Nullable<Binary>,
+        audio_duration -> Nullable<Integer>,
+        audio_waveform -> Nullable<Binary>,
     }
 }
 
@@ -113,6 +115,17 @@ diesel::table! {
         is_remote_deleted -> Bool,
         sending_has_failed -> Bool,
         quote_id -> Nullable<Integer>,
+        viewed_timestamp -> Nullable<Timestamp>,
+        is_view_once -> Bool,
+    }
+}
+
+diesel::table! {
+    outbox (message_id) {
+        message_id -> Integer,
+        attempts -> Integer,
+        next_attempt -> Timestamp,
+        last_error -> Nullable<Text>,
     }
 }
 
@@ -141,6 +154,8 @@ diesel::table! {
         delivered -> Nullable<Timestamp>,
         read -> Nullable<Timestamp>,
         viewed -> Nullable<Timestamp>,
+        sent -> Nullable<Timestamp>,
+        failed -> Nullable<Timestamp>,
     }
 }
 
@@ -174,6 +189,14 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    sender_certificates (kind) {
+        kind -> Text,
+        certificate -> Binary,
+        expires -> Timestamp,
+    }
+}
+
 diesel::table! {
     sender_key_records (address, device, distribution_id) {
         address -> Text,
@@ -184,6 +207,40 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    sender_key_distributions (group_v2_id) {
+        group_v2_id -> Text,
+        distribution_id -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    sender_key_shared (distribution_id, address, device) {
+        distribution_id -> Text,
+        address -> Text,
+        device -> Integer,
+        shared_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    sent_content_log (id) {
+        id -> Integer,
+        timestamp -> Timestamp,
+        content -> Binary,
+        sent_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    sent_content_recipients (sent_content_id, address, device_id) {
+        sent_content_id -> Integer,
+        address -> Text,
+        device_id -> Integer,
+    }
+}
+
 diesel::table! {
     session_records (address, device_id) {
         address -> Text,
@@ -240,10 +297,13 @@ diesel::joinable!(group_v2_members -> group_v2s (group_v2_id));
 diesel::joinable!(group_v2_members -> recipients (recipient_id));
 diesel::joinable!(messages -> recipients (sender_recipient_id));
 diesel::joinable!(messages -> sessions (session_id));
+diesel::joinable!(outbox -> messages (message_id));
 diesel::joinable!(reactions -> messages (message_id));
 diesel::joinable!(reactions -> recipients (author));
 diesel::joinable!(receipts -> messages (message_id));
 diesel::joinable!(receipts -> recipients (recipient_id));
+diesel::joinable!(sender_key_distributions -> group_v2s (group_v2_id));
+diesel::joinable!(sent_content_recipients -> sent_content_log (sent_content_id));
 diesel::joinable!(sessions -> group_v1s (group_v1_id));
 diesel::joinable!(sessions -> group_v2s (group_v2_id));
 diesel::joinable!(sessions -> recipients (direct_message_recipient_id));
@@ -257,11 +317,17 @@ diesel::allow_tables_to_appear_in_same_query!(
     identity_records,
     kyber_prekeys,
     messages,
+    outbox,
     prekeys,
     reactions,
     receipts,
     recipients,
+    sender_certificates,
+    sender_key_distributions,
     sender_key_records,
+    sender_key_shared,
+    sent_content_log,
+    sent_content_recipients,
     session_records,
     sessions,
     signed_prekeys,
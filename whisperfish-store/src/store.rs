@@ -1,9 +1,18 @@
 pub mod orm;
 
+mod backup;
+mod contact_discovery;
 mod encryption;
+mod export;
+mod maintenance;
 pub mod migrations;
 pub mod observer;
+mod outbox;
 mod protocol_store;
+mod recipient_resolution;
+mod rekey;
+mod sender_keys;
+mod sent_content;
 mod utils;
 
 use self::orm::{AugmentedMessage, UnidentifiedAccessMode};
@@ -33,6 +42,12 @@ use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, MutexGuard};
 use uuid::Uuid;
 
+pub use self::backup::*;
+pub use self::contact_discovery::*;
+pub use self::export::*;
+pub use self::maintenance::*;
+pub use self::recipient_resolution::*;
+
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
 sql_function!(
@@ -49,6 +64,16 @@ pub enum TrustLevel {
     Uncertain,
 }
 
+/// Which of our two identities a [Storage] acts as in the Signal protocol.
+///
+/// Messages to our phone number are encrypted for our PNI, which has its own identity key and
+/// registration id.  Everything else, like the sessions and pre-keys, is shared.
+#[derive(Clone, Copy, Eq, Debug, PartialEq)]
+pub enum Identity {
+    Aci,
+    Pni,
+}
+
 /// Session as it relates to the schema
 #[derive(Queryable, Debug, Clone)]
 pub struct Session {
@@ -110,6 +135,16 @@ pub struct NewMessage {
     pub expires_in: Option<std::time::Duration>,
 }
 
+/// ID-free model for attachments that are created locally, e.g. when composing a message.
+#[derive(Clone, Debug, Default)]
+pub struct NewAttachment {
+    pub path: String,
+    pub mime_type: String,
+    pub caption: Option<String>,
+    pub is_voice_note: bool,
+    pub is_borderless: bool,
+}
+
 #[derive(Clone, Debug)]
 #[allow(clippy::large_enum_variant)]
 pub enum GroupContext {
@@ -188,11 +223,65 @@ pub fn temp() -> StorageLocation<tempfile::TempDir> {
 }
 
 pub fn default_location() -> Result<StorageLocation<PathBuf>, anyhow::Error> {
+    account_location(None)
+}
+
+/// The storage location of an account.
+///
+/// `None` is the default account, which lives at the root for compatibility; additional accounts
+/// live in `accounts/<name>` below it.
+pub fn account_location(account: Option<&str>) -> Result<StorageLocation<PathBuf>, anyhow::Error> {
     let data_dir = dirs::data_local_dir().context("Could not find data directory.")?;
+    let root = data_dir.join("be.rubdos").join("harbour-whisperfish");
+
+    Ok(StorageLocation::Path(match account {
+        None => root,
+        Some(account) => {
+            anyhow::ensure!(
+                is_valid_account_name(account),
+                "Invalid account name {:?}",
+                account
+            );
+            root.join("accounts").join(account)
+        }
+    }))
+}
 
-    Ok(StorageLocation::Path(
-        data_dir.join("be.rubdos").join("harbour-whisperfish"),
-    ))
+/// Account names end up in paths and D-Bus names, so they are restricted to lowercase letters,
+/// digits and underscores.
+pub fn is_valid_account_name(account: &str) -> bool {
+    !account.is_empty()
+        && account.len() <= 32
+        && account
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// The names of the additional accounts, i.e. all accounts except the default one.
+pub fn list_accounts() -> Result<Vec<String>, anyhow::Error> {
+    let accounts_dir = default_location()?.join("accounts");
+    if !accounts_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut accounts = Vec::new();
+    for entry in std::fs::read_dir(&accounts_dir)
+        .with_context(|| format!("Could not list {}", accounts_dir.display()))?
+    {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        if let Some(name) = entry
+            .file_name()
+            .to_str()
+            .filter(|n| is_valid_account_name(n))
+        {
+            accounts.push(name.to_owned());
+        }
+    }
+    accounts.sort();
+    Ok(accounts)
 }
 
 impl<P: AsRef<Path>> std::ops::Deref for StorageLocation<P> {
@@ -227,14 +316,15 @@ pub struct Storage {
     db: Arc<AssertUnwindSafe<Mutex<SqliteConnection>>>,
     observatory: Arc<tokio::sync::RwLock<observer::Observatory>>,
     config: Arc<SignalConfig>,
-    store_enc: Option<encryption::StorageEncryption>,
+    /// Shared between the clones, such that a new password is picked up by all of them.
+    store_enc: Arc<std::sync::RwLock<Option<encryption::StorageEncryption>>>,
     protocol_store: Arc<tokio::sync::RwLock<ProtocolStore>>,
     credential_cache: Arc<tokio::sync::RwLock<InMemoryCredentialsCache>>,
     path: PathBuf,
     aci_identity_key_pair: Arc<tokio::sync::RwLock<Option<IdentityKeyPair>>>,
-    // XXX: Implement PNI https://gitlab.com/whisperfish/whisperfish/-/issues/459
-    #[allow(unused)]
     pni_identity_key_pair: Arc<tokio::sync::RwLock<Option<IdentityKeyPair>>>,
+    /// The identity used by the protocol store traits, see [Storage::pni_store].
+    identity: Identity,
 }
 
 /// Fetches an `orm::Session`, for which the supplied closure can impose constraints.
@@ -291,7 +381,17 @@ impl Storage {
     }
 
     pub fn is_encrypted(&self) -> bool {
-        self.store_enc.is_some()
+        self.store_enc
+            .read()
+            .expect("storage encryption lock")
+            .is_some()
+    }
+
+    fn store_enc(&self) -> Option<encryption::StorageEncryption> {
+        self.store_enc
+            .read()
+            .expect("storage encryption lock")
+            .clone()
     }
 
     pub fn clear_old_logs(
@@ -330,6 +430,41 @@ impl Storage {
         Ok(())
     }
 
+    /// Generates fresh salts for the database and the storage files, and derives the keys from
+    /// `password`.  Without a password, the storage is left unencrypted.
+    async fn generate_encryption(
+        path: &Path,
+        password: Option<&str>,
+    ) -> Result<Option<encryption::StorageEncryption>, anyhow::Error> {
+        let password = match password {
+            Some(password) => password,
+            None => return Ok(None),
+        };
+
+        let db_salt_path = path.join("db").join("salt");
+        let storage_salt_path = path.join("storage").join("salt");
+
+        let (storage_salt, db_salt) = Self::generate_salts();
+        utils::write_file_async(db_salt_path, &db_salt).await?;
+        utils::write_file_async(storage_salt_path, &storage_salt).await?;
+
+        Ok(Some(
+            encryption::StorageEncryption::new(password.to_string(), storage_salt, db_salt).await?,
+        ))
+    }
+
+    /// Returns a fresh storage salt and database salt.
+    fn generate_salts() -> ([u8; 8], [u8; 8]) {
+        use rand::RngCore;
+        log::info!("Generating salts");
+        let mut storage_salt = [0u8; 8];
+        let mut db_salt = [0u8; 8];
+        let mut rng = rand::thread_rng();
+        rng.fill_bytes(&mut storage_salt);
+        rng.fill_bytes(&mut db_salt);
+        (storage_salt, db_salt)
+    }
+
     /// Writes (*overwrites*) a new Storage object to the provided path.
     #[allow(clippy::too_many_arguments)]
     pub async fn new<T: AsRef<Path>>(
@@ -349,28 +484,7 @@ impl Storage {
         Self::scaffold_directories(path)?;
 
         // 1. Generate both salts if needed and create a storage encryption object if necessary
-        let store_enc = if let Some(password) = password {
-            let db_salt_path = path.join("db").join("salt");
-            let storage_salt_path = path.join("storage").join("salt");
-
-            use rand::RngCore;
-            log::info!("Generating salts");
-            let mut db_salt = [0u8; 8];
-            let mut storage_salt = [0u8; 8];
-            let mut rng = rand::thread_rng();
-            rng.fill_bytes(&mut db_salt);
-            rng.fill_bytes(&mut storage_salt);
-
-            utils::write_file_async(db_salt_path, &db_salt).await?;
-            utils::write_file_async(storage_salt_path, &storage_salt).await?;
-
-            Some(
-                encryption::StorageEncryption::new(password.to_string(), storage_salt, db_salt)
-                    .await?,
-            )
-        } else {
-            None
-        };
+        let store_enc = Self::generate_encryption(path, password).await?;
 
         // 2. Open DB
         let db = Self::open_db(db_path, store_enc.as_ref().map(|x| x.get_database_key())).await?;
@@ -410,7 +524,7 @@ impl Storage {
             db: Arc::new(AssertUnwindSafe(Mutex::new(db))),
             observatory: Default::default(),
             config,
-            store_enc,
+            store_enc: Arc::new(std::sync::RwLock::new(store_enc)),
             protocol_store: Arc::new(tokio::sync::RwLock::new(protocol_store)),
             credential_cache: Arc::new(tokio::sync::RwLock::new(
                 InMemoryCredentialsCache::default(),
@@ -418,6 +532,7 @@ impl Storage {
             path: path.to_path_buf(),
             aci_identity_key_pair: Arc::new(tokio::sync::RwLock::new(Some(aci_identity_key_pair))),
             pni_identity_key_pair: Arc::new(tokio::sync::RwLock::new(Some(pni_identity_key_pair))),
+            identity: Identity::Aci,
         })
     }
 
@@ -428,6 +543,10 @@ impl Storage {
     ) -> Result<Storage, anyhow::Error> {
         let path: &Path = std::ops::Deref::deref(db_path);
 
+        Self::recover_rekey(path, password.as_deref())
+            .await
+            .context("Recovering from an interrupted password change")?;
+
         let store_enc = if let Some(password) = password {
             // Get storage and db salt
             let storage_salt = utils::read_salt_file(path.join("storage").join("salt")).await?;
@@ -445,13 +564,19 @@ impl Storage {
             .await
             .context("Opening database")?;
 
+        if let Some(store_enc) = &store_enc {
+            Self::upgrade_files(path, store_enc)
+                .await
+                .context("Upgrading the encrypted files")?;
+        }
+
         let protocol_store = ProtocolStore::open().await;
 
         let storage = Storage {
             db: Arc::new(AssertUnwindSafe(Mutex::new(db))),
             observatory: Default::default(),
             config,
-            store_enc,
+            store_enc: Arc::new(std::sync::RwLock::new(store_enc)),
             protocol_store: Arc::new(tokio::sync::RwLock::new(protocol_store)),
             credential_cache: Arc::new(tokio::sync::RwLock::new(
                 InMemoryCredentialsCache::default(),
@@ -459,14 +584,83 @@ impl Storage {
             path: path.to_path_buf(),
             aci_identity_key_pair: Arc::new(tokio::sync::RwLock::new(None)),
             pni_identity_key_pair: Arc::new(tokio::sync::RwLock::new(None)),
+            identity: Identity::Aci,
         };
 
         Ok(storage)
     }
 
+    /// Re-encrypts the files in `storage/identity` that are still in the legacy format.
+    ///
+    /// A file is first written next to the original, so that it is never half-written.
+    async fn upgrade_files(
+        path: &Path,
+        store_enc: &encryption::StorageEncryption,
+    ) -> Result<(), anyhow::Error> {
+        let identity_dir = path.join("storage").join("identity");
+        if !identity_dir.exists() {
+            return Ok(());
+        }
+
+        let mut upgraded = 0;
+        for entry in std::fs::read_dir(&identity_dir)? {
+            let entry = entry?;
+            if !entry.metadata()?.is_file() {
+                continue;
+            }
+            let file = entry.path();
+            // Files with an extension are leftovers of an interrupted write.
+            if file.extension().is_some() {
+                continue;
+            }
+            let mut contents = utils::read_file_async(&file).await?;
+            if store_enc
+                .upgrade(&mut contents)
+                .with_context(|| format!("Could not decrypt {}", file.display()))?
+            {
+                let partial = file.with_extension("upgrade");
+                utils::write_file_async(&partial, &contents).await?;
+                std::fs::rename(&partial, &file)?;
+                upgraded += 1;
+            }
+        }
+        if upgraded > 0 {
+            log::info!("Upgraded the encryption of {} files", upgraded);
+        }
+        Ok(())
+    }
+
     async fn open_db<T: AsRef<Path>>(
         db_path: &StorageLocation<T>,
         database_key: Option<&[u8]>,
+    ) -> anyhow::Result<SqliteConnection, anyhow::Error> {
+        let mut db = Self::connect(db_path, database_key)?;
+
+        // Run migrations.
+        // We execute the transactions without foreign key checking enabled.
+        // This is because foreign_keys=OFF implies that foreign key references are
+        // not renamed when their parent table is renamed on *old SQLite version*.
+        // https://stackoverflow.com/questions/67006159/how-to-re-parent-a-table-foreign-key-in-sqlite-after-recreating-the-parent
+        // We can very probably do normal foreign_key checking again when we are on a more recent
+        // SQLite.
+        // That said, our check_foreign_keys() does output more useful information for when things
+        // go haywire, albeit a bit later.
+        db.batch_execute("PRAGMA foreign_keys = OFF;").unwrap();
+        db.transaction::<_, anyhow::Error, _>(|db| {
+            db.run_pending_migrations(MIGRATIONS)
+                .map_err(|e| anyhow::anyhow!("Running migrations: {}", e))?;
+            crate::check_foreign_keys(db)?;
+            Ok(())
+        })?;
+        db.batch_execute("PRAGMA foreign_keys = ON;").unwrap();
+
+        Ok(db)
+    }
+
+    /// Opens the database with `database_key`, without running the migrations.
+    fn connect<T: AsRef<Path>>(
+        db_path: &StorageLocation<T>,
+        database_key: Option<&[u8]>,
     ) -> anyhow::Result<SqliteConnection, anyhow::Error> {
         log::info!("Opening DB");
         let mut db = db_path.open_db()?;
@@ -509,24 +703,6 @@ impl Storage {
         // XXX: Do we have to signal somehow that the password was wrong?
         //      Offer retries?
 
-        // Run migrations.
-        // We execute the transactions without foreign key checking enabled.
-        // This is because foreign_keys=OFF implies that foreign key references are
-        // not renamed when their parent table is renamed on *old SQLite version*.
-        // https://stackoverflow.com/questions/67006159/how-to-re-parent-a-table-foreign-key-in-sqlite-after-recreating-the-parent
-        // We can very probably do normal foreign_key checking again when we are on a more recent
-        // SQLite.
-        // That said, our check_foreign_keys() does output more useful information for when things
-        // go haywire, albeit a bit later.
-        db.batch_execute("PRAGMA foreign_keys = OFF;").unwrap();
-        db.transaction::<_, anyhow::Error, _>(|db| {
-            db.run_pending_migrations(MIGRATIONS)
-                .map_err(|e| anyhow::anyhow!("Running migrations: {}", e))?;
-            crate::check_foreign_keys(db)?;
-            Ok(())
-        })?;
-        db.batch_execute("PRAGMA foreign_keys = ON;").unwrap();
-
         Ok(db)
     }
 
@@ -566,7 +742,7 @@ impl Storage {
         &self,
         path: impl AsRef<std::path::Path>,
     ) -> Result<Vec<u8>, anyhow::Error> {
-        utils::read_file_async_encrypted(path, self.store_enc.as_ref()).await
+        utils::read_file_async_encrypted(path, self.store_enc().as_ref()).await
     }
 
     pub async fn write_file(
@@ -574,7 +750,7 @@ impl Storage {
         path: impl AsRef<std::path::Path>,
         content: impl Into<Vec<u8>>,
     ) -> Result<(), anyhow::Error> {
-        utils::write_file_async_encrypted(path, content, self.store_enc.as_ref()).await
+        utils::write_file_async_encrypted(path, content, self.store_enc().as_ref()).await
     }
 
     /// Process reaction and store in database.
@@ -687,7 +863,8 @@ impl Storage {
         if uuid.is_none() {
             log::warn!("No uuid set. Continuing with only e164");
         }
-        Some(self.merge_and_fetch_recipient(e164, uuid, None, TrustLevel::Certain))
+        self.merge_and_fetch_recipient(e164, uuid, None, TrustLevel::Certain)
+            .ok()
     }
 
     pub fn fetch_recipient_by_phonenumber(
@@ -771,6 +948,29 @@ impl Storage {
         affected > 0
     }
 
+    /// Usernames are case insensitive, and stored in lower case.
+    pub fn set_recipient_username(&self, recipient_id: i32, new_username: Option<&str>) -> bool {
+        use crate::schema::recipients::dsl::*;
+        let affected = diesel::update(recipients)
+            .set(username.eq(new_username.map(str::to_lowercase)))
+            .filter(id.eq(recipient_id))
+            .execute(&mut *self.db())
+            .expect("existing record updated");
+        if affected > 0 {
+            self.observe_update(recipients, recipient_id);
+        }
+        affected > 0
+    }
+
+    pub fn fetch_recipient_by_username(&self, recipient_username: &str) -> Option<orm::Recipient> {
+        use crate::schema::recipients::dsl::*;
+        recipients
+            .filter(username.eq(recipient_username.to_lowercase()))
+            .first(&mut *self.db())
+            .optional()
+            .expect("db")
+    }
+
     pub fn mark_profile_outdated(&self, recipient_uuid: Uuid) -> Option<orm::Recipient> {
         use crate::schema::recipients::dsl::*;
         diesel::update(recipients)
@@ -844,9 +1044,9 @@ impl Storage {
         pni: Option<Uuid>,
         new_profile_key: &[u8],
         trust_level: TrustLevel,
-    ) -> (orm::Recipient, bool) {
+    ) -> Result<(orm::Recipient, bool), anyhow::Error> {
         // XXX check profile_key length
-        let recipient = self.merge_and_fetch_recipient(phonenumber, uuid, pni, trust_level);
+        let recipient = self.merge_and_fetch_recipient(phonenumber, uuid, pni, trust_level)?;
 
         let is_unset = recipient.profile_key.is_none()
             || recipient.profile_key.as_ref().map(Vec::len) == Some(0);
@@ -863,7 +1063,7 @@ impl Storage {
                         .execute(&mut *self.db())
                         .expect("existing record updated");
                 }
-                return (recipient, false);
+                return Ok((recipient, false));
             }
 
             use crate::schema::recipients::dsl::*;
@@ -882,403 +1082,11 @@ impl Storage {
             }
         }
         // Re-fetch recipient with updated key
-        (
+        Ok((
             self.fetch_recipient_by_id(recipient.id)
                 .expect("fetch existing record"),
             true,
-        )
-    }
-
-    /// Equivalent of Androids `RecipientDatabase::getAndPossiblyMerge`.
-    ///
-    /// XXX: This does *not* trigger observations for removed recipients.
-    pub fn merge_and_fetch_recipient(
-        &self,
-        phonenumber: Option<PhoneNumber>,
-        uuid: Option<Uuid>,
-        _pni: Option<Uuid>,
-        trust_level: TrustLevel,
-    ) -> orm::Recipient {
-        let (id, uuid, phonenumber, changed) = self
-            .db()
-            .transaction::<_, Error, _>(|db| {
-                Self::merge_and_fetch_recipient_inner(db, phonenumber, uuid, trust_level)
-            })
-            .expect("database");
-        let recipient = match (id, uuid, &phonenumber) {
-            (Some(id), _, _) => self
-                .fetch_recipient_by_id(id)
-                .expect("existing updated recipient"),
-            (_, Some(uuid), _) => self
-                .fetch_recipient_by_uuid(uuid)
-                .expect("existing updated recipient"),
-            (_, _, Some(e164)) => self
-                .fetch_recipient_by_phonenumber(e164)
-                .expect("existing updated recipient"),
-            (None, None, None) => {
-                unreachable!("this should get implemented with an Either or custom enum instead")
-            }
-        };
-        if changed {
-            self.observe_update(crate::schema::recipients::table, recipient.id);
-        }
-
-        recipient
-    }
-
-    // Inner method because the coverage report is then sensible.
-    #[allow(clippy::type_complexity)]
-    // XXX this should get implemented with an Either or custom enum instead
-    fn merge_and_fetch_recipient_inner(
-        db: &mut SqliteConnection,
-        phonenumber: Option<PhoneNumber>,
-        uuid: Option<Uuid>,
-        trust_level: TrustLevel,
-    ) -> Result<(Option<i32>, Option<Uuid>, Option<PhoneNumber>, bool), Error> {
-        if phonenumber.is_none() && uuid.is_none() {
-            panic!("merge_and_fetch_recipient requires at least one of e164 or uuid");
-        }
-
-        use schema::recipients;
-        let by_e164: Option<orm::Recipient> = phonenumber
-            .as_ref()
-            .map(|phonenumber| {
-                recipients::table
-                    .filter(recipients::e164.eq(phonenumber.to_string()))
-                    .first(db)
-                    .optional()
-            })
-            .transpose()?
-            .flatten();
-        let by_uuid: Option<orm::Recipient> = uuid
-            .map(|uuid| {
-                recipients::table
-                    .filter(recipients::uuid.eq(uuid.to_string()))
-                    .first(db)
-                    .optional()
-            })
-            .transpose()?
-            .flatten();
-
-        match (by_e164, by_uuid) {
-            (Some(by_e164), Some(by_uuid)) if by_e164.id == by_uuid.id => {
-                // Both are equal, easy.
-                Ok((Some(by_uuid.id), None, None, false))
-            }
-            (Some(by_e164), Some(by_uuid)) => {
-                log::warn!(
-                    "Conflicting results for {} and {}. Finding a resolution.",
-                    by_e164.e164.as_ref().unwrap(),
-                    by_uuid.uuid.as_ref().unwrap()
-                );
-                match (by_e164.uuid, trust_level) {
-                    (Some(_uuid), TrustLevel::Certain) => {
-                        log::info!("Differing UUIDs, high trust, likely case of reregistration. Stripping the old account, updating new.");
-                        // Strip the old one
-                        diesel::update(recipients::table)
-                            .set(recipients::e164.eq::<Option<String>>(None))
-                            .filter(recipients::id.eq(by_e164.id))
-                            .execute(db)?;
-                        // Set the new one
-                        diesel::update(recipients::table)
-                            .set(
-                                recipients::e164
-                                    .eq(phonenumber.as_ref().map(PhoneNumber::to_string)),
-                            )
-                            .filter(recipients::id.eq(by_uuid.id))
-                            .execute(db)?;
-                        // Fetch again for the update
-                        Ok((Some(by_uuid.id), None, None, true))
-                    }
-                    (Some(_uuid), TrustLevel::Uncertain) => {
-                        log::info!("Differing UUIDs, low trust, likely case of reregistration. Doing absolutely nothing. Sorry.");
-                        Ok((Some(by_uuid.id), None, None, false))
-                    }
-                    (None, TrustLevel::Certain) => {
-                        log::info!(
-                            "Merging contacts: one with e164, the other only uuid, high trust."
-                        );
-                        let merged = Self::merge_recipients_inner(db, by_e164.id, by_uuid.id)?;
-                        // XXX probably more recipient identifiers should be moved
-                        diesel::update(recipients::table)
-                            .set(
-                                recipients::e164
-                                    .eq(phonenumber.as_ref().map(PhoneNumber::to_string)),
-                            )
-                            .filter(recipients::id.eq(merged))
-                            .execute(db)?;
-
-                        Ok((Some(merged), None, None, true))
-                    }
-                    (None, TrustLevel::Uncertain) => {
-                        log::info!(
-                            "Not merging contacts: one with e164, the other only uuid, low trust."
-                        );
-                        Ok((Some(by_uuid.id), None, None, false))
-                    }
-                }
-            }
-            (None, Some(by_uuid)) => {
-                if let Some(phonenumber) = phonenumber {
-                    match trust_level {
-                        TrustLevel::Certain => {
-                            log::info!(
-                                "Found phone number {} for contact {}. High trust, so updating.",
-                                phonenumber,
-                                by_uuid.uuid.as_ref().unwrap()
-                            );
-                            diesel::update(recipients::table)
-                                .set(recipients::e164.eq(phonenumber.to_string()))
-                                .filter(recipients::id.eq(by_uuid.id))
-                                .execute(db)?;
-                            Ok((Some(by_uuid.id), None, None, true))
-                        }
-                        TrustLevel::Uncertain => {
-                            log::info!("Found phone number {} for contact {}. Low trust, so doing nothing. Sorry again.", phonenumber, by_uuid.uuid.as_ref().unwrap());
-                            Ok((Some(by_uuid.id), None, None, false))
-                        }
-                    }
-                } else {
-                    Ok((Some(by_uuid.id), None, None, false))
-                }
-            }
-            (Some(by_e164), None) => {
-                if let Some(uuid) = uuid {
-                    match trust_level {
-                        TrustLevel::Certain => {
-                            log::info!(
-                                "Found UUID {} for contact {}. High trust, so updating.",
-                                uuid,
-                                by_e164.e164.unwrap()
-                            );
-                            diesel::update(recipients::table)
-                                .set(recipients::uuid.eq(uuid.to_string()))
-                                .filter(recipients::id.eq(by_e164.id))
-                                .execute(db)?;
-                            Ok((Some(by_e164.id), None, None, true))
-                        }
-                        TrustLevel::Uncertain => {
-                            log::info!(
-                                "Found UUID {} for contact {}. Low trust, creating a new contact.",
-                                uuid,
-                                by_e164.e164.unwrap()
-                            );
-
-                            diesel::insert_into(recipients::table)
-                                .values(recipients::uuid.eq(uuid.to_string()))
-                                .execute(db)
-                                .expect("insert new recipient");
-                            Ok((None, Some(uuid), None, true))
-                        }
-                    }
-                } else {
-                    Ok((Some(by_e164.id), None, None, false))
-                }
-            }
-            (None, None) => {
-                let insert_e164 = (trust_level == TrustLevel::Certain) || uuid.is_none();
-                let insert_phonenumber = if insert_e164 { phonenumber } else { None };
-                diesel::insert_into(recipients::table)
-                    .values((
-                        recipients::e164
-                            .eq(insert_phonenumber.as_ref().map(PhoneNumber::to_string)),
-                        recipients::uuid.eq(uuid.as_ref().map(Uuid::to_string)),
-                    ))
-                    .execute(db)
-                    .expect("insert new recipient");
-
-                Ok((None, uuid, insert_phonenumber, true))
-            }
-        }
-    }
-
-    /// Merge source_id into dest_id.
-    ///
-    /// Executes `merge_recipient_inner` inside a transaction, and then returns the result.
-    #[allow(unused)]
-    fn merge_recipients(&self, source_id: i32, dest_id: i32) -> orm::Recipient {
-        let mut db = self.db();
-        let merged_id = db
-            .transaction::<_, Error, _>(|db| Self::merge_recipients_inner(db, source_id, dest_id))
-            .expect("consistent migration");
-
-        log::trace!("Contact merge committed.");
-
-        self.observe_delete(schema::recipients::table, source_id);
-        self.observe_update(schema::recipients::table, dest_id);
-
-        self.fetch_recipient_by_id(merged_id)
-            .expect("existing contact")
-    }
-
-    // Inner method because the coverage report is then sensible.
-    fn merge_recipients_inner(
-        db: &mut SqliteConnection,
-        source_id: i32,
-        dest_id: i32,
-    ) -> Result<i32, diesel::result::Error> {
-        log::info!(
-            "Merge of contacts {} and {}. Will move all into {}",
-            source_id,
-            dest_id,
-            dest_id
-        );
-
-        // Defer constraints, we're moving a lot of data, inside of a transaction,
-        // and if we have a bug it definitely needs more research anyway.
-        db.batch_execute("PRAGMA defer_foreign_keys = ON;")?;
-
-        use schema::*;
-
-        // 1. Merge messages senders.
-        let message_count = diesel::update(messages::table)
-            .filter(messages::sender_recipient_id.eq(source_id))
-            .set(messages::sender_recipient_id.eq(dest_id))
-            .execute(db)?;
-        log::trace!("Merging messages: {}", message_count);
-
-        // 2. Merge group V1 membership:
-        //    - Delete duplicate memberships.
-        //      We fetch the dest_id group memberships,
-        //      and delete the source_id memberships that have the same group.
-        //      Ideally, this would be a single self-join query,
-        //      but Diesel doesn't like that yet.
-        let target_memberships_v1: Vec<String> = group_v1_members::table
-            .select(group_v1_members::group_v1_id)
-            .filter(group_v1_members::recipient_id.eq(dest_id))
-            .load(db)?;
-        let deleted_memberships_v1 = diesel::delete(group_v1_members::table)
-            .filter(
-                group_v1_members::group_v1_id
-                    .eq_any(&target_memberships_v1)
-                    .and(group_v1_members::recipient_id.eq(source_id)),
-            )
-            .execute(db)?;
-        //    - Update the rest
-        let updated_memberships_v1 = diesel::update(group_v1_members::table)
-            .filter(group_v1_members::recipient_id.eq(source_id))
-            .set(group_v1_members::recipient_id.eq(dest_id))
-            .execute(db)?;
-        log::trace!(
-            "Merging Group V1 memberships: deleted duplicate {}/{}, moved {}/{}.",
-            deleted_memberships_v1,
-            target_memberships_v1.len(),
-            updated_memberships_v1,
-            target_memberships_v1.len()
-        );
-
-        // 3. Merge sessions:
-        let source_session: Option<orm::DbSession> = sessions::table
-            .filter(sessions::direct_message_recipient_id.eq(source_id))
-            .first(db)
-            .optional()?;
-        let target_session: Option<orm::DbSession> = sessions::table
-            .filter(sessions::direct_message_recipient_id.eq(dest_id))
-            .first(db)
-            .optional()?;
-        match (source_session, target_session) {
-            (Some(source_session), Some(target_session)) => {
-                // Both recipients have a session.
-                // Move the source session's messages to the target session,
-                // then drop the source session.
-                let updated_message_count = diesel::update(messages::table)
-                    .filter(messages::session_id.eq(source_session.id))
-                    .set(messages::session_id.eq(target_session.id))
-                    .execute(db)?;
-                let dropped_session_count = diesel::delete(sessions::table)
-                    .filter(sessions::id.eq(source_session.id))
-                    .execute(db)?;
-
-                assert_eq!(dropped_session_count, 1, "Drop the single source session.");
-
-                log::trace!(
-                    "Updating source session's messages ({} total). Dropped source session.",
-                    updated_message_count
-                );
-            }
-            (Some(source_session), None) => {
-                log::info!("Strange, no session for the target_id. Updating source.");
-                let updated_session = diesel::update(sessions::table)
-                    .filter(sessions::id.eq(source_session.id))
-                    .set(sessions::direct_message_recipient_id.eq(dest_id))
-                    .execute(db)?;
-                assert_eq!(updated_session, 1, "Update source session");
-            }
-            (None, Some(_target_session)) => {
-                log::info!("Strange, no session for the source_id. Continuing.");
-            }
-            (None, None) => {
-                log::warn!("Strange, neither recipient has a session. Continuing.");
-            }
-        }
-
-        // 4. Merge reactions
-        //    This too would benefit from a subquery or self-join.
-        let target_reactions: Vec<i32> = reactions::table
-            .select(reactions::reaction_id)
-            .filter(reactions::author.eq(dest_id))
-            .load(db)?;
-        // Delete duplicates from source.
-        // We're not going to merge based on receive time,
-        // although that would be the "right" thing to do.
-        // Let's hope we never really take this path.
-        let deleted_reactions = diesel::delete(reactions::table)
-            .filter(
-                reactions::author
-                    .eq(source_id)
-                    .and(reactions::message_id.eq_any(target_reactions)),
-            )
-            .execute(db)?;
-        log::log!(
-            if deleted_reactions > 0 {
-                log::Level::Warn
-            } else {
-                log::Level::Trace
-            },
-            "Deleted {} reactions. Please file an issue if > 0",
-            deleted_reactions
-        );
-        let updated_reactions = diesel::update(reactions::table)
-            .filter(reactions::author.eq(source_id))
-            .set(reactions::author.eq(dest_id))
-            .execute(db)?;
-        log::trace!("Updated {} reactions", updated_reactions);
-
-        // 5. Update receipts
-        //    Same thing: delete the duplicates (although merging would be better),
-        //    and update the rest.
-        let target_receipts: Vec<i32> = receipts::table
-            .select(receipts::message_id)
-            .filter(receipts::recipient_id.eq(dest_id))
-            .load(db)?;
-        let deleted_receipts = diesel::delete(receipts::table)
-            .filter(
-                receipts::recipient_id
-                    .eq(source_id)
-                    .and(receipts::message_id.eq_any(target_receipts)),
-            )
-            .execute(db)?;
-        log::log!(
-            if deleted_receipts > 0 {
-                log::Level::Warn
-            } else {
-                log::Level::Trace
-            },
-            "Deleted {} receipts. Please file an issue if > 0",
-            deleted_receipts
-        );
-        let updated_receipts = diesel::update(receipts::table)
-            .filter(receipts::recipient_id.eq(source_id))
-            .set(receipts::recipient_id.eq(dest_id))
-            .execute(db)?;
-        log::trace!("Updated {} receipts", updated_receipts);
-
-        let deleted = diesel::delete(recipients::table)
-            .filter(recipients::id.eq(source_id))
-            .execute(db)?;
-        log::trace!("Deleted {} recipient", deleted);
-        assert_eq!(deleted, 1, "delete only one recipient");
-        Ok(dest_id)
+        ))
     }
 
     pub fn fetch_recipient_by_uuid(&self, recipient_uuid: Uuid) -> Option<orm::Recipient> {
@@ -1316,6 +1124,53 @@ impl Storage {
         }
     }
 
+    pub fn fetch_recipient_by_pni(&self, recipient_pni: Uuid) -> Option<orm::Recipient> {
+        use crate::schema::recipients::dsl::*;
+        recipients
+            .filter(pni.eq(recipient_pni.to_string()))
+            .first(&mut *self.db())
+            .optional()
+            .expect("db")
+    }
+
+    pub fn fetch_or_insert_recipient_by_pni(&self, new_pni: Uuid) -> orm::Recipient {
+        use crate::schema::recipients::dsl::*;
+
+        let new_pni = new_pni.to_string();
+        let mut db = self.db();
+        let db = &mut *db;
+        if let Ok(recipient) = recipients.filter(pni.eq(&new_pni)).first(db) {
+            recipient
+        } else {
+            diesel::insert_into(recipients)
+                .values(pni.eq(&new_pni))
+                .execute(db)
+                .expect("insert new recipient");
+            let recipient: orm::Recipient = recipients
+                .filter(pni.eq(&new_pni))
+                .first(db)
+                .expect("newly inserted recipient");
+            self.observe_insert(recipients, recipient.id);
+            recipient
+        }
+    }
+
+    /// Whether `recipient_id` should get our PNI signature with the next message, because they
+    /// wrote to our phone number.
+    pub fn set_recipient_needs_pni_signature(&self, recipient_id: i32, needed: bool) -> bool {
+        use crate::schema::recipients::dsl::*;
+        let affected = diesel::update(recipients)
+            .set(needs_pni_signature.eq(needed))
+            .filter(id.eq(recipient_id))
+            .filter(needs_pni_signature.ne(needed))
+            .execute(&mut *self.db())
+            .expect("existing record updated");
+        if affected > 0 {
+            self.observe_update(recipients, recipient_id);
+        }
+        affected > 0
+    }
+
     pub fn fetch_or_insert_recipient_by_phonenumber(
         &self,
         phonenumber: &PhoneNumber,
@@ -1404,6 +1259,95 @@ impl Storage {
         }
     }
 
+    /// Marks a message as viewed, e.g. when a voice note has been played.
+    ///
+    /// Returns the message if it had not been viewed before, such that a viewed receipt can be
+    /// sent.
+    pub fn mark_message_viewed(&self, mid: i32) -> Option<orm::Message> {
+        log::trace!("Called mark_message_viewed({})", mid);
+        use schema::messages::dsl::*;
+
+        let affected_rows =
+            diesel::update(messages.filter(id.eq(mid).and(viewed_timestamp.is_null())))
+                .set(viewed_timestamp.eq(chrono::Utc::now().naive_utc()))
+                .execute(&mut *self.db())
+                .expect("mark message viewed");
+
+        if affected_rows == 0 {
+            return None;
+        }
+
+        let message = self.fetch_message_by_id(mid)?;
+        self.observe_update(messages, message.id)
+            .with_relation(schema::sessions::table, message.session_id);
+        Some(message)
+    }
+
+    /// Flags a message as view-once media.
+    pub fn mark_message_view_once(&self, mid: i32) {
+        log::trace!("Called mark_message_view_once({})", mid);
+        use schema::messages::dsl::*;
+
+        diesel::update(messages.filter(id.eq(mid)))
+            .set(is_view_once.eq(true))
+            .execute(&mut *self.db())
+            .expect("mark message view once");
+    }
+
+    /// Removes the files of a view-once message and forgets their paths.
+    ///
+    /// The attachment rows themselves are kept, such that the UI can still show that there *was*
+    /// some media. Returns the number of deleted files.
+    pub fn erase_view_once_attachments(&self, mid: i32) -> usize {
+        log::trace!("Called erase_view_once_attachments({})", mid);
+
+        let message = match self.fetch_message_by_id(mid) {
+            Some(message) => message,
+            None => {
+                log::warn!("Tried to erase attachments of non-existing message {}", mid);
+                return 0;
+            }
+        };
+        if !message.is_view_once {
+            log::warn!("Refusing to erase attachments of regular message {}", mid);
+            return 0;
+        }
+
+        let mut n_files = 0;
+        for attachment in self.fetch_attachments_for_message(mid) {
+            if let Some(path) = &attachment.attachment_path {
+                match std::fs::remove_file(path) {
+                    Ok(()) => {
+                        log::trace!("Deleted view-once file {}", path);
+                        n_files += 1;
+                    }
+                    Err(e) => log::warn!("Could not delete view-once file {}: {:?}", path, e),
+                }
+            }
+
+            diesel::update(schema::attachments::table)
+                .filter(schema::attachments::id.eq(attachment.id))
+                .set(schema::attachments::attachment_path.eq(None::<String>))
+                .execute(&mut *self.db())
+                .expect("clear view-once attachment path");
+            self.observe_update(schema::attachments::table, attachment.id)
+                .with_relation(schema::messages::table, mid);
+        }
+
+        n_files
+    }
+
+    /// Overrides the time at which a message was received, e.g. for messages that are imported.
+    pub fn set_message_received_timestamp(&self, mid: i32, timestamp: NaiveDateTime) {
+        log::trace!("Called set_message_received_timestamp({})", mid);
+        use schema::messages::dsl::*;
+        diesel::update(messages.filter(id.eq(mid)))
+            .set(received_timestamp.eq(timestamp))
+            .execute(&mut *self.db())
+            .expect("update received timestamp");
+        self.observe_update(messages, mid);
+    }
+
     /// Marks the message with a certain timestamp as received by a certain person.
     pub fn mark_message_received(
         &self,
@@ -1415,8 +1359,9 @@ impl Storage {
         let delivered_at = delivered_at.unwrap_or_else(chrono::Utc::now).naive_utc();
 
         // Find the recipient
-        let recipient =
-            self.merge_and_fetch_recipient(None, Some(receiver_uuid), None, TrustLevel::Certain);
+        let recipient = self
+            .merge_and_fetch_recipient(None, Some(receiver_uuid), None, TrustLevel::Certain)
+            .ok()?;
         let message_id = schema::messages::table
             .select(schema::messages::id)
             .filter(schema::messages::server_timestamp.eq(timestamp))
@@ -2062,6 +2007,58 @@ impl Storage {
         };
     }
 
+    /// Record the transformation applied to an outgoing attachment before upload.
+    ///
+    /// `properties` is the JSON-serialized transform, which includes the path and the dimensions
+    /// of the transformed file.  The attachment itself keeps describing the original.
+    pub fn store_attachment_transform(&self, attachment_id: i32, properties: &str) {
+        use schema::attachments::dsl::*;
+        log::trace!("Called store_attachment_transform({})", attachment_id);
+
+        let count = diesel::update(attachments.filter(id.eq(attachment_id)))
+            .set(transform_properties.eq(properties))
+            .execute(&mut *self.db())
+            .expect("store attachment transform properties");
+
+        if count == 1 {
+            self.observe_update(attachments, attachment_id);
+        } else {
+            log::error!(
+                "Could not save transform properties to attachment {}",
+                attachment_id
+            );
+        }
+    }
+
+    /// Mark an attachment as a voice note, and store its duration and waveform summary.
+    pub fn store_voice_note_summary(
+        &self,
+        attachment_id: i32,
+        duration: Option<std::time::Duration>,
+        waveform: Option<&[u8]>,
+    ) {
+        use schema::attachments::dsl::*;
+        log::trace!("Called store_voice_note_summary({})", attachment_id);
+
+        let count = diesel::update(attachments.filter(id.eq(attachment_id)))
+            .set((
+                is_voice_note.eq(true),
+                audio_duration.eq(duration.map(|d| d.as_millis() as i32)),
+                audio_waveform.eq(waveform),
+            ))
+            .execute(&mut *self.db())
+            .expect("store voice note summary");
+
+        if count == 1 {
+            self.observe_update(attachments, attachment_id);
+        } else {
+            log::error!(
+                "Could not save voice note summary to attachment {}",
+                attachment_id
+            );
+        }
+    }
+
     /// Create a new message. This was transparent within SaveMessage in Go.
     ///
     /// Panics is new_message.session_id is None.
@@ -2146,43 +2143,80 @@ impl Storage {
         log::trace!("Inserted message id {}", latest_message.id);
 
         if let Some(path) = &new_message.attachment {
-            let affected_rows = {
-                let att_file = File::open(path).unwrap();
-                let att_size = match att_file.metadata() {
-                    Ok(m) => Some(m.len() as i32),
-                    Err(_) => None,
-                };
-
-                let att_path = Path::new(path);
-                let att_filename = att_path.file_name().map(|s| s.to_str().unwrap());
-
-                use schema::attachments::dsl::*;
-                diesel::insert_into(attachments)
-                    .values((
-                        message_id.eq(latest_message.id),
-                        content_type.eq(new_message.mime_type.as_ref().unwrap()),
-                        attachment_path.eq(path),
-                        size.eq(att_size),
-                        file_name.eq(att_filename),
-                        is_voice_note.eq(false),
-                        is_borderless.eq(false),
-                        is_quote.eq(false),
-                    ))
-                    .execute(&mut *self.db())
-                    .expect("Insert attachment")
-            };
-            self.observe_insert(schema::attachments::table, PrimaryKey::Unknown)
-                .with_relation(schema::messages::table, latest_message.id);
-
-            assert_eq!(
-                affected_rows, 1,
-                "Did not insert the attachment. Dazed and confused."
-            );
+            if let Err(e) = self.insert_local_attachment(
+                latest_message.id,
+                0,
+                &NewAttachment {
+                    path: path.clone(),
+                    mime_type: new_message.mime_type.clone().unwrap(),
+                    ..Default::default()
+                },
+            ) {
+                log::error!(
+                    "Message {} loses its attachment: {:#}",
+                    latest_message.id,
+                    e
+                );
+            }
         }
 
         latest_message
     }
 
+    /// Attach a local file to a message, at position `order` in the message's album.
+    ///
+    /// Fails when the file cannot be opened.
+    pub fn insert_local_attachment(
+        &self,
+        mid: i32,
+        order: i32,
+        new_attachment: &NewAttachment,
+    ) -> Result<orm::Attachment, anyhow::Error> {
+        log::trace!("Called insert_local_attachment({}, {})", mid, order);
+
+        let affected_rows = {
+            let att_file = File::open(&new_attachment.path)
+                .with_context(|| format!("Could not open attachment {}", new_attachment.path))?;
+            let att_size = match att_file.metadata() {
+                Ok(m) => Some(m.len() as i32),
+                Err(_) => None,
+            };
+
+            let att_path = Path::new(&new_attachment.path);
+            let att_filename = att_path.file_name().and_then(|s| s.to_str());
+
+            use schema::attachments::dsl::*;
+            diesel::insert_into(attachments)
+                .values((
+                    message_id.eq(mid),
+                    content_type.eq(&new_attachment.mime_type),
+                    attachment_path.eq(&new_attachment.path),
+                    size.eq(att_size),
+                    file_name.eq(att_filename),
+                    caption.eq(&new_attachment.caption),
+                    display_order.eq(order),
+                    is_voice_note.eq(new_attachment.is_voice_note),
+                    is_borderless.eq(new_attachment.is_borderless),
+                    is_quote.eq(false),
+                ))
+                .execute(&mut *self.db())
+                .expect("Insert attachment")
+        };
+
+        assert_eq!(
+            affected_rows, 1,
+            "Did not insert the attachment. Dazed and confused."
+        );
+
+        let latest_attachment = self.fetch_latest_attachment().expect("inserted attachment");
+        self.observe_insert(
+            schema::attachments::table,
+            PrimaryKey::RowId(latest_attachment.id),
+        )
+        .with_relation(schema::messages::table, mid);
+        Ok(latest_attachment)
+    }
+
     /// This was implicit in Go, which probably didn't use threads.
     ///
     /// It needs to be locked from the outside because sqlite sucks.
@@ -2210,6 +2244,16 @@ impl Storage {
         query.first(&mut *self.db()).ok()
     }
 
+    /// All messages with this timestamp, which only their session and sender tell apart.
+    pub fn fetch_messages_by_timestamp(&self, ts: NaiveDateTime) -> Vec<orm::Message> {
+        log::trace!("Called fetch_messages_by_timestamp({})", ts);
+        schema::messages::table
+            .filter(schema::messages::server_timestamp.eq(ts))
+            .order_by(schema::messages::id)
+            .load(&mut *self.db())
+            .expect("db")
+    }
+
     pub fn fetch_recipient_by_id(&self, id: i32) -> Option<orm::Recipient> {
         log::trace!("Called fetch_recipient_by_id({})", id);
         schema::recipients::table
@@ -2280,6 +2324,18 @@ impl Storage {
         count as _
     }
 
+    /// Return the amount of unread messages in a session
+    pub fn unread_count(&self, sid: i32) -> i32 {
+        log::trace!("Called unread_count({})", sid);
+        let count: i64 = schema::messages::table
+            .filter(schema::messages::session_id.eq(sid))
+            .filter(schema::messages::is_read.is(false))
+            .count()
+            .get_result(&mut *self.db())
+            .expect("db");
+        count as _
+    }
+
     pub fn fetch_augmented_message(&self, message_id: i32) -> Option<orm::AugmentedMessage> {
         let message = self.fetch_message_by_id(message_id)?;
         let receipts = self.fetch_message_receipts(message.id);
@@ -2476,44 +2532,45 @@ impl Storage {
         n_messages
     }
 
-    /// Marks all messages that are outbound and unsent as failed.
-    pub fn mark_pending_messages_failed(&self) -> usize {
-        use schema::messages::dsl::*;
-        let failed_messages: Vec<orm::Message> = messages
-            .filter(
-                sent_timestamp
-                    .is_null()
-                    .and(is_outbound)
-                    .and(sending_has_failed.eq(false)),
-            )
+    /// The sealed sender certificates that have not expired yet.
+    pub fn fetch_sender_certificates(&self) -> Vec<orm::SenderCertificate> {
+        use schema::sender_certificates::dsl::*;
+        sender_certificates
+            .filter(expires.gt(Utc::now().naive_utc()))
             .load(&mut *self.db())
-            .unwrap();
+            .expect("db")
+    }
 
-        let count = diesel::update(messages)
-            .filter(
-                sent_timestamp
-                    .is_null()
-                    .and(is_outbound)
-                    .and(sending_has_failed.eq(false)),
-            )
-            .set(schema::messages::sending_has_failed.eq(true))
+    /// Stores a sealed sender certificate, replacing the one of the same `kind`.
+    pub fn save_sender_certificate(
+        &self,
+        cert_kind: &str,
+        cert: &[u8],
+        cert_expires: NaiveDateTime,
+    ) {
+        use schema::sender_certificates::dsl::*;
+        diesel::insert_into(sender_certificates)
+            .values((
+                kind.eq(cert_kind),
+                certificate.eq(cert),
+                expires.eq(cert_expires),
+            ))
             .execute(&mut *self.db())
-            .unwrap();
-        assert_eq!(failed_messages.len(), count);
+            .expect("save sender certificate");
 
-        for message in failed_messages {
-            self.observe_update(schema::messages::table, message.id);
-        }
-        let level = if count == 0 {
-            log::Level::Trace
-        } else {
-            log::Level::Warn
-        };
-        log::log!(level, "Set {} messages to failed", count);
-        count
+        // Expired certificates are of no use anymore.
+        let removed =
+            diesel::delete(sender_certificates.filter(expires.le(Utc::now().naive_utc())))
+                .execute(&mut *self.db())
+                .expect("delete expired sender certificates");
+        log::trace!(
+            "Saved {} sender certificate, removed {} expired ones",
+            cert_kind,
+            removed
+        );
     }
 
-    /// Marks a message as failed to send
+    /// Marks a message as failed to send, and takes it out of the outbox.
     pub fn fail_message(&self, mid: i32) {
         log::trace!("Setting message {} to failed", mid);
         diesel::update(schema::messages::table)
@@ -2521,10 +2578,13 @@ impl Storage {
             .set(schema::messages::sending_has_failed.eq(true))
             .execute(&mut *self.db())
             .unwrap();
+        self.remove_from_outbox(mid);
         self.observe_update(schema::messages::table, mid);
     }
 
+    /// Marks a message as sent, and takes it out of the outbox.
     pub fn dequeue_message(&self, mid: i32, sent_time: NaiveDateTime, unidentified: bool) {
+        self.remove_from_outbox(mid);
         diesel::update(schema::messages::table)
             .filter(schema::messages::id.eq(mid))
             .set((
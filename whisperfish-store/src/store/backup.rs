@@ -0,0 +1,535 @@
+//! Passphrase-encrypted backups of a whole account.
+//!
+//! A backup is a single file that holds everything needed to resume on another device without
+//! registering again: the contents of the database (which includes the protocol store), the
+//! identity files, the [SignalConfig], and the attachment and avatar files.
+//!
+//! The file starts with a magic, a format version and the salt of the passphrase, followed by a
+//! sequence of frames.  Every frame is a little endian `u32` length, followed by a
+//! bincode-serialized [BackupEntry], encrypted with [encryption::StorageEncryption].  The last
+//! frame is always [BackupEntry::End], so that a truncated backup is detected.  Files are split
+//! over a [BackupEntry] and as many [BackupEntry::Chunk]s as needed, so that neither exporting nor
+//! restoring holds a whole file in memory.
+
+use super::*;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+
+/// Name under which backups of an account are exported, and under which they are looked for when
+/// setting up that account.  `None` is the default account.
+pub fn account_backup_file_name(account: Option<&str>) -> String {
+    match account {
+        None => "harbour-whisperfish.wfbackup".into(),
+        Some(account) => format!("harbour-whisperfish-{}.wfbackup", account),
+    }
+}
+
+const BACKUP_MAGIC: &[u8; 8] = b"WFBACKUP";
+/// Version 2 encrypts the frames in the current file format of [encryption::StorageEncryption],
+/// version 3 splits files into chunks.  Older backups are still read.
+const BACKUP_VERSION: u8 = 3;
+/// Largest amount of file contents in a single frame.
+const BACKUP_CHUNK_SIZE: u64 = 1024 * 1024;
+/// Shortest passphrase that a backup is exported with, in characters.
+pub const MIN_BACKUP_PASSPHRASE_LENGTH: usize = 12;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+enum BackupEntry {
+    /// The [SignalConfig], as YAML.
+    Config(String),
+    /// A plaintext copy of the database.
+    Database(Vec<u8>),
+    /// A file from `storage/identity`, decrypted.
+    Identity {
+        name: String,
+        contents: Vec<u8>,
+    },
+    Attachment {
+        name: String,
+        contents: Vec<u8>,
+    },
+    Avatar {
+        name: String,
+        contents: Vec<u8>,
+    },
+    /// Marks the end of the backup, with the amount of entries that came before.
+    End {
+        entries: u64,
+    },
+    /// The next part of the file of the preceding entry.
+    Chunk(Vec<u8>),
+}
+
+/// Outcome of [Storage::export_backup].
+#[derive(Clone, Debug, Default)]
+pub struct BackupSummary {
+    /// Amount of entries in the backup, excluding the end marker.
+    pub entries: u64,
+    /// Size of the backup file, in bytes.
+    pub bytes: u64,
+}
+
+struct BackupWriter {
+    file: std::io::BufWriter<File>,
+    store_enc: encryption::StorageEncryption,
+    entries: u64,
+}
+
+impl BackupWriter {
+    fn create(path: &Path, passphrase: &str) -> Result<Self, anyhow::Error> {
+        use rand::RngCore;
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let store_enc = encryption::StorageEncryption::for_archive_blocking(passphrase, salt)?;
+
+        let mut file = std::io::BufWriter::new(
+            File::create(path)
+                .with_context(|| format!("Could not create backup {}", path.display()))?,
+        );
+        file.write_all(BACKUP_MAGIC)?;
+        file.write_all(&[BACKUP_VERSION])?;
+        file.write_all(&salt)?;
+
+        Ok(Self {
+            file,
+            store_enc,
+            entries: 0,
+        })
+    }
+
+    fn write(&mut self, entry: &BackupEntry) -> Result<(), anyhow::Error> {
+        let mut frame = bincode::serialize(entry)?;
+        self.store_enc.encrypt(&mut frame);
+        let len = u32::try_from(frame.len()).context("Backup entry too large")?;
+        self.file.write_all(&len.to_le_bytes())?;
+        self.file.write_all(&frame)?;
+        if !matches!(entry, BackupEntry::End { .. }) {
+            self.entries += 1;
+        }
+        Ok(())
+    }
+
+    /// Writes the file at `path` as `entry`, followed by chunks for what did not fit in it.
+    fn write_file(
+        &mut self,
+        path: &Path,
+        entry: impl FnOnce(Vec<u8>) -> BackupEntry,
+    ) -> Result<(), anyhow::Error> {
+        let mut file = File::open(path)?;
+        let mut chunk = Vec::new();
+        (&mut file)
+            .take(BACKUP_CHUNK_SIZE)
+            .read_to_end(&mut chunk)?;
+        self.write(&entry(chunk))?;
+        loop {
+            let mut chunk = Vec::new();
+            (&mut file)
+                .take(BACKUP_CHUNK_SIZE)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                return Ok(());
+            }
+            self.write(&BackupEntry::Chunk(chunk))?;
+        }
+    }
+
+    fn finish(mut self) -> Result<u64, anyhow::Error> {
+        self.write(&BackupEntry::End {
+            entries: self.entries,
+        })?;
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        Ok(self.entries)
+    }
+}
+
+struct BackupReader {
+    file: std::io::BufReader<File>,
+    store_enc: encryption::StorageEncryption,
+    /// Bytes left in the file, which bounds the length of the next frame.
+    remaining: u64,
+}
+
+impl BackupReader {
+    async fn open(path: &Path, passphrase: &str) -> Result<Self, anyhow::Error> {
+        let file = File::open(path)
+            .with_context(|| format!("Could not open backup {}", path.display()))?;
+        let size = file.metadata()?.len();
+        let mut file = std::io::BufReader::new(file);
+
+        let mut magic = [0u8; 8];
+        let mut version = [0u8; 1];
+        let mut salt = [0u8; 16];
+        file.read_exact(&mut magic)
+            .context("Backup is missing its header")?;
+        anyhow::ensure!(&magic == BACKUP_MAGIC, "Not a Whisperfish backup");
+        file.read_exact(&mut version)?;
+        anyhow::ensure!(
+            (1..=BACKUP_VERSION).contains(&version[0]),
+            "Unsupported backup version {}",
+            version[0]
+        );
+        file.read_exact(&mut salt)?;
+
+        let store_enc =
+            encryption::StorageEncryption::for_archive(passphrase.to_string(), salt).await?;
+        let remaining = size - (magic.len() + version.len() + salt.len()) as u64;
+        Ok(Self {
+            file,
+            store_enc,
+            remaining,
+        })
+    }
+
+    /// Reads the next frame, still encrypted.
+    fn read_frame(&mut self) -> Result<Vec<u8>, anyhow::Error> {
+        let mut len = [0u8; 4];
+        self.file
+            .read_exact(&mut len)
+            .context("Backup is truncated")?;
+        // The length is not authenticated yet, so do not allocate more than the file can hold.
+        let len = u64::from(u32::from_le_bytes(len));
+        anyhow::ensure!(len + 4 <= self.remaining, "Backup is truncated or corrupt");
+        self.remaining -= len + 4;
+
+        let mut frame = vec![0u8; len as usize];
+        self.file
+            .read_exact(&mut frame)
+            .context("Backup is truncated")?;
+        Ok(frame)
+    }
+
+    fn read(&mut self) -> Result<BackupEntry, anyhow::Error> {
+        let mut frame = self.read_frame()?;
+        self.store_enc
+            .decrypt(&mut frame)
+            .context("Could not decrypt backup; probably wrong passphrase")?;
+        Ok(bincode::deserialize(&frame)?)
+    }
+}
+
+/// Only accept plain file names from a backup, so that it cannot write outside of the storage.
+fn backup_file_name(name: &str) -> Result<&str, anyhow::Error> {
+    match Path::new(name).file_name().and_then(|n| n.to_str()) {
+        Some(file_name) if file_name == name => Ok(name),
+        _ => anyhow::bail!("Invalid file name {:?} in backup", name),
+    }
+}
+
+fn create_with_contents(path: &Path, contents: &[u8]) -> Result<File, anyhow::Error> {
+    let mut file = File::create(path)?;
+    file.write_all(contents)?;
+    Ok(file)
+}
+
+fn sql_string(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Copies the contents of `db` into a new database at `target`, encrypted with `database_key` and
+/// the same parameters as [Storage::open_db] uses, or unencrypted without a key.
+pub(super) fn sqlcipher_export(
+    db: &mut SqliteConnection,
+    target: &Path,
+    database_key: Option<&[u8]>,
+) -> Result<(), anyhow::Error> {
+    let target = target
+        .to_str()
+        .context("path to db contains a non-UTF8 character, please file a bug.")?;
+    let key = match database_key {
+        Some(key) => format!("\"x'{}'\"", hex::encode(key)),
+        None => "''".into(),
+    };
+    db.batch_execute(&format!(
+        "ATTACH DATABASE {} AS target KEY {};",
+        sql_string(target),
+        key
+    ))
+    .context("attaching database")?;
+    if database_key.is_some() {
+        db.batch_execute(
+            "PRAGMA target.cipher_page_size = 4096;
+            PRAGMA target.kdf_iter = 64000;
+            PRAGMA target.cipher_hmac_algorithm = HMAC_SHA1;
+            PRAGMA target.cipher_kdf_algorithm = PBKDF2_HMAC_SHA1;",
+        )
+        .context("setting cipher parameters")?;
+    }
+    let exported = db.batch_execute("SELECT sqlcipher_export('target');");
+    db.batch_execute("DETACH DATABASE target;")
+        .context("detaching database")?;
+    exported.context("exporting database")?;
+    Ok(())
+}
+
+impl Storage {
+    /// Checks whether `passphrase` opens the backup at `path`, by decrypting its first frame.
+    ///
+    /// Errors are reserved for backups that cannot be read at all.
+    pub async fn check_backup_passphrase(
+        path: &Path,
+        passphrase: &str,
+    ) -> Result<bool, anyhow::Error> {
+        let mut reader = BackupReader::open(path, passphrase).await?;
+        let mut frame = reader.read_frame()?;
+        Ok(reader.store_enc.decrypt(&mut frame).is_ok())
+    }
+
+    /// Writes a backup of the whole account to `target`, encrypted with `passphrase`.
+    ///
+    /// The backup is first written next to `target`, and only moved in place when complete.
+    /// This blocks for as long as the backup takes, so run it on a threadpool.
+    pub fn export_backup(
+        &self,
+        target: &Path,
+        passphrase: &str,
+        avatar_dir: Option<&Path>,
+    ) -> Result<BackupSummary, anyhow::Error> {
+        log::trace!("Called export_backup({})", target.display());
+        anyhow::ensure!(
+            passphrase.chars().count() >= MIN_BACKUP_PASSPHRASE_LENGTH,
+            "A backup needs a passphrase of at least {} characters",
+            MIN_BACKUP_PASSPHRASE_LENGTH
+        );
+
+        let partial = target.with_extension("part");
+        let mut writer = BackupWriter::create(&partial, passphrase)?;
+
+        writer.write(&BackupEntry::Config(serde_yaml::to_string(&*self.config)?))?;
+
+        // The scratch directory lives in the storage, so that the plaintext copy of the database
+        // does not end up on a shared file system.
+        let scratch = tempfile::tempdir_in(&self.path)?;
+        let plaintext_db = scratch.path().join("plaintext.db");
+        self.export_plaintext_db(&plaintext_db)?;
+        writer.write_file(&plaintext_db, BackupEntry::Database)?;
+        drop(scratch);
+
+        let store_enc = self.store_enc();
+        let identity_dir = self.path.join("storage").join("identity");
+        for entry in std::fs::read_dir(&identity_dir)? {
+            let entry = entry?;
+            if !entry.metadata()?.is_file() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let mut contents = std::fs::read(entry.path())?;
+            if let Some(store_enc) = &store_enc {
+                store_enc.decrypt(&mut contents)?;
+            }
+            writer.write(&BackupEntry::Identity { name, contents })?;
+        }
+
+        let attachment_paths: Vec<String> = schema::attachments::table
+            .select(schema::attachments::attachment_path)
+            .filter(schema::attachments::attachment_path.is_not_null())
+            .load::<Option<String>>(&mut *self.db())?
+            .into_iter()
+            .flatten()
+            .collect();
+        for path in attachment_paths {
+            let path = Path::new(&path);
+            let name = match path.file_name() {
+                Some(name) => name.to_string_lossy().into_owned(),
+                None => continue,
+            };
+            if let Err(e) = std::fs::metadata(path) {
+                log::warn!("Skipping attachment {}: {}", path.display(), e);
+                continue;
+            }
+            writer.write_file(path, |contents| BackupEntry::Attachment { name, contents })?;
+        }
+
+        if let Some(avatar_dir) = avatar_dir.filter(|dir| dir.exists()) {
+            for entry in std::fs::read_dir(avatar_dir)? {
+                let entry = entry?;
+                if !entry.metadata()?.is_file() {
+                    continue;
+                }
+                let name = entry.file_name().to_string_lossy().into_owned();
+                writer.write_file(&entry.path(), |contents| BackupEntry::Avatar {
+                    name,
+                    contents,
+                })?;
+            }
+        }
+
+        let entries = writer.finish()?;
+        // Sailjail mounts the directories separately, which makes a rename fail.
+        if std::fs::rename(&partial, target).is_err() {
+            std::fs::copy(&partial, target)
+                .with_context(|| format!("Could not write backup {}", target.display()))?;
+            std::fs::remove_file(&partial)?;
+        }
+
+        let bytes = std::fs::metadata(target)?.len();
+        log::info!(
+            "Exported backup with {} entries, {} bytes, to {}",
+            entries,
+            bytes,
+            target.display()
+        );
+        Ok(BackupSummary { entries, bytes })
+    }
+
+    /// Restores a backup into a new Storage at the provided path, *overwriting* what is there.
+    ///
+    /// The restored storage is encrypted with `password`, which is independent of the passphrase
+    /// of the backup.  The account fields of `config` are set from the backup, but the config is
+    /// not written to disk.
+    pub async fn restore_backup<T: AsRef<Path>>(
+        config: Arc<SignalConfig>,
+        db_path: &StorageLocation<T>,
+        backup: &Path,
+        passphrase: &str,
+        password: Option<&str>,
+    ) -> Result<Storage, anyhow::Error> {
+        log::info!("Restoring backup {}", backup.display());
+        let path: &Path = std::ops::Deref::deref(db_path);
+        let mut reader = BackupReader::open(backup, passphrase).await?;
+        // Read ahead before touching the storage, so that a wrong passphrase leaves it alone.
+        let mut next = Some(reader.read()?);
+
+        Self::scaffold_directories(path)?;
+        let store_enc = Self::generate_encryption(path, password).await?;
+
+        let identity_dir = path.join("storage").join("identity");
+        let attachment_dir = path.join("storage").join("attachments");
+        let avatar_dir = path.join("storage").join("avatars");
+
+        let scratch = tempfile::tempdir_in(path)?;
+        let plaintext_db = scratch.path().join("plaintext.db");
+
+        let mut restored_config = None;
+        let mut restored_attachments = HashSet::new();
+        // The file that chunks are appended to.
+        let mut open_file: Option<File> = None;
+        let mut entries = 0;
+        loop {
+            let entry = match next.take() {
+                Some(entry) => entry,
+                None => reader.read()?,
+            };
+            if !matches!(entry, BackupEntry::Chunk(_)) {
+                open_file = None;
+            }
+            match entry {
+                BackupEntry::Config(yaml) => {
+                    let backup_config: SignalConfig = serde_yaml::from_str(&yaml)?;
+                    restored_config = Some(backup_config);
+                }
+                BackupEntry::Database(contents) => {
+                    open_file = Some(create_with_contents(&plaintext_db, &contents)?);
+                }
+                BackupEntry::Identity { name, contents } => {
+                    let file = identity_dir.join(backup_file_name(&name)?);
+                    utils::write_file_async_encrypted(file, contents, store_enc.as_ref()).await?;
+                }
+                BackupEntry::Attachment { name, contents } => {
+                    let file = attachment_dir.join(backup_file_name(&name)?);
+                    open_file = Some(create_with_contents(&file, &contents)?);
+                    restored_attachments.insert(name);
+                }
+                BackupEntry::Avatar { name, contents } => {
+                    let file = avatar_dir.join(backup_file_name(&name)?);
+                    open_file = Some(create_with_contents(&file, &contents)?);
+                }
+                BackupEntry::Chunk(contents) => {
+                    open_file
+                        .as_mut()
+                        .context("Backup holds a chunk without a file")?
+                        .write_all(&contents)?;
+                }
+                BackupEntry::End { entries: expected } => {
+                    anyhow::ensure!(
+                        entries == expected,
+                        "Backup holds {} entries, expected {}",
+                        entries,
+                        expected
+                    );
+                    break;
+                }
+            }
+            entries += 1;
+        }
+
+        let restored_config =
+            restored_config.context("Backup does not contain the account configuration")?;
+        anyhow::ensure!(
+            plaintext_db.exists(),
+            "Backup does not contain the database"
+        );
+
+        let db_file = path.join("db").join("harbour-whisperfish.db");
+        if db_file.exists() {
+            std::fs::remove_file(&db_file)?;
+        }
+        Self::import_plaintext_db(
+            &plaintext_db,
+            &db_file,
+            store_enc.as_ref().map(|x| x.get_database_key()),
+        )?;
+        drop(scratch);
+
+        if let Some(tel) = restored_config.get_tel() {
+            config.set_tel(tel);
+        }
+        if let Some(uuid) = restored_config.get_uuid() {
+            config.set_uuid(uuid);
+        }
+        if let Some(pni) = restored_config.get_pni() {
+            config.set_pni(pni);
+        }
+        config.set_device_id(restored_config.get_device_id().into());
+
+        let storage = Self::open(config, db_path, password.map(String::from)).await?;
+        storage.relocate_attachments(&attachment_dir, &restored_attachments);
+        log::info!("Restored backup with {} entries", entries);
+        Ok(storage)
+    }
+
+    /// Copies the contents of the database into a new, unencrypted database at `target`.
+    fn export_plaintext_db(&self, target: &Path) -> Result<(), anyhow::Error> {
+        sqlcipher_export(&mut self.db(), target, None)
+    }
+
+    /// Copies the unencrypted database at `source` into a new database at `target`, encrypted
+    /// with the same parameters as [Storage::open_db] uses.
+    fn import_plaintext_db(
+        source: &Path,
+        target: &Path,
+        database_key: Option<&[u8]>,
+    ) -> Result<(), anyhow::Error> {
+        let source = source
+            .to_str()
+            .context("path to db contains a non-UTF8 character, please file a bug.")?;
+
+        let mut db = SqliteConnection::establish(source)?;
+        sqlcipher_export(&mut db, target, database_key)
+    }
+
+    /// Points the attachments of which the file was restored into `dir` to their new location.
+    fn relocate_attachments(&self, dir: &Path, restored: &HashSet<String>) {
+        use schema::attachments::dsl::*;
+        let paths: Vec<(i32, Option<String>)> = attachments
+            .select((id, attachment_path))
+            .load(&mut *self.db())
+            .expect("db");
+
+        for (attachment_id, path) in paths {
+            let name = match path.as_deref().and_then(|p| Path::new(p).file_name()) {
+                Some(name) => name.to_string_lossy().into_owned(),
+                None => continue,
+            };
+            if !restored.contains(&name) {
+                continue;
+            }
+            let new_path = dir.join(&name);
+            diesel::update(attachments.filter(id.eq(attachment_id)))
+                .set(attachment_path.eq(new_path.to_str()))
+                .execute(&mut *self.db())
+                .expect("update attachment path");
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Contact discovery: finding out which phone numbers belong to Signal users.
+//!
+//! The lookup itself is done by a [ContactDiscoveryService].  [Storage::discover_contacts] merges
+//! the results into the recipients, and keeps their `is_registered` flag up to date.
+//!
+//! XXX: Signal's contact discovery service (CDSI) runs in an SGX enclave, and is reached through
+//! a Noise channel over a websocket after remote attestation.  libsignal-service has no client
+//! for it yet, so only the tests plug in a service, and the app updates `is_registered` only when
+//! sending messages.
+
+use super::*;
+use std::collections::HashSet;
+
+/// A phone number that is registered with Signal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveredContact {
+    pub e164: PhoneNumber,
+    /// Only known when the service was allowed to reveal it, i.e. when the profile key of the
+    /// contact is known, or the contact is discoverable by phone number.
+    pub aci: Option<Uuid>,
+    pub pni: Option<Uuid>,
+}
+
+/// Looks up phone numbers with a contact discovery service.
+#[async_trait::async_trait(?Send)]
+pub trait ContactDiscoveryService {
+    /// Returns the numbers of `e164s` that are registered with Signal.  Numbers that are not
+    /// registered are left out.
+    async fn discover(
+        &mut self,
+        e164s: &[PhoneNumber],
+    ) -> Result<Vec<DiscoveredContact>, anyhow::Error>;
+}
+
+/// Outcome of [Storage::discover_contacts].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ContactDiscoveryReport {
+    /// Number of distinct phone numbers that were looked up.
+    pub queried: usize,
+    /// Number of phone numbers that are registered with Signal.
+    pub registered: usize,
+    /// Number of known recipients that are no longer registered.
+    pub unregistered: usize,
+}
+
+impl Storage {
+    /// Looks up `e164s`, together with the phone numbers of all known recipients, and records
+    /// the results.
+    pub async fn discover_contacts(
+        &self,
+        service: &mut dyn ContactDiscoveryService,
+        e164s: impl IntoIterator<Item = PhoneNumber>,
+    ) -> Result<ContactDiscoveryReport, anyhow::Error> {
+        let known = self
+            .fetch_recipients()
+            .into_iter()
+            .filter_map(|recipient| recipient.e164);
+        let queried: Vec<PhoneNumber> = e164s
+            .into_iter()
+            .chain(known)
+            .unique_by(PhoneNumber::to_string)
+            .collect();
+        if queried.is_empty() {
+            return Ok(ContactDiscoveryReport::default());
+        }
+
+        log::info!("Looking up {} phone numbers", queried.len());
+        let found = service
+            .discover(&queried)
+            .await
+            .context("Contact discovery")?;
+        Ok(self.apply_contact_discovery(&queried, &found))
+    }
+
+    /// Records the outcome of a contact discovery lookup of `queried`.
+    ///
+    /// Registered numbers are merged into the recipients, numbers without result mark their
+    /// recipient, if any, as unregistered.  No recipients are created for unregistered numbers.
+    pub fn apply_contact_discovery(
+        &self,
+        queried: &[PhoneNumber],
+        found: &[DiscoveredContact],
+    ) -> ContactDiscoveryReport {
+        let mut report = ContactDiscoveryReport {
+            queried: queried.len(),
+            ..Default::default()
+        };
+
+        for contact in found {
+            let recipient = self
+                .merge_and_fetch_recipient(
+                    Some(contact.e164.clone()),
+                    contact.aci,
+                    contact.pni,
+                    TrustLevel::Certain,
+                )
+                .expect("recipient with a phone number");
+            self.set_recipient_registered(&recipient, true);
+            report.registered += 1;
+        }
+
+        let found: HashSet<String> = found.iter().map(|x| x.e164.to_string()).collect();
+        for e164 in queried {
+            if found.contains(&e164.to_string()) {
+                continue;
+            }
+            if let Some(recipient) = self.fetch_recipient_by_phonenumber(e164) {
+                if self.set_recipient_registered(&recipient, false) {
+                    report.unregistered += 1;
+                }
+            }
+        }
+
+        log::info!(
+            "Contact discovery: {} of {} phone numbers are registered, {} recipients unregistered",
+            report.registered,
+            report.queried,
+            report.unregistered
+        );
+        report
+    }
+
+    /// Returns whether the flag changed.
+    fn set_recipient_registered(&self, recipient: &orm::Recipient, registered: bool) -> bool {
+        if recipient.is_registered == registered {
+            return false;
+        }
+
+        use schema::recipients::dsl::*;
+        diesel::update(recipients.filter(id.eq(recipient.id)))
+            .set(is_registered.eq(registered))
+            .execute(&mut *self.db())
+            .expect("mark recipient (un)registered");
+        self.observe_update(recipients, recipient.id);
+        true
+    }
+
+    /// The number of recipients with a phone number that are registered with Signal.
+    pub fn count_registered_contacts(&self) -> usize {
+        use schema::recipients::dsl::*;
+        let count: i64 = recipients
+            .filter(e164.is_not_null())
+            .filter(is_registered.eq(true))
+            .count()
+            .get_result(&mut *self.db())
+            .expect("db");
+        count as usize
+    }
+}
@@ -1,25 +1,40 @@
 use anyhow::Context;
 use secrecy::ExposeSecret;
 
+/// Header of files in the current format: a magic and the format version.
+///
+/// Files without this header are in the legacy format of Go-Whisperfish: a random IV, the AES-128-CBC
+/// ciphertext and an HMAC-SHA256, with a key derived by PBKDF2-SHA1.
+const FILE_MAGIC: &[u8; 3] = b"WFE";
+const FILE_VERSION: u8 = 2;
+const FILE_HEADER_LEN: usize = FILE_MAGIC.len() + 1;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
 /// Functions to encrypt and decrypt storage files
 ///
 /// This module collects cryptography functions that are tied to the storage module. Internally the
-/// `secrecy` crate is used to prevent any access to keys' memory. Please look at the documentation
-/// of the `block_modes` crate for more information
+/// `secrecy` crate is used to prevent any access to keys' memory.
+///
+/// Files are written with AES-256-GCM, under a key derived by Argon2id, behind a versioned header.
+/// Files in the legacy AES-128-CBC+HMAC format can still be decrypted, and are upgraded by
+/// [StorageEncryption::upgrade].  For the legacy format, please look at the documentation of the
+/// `block_modes` crate
 /// (<https://docs.rs/block-modes/0.8.1/block_modes/trait.BlockMode.html#method.encrypt>) and to the
 /// corresponding RFC (<https://datatracker.ietf.org/doc/html/rfc5652#section-6.3>).
-// XXX This crypto module should use libsodium in the future!
 #[derive(Debug, Clone)]
 pub struct StorageEncryption {
     /// Key for local files
+    key_file: secrecy::Secret<[u8; 32]>,
+    /// Key for local files in the legacy format
     key_storage: secrecy::Secret<[u8; 16 + 20]>,
     /// Key for the sqlite database
     key_database: secrecy::Secret<[u8; 32]>,
 }
 
 impl StorageEncryption {
-    /// Derives a storage and a database key. The database key is used to en-/decrypt the database,
-    /// the storage key is used to en-/decrypt files on the local hard drive.
+    /// Derives the storage and the database keys. The database key is used to en-/decrypt the
+    /// database, the storage keys are used to en-/decrypt files on the local hard drive.
     // XXX Is the use of threadpools necessary?
     pub async fn new(
         password: String,
@@ -29,7 +44,11 @@ impl StorageEncryption {
         tokio::task::spawn_blocking(move || -> Result<Self, anyhow::Error> {
             let password = password.as_bytes();
 
-            // Derive storage key
+            // Derive file key
+            let key_file = derive_file_key(password, &salt_storage)?;
+            log::trace!("Computed the file key, salt was {:?}", salt_storage);
+
+            // Derive legacy storage key
             let mut key_storage = [0u8; 16 + 20];
             // Please don't blame me, I'm only the implementer.
             pbkdf2::pbkdf2::<hmac::Hmac<sha1::Sha1>>(
@@ -49,6 +68,7 @@ impl StorageEncryption {
 
             // Create self and return
             Ok(Self {
+                key_file: secrecy::Secret::new(key_file),
                 key_storage: secrecy::Secret::new(key_storage),
                 key_database: secrecy::Secret::new(key_database),
             })
@@ -57,8 +77,126 @@ impl StorageEncryption {
         .map_err(anyhow::Error::from)?
     }
 
-    /// Encrypt data in place. Uses the storage key. IV and MAC are appended to the msg vector.
+    /// Derives the keys for an archive that leaves the device, like a backup.
+    ///
+    /// All keys are derived with scrypt, instead of the PBKDF2 that is kept around for the
+    /// legacy storage key for compatibility with Go-Whisperfish. Only the file keys are used; the
+    /// legacy one to read archives that were written in the legacy format.
+    pub async fn for_archive(password: String, salt: [u8; 16]) -> Result<Self, anyhow::Error> {
+        tokio::task::spawn_blocking(move || Self::for_archive_blocking(&password, salt))
+            .await
+            .map_err(anyhow::Error::from)?
+    }
+
+    /// Blocking variant of [StorageEncryption::for_archive], for callers that already run on a
+    /// threadpool.
+    pub fn for_archive_blocking(password: &str, salt: [u8; 16]) -> Result<Self, anyhow::Error> {
+        let params = scrypt::Params::new(14, 8, 1).unwrap();
+        // scrypt ends with PBKDF2, so the first bytes do not depend on the length of the key.
+        let mut key = [0u8; 16 + 20 + 32 + 32];
+        scrypt::scrypt(password.as_bytes(), &salt, &params, &mut key)
+            .context("Cannot compute archive key")?;
+
+        let mut key_storage = [0u8; 16 + 20];
+        let mut key_database = [0u8; 32];
+        let mut key_file = [0u8; 32];
+        key_storage.copy_from_slice(&key[..16 + 20]);
+        key_database.copy_from_slice(&key[16 + 20..16 + 20 + 32]);
+        key_file.copy_from_slice(&key[16 + 20 + 32..]);
+
+        Ok(Self {
+            key_file: secrecy::Secret::new(key_file),
+            key_storage: secrecy::Secret::new(key_storage),
+            key_database: secrecy::Secret::new(key_database),
+        })
+    }
+
+    /// Encrypts data in place, in the current format.  The header, the nonce and the tag are added
+    /// to the msg vector.
     pub fn encrypt(&self, msg: &mut Vec<u8>) {
+        use aes_gcm::aead::{Aead, NewAead, Payload};
+        use rand::RngCore;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let header = file_header();
+        let cipher =
+            aes_gcm::Aes256Gcm::new(aes_gcm::Key::from_slice(self.key_file.expose_secret()));
+        let ciphertext = cipher
+            .encrypt(
+                aes_gcm::Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &msg[..],
+                    aad: &header,
+                },
+            )
+            .expect("AES GCM encryption error");
+
+        let mut out = Vec::with_capacity(FILE_HEADER_LEN + NONCE_LEN + ciphertext.len());
+        out.extend_from_slice(&header);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        *msg = out;
+    }
+
+    /// Decrypts message in place, in the current or in the legacy format.
+    pub fn decrypt(&self, msg: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        // A legacy file starts with a random IV, which could accidentally look like the header.
+        if is_current_format(msg) {
+            match self.decrypt_current(msg) {
+                Ok(cleartext) => {
+                    *msg = cleartext;
+                    return Ok(());
+                }
+                Err(e) if msg.len() < 16 + 32 => return Err(e),
+                Err(_) => log::debug!("Not in the current format, trying the legacy format"),
+            }
+        }
+        self.decrypt_legacy(msg)
+    }
+
+    /// Re-encrypts a message in the legacy format in the current format.
+    ///
+    /// Returns whether the message was upgraded.  Messages in the current format are left alone.
+    pub fn upgrade(&self, msg: &mut Vec<u8>) -> Result<bool, anyhow::Error> {
+        if is_current_format(msg) && self.decrypt_current(msg).is_ok() {
+            return Ok(false);
+        }
+        self.decrypt_legacy(msg)?;
+        self.encrypt(msg);
+        Ok(true)
+    }
+
+    fn decrypt_current(&self, msg: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        use aes_gcm::aead::{Aead, NewAead, Payload};
+
+        anyhow::ensure!(
+            msg.len() >= FILE_HEADER_LEN + NONCE_LEN + TAG_LEN,
+            "Attempt at decrypting a message with length {} smaller than minimum length {}",
+            msg.len(),
+            FILE_HEADER_LEN + NONCE_LEN + TAG_LEN
+        );
+        let (header, rest) = msg.split_at(FILE_HEADER_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        let cipher =
+            aes_gcm::Aes256Gcm::new(aes_gcm::Key::from_slice(self.key_file.expose_secret()));
+        cipher
+            .decrypt(
+                aes_gcm::Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("AES GCM decryption error"))
+    }
+
+    /// Encrypts data in place in the legacy format. Uses the legacy storage key. IV and MAC are
+    /// appended to the msg vector.
+    #[cfg(test)]
+    fn encrypt_legacy(&self, msg: &mut Vec<u8>) {
         // Load traits
         use block_modes::BlockMode;
         use hmac::{Mac, NewMac};
@@ -114,8 +252,8 @@ impl StorageEncryption {
         *msg = iv;
     }
 
-    /// Decrypts message in place. Expects IV and MAC also in msg vector.
-    pub fn decrypt(&self, msg: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+    /// Decrypts message in place in the legacy format. Expects IV and MAC also in msg vector.
+    fn decrypt_legacy(&self, msg: &mut Vec<u8>) -> Result<(), anyhow::Error> {
         use block_modes::BlockMode;
         use hmac::{Mac, NewMac};
 
@@ -168,6 +306,30 @@ impl StorageEncryption {
     }
 }
 
+fn file_header() -> [u8; FILE_HEADER_LEN] {
+    let mut header = [0u8; FILE_HEADER_LEN];
+    header[..FILE_MAGIC.len()].copy_from_slice(FILE_MAGIC);
+    header[FILE_MAGIC.len()] = FILE_VERSION;
+    header
+}
+
+fn is_current_format(msg: &[u8]) -> bool {
+    msg.starts_with(&file_header())
+}
+
+/// Argon2id with the parameters recommended by OWASP: 19 MiB of memory, two passes.
+fn derive_file_key(password: &[u8], salt: &[u8]) -> Result<[u8; 32], anyhow::Error> {
+    let params = argon2::Params::new(19 * 1024, 2, 1, Some(32))
+        .map_err(|e| anyhow::anyhow!("Invalid Argon2 parameters: {}", e))?;
+    let argon2 = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+
+    let mut key = [0u8; 32];
+    argon2
+        .hash_password_into(password, salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Cannot compute file key: {}", e))?;
+    Ok(key)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -220,9 +382,9 @@ mod tests {
         );
     }
 
-    /// In this function we test whether padding of input vector is correct even if length of the
-    /// input vector is the same size as aes block size. According to the RFC a whole block is
-    /// added if message length is a multiple of aes block size.
+    /// In this function we test whether padding of input vector is correct in the legacy format
+    /// even if length of the input vector is the same size as aes block size. According to the
+    /// RFC a whole block is added if message length is a multiple of aes block size.
     #[tokio::test]
     async fn check_padding_length() {
         let crypto = StorageEncryption::new(String::from("my secret key"), [0u8; 8], [0u8; 8])
@@ -231,7 +393,7 @@ mod tests {
 
         // First, create a cleartext equal to aes block size. We expect padding of one block size.
         let mut cleartext = vec![1u8; aes::BLOCK_SIZE];
-        crypto.encrypt(&mut cleartext);
+        crypto.encrypt_legacy(&mut cleartext);
 
         // Check whether ciphertext is of this length: 2 * aes block size + iv + mac
         assert_eq!(cleartext.len(), 2 * aes::BLOCK_SIZE + 16 + 32);
@@ -239,7 +401,7 @@ mod tests {
         // Next, create a cleartext equal to aes block size + 1. We expect a padding of block_size
         // - 1.
         let mut cleartext = vec![1u8; aes::BLOCK_SIZE + 1];
-        crypto.encrypt(&mut cleartext);
+        crypto.encrypt_legacy(&mut cleartext);
 
         // Check whether ciphertext is of this length: 2 * aes block size + iv + mac
         assert_eq!(cleartext.len(), 2 * aes::BLOCK_SIZE + 16 + 32);
@@ -247,7 +409,7 @@ mod tests {
         // Next, create a cleartext equal to aes block size - 1. We expect a padding of 1 to the
         // full block size.
         let mut cleartext = vec![1u8; aes::BLOCK_SIZE - 1];
-        crypto.encrypt(&mut cleartext);
+        crypto.encrypt_legacy(&mut cleartext);
 
         // Check whether ciphertext is of this length: aes block size + iv + mac
         assert_eq!(cleartext.len(), aes::BLOCK_SIZE + 16 + 32);
@@ -311,6 +473,146 @@ mod tests {
         assert_eq!(my_cleartext, my_ciphertext.as_slice());
     }
 
+    /// Files are written in the current format: header, nonce, ciphertext and tag.
+    #[tokio::test]
+    async fn current_format() {
+        let crypto = StorageEncryption::new(String::from("my secret key"), [0u8; 8], [0u8; 8])
+            .await
+            .unwrap();
+
+        let cleartext = b"my secret message";
+        let mut ciphertext = cleartext.to_vec();
+        crypto.encrypt(&mut ciphertext);
+
+        assert_eq!(&ciphertext[..3], b"WFE");
+        assert_eq!(ciphertext[3], FILE_VERSION);
+        assert_eq!(
+            ciphertext.len(),
+            FILE_HEADER_LEN + NONCE_LEN + cleartext.len() + TAG_LEN
+        );
+
+        crypto.decrypt(&mut ciphertext).unwrap();
+        assert_eq!(cleartext, ciphertext.as_slice());
+    }
+
+    /// Flipping a single bit, also in the header, makes decryption fail.
+    #[tokio::test]
+    async fn current_format_is_authenticated() {
+        let crypto = StorageEncryption::new(String::from("my secret key"), [0u8; 8], [0u8; 8])
+            .await
+            .unwrap();
+
+        let mut ciphertext = b"my secret message".to_vec();
+        crypto.encrypt(&mut ciphertext);
+
+        for &i in &[
+            3,
+            FILE_HEADER_LEN,
+            FILE_HEADER_LEN + NONCE_LEN,
+            ciphertext.len() - 1,
+        ] {
+            let mut tampered = ciphertext.clone();
+            tampered[i] ^= 1;
+            assert!(crypto.decrypt(&mut tampered).is_err(), "byte {}", i);
+        }
+    }
+
+    /// The file key depends on the password and on the salt.
+    #[tokio::test]
+    async fn current_format_key_derivation() {
+        let crypto = StorageEncryption::new(String::from("my secret key"), [0u8; 8], [0u8; 8])
+            .await
+            .unwrap();
+        let other_salt = StorageEncryption::new(String::from("my secret key"), [1u8; 8], [0u8; 8])
+            .await
+            .unwrap();
+        let other_password =
+            StorageEncryption::new(String::from("my other secret key"), [0u8; 8], [0u8; 8])
+                .await
+                .unwrap();
+
+        let mut ciphertext = b"my secret message".to_vec();
+        crypto.encrypt(&mut ciphertext);
+
+        assert!(other_salt.decrypt(&mut ciphertext.clone()).is_err());
+        assert!(other_password.decrypt(&mut ciphertext.clone()).is_err());
+        assert_ne!(
+            crypto.key_file.expose_secret(),
+            other_salt.key_file.expose_secret()
+        );
+    }
+
+    /// Legacy files can still be read, and are upgraded to the current format.
+    #[tokio::test]
+    async fn upgrade_legacy_format() {
+        let crypto = StorageEncryption::new(String::from("my secret key"), [0u8; 8], [0u8; 8])
+            .await
+            .unwrap();
+
+        let cleartext = b"my secret message";
+        let mut legacy = cleartext.to_vec();
+        crypto.encrypt_legacy(&mut legacy);
+        assert!(!is_current_format(&legacy));
+
+        let mut decrypted = legacy.clone();
+        crypto.decrypt(&mut decrypted).unwrap();
+        assert_eq!(cleartext, decrypted.as_slice());
+
+        let mut upgraded = legacy;
+        assert!(crypto.upgrade(&mut upgraded).unwrap());
+        assert!(is_current_format(&upgraded));
+        let current = upgraded.clone();
+        assert!(!crypto.upgrade(&mut upgraded).unwrap());
+        assert_eq!(current, upgraded);
+
+        crypto.decrypt(&mut upgraded).unwrap();
+        assert_eq!(cleartext, upgraded.as_slice());
+    }
+
+    /// A legacy file whose random IV starts like the header is still read as legacy file.
+    #[tokio::test]
+    async fn legacy_format_that_looks_current() {
+        let crypto = StorageEncryption::new(String::from("my secret key"), [0u8; 8], [0u8; 8])
+            .await
+            .unwrap();
+
+        let cleartext = b"my secret message";
+        let mut legacy = cleartext.to_vec();
+        crypto.encrypt_legacy(&mut legacy);
+        // The IV is not covered by the encryption, only by the MAC, so recompute that.
+        legacy[..FILE_HEADER_LEN].copy_from_slice(&file_header());
+        let mac = {
+            use hmac::{Mac, NewMac};
+            let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(
+                &crypto.key_storage.expose_secret()[16..],
+            )
+            .unwrap();
+            mac.update(&legacy[..legacy.len() - 32]);
+            mac.finalize().into_bytes()
+        };
+        let len = legacy.len();
+        legacy[len - 32..].copy_from_slice(&mac);
+        assert!(is_current_format(&legacy));
+
+        // Changing the IV changes the start of the first block, but the file is readable.
+        crypto.decrypt(&mut legacy).unwrap();
+        assert_eq!(&cleartext[FILE_HEADER_LEN..], &legacy[FILE_HEADER_LEN..]);
+    }
+
+    #[tokio::test]
+    async fn archive_keys_are_stable() {
+        let crypto = StorageEncryption::for_archive("lol".into(), [5u8; 16])
+            .await
+            .unwrap();
+
+        // The keys of archives written before the current format.
+        let params = scrypt::Params::new(14, 8, 1).unwrap();
+        let mut key = [0u8; 16 + 20 + 32];
+        scrypt::scrypt(b"lol", &[5u8; 16], &params, &mut key).unwrap();
+        assert_eq!(crypto.key_storage.expose_secret()[..], key[..16 + 20]);
+        assert_eq!(crypto.key_database.expose_secret()[..], key[16 + 20..]);
+    }
+
     #[tokio::test]
     async fn decrypt_too_short() {
         let crypto = StorageEncryption::new("lol".into(), [0u8; 8], [0u8; 8])
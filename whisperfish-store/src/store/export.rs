@@ -0,0 +1,443 @@
+//! Export of a single conversation to a file that can be read without Whisperfish.
+//!
+//! The conversation is written as HTML, JSON or plain text.  Attachments are copied into a
+//! directory next to the exported file, and are referred to by their relative path, such that
+//! the export can be moved around as a whole.
+
+use super::*;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Html,
+    Json,
+    Text,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Html => "html",
+            Self::Json => "json",
+            Self::Text => "txt",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "html" | "htm" => Ok(Self::Html),
+            "json" => Ok(Self::Json),
+            "text" | "txt" | "plain" => Ok(Self::Text),
+            _ => anyhow::bail!("Unknown export format {:?}; use html, json or text", s),
+        }
+    }
+}
+
+/// Outcome of [Storage::export_session].
+#[derive(Clone, Debug)]
+pub struct ExportSummary {
+    /// The exported conversation.
+    pub path: PathBuf,
+    pub messages: usize,
+    /// Number of attachments that were copied along.
+    pub attachments: usize,
+}
+
+#[derive(serde::Serialize)]
+struct ExportedConversation {
+    name: String,
+    is_group: bool,
+    exported_at: String,
+    messages: Vec<ExportedMessage>,
+}
+
+#[derive(serde::Serialize)]
+struct ExportedMessage {
+    id: i32,
+    sender: String,
+    is_outbound: bool,
+    /// Milliseconds since the epoch, as used by Signal.
+    timestamp: i64,
+    date: String,
+    text: Option<String>,
+    is_remote_deleted: bool,
+    quote: Option<ExportedQuote>,
+    attachments: Vec<ExportedAttachment>,
+    reactions: Vec<ExportedReaction>,
+}
+
+#[derive(serde::Serialize)]
+struct ExportedQuote {
+    sender: String,
+    text: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct ExportedAttachment {
+    content_type: String,
+    /// Path relative to the exported file, or `None` if the attachment was never downloaded.
+    file: Option<String>,
+    caption: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct ExportedReaction {
+    sender: String,
+    emoji: String,
+}
+
+impl Storage {
+    /// Exports a session into `target_dir`, and returns where it was written.
+    ///
+    /// The file is named after the session, and its attachments are copied into a directory
+    /// with the same name and an `_attachments` suffix.
+    pub fn export_session(
+        &self,
+        sid: i32,
+        format: ExportFormat,
+        target_dir: &Path,
+    ) -> Result<ExportSummary, anyhow::Error> {
+        log::trace!("Called export_session({}, {:?})", sid, format);
+        let session = self
+            .fetch_session_by_id(sid)
+            .with_context(|| format!("No session with id {}", sid))?;
+
+        let name = match &session.r#type {
+            orm::SessionType::DirectMessage(recipient) => recipient.name().into_owned(),
+            orm::SessionType::GroupV1(group) => group.name.clone(),
+            orm::SessionType::GroupV2(group) => group.name.clone(),
+        };
+        let stem = format!(
+            "{}-{}",
+            sanitize_file_name(&name),
+            Utc::now().format("%Y%m%d-%H%M%S")
+        );
+        std::fs::create_dir_all(target_dir)
+            .with_context(|| format!("Could not create {}", target_dir.display()))?;
+        let path = target_dir.join(&stem).with_extension(format.extension());
+        let attachment_dir_name = format!("{}_attachments", stem);
+        let attachment_dir = target_dir.join(&attachment_dir_name);
+
+        let self_recipient = self.fetch_self_recipient();
+        let self_id = self_recipient.as_ref().map(|r| r.id);
+        let self_name = self_recipient
+            .map(|r| r.name().into_owned())
+            .unwrap_or_else(|| "Me".into());
+        let mut names: HashMap<i32, String> = HashMap::new();
+        let mut sender_name = |rid: Option<i32>, is_outbound: bool| -> String {
+            match rid {
+                _ if is_outbound => self_name.clone(),
+                Some(rid) => names
+                    .entry(rid)
+                    .or_insert_with(|| {
+                        self.fetch_recipient_by_id(rid)
+                            .map(|r| r.name().into_owned())
+                            .unwrap_or_else(|| "Unknown".into())
+                    })
+                    .clone(),
+                None => "Unknown".into(),
+            }
+        };
+
+        let mut copied = 0;
+        let mut messages = Vec::new();
+        // Messages come newest first, but a transcript reads top to bottom.
+        for augmented in self.fetch_all_messages_augmented(sid).into_iter().rev() {
+            let message = augmented.inner;
+
+            let quote = message
+                .quote_id
+                .and_then(|qid| self.fetch_message_by_id(qid))
+                .map(|quoted| ExportedQuote {
+                    sender: sender_name(quoted.sender_recipient_id, quoted.is_outbound),
+                    text: quoted.text,
+                });
+
+            let mut attachments = Vec::new();
+            if augmented.attachments > 0 {
+                for attachment in self.fetch_attachments_for_message(message.id) {
+                    let file = match &attachment.attachment_path {
+                        // View-once media stays private, also in an export.
+                        Some(source) if !message.is_view_once => {
+                            match copy_attachment(source, &attachment_dir) {
+                                Ok(file_name) => {
+                                    copied += 1;
+                                    Some(format!("{}/{}", attachment_dir_name, file_name))
+                                }
+                                Err(e) => {
+                                    log::warn!("Could not export attachment {}: {}", source, e);
+                                    None
+                                }
+                            }
+                        }
+                        _ => None,
+                    };
+                    attachments.push(ExportedAttachment {
+                        content_type: attachment.content_type,
+                        file,
+                        caption: attachment.caption,
+                    });
+                }
+            }
+
+            let reactions = self
+                .fetch_reactions_for_message(message.id)
+                .into_iter()
+                .map(|(reaction, recipient)| ExportedReaction {
+                    sender: if Some(recipient.id) == self_id {
+                        self_name.clone()
+                    } else {
+                        recipient.name().into_owned()
+                    },
+                    emoji: reaction.emoji,
+                })
+                .collect();
+
+            messages.push(ExportedMessage {
+                id: message.id,
+                sender: sender_name(message.sender_recipient_id, message.is_outbound),
+                is_outbound: message.is_outbound,
+                timestamp: message.server_timestamp.timestamp_millis(),
+                date: message
+                    .server_timestamp
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string(),
+                text: message.text,
+                is_remote_deleted: message.is_remote_deleted,
+                quote,
+                attachments,
+                reactions,
+            });
+        }
+
+        let conversation = ExportedConversation {
+            name,
+            is_group: session.is_group(),
+            exported_at: Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            messages,
+        };
+        let contents = match format {
+            ExportFormat::Html => render_html(&conversation),
+            ExportFormat::Json => serde_json::to_string_pretty(&conversation)?,
+            ExportFormat::Text => render_text(&conversation),
+        };
+        std::fs::write(&path, contents)
+            .with_context(|| format!("Could not write {}", path.display()))?;
+
+        log::info!(
+            "Exported {} messages and {} attachments to {}",
+            conversation.messages.len(),
+            copied,
+            path.display()
+        );
+        Ok(ExportSummary {
+            path,
+            messages: conversation.messages.len(),
+            attachments: copied,
+        })
+    }
+}
+
+/// Copies an attachment into `dir`, keeping its (random) file name.
+fn copy_attachment(source: &str, dir: &Path) -> Result<String, anyhow::Error> {
+    let source = Path::new(source);
+    let file_name = source
+        .file_name()
+        .context("Attachment path has no file name")?
+        .to_string_lossy()
+        .into_owned();
+    std::fs::create_dir_all(dir)?;
+    std::fs::copy(source, dir.join(&file_name))?;
+    Ok(file_name)
+}
+
+fn sanitize_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() {
+        "conversation".into()
+    } else {
+        name
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn render_text(conversation: &ExportedConversation) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{}", conversation.name);
+    let _ = writeln!(out, "Exported on {} UTC", conversation.exported_at);
+
+    for message in &conversation.messages {
+        let _ = writeln!(out);
+        let _ = writeln!(out, "[{}] {}:", message.date, message.sender);
+        if let Some(quote) = &message.quote {
+            let _ = writeln!(
+                out,
+                "  > {}: {}",
+                quote.sender,
+                quote.text.as_deref().unwrap_or("")
+            );
+        }
+        if message.is_remote_deleted {
+            let _ = writeln!(out, "  (this message was deleted)");
+        } else if let Some(text) = &message.text {
+            for line in text.lines() {
+                let _ = writeln!(out, "  {}", line);
+            }
+        }
+        for attachment in &message.attachments {
+            let _ = writeln!(
+                out,
+                "  [{}: {}]",
+                attachment.content_type,
+                attachment.file.as_deref().unwrap_or("not available")
+            );
+        }
+        if !message.reactions.is_empty() {
+            let reactions: Vec<_> = message
+                .reactions
+                .iter()
+                .map(|r| format!("{} {}", r.emoji, r.sender))
+                .collect();
+            let _ = writeln!(out, "  Reactions: {}", reactions.join(", "));
+        }
+    }
+    out
+}
+
+fn render_html(conversation: &ExportedConversation) -> String {
+    let mut out = String::new();
+    let title = escape_html(&conversation.name);
+    let _ = write!(
+        out,
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; max-width: 50em; margin: auto; background: #f4f4f4; }}
+.message {{ background: #fff; border-radius: 0.5em; margin: 0.5em 3em 0.5em 0; padding: 0.5em; }}
+.outbound {{ background: #dcebfc; margin: 0.5em 0 0.5em 3em; }}
+.meta {{ color: #666; font-size: small; }}
+.quote {{ border-left: 3px solid #999; padding-left: 0.5em; color: #444; }}
+.reactions {{ font-size: small; }}
+img, video {{ max-width: 100%; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+<p class="meta">Exported on {date} UTC</p>
+"#,
+        title = title,
+        date = conversation.exported_at,
+    );
+
+    for message in &conversation.messages {
+        let _ = writeln!(
+            out,
+            r#"<div class="message{}" id="message-{}">"#,
+            if message.is_outbound { " outbound" } else { "" },
+            message.id,
+        );
+        let _ = writeln!(
+            out,
+            r#"<div class="meta"><b>{}</b> {}</div>"#,
+            escape_html(&message.sender),
+            message.date,
+        );
+        if let Some(quote) = &message.quote {
+            let _ = writeln!(
+                out,
+                r#"<div class="quote"><b>{}</b><br>{}</div>"#,
+                escape_html(&quote.sender),
+                escape_html(quote.text.as_deref().unwrap_or("")).replace('\n', "<br>"),
+            );
+        }
+        for attachment in &message.attachments {
+            let _ = match &attachment.file {
+                Some(file) if attachment.content_type.starts_with("image/") => writeln!(
+                    out,
+                    r#"<div><a href="{0}"><img src="{0}"></a></div>"#,
+                    escape_html(file)
+                ),
+                Some(file) if attachment.content_type.starts_with("video/") => writeln!(
+                    out,
+                    r#"<div><video controls src="{}"></video></div>"#,
+                    escape_html(file)
+                ),
+                Some(file) if attachment.content_type.starts_with("audio/") => writeln!(
+                    out,
+                    r#"<div><audio controls src="{}"></audio></div>"#,
+                    escape_html(file)
+                ),
+                Some(file) => writeln!(
+                    out,
+                    r#"<div><a href="{0}">{0}</a></div>"#,
+                    escape_html(file)
+                ),
+                None => writeln!(
+                    out,
+                    r#"<div class="meta">[{}: not available]</div>"#,
+                    escape_html(&attachment.content_type)
+                ),
+            };
+            if let Some(caption) = &attachment.caption {
+                let _ = writeln!(out, "<div>{}</div>", escape_html(caption));
+            }
+        }
+        if message.is_remote_deleted {
+            let _ = writeln!(out, "<div><i>This message was deleted.</i></div>");
+        } else if let Some(text) = &message.text {
+            let _ = writeln!(
+                out,
+                "<div>{}</div>",
+                escape_html(text).replace('\n', "<br>")
+            );
+        }
+        if !message.reactions.is_empty() {
+            let reactions: Vec<_> = message
+                .reactions
+                .iter()
+                .map(|r| format!("{} {}", escape_html(&r.emoji), escape_html(&r.sender)))
+                .collect();
+            let _ = writeln!(
+                out,
+                r#"<div class="reactions">{}</div>"#,
+                reactions.join(", ")
+            );
+        }
+        let _ = writeln!(out, "</div>");
+    }
+
+    out.push_str("</body>\n</html>\n");
+    out
+}
@@ -0,0 +1,275 @@
+//! Reconciliation of the files in the storage directories with the database.
+//!
+//! Attachment files can get orphaned in several ways: deleted outbound messages keep their camera
+//! pictures and scaled copies around, failed downloads leave partial files, and older versions of
+//! Whisperfish did not clean up at all.  The same holds for avatars of recipients and groups that
+//! have since disappeared.
+
+use super::*;
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// Files younger than this are never considered orphaned, because they may belong to an
+/// attachment that is still being downloaded or queued.
+pub const GARBAGE_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// What to do with files that are not referenced from the database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OrphanAction {
+    /// Only report the orphans.
+    Report,
+    /// Move the orphans into the given directory.
+    Quarantine(PathBuf),
+    /// Delete the orphans.
+    Delete,
+}
+
+/// Outcome of [Storage::collect_garbage].
+#[derive(Clone, Debug, Default)]
+pub struct GarbageReport {
+    /// Files that are not referenced from the database.
+    pub orphans: Vec<PathBuf>,
+    /// Total size of the orphaned files, in bytes.
+    pub orphan_bytes: u64,
+    /// Attachments whose file has gone missing.
+    pub missing_attachments: Vec<i32>,
+}
+
+/// Disk space taken by the attachments of a single session.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionDiskUsage {
+    pub session_id: i32,
+    pub attachment_count: i64,
+    pub bytes: i64,
+}
+
+/// The part of the attachment transform properties we care about.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TransformedPath {
+    transformed_path: Option<String>,
+}
+
+impl Storage {
+    /// Reconciles the files in the attachment and avatar directories with the database.
+    ///
+    /// Files are matched by name: attachments are stored under random names, and avatars are
+    /// named after the UUID of the recipient or the id of the group.  The attachments of queued
+    /// messages are kept like any other, and so are the files that a draft mentions.
+    ///
+    /// This walks the file system, so run it on a threadpool.
+    pub fn collect_garbage(
+        &self,
+        attachment_dirs: &[PathBuf],
+        avatar_dir: Option<&Path>,
+        action: &OrphanAction,
+        grace_period: Duration,
+    ) -> Result<GarbageReport, anyhow::Error> {
+        log::trace!("Called collect_garbage({:?})", action);
+        let mut report = GarbageReport::default();
+
+        let (mut attachment_files, missing) = self.referenced_attachment_files();
+        attachment_files.extend(self.referenced_draft_files());
+        report.missing_attachments = missing;
+        for dir in attachment_dirs {
+            collect_orphans(dir, &attachment_files, grace_period, &mut report)?;
+        }
+
+        if let Some(avatar_dir) = avatar_dir {
+            let avatar_files = self.referenced_avatar_files();
+            collect_orphans(avatar_dir, &avatar_files, grace_period, &mut report)?;
+        }
+
+        if !report.orphans.is_empty() {
+            log::info!(
+                "Found {} orphaned files, {} bytes in total",
+                report.orphans.len(),
+                report.orphan_bytes
+            );
+        }
+        if !report.missing_attachments.is_empty() {
+            log::warn!(
+                "{} attachments refer to a missing file",
+                report.missing_attachments.len()
+            );
+        }
+
+        match action {
+            OrphanAction::Report => {}
+            OrphanAction::Delete => {
+                for orphan in &report.orphans {
+                    std::fs::remove_file(orphan)
+                        .with_context(|| format!("Could not delete {}", orphan.display()))?;
+                    log::trace!("Deleted orphan {}", orphan.display());
+                }
+            }
+            OrphanAction::Quarantine(quarantine) => {
+                std::fs::create_dir_all(quarantine).with_context(|| {
+                    format!("Could not create quarantine {}", quarantine.display())
+                })?;
+                for orphan in &report.orphans {
+                    let target = quarantine.join(orphan.file_name().expect("file name"));
+                    // Sailjail mounts the directories separately, which makes a rename fail.
+                    if std::fs::rename(orphan, &target).is_err() {
+                        std::fs::copy(orphan, &target).with_context(|| {
+                            format!("Could not quarantine {}", orphan.display())
+                        })?;
+                        std::fs::remove_file(orphan)?;
+                    }
+                    log::trace!("Quarantined orphan {}", orphan.display());
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Sums the size of the attachments on disk, per session, largest first.
+    pub fn fetch_session_disk_usage(&self) -> Vec<SessionDiskUsage> {
+        log::trace!("Called fetch_session_disk_usage()");
+        use diesel::dsl::{count, sum};
+        use schema::{attachments, messages};
+
+        let usage: Vec<(i32, i64, Option<i64>)> = attachments::table
+            .inner_join(messages::table)
+            .filter(attachments::attachment_path.is_not_null())
+            .group_by(messages::session_id)
+            .select((
+                messages::session_id,
+                count(attachments::id),
+                sum(attachments::size),
+            ))
+            .load(&mut *self.db())
+            .expect("db");
+
+        usage
+            .into_iter()
+            .map(|(session_id, attachment_count, bytes)| SessionDiskUsage {
+                session_id,
+                attachment_count,
+                bytes: bytes.unwrap_or(0),
+            })
+            .sorted_by_key(|usage| std::cmp::Reverse(usage.bytes))
+            .collect()
+    }
+
+    /// Returns the names of all attachment files the database knows of, including scaled copies,
+    /// together with the ids of the attachments of which the file is missing.
+    fn referenced_attachment_files(&self) -> (HashSet<String>, Vec<i32>) {
+        use schema::attachments::dsl::*;
+        let rows: Vec<(i32, Option<String>, Option<String>)> = attachments
+            .select((id, attachment_path, transform_properties))
+            .load(&mut *self.db())
+            .expect("db");
+
+        let mut files = HashSet::new();
+        let mut missing = Vec::new();
+        for (attachment_id, path, properties) in rows {
+            if let Some(path) = path {
+                if !Path::new(&path).exists() {
+                    missing.push(attachment_id);
+                }
+                files.extend(file_name(&path));
+            }
+
+            let transformed = properties
+                .as_deref()
+                .and_then(|p| serde_json::from_str::<TransformedPath>(p).ok())
+                .and_then(|p| p.transformed_path);
+            if let Some(transformed) = transformed {
+                files.extend(file_name(&transformed));
+            }
+        }
+        (files, missing)
+    }
+
+    /// Returns the names of the files that drafts mention, e.g. a path pasted in a message that
+    /// is still being written.
+    fn referenced_draft_files(&self) -> HashSet<String> {
+        let drafts: Vec<Option<String>> = schema::sessions::table
+            .select(schema::sessions::draft)
+            .filter(schema::sessions::draft.is_not_null())
+            .load(&mut *self.db())
+            .expect("db");
+
+        drafts
+            .iter()
+            .flatten()
+            .flat_map(|draft| draft.split_whitespace())
+            .filter_map(file_name)
+            .collect()
+    }
+
+    /// Returns the names of all avatars that may be in use.
+    fn referenced_avatar_files(&self) -> HashSet<String> {
+        let uuids: Vec<Option<String>> = schema::recipients::table
+            .select(schema::recipients::uuid)
+            .load(&mut *self.db())
+            .expect("db");
+        let groups_v1: Vec<String> = schema::group_v1s::table
+            .select(schema::group_v1s::id)
+            .load(&mut *self.db())
+            .expect("db");
+        let groups_v2: Vec<String> = schema::group_v2s::table
+            .select(schema::group_v2s::id)
+            .load(&mut *self.db())
+            .expect("db");
+
+        uuids
+            .into_iter()
+            .flatten()
+            .chain(groups_v1)
+            .chain(groups_v2)
+            .collect()
+    }
+}
+
+fn file_name(path: &str) -> Option<String> {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(String::from)
+}
+
+/// Adds the files in `dir` that are not in `referenced` to the report.
+fn collect_orphans(
+    dir: &Path,
+    referenced: &HashSet<String>,
+    grace_period: Duration,
+    report: &mut GarbageReport,
+) -> Result<(), anyhow::Error> {
+    if !dir.exists() {
+        log::debug!("{} does not exist, nothing to collect", dir.display());
+        return Ok(());
+    }
+
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("Could not list {}", dir.display()))?
+    {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let is_referenced = entry
+            .file_name()
+            .to_str()
+            .map_or(true, |name| referenced.contains(name));
+        if is_referenced {
+            continue;
+        }
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .unwrap_or_default();
+        if age < grace_period {
+            log::trace!("Leaving recent file {} alone", entry.path().display());
+            continue;
+        }
+
+        report.orphan_bytes += metadata.len();
+        report.orphans.push(entry.path());
+    }
+    Ok(())
+}
@@ -119,6 +119,9 @@ pub struct Message {
     pub sending_has_failed: bool,
 
     pub quote_id: Option<i32>,
+
+    pub viewed_timestamp: Option<NaiveDateTime>,
+    pub is_view_once: bool,
 }
 
 impl Display for Message {
@@ -174,6 +177,8 @@ impl Default for Message {
             is_remote_deleted: Default::default(),
             sending_has_failed: Default::default(),
             quote_id: Default::default(),
+            viewed_timestamp: Default::default(),
+            is_view_once: Default::default(),
         }
     }
 }
@@ -406,6 +411,46 @@ impl Display for SenderKeyRecord {
     }
 }
 
+/// A sealed sender certificate, as issued by the server.
+#[derive(Queryable, Identifiable, Insertable, Debug, Clone)]
+#[diesel(primary_key(kind))]
+pub struct SenderCertificate {
+    pub kind: String,
+    pub certificate: Vec<u8>,
+    pub expires: NaiveDateTime,
+}
+
+impl Display for SenderCertificate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(
+            f,
+            "SenderCertificate {{ kind: \"{}\", expires: \"{}\" }}",
+            &self.kind, &self.expires
+        )
+    }
+}
+
+/// An outgoing message that still has to be sent, see [crate::store::Storage::enqueue_outgoing].
+#[derive(Queryable, Identifiable, Debug, Clone)]
+#[diesel(table_name = outbox, primary_key(message_id))]
+pub struct OutboxEntry {
+    pub message_id: i32,
+    /// Failed attempts so far.
+    pub attempts: i32,
+    pub next_attempt: NaiveDateTime,
+    pub last_error: Option<String>,
+}
+
+impl Display for OutboxEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(
+            f,
+            "OutboxEntry {{ message_id: {}, attempts: {}, next_attempt: \"{}\" }}",
+            &self.message_id, &self.attempts, &self.next_attempt
+        )
+    }
+}
+
 impl Recipient {
     pub fn unidentified_access_key(&self) -> Option<[u8; 16]> {
         self.profile_key()
@@ -538,6 +583,9 @@ pub struct Attachment {
     pub cdn_number: Option<i32>,
     pub caption: Option<String>,
     pub pointer: Option<Vec<u8>>,
+    /// Duration of a voice note, in milliseconds.
+    pub audio_duration: Option<i32>,
+    pub audio_waveform: Option<Vec<u8>>,
 }
 
 impl Display for Attachment {
@@ -622,6 +670,29 @@ pub struct Receipt {
     pub delivered: Option<NaiveDateTime>,
     pub read: Option<NaiveDateTime>,
     pub viewed: Option<NaiveDateTime>,
+    /// When the message was sent to this recipient; only for our own messages.
+    pub sent: Option<NaiveDateTime>,
+    /// When sending the message to this recipient failed, if it was not sent since.
+    pub failed: Option<NaiveDateTime>,
+}
+
+impl Receipt {
+    /// How far the message got with this recipient, as shown in the message details.
+    pub fn status(&self) -> &'static str {
+        if self.viewed.is_some() {
+            "viewed"
+        } else if self.read.is_some() {
+            "read"
+        } else if self.delivered.is_some() {
+            "delivered"
+        } else if self.sent.is_some() {
+            "sent"
+        } else if self.failed.is_some() {
+            "failed"
+        } else {
+            "pending"
+        }
+    }
 }
 
 impl Session {
@@ -1235,6 +1306,8 @@ mod tests {
             cdn_number: None,
             caption: Some("Funny cat!".into()),
             pointer: None,
+            audio_duration: None,
+            audio_waveform: None,
         }
     }
 
@@ -1277,6 +1350,8 @@ mod tests {
                     delivered: Some(timestamp),
                     read: Some(timestamp),
                     viewed: Some(timestamp),
+                    sent: Some(timestamp),
+                    failed: None,
                 },
                 get_recipient(),
             )],
@@ -0,0 +1,238 @@
+//! The outbox: outgoing messages that still have to reach (some of) their recipients.
+//!
+//! A message stays in the outbox until it is sent to everyone, or until sending failed for a
+//! reason that retrying will not fix.  The entry counts the failed attempts and tells when to try
+//! again.  Recipients that already got the message are recorded in the `sent` column of their
+//! receipt, so that a retry skips them; the ones it failed for in the `failed` column.
+
+use super::*;
+
+impl Storage {
+    /// Puts the message in the outbox, to be sent right away.
+    ///
+    /// A message that was in the outbox already starts over, and a failed message is no longer
+    /// marked as failed.
+    pub fn enqueue_outgoing(&self, mid: i32) {
+        use schema::outbox;
+
+        let now = Utc::now().naive_utc();
+        diesel::insert_into(outbox::table)
+            .values((
+                outbox::message_id.eq(mid),
+                outbox::attempts.eq(0),
+                outbox::next_attempt.eq(now),
+            ))
+            .on_conflict(outbox::message_id)
+            .do_update()
+            .set((
+                outbox::attempts.eq(0),
+                outbox::next_attempt.eq(now),
+                outbox::last_error.eq::<Option<String>>(None),
+            ))
+            .execute(&mut *self.db())
+            .expect("db");
+
+        let unfailed = diesel::update(schema::messages::table)
+            .filter(
+                schema::messages::id
+                    .eq(mid)
+                    .and(schema::messages::sending_has_failed.eq(true)),
+            )
+            .set(schema::messages::sending_has_failed.eq(false))
+            .execute(&mut *self.db())
+            .expect("db");
+        if unfailed > 0 {
+            self.observe_update(schema::messages::table, mid);
+        }
+    }
+
+    pub fn fetch_outbox_entry(&self, mid: i32) -> Option<orm::OutboxEntry> {
+        schema::outbox::table
+            .filter(schema::outbox::message_id.eq(mid))
+            .first(&mut *self.db())
+            .optional()
+            .expect("db")
+    }
+
+    /// The entries whose next attempt is due at `now`, oldest first.
+    pub fn fetch_due_outbox_entries(&self, now: NaiveDateTime) -> Vec<orm::OutboxEntry> {
+        schema::outbox::table
+            .filter(schema::outbox::next_attempt.le(now))
+            .order_by(schema::outbox::message_id.asc())
+            .load(&mut *self.db())
+            .expect("db")
+    }
+
+    /// Counts a failed attempt, and schedules the next one.
+    pub fn reschedule_outgoing(
+        &self,
+        mid: i32,
+        next_attempt: NaiveDateTime,
+        error: &str,
+    ) -> Option<orm::OutboxEntry> {
+        use schema::outbox;
+
+        diesel::update(outbox::table)
+            .filter(outbox::message_id.eq(mid))
+            .set((
+                outbox::attempts.eq(outbox::attempts + 1),
+                outbox::next_attempt.eq(next_attempt),
+                outbox::last_error.eq(error),
+            ))
+            .execute(&mut *self.db())
+            .expect("db");
+        self.fetch_outbox_entry(mid)
+    }
+
+    /// Makes every entry due now, e.g. because the connection is back.  Returns how many there are.
+    pub fn retry_outbox_now(&self) -> usize {
+        diesel::update(schema::outbox::table)
+            .set(schema::outbox::next_attempt.eq(Utc::now().naive_utc()))
+            .execute(&mut *self.db())
+            .expect("db")
+    }
+
+    /// Removes the message from the outbox, because it was sent or failed for good.
+    pub fn remove_from_outbox(&self, mid: i32) {
+        diesel::delete(schema::outbox::table)
+            .filter(schema::outbox::message_id.eq(mid))
+            .execute(&mut *self.db())
+            .expect("db");
+    }
+
+    /// Records that the message reached `recipient_id`.
+    pub fn mark_message_sent_to(&self, mid: i32, recipient_id: i32, sent_at: NaiveDateTime) {
+        use schema::receipts;
+
+        diesel::insert_into(receipts::table)
+            .values((
+                receipts::message_id.eq(mid),
+                receipts::recipient_id.eq(recipient_id),
+                receipts::sent.eq(sent_at),
+            ))
+            .on_conflict((receipts::message_id, receipts::recipient_id))
+            .do_update()
+            .set((
+                receipts::sent.eq(sent_at),
+                receipts::failed.eq::<Option<NaiveDateTime>>(None),
+            ))
+            .execute(&mut *self.db())
+            .expect("db");
+        self.observe_upsert(receipts::table, PrimaryKey::Unknown)
+            .with_relation(schema::messages::table, mid)
+            .with_relation(schema::recipients::table, recipient_id);
+    }
+
+    /// Records that the message could not be sent to `recipient_id`, so a retry sends it to them.
+    pub fn mark_message_failed_for(&self, mid: i32, recipient_id: i32, failed_at: NaiveDateTime) {
+        use schema::receipts;
+
+        diesel::insert_into(receipts::table)
+            .values((
+                receipts::message_id.eq(mid),
+                receipts::recipient_id.eq(recipient_id),
+                receipts::failed.eq(failed_at),
+            ))
+            .on_conflict((receipts::message_id, receipts::recipient_id))
+            .do_update()
+            .set(receipts::failed.eq(failed_at))
+            .execute(&mut *self.db())
+            .expect("db");
+        self.observe_upsert(receipts::table, PrimaryKey::Unknown)
+            .with_relation(schema::messages::table, mid)
+            .with_relation(schema::recipients::table, recipient_id);
+    }
+
+    /// The recipients that should have gotten the message, but did not.
+    pub fn fetch_message_failed_for(&self, mid: i32) -> Vec<i32> {
+        schema::receipts::table
+            .select(schema::receipts::recipient_id)
+            .filter(
+                schema::receipts::message_id
+                    .eq(mid)
+                    .and(schema::receipts::sent.is_null())
+                    .and(schema::receipts::failed.is_not_null()),
+            )
+            .load(&mut *self.db())
+            .expect("db")
+    }
+
+    /// The recipients that already got the message.
+    pub fn fetch_message_sent_to(&self, mid: i32) -> Vec<i32> {
+        schema::receipts::table
+            .select(schema::receipts::recipient_id)
+            .filter(
+                schema::receipts::message_id
+                    .eq(mid)
+                    .and(schema::receipts::sent.is_not_null()),
+            )
+            .load(&mut *self.db())
+            .expect("db")
+    }
+
+    /// The recipients the message is meant for: the other party of its conversation, or the
+    /// other registered members of its group.
+    pub fn fetch_message_intended_recipients(&self, mid: i32) -> Vec<orm::Recipient> {
+        let message = match self.fetch_message_by_id(mid) {
+            Some(message) => message,
+            None => return Vec::new(),
+        };
+        let session = self
+            .fetch_session_by_id(message.session_id)
+            .expect("session of message");
+        match session.r#type {
+            orm::SessionType::DirectMessage(recipient) => vec![recipient],
+            orm::SessionType::GroupV2(group) => {
+                let self_id = self.fetch_self_recipient().map(|r| r.id);
+                self.fetch_group_members_by_group_v2_id(&group.id)
+                    .into_iter()
+                    .map(|(_member, recipient)| recipient)
+                    .filter(|recipient| {
+                        recipient.is_registered
+                            && recipient.uuid.is_some()
+                            && Some(recipient.id) != self_id
+                    })
+                    .collect()
+            }
+            orm::SessionType::GroupV1(_) => Vec::new(),
+        }
+    }
+
+    /// Marks the message as failed for every intended recipient that did not get it yet.
+    /// Returns how many there are.
+    pub fn mark_unsent_recipients_failed(&self, mid: i32) -> usize {
+        let sent_to = self.fetch_message_sent_to(mid);
+        let failed_at = Utc::now().naive_utc();
+        let mut count = 0;
+        for recipient in self.fetch_message_intended_recipients(mid) {
+            if !sent_to.contains(&recipient.id) {
+                self.mark_message_failed_for(mid, recipient.id, failed_at);
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Puts outgoing messages that are neither sent nor failed, but not in the outbox, back in.
+    pub fn requeue_pending_messages(&self) -> usize {
+        use schema::messages;
+
+        let pending: Vec<i32> = messages::table
+            .select(messages::id)
+            .filter(
+                messages::sent_timestamp
+                    .is_null()
+                    .and(messages::is_outbound)
+                    .and(messages::sending_has_failed.eq(false))
+                    .and(diesel::dsl::not(messages::id.eq_any(
+                        schema::outbox::table.select(schema::outbox::message_id),
+                    ))),
+            )
+            .load(&mut *self.db())
+            .expect("db");
+        for mid in &pending {
+            self.enqueue_outgoing(*mid);
+        }
+        pending.len()
+    }
+}
@@ -131,7 +131,10 @@ impl Storage {
         _: Context,
     ) -> Result<u32, SignalProtocolError> {
         let path = self.path.join("storage").join("identity").join("pni_regid");
-        if !tokio::fs::try_exists(&path).await.expect("fs error") {
+        let exists = tokio::fs::try_exists(&path).await.map_err(|e| {
+            SignalProtocolError::InvalidArgument(format!("Cannot look for PNI regid {}", e))
+        })?;
+        if !exists {
             log::info!("Generating PNI regid");
             let regid = generate_registration_id(&mut rand::thread_rng());
 
@@ -166,48 +169,184 @@ impl Storage {
     }
 }
 
+impl Storage {
+    /// A clone of this storage that acts as our PNI in the Signal protocol.
+    ///
+    /// Used to decrypt the messages that are sent to our phone number.
+    pub fn pni_store(&self) -> Storage {
+        Storage {
+            identity: Identity::Pni,
+            ..self.clone()
+        }
+    }
+
+    async fn get_aci_identity_key_pair(&self) -> Result<IdentityKeyPair, SignalProtocolError> {
+        if let Some(identity_key_pair) = *self.aci_identity_key_pair.read().await {
+            return Ok(identity_key_pair);
+        }
+
+        let mut identity_key_pair = self.aci_identity_key_pair.write().await;
+        let _lock = self.protocol_store.read().await;
+
+        log::trace!("Reading own identity key pair");
+        let key_pair = self.read_identity_key_pair("identity_key").await?;
+        *identity_key_pair = Some(key_pair);
+        Ok(key_pair)
+    }
+
+    /// Returns our PNI identity key pair.
+    ///
+    /// Storages created before PNI support have no PNI identity.  The primary device creates one,
+    /// linked devices get it from the primary; both store it with [Storage::set_pni_identity].
+    pub async fn get_pni_identity_key_pair(&self) -> Result<IdentityKeyPair, SignalProtocolError> {
+        if let Some(identity_key_pair) = *self.pni_identity_key_pair.read().await {
+            return Ok(identity_key_pair);
+        }
+
+        let mut identity_key_pair = self.pni_identity_key_pair.write().await;
+        let _lock = self.protocol_store.read().await;
+
+        if !self.has_pni_identity_file().await? {
+            return Err(SignalProtocolError::InvalidArgument(
+                "No PNI identity key yet".into(),
+            ));
+        }
+        log::trace!("Reading own PNI identity key pair");
+        let key_pair = self.read_identity_key_pair("pni_identity_key").await?;
+        *identity_key_pair = Some(key_pair);
+        Ok(key_pair)
+    }
+
+    /// Whether we have a PNI identity, see [Storage::get_pni_identity_key_pair].
+    pub async fn has_pni_identity(&self) -> Result<bool, SignalProtocolError> {
+        if self.pni_identity_key_pair.read().await.is_some() {
+            return Ok(true);
+        }
+        let _lock = self.protocol_store.read().await;
+        self.has_pni_identity_file().await
+    }
+
+    async fn has_pni_identity_file(&self) -> Result<bool, SignalProtocolError> {
+        let path = self
+            .path
+            .join("storage")
+            .join("identity")
+            .join("pni_identity_key");
+        tokio::fs::try_exists(&path).await.map_err(|e| {
+            SignalProtocolError::InvalidArgument(format!("Cannot look for PNI identity key {}", e))
+        })
+    }
+
+    async fn read_identity_key_pair(
+        &self,
+        file_name: &str,
+    ) -> Result<IdentityKeyPair, SignalProtocolError> {
+        use std::convert::TryFrom;
+        let path = self.path.join("storage").join("identity").join(file_name);
+        let mut buf = self.read_file(path).await.map_err(|e| {
+            SignalProtocolError::InvalidArgument(format!("Cannot read {} {}", file_name, e))
+        })?;
+        buf.insert(0, DJB_TYPE);
+        let public = IdentityKey::decode(&buf[0..33])?;
+        let private = PrivateKey::try_from(&buf[33..])?;
+        Ok(IdentityKeyPair::new(public, private))
+    }
+
+    /// Replaces our PNI identity, e.g. after a change of phone number or when the primary device
+    /// shares it.
+    pub async fn set_pni_identity(
+        &self,
+        key_pair: IdentityKeyPair,
+        regid: Option<u32>,
+    ) -> Result<(), anyhow::Error> {
+        let mut identity_key_pair = self.pni_identity_key_pair.write().await;
+        let _lock = self.protocol_store.write().await;
+
+        log::info!("Storing a new PNI identity");
+        let identity_path = self.path.join("storage").join("identity");
+        self.write_file(
+            identity_path.join("pni_identity_key"),
+            ProtocolStore::serialize_identity_key(key_pair),
+        )
+        .await?;
+        if let Some(regid) = regid {
+            self.write_file(
+                identity_path.join("pni_regid"),
+                format!("{}", regid).into_bytes(),
+            )
+            .await?;
+        }
+        *identity_key_pair = Some(key_pair);
+        Ok(())
+    }
+
+    /// Signs our ACI identity key with our PNI identity key.
+    ///
+    /// Sent along with messages to contacts that know us only by phone number, such that they can
+    /// link our PNI to our ACI.
+    pub async fn pni_signature(&self) -> Result<Vec<u8>, SignalProtocolError> {
+        let aci = self.get_aci_identity_key_pair().await?;
+        let pni = self.get_pni_identity_key_pair().await?;
+        let signature = pni.sign_alternate_identity(aci.identity_key(), &mut rand::thread_rng())?;
+        Ok(signature.into_vec())
+    }
+
+    /// Verifies that the holder of the PNI identity signed the ACI identity, see
+    /// [Storage::pni_signature].
+    ///
+    /// Both identities need to be known, i.e. we need to have a session with both.
+    pub fn verify_pni_signature(&self, aci: Uuid, pni: Uuid, signature: &[u8]) -> bool {
+        let device_id = DeviceId::from(libsignal_service::push_service::DEFAULT_DEVICE_ID);
+        let aci_key = self.fetch_identity_key(&ProtocolAddress::new(aci.to_string(), device_id));
+        let pni_key = self.fetch_identity_key(&ProtocolAddress::new(pni.to_string(), device_id));
+        match (aci_key, pni_key) {
+            (Some(aci_key), Some(pni_key)) => pni_key
+                .verify_alternate_identity(&aci_key, signature)
+                .unwrap_or_else(|e| {
+                    log::warn!("Invalid PNI signature: {}", e);
+                    false
+                }),
+            _ => {
+                log::warn!("Cannot verify PNI signature of {}: identity unknown", aci);
+                false
+            }
+        }
+    }
+
+    /// Links `pni` to the recipient with `aci`, if the PNI signature is valid.
+    pub fn process_pni_signature(
+        &self,
+        aci: Uuid,
+        pni: Uuid,
+        signature: &[u8],
+    ) -> Option<orm::Recipient> {
+        if !self.verify_pni_signature(aci, pni, signature) {
+            log::warn!("Dropping PNI signature of {} for {}", aci, pni);
+            return None;
+        }
+        log::info!("Verified PNI signature of {}", aci);
+        self.merge_and_fetch_recipient(None, Some(aci), Some(pni), TrustLevel::Certain)
+            .ok()
+    }
+}
+
 #[async_trait::async_trait(?Send)]
 impl protocol::IdentityKeyStore for Storage {
     async fn get_identity_key_pair(
         &self,
         _: Context,
     ) -> Result<IdentityKeyPair, SignalProtocolError> {
-        let identity_key_pair = self.aci_identity_key_pair.read().await;
-
-        if let Some(identity_key_pair) = *identity_key_pair {
-            Ok(identity_key_pair)
-        } else {
-            drop(identity_key_pair);
-
-            let mut identity_key_pair = self.aci_identity_key_pair.write().await;
-
-            let _lock = self.protocol_store.read().await;
-
-            log::trace!("Reading own identity key pair");
-            let path = self
-                .path
-                .join("storage")
-                .join("identity")
-                .join("identity_key");
-            let key_pair = {
-                use std::convert::TryFrom;
-                let mut buf = self.read_file(path).await.map_err(|e| {
-                    SignalProtocolError::InvalidArgument(format!(
-                        "Cannot read own identity key {}",
-                        e
-                    ))
-                })?;
-                buf.insert(0, DJB_TYPE);
-                let public = IdentityKey::decode(&buf[0..33])?;
-                let private = PrivateKey::try_from(&buf[33..])?;
-                IdentityKeyPair::new(public, private)
-            };
-            *identity_key_pair = Some(key_pair);
-            Ok(identity_key_pair.unwrap())
+        match self.identity {
+            Identity::Aci => self.get_aci_identity_key_pair().await,
+            Identity::Pni => self.get_pni_identity_key_pair().await,
         }
     }
 
-    async fn get_local_registration_id(&self, _: Context) -> Result<u32, SignalProtocolError> {
+    async fn get_local_registration_id(&self, ctx: Context) -> Result<u32, SignalProtocolError> {
+        if self.identity == Identity::Pni {
+            return self.get_local_pni_registration_id(ctx).await;
+        }
+
         log::trace!("Reading regid");
         let _lock = self.protocol_store.read().await;
 
@@ -476,6 +615,10 @@ impl Storage {
         let ret = previous.as_ref() == Some(key);
 
         if previous.is_some() {
+            if previous.as_ref() != Some(key) {
+                // A new identity lost the sender keys we gave the old one.
+                self.clear_sender_key_shared_with(addr.name());
+            }
             diesel::update(identity_records)
                 .filter(address.eq(addr.name()))
                 .set(record.eq(key.serialize().to_vec()))
@@ -684,7 +827,8 @@ impl SenderKeyStore for Storage {
 
         {
             use crate::schema::sender_key_records::dsl::*;
-            diesel::insert_into(sender_key_records)
+            // Every message we encrypt with our own key advances it, so this overwrites.
+            diesel::replace_into(sender_key_records)
                 .values(to_insert)
                 .execute(&mut *self.db())
                 .expect("db");
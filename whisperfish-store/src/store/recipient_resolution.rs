@@ -0,0 +1,664 @@
+//! Recipient resolution: finding the recipient behind an ACI, a PNI and a phone number.
+//!
+//! Equivalent of Androids `RecipientTable::processPnpTuple`.  One person can be known by several
+//! identifiers, and we learn them in any order: a phone number from the address book, a PNI from
+//! contact discovery, an ACI from an envelope.  [Storage::resolve_recipient] looks all of them up,
+//! merges recipients that turn out to be the same person, and takes phone numbers and PNIs away
+//! from accounts that no longer have them.
+
+use super::*;
+
+/// The identifiers of a recipient, as far as they are known.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecipientIdentifiers {
+    pub e164: Option<PhoneNumber>,
+    /// The UUID of the account.
+    pub aci: Option<Uuid>,
+    /// The UUID of the phone number.
+    pub pni: Option<Uuid>,
+}
+
+impl RecipientIdentifiers {
+    pub fn is_empty(&self) -> bool {
+        self.e164.is_none() && self.aci.is_none() && self.pni.is_none()
+    }
+}
+
+/// A change to the recipients made by [Storage::resolve_recipient].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RecipientEvent {
+    Inserted(i32),
+    /// Identifiers were added to or changed on the recipient.
+    Updated(i32),
+    /// `source` turned out to be the same person as `dest`, and was merged into it.
+    Merged {
+        source: i32,
+        dest: i32,
+    },
+    /// The phone number or PNI of the recipient now belongs to another account.
+    Stripped(i32),
+    /// The account moved to another phone number.
+    NumberChanged {
+        id: i32,
+        old: PhoneNumber,
+        new: PhoneNumber,
+    },
+}
+
+/// Outcome of [Storage::resolve_recipient].
+#[derive(Clone, Debug)]
+pub struct RecipientResolution {
+    pub recipient: orm::Recipient,
+    /// In the order they happened.
+    pub events: Vec<RecipientEvent>,
+}
+
+impl Storage {
+    /// Fetches the recipient with these identifiers, see [Storage::resolve_recipient].
+    ///
+    /// Fails when no identifier is given.
+    pub fn merge_and_fetch_recipient(
+        &self,
+        phonenumber: Option<PhoneNumber>,
+        uuid: Option<Uuid>,
+        pni: Option<Uuid>,
+        trust_level: TrustLevel,
+    ) -> Result<orm::Recipient, anyhow::Error> {
+        let ids = RecipientIdentifiers {
+            e164: phonenumber,
+            aci: uuid,
+            pni,
+        };
+        self.resolve_recipient(ids, trust_level)
+            .map(|resolution| resolution.recipient)
+            .context("merge_and_fetch_recipient requires at least one of e164, uuid or pni")
+    }
+
+    /// Finds or inserts the recipient with these identifiers.  Returns `None` when none are given.
+    ///
+    /// With [TrustLevel::Certain], the identifiers are known to belong together, e.g. because they
+    /// come from an envelope or from contact discovery:
+    ///
+    /// - recipients that only had the PNI or the phone number, and no ACI, are merged into the
+    ///   recipient with the ACI;
+    /// - another account with the same PNI or phone number loses it, as the number was
+    ///   re-registered or changed hands;
+    /// - when the account changed its phone number, a message is added to its conversation.
+    ///
+    /// With [TrustLevel::Uncertain], nothing is merged or taken away.  An ACI is only looked up,
+    /// or stored on its own.  Without an ACI, the PNI and phone number are only added to a
+    /// recipient when no other recipient has them.
+    pub fn resolve_recipient(
+        &self,
+        ids: RecipientIdentifiers,
+        trust_level: TrustLevel,
+    ) -> Option<RecipientResolution> {
+        if ids.is_empty() {
+            return None;
+        }
+
+        let mut events = Vec::new();
+        let id = self
+            .db()
+            .transaction::<_, Error, _>(|db| {
+                events.clear();
+                Self::resolve_recipient_inner(db, &ids, trust_level, &mut events)
+            })
+            .expect("database");
+
+        for event in &events {
+            match event {
+                RecipientEvent::Inserted(id) => {
+                    self.observe_insert(schema::recipients::table, *id);
+                }
+                RecipientEvent::Updated(id) | RecipientEvent::Stripped(id) => {
+                    self.observe_update(schema::recipients::table, *id);
+                }
+                RecipientEvent::Merged { source, dest } => {
+                    self.observe_delete(schema::recipients::table, *source);
+                    self.observe_update(schema::recipients::table, *dest);
+                }
+                RecipientEvent::NumberChanged { id, old, new } => {
+                    self.insert_number_changed_message(*id, old, new);
+                }
+            }
+        }
+
+        let recipient = self
+            .fetch_recipient_by_id(id)
+            .expect("existing resolved recipient");
+        Some(RecipientResolution { recipient, events })
+    }
+
+    /// Merge source_id into dest_id.
+    ///
+    /// Executes `merge_recipient_inner` inside a transaction, and then returns the result.
+    pub fn merge_recipients(&self, source_id: i32, dest_id: i32) -> orm::Recipient {
+        let mut db = self.db();
+        let merged_id = db
+            .transaction::<_, Error, _>(|db| Self::merge_recipients_inner(db, source_id, dest_id))
+            .expect("consistent migration");
+        drop(db);
+
+        log::trace!("Contact merge committed.");
+
+        self.observe_delete(schema::recipients::table, source_id);
+        self.observe_update(schema::recipients::table, dest_id);
+
+        self.fetch_recipient_by_id(merged_id)
+            .expect("existing contact")
+    }
+
+    /// Tells the user in the conversation with `recipient_id` that they moved to another number.
+    fn insert_number_changed_message(
+        &self,
+        recipient_id: i32,
+        old: &PhoneNumber,
+        new: &PhoneNumber,
+    ) {
+        let recipient = self
+            .fetch_recipient_by_id(recipient_id)
+            .expect("existing resolved recipient");
+        if recipient.uuid.is_some() && recipient.uuid == self.config.get_uuid() {
+            // We changed our own number; that is shown in the settings.
+            return;
+        }
+        let session = match self.fetch_session_by_recipient_id(recipient_id) {
+            Some(session) => session,
+            None => return,
+        };
+        log::info!(
+            "{} changed their number from {} to {}.",
+            recipient.e164_or_uuid(),
+            old,
+            new
+        );
+        self.create_message(&NewMessage {
+            session_id: session.id,
+            source_e164: None,
+            source_uuid: recipient.uuid,
+            text: format!(
+                "[Whisperfish] This contact changed their phone number to {}.",
+                new
+            ), // XXX Translate
+            timestamp: Utc::now().naive_utc(),
+            sent: false,
+            received: true,
+            is_read: false,
+            flags: 0,
+            attachment: None,
+            mime_type: None,
+            has_attachment: false,
+            outgoing: false,
+            is_unidentified: false,
+            quote_timestamp: None,
+            expires_in: None,
+        });
+    }
+
+    // Inner method because the coverage report is then sensible.
+    fn resolve_recipient_inner(
+        db: &mut SqliteConnection,
+        ids: &RecipientIdentifiers,
+        trust_level: TrustLevel,
+        events: &mut Vec<RecipientEvent>,
+    ) -> Result<i32, Error> {
+        use schema::recipients;
+
+        let by_aci: Option<orm::Recipient> = match ids.aci {
+            Some(aci) => recipients::table
+                .filter(recipients::uuid.eq(aci.to_string()))
+                .first(db)
+                .optional()?,
+            None => None,
+        };
+        let by_pni: Option<orm::Recipient> = match ids.pni {
+            Some(pni) => recipients::table
+                .filter(recipients::pni.eq(pni.to_string()))
+                .first(db)
+                .optional()?,
+            None => None,
+        };
+        let by_e164: Option<orm::Recipient> = match &ids.e164 {
+            Some(e164) => recipients::table
+                .filter(recipients::e164.eq(e164.to_string()))
+                .first(db)
+                .optional()?,
+            None => None,
+        };
+
+        match trust_level {
+            TrustLevel::Certain => Self::resolve_certain(db, ids, by_aci, by_pni, by_e164, events),
+            TrustLevel::Uncertain => {
+                Self::resolve_uncertain(db, ids, by_aci, by_pni, by_e164, events)
+            }
+        }
+    }
+
+    fn resolve_uncertain(
+        db: &mut SqliteConnection,
+        ids: &RecipientIdentifiers,
+        by_aci: Option<orm::Recipient>,
+        by_pni: Option<orm::Recipient>,
+        by_e164: Option<orm::Recipient>,
+        events: &mut Vec<RecipientEvent>,
+    ) -> Result<i32, Error> {
+        if let Some(aci) = ids.aci {
+            if let Some(by_aci) = by_aci {
+                return Ok(by_aci.id);
+            }
+            log::info!("New ACI {}. Low trust, so only storing the ACI.", aci);
+            let only_aci = RecipientIdentifiers {
+                aci: Some(aci),
+                ..Default::default()
+            };
+            let id = Self::insert_recipient(db, &only_aci)?;
+            events.push(RecipientEvent::Inserted(id));
+            return Ok(id);
+        }
+
+        let target = match (&by_pni, &by_e164) {
+            (Some(target), _) | (None, Some(target)) => target,
+            (None, None) => {
+                let id = Self::insert_recipient(db, ids)?;
+                events.push(RecipientEvent::Inserted(id));
+                return Ok(id);
+            }
+        };
+
+        // Only fill in what is missing, and what no other recipient has.
+        let additions = RecipientIdentifiers {
+            e164: ids
+                .e164
+                .clone()
+                .filter(|_| target.e164.is_none() && by_e164.is_none()),
+            aci: None,
+            pni: ids.pni.filter(|_| target.pni.is_none() && by_pni.is_none()),
+        };
+        if let (Some(by_pni), Some(by_e164)) = (&by_pni, &by_e164) {
+            if by_pni.id != by_e164.id {
+                log::info!(
+                    "PNI and phone number belong to {} and {}. Low trust, so not merging.",
+                    by_pni.e164_or_uuid(),
+                    by_e164.e164_or_uuid()
+                );
+            }
+        }
+        if Self::update_identifiers(db, target, &additions)? {
+            events.push(RecipientEvent::Updated(target.id));
+        }
+        Ok(target.id)
+    }
+
+    fn resolve_certain(
+        db: &mut SqliteConnection,
+        ids: &RecipientIdentifiers,
+        by_aci: Option<orm::Recipient>,
+        by_pni: Option<orm::Recipient>,
+        by_e164: Option<orm::Recipient>,
+        events: &mut Vec<RecipientEvent>,
+    ) -> Result<i32, Error> {
+        use schema::recipients;
+
+        // The recipient that ends up with all identifiers.  The one with the ACI if it exists.
+        // Otherwise, with an ACI given, one that has no ACI yet; without an ACI given, preferably
+        // one that has an ACI, as that is the account the phone number belongs to now.
+        let hits: Vec<&orm::Recipient> = vec![&by_aci, &by_pni, &by_e164]
+            .into_iter()
+            .filter_map(Option::as_ref)
+            .collect();
+        let target: Option<orm::Recipient> = match &by_aci {
+            Some(by_aci) => Some(by_aci.clone()),
+            None if ids.aci.is_some() => hits.iter().find(|r| r.uuid.is_none()).cloned().cloned(),
+            None => hits
+                .iter()
+                .find(|r| r.uuid.is_some())
+                .or_else(|| hits.first())
+                .cloned()
+                .cloned(),
+        };
+        let target_id = target.as_ref().map(|target| target.id);
+
+        // Identifiers of merged recipients that the target should take over.
+        let mut carried = RecipientIdentifiers::default();
+        let mut handled = Vec::new();
+        for other in vec![&by_pni, &by_e164]
+            .into_iter()
+            .filter_map(Option::as_ref)
+        {
+            if Some(other.id) == target_id || handled.contains(&other.id) {
+                continue;
+            }
+            handled.push(other.id);
+
+            match (&target, other.uuid) {
+                (Some(target), None) => {
+                    log::info!(
+                        "Merging contacts: {} has no ACI and belongs to {}. High trust.",
+                        other.e164_or_uuid(),
+                        target.e164_or_uuid()
+                    );
+                    Self::merge_recipients_inner(db, other.id, target.id)?;
+                    events.push(RecipientEvent::Merged {
+                        source: other.id,
+                        dest: target.id,
+                    });
+                    if ids.e164.is_none() && target.e164.is_none() && carried.e164.is_none() {
+                        carried.e164 = other.e164.clone();
+                    }
+                    if ids.pni.is_none() && target.pni.is_none() && carried.pni.is_none() {
+                        carried.pni = other.pni;
+                    }
+                }
+                _ => {
+                    log::info!(
+                        "{} lost its phone number to another account. High trust, so stripping it.",
+                        other.e164_or_uuid()
+                    );
+                    if ids.e164.is_some() && other.e164 == ids.e164 {
+                        diesel::update(recipients::table)
+                            .set(recipients::e164.eq::<Option<String>>(None))
+                            .filter(recipients::id.eq(other.id))
+                            .execute(db)?;
+                    }
+                    if ids.pni.is_some() && other.pni == ids.pni {
+                        diesel::update(recipients::table)
+                            .set(recipients::pni.eq::<Option<String>>(None))
+                            .filter(recipients::id.eq(other.id))
+                            .execute(db)?;
+                    }
+                    events.push(RecipientEvent::Stripped(other.id));
+                }
+            }
+        }
+
+        let wanted = RecipientIdentifiers {
+            e164: ids.e164.clone().or(carried.e164),
+            aci: ids.aci,
+            pni: ids.pni.or(carried.pni),
+        };
+        let target = match target {
+            Some(target) => target,
+            None => {
+                let id = Self::insert_recipient(db, &wanted)?;
+                events.push(RecipientEvent::Inserted(id));
+                return Ok(id);
+            }
+        };
+
+        if let (Some(_), Some(old), Some(new)) = (target.uuid, &target.e164, &wanted.e164) {
+            if old != new {
+                events.push(RecipientEvent::NumberChanged {
+                    id: target.id,
+                    old: old.clone(),
+                    new: new.clone(),
+                });
+            }
+        }
+        if Self::update_identifiers(db, &target, &wanted)? {
+            events.push(RecipientEvent::Updated(target.id));
+        }
+        Ok(target.id)
+    }
+
+    fn insert_recipient(
+        db: &mut SqliteConnection,
+        ids: &RecipientIdentifiers,
+    ) -> Result<i32, Error> {
+        use schema::recipients;
+
+        diesel::insert_into(recipients::table)
+            .values((
+                recipients::e164.eq(ids.e164.as_ref().map(PhoneNumber::to_string)),
+                recipients::uuid.eq(ids.aci.as_ref().map(Uuid::to_string)),
+                recipients::pni.eq(ids.pni.as_ref().map(Uuid::to_string)),
+            ))
+            .execute(db)?;
+        diesel::select(last_insert_rowid()).get_result(db)
+    }
+
+    /// Sets the given identifiers that differ from those of `recipient`.  Returns whether any did.
+    fn update_identifiers(
+        db: &mut SqliteConnection,
+        recipient: &orm::Recipient,
+        ids: &RecipientIdentifiers,
+    ) -> Result<bool, Error> {
+        use schema::recipients;
+
+        let mut changed = false;
+        if ids.e164.is_some() && ids.e164 != recipient.e164 {
+            diesel::update(recipients::table)
+                .set(recipients::e164.eq(ids.e164.as_ref().map(PhoneNumber::to_string)))
+                .filter(recipients::id.eq(recipient.id))
+                .execute(db)?;
+            changed = true;
+        }
+        if ids.aci.is_some() && ids.aci != recipient.uuid {
+            diesel::update(recipients::table)
+                .set(recipients::uuid.eq(ids.aci.as_ref().map(Uuid::to_string)))
+                .filter(recipients::id.eq(recipient.id))
+                .execute(db)?;
+            changed = true;
+        }
+        if ids.pni.is_some() && ids.pni != recipient.pni {
+            diesel::update(recipients::table)
+                .set(recipients::pni.eq(ids.pni.as_ref().map(Uuid::to_string)))
+                .filter(recipients::id.eq(recipient.id))
+                .execute(db)?;
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    // Inner method because the coverage report is then sensible.
+    fn merge_recipients_inner(
+        db: &mut SqliteConnection,
+        source_id: i32,
+        dest_id: i32,
+    ) -> Result<i32, diesel::result::Error> {
+        log::info!(
+            "Merge of contacts {} and {}. Will move all into {}",
+            source_id,
+            dest_id,
+            dest_id
+        );
+
+        // Defer constraints, we're moving a lot of data, inside of a transaction,
+        // and if we have a bug it definitely needs more research anyway.
+        db.batch_execute("PRAGMA defer_foreign_keys = ON;")?;
+
+        use schema::*;
+
+        // 1. Merge messages senders.
+        let message_count = diesel::update(messages::table)
+            .filter(messages::sender_recipient_id.eq(source_id))
+            .set(messages::sender_recipient_id.eq(dest_id))
+            .execute(db)?;
+        log::trace!("Merging messages: {}", message_count);
+
+        // 2. Merge group V1 membership:
+        //    - Delete duplicate memberships.
+        //      We fetch the dest_id group memberships,
+        //      and delete the source_id memberships that have the same group.
+        //      Ideally, this would be a single self-join query,
+        //      but Diesel doesn't like that yet.
+        let target_memberships_v1: Vec<String> = group_v1_members::table
+            .select(group_v1_members::group_v1_id)
+            .filter(group_v1_members::recipient_id.eq(dest_id))
+            .load(db)?;
+        let deleted_memberships_v1 = diesel::delete(group_v1_members::table)
+            .filter(
+                group_v1_members::group_v1_id
+                    .eq_any(&target_memberships_v1)
+                    .and(group_v1_members::recipient_id.eq(source_id)),
+            )
+            .execute(db)?;
+        //    - Update the rest
+        let updated_memberships_v1 = diesel::update(group_v1_members::table)
+            .filter(group_v1_members::recipient_id.eq(source_id))
+            .set(group_v1_members::recipient_id.eq(dest_id))
+            .execute(db)?;
+        log::trace!(
+            "Merging Group V1 memberships: deleted duplicate {}/{}, moved {}/{}.",
+            deleted_memberships_v1,
+            target_memberships_v1.len(),
+            updated_memberships_v1,
+            target_memberships_v1.len()
+        );
+
+        //    Same for group V2 membership.
+        let target_memberships_v2: Vec<String> = group_v2_members::table
+            .select(group_v2_members::group_v2_id)
+            .filter(group_v2_members::recipient_id.eq(dest_id))
+            .load(db)?;
+        let deleted_memberships_v2 = diesel::delete(group_v2_members::table)
+            .filter(
+                group_v2_members::group_v2_id
+                    .eq_any(&target_memberships_v2)
+                    .and(group_v2_members::recipient_id.eq(source_id)),
+            )
+            .execute(db)?;
+        let updated_memberships_v2 = diesel::update(group_v2_members::table)
+            .filter(group_v2_members::recipient_id.eq(source_id))
+            .set(group_v2_members::recipient_id.eq(dest_id))
+            .execute(db)?;
+        log::trace!(
+            "Merging Group V2 memberships: deleted duplicate {}/{}, moved {}.",
+            deleted_memberships_v2,
+            target_memberships_v2.len(),
+            updated_memberships_v2,
+        );
+
+        // 3. Merge sessions:
+        let source_session: Option<orm::DbSession> = sessions::table
+            .filter(sessions::direct_message_recipient_id.eq(source_id))
+            .first(db)
+            .optional()?;
+        let target_session: Option<orm::DbSession> = sessions::table
+            .filter(sessions::direct_message_recipient_id.eq(dest_id))
+            .first(db)
+            .optional()?;
+        match (source_session, target_session) {
+            (Some(source_session), Some(target_session)) => {
+                // Both recipients have a session.
+                // Move the source session's messages to the target session,
+                // then drop the source session.
+                let updated_message_count = diesel::update(messages::table)
+                    .filter(messages::session_id.eq(source_session.id))
+                    .set(messages::session_id.eq(target_session.id))
+                    .execute(db)?;
+                let dropped_session_count = diesel::delete(sessions::table)
+                    .filter(sessions::id.eq(source_session.id))
+                    .execute(db)?;
+
+                assert_eq!(dropped_session_count, 1, "Drop the single source session.");
+
+                log::trace!(
+                    "Updating source session's messages ({} total). Dropped source session.",
+                    updated_message_count
+                );
+            }
+            (Some(source_session), None) => {
+                log::info!("Strange, no session for the target_id. Updating source.");
+                let updated_session = diesel::update(sessions::table)
+                    .filter(sessions::id.eq(source_session.id))
+                    .set(sessions::direct_message_recipient_id.eq(dest_id))
+                    .execute(db)?;
+                assert_eq!(updated_session, 1, "Update source session");
+            }
+            (None, Some(_target_session)) => {
+                log::info!("Strange, no session for the source_id. Continuing.");
+            }
+            (None, None) => {
+                log::warn!("Strange, neither recipient has a session. Continuing.");
+            }
+        }
+
+        // 4. Merge reactions
+        //    This too would benefit from a subquery or self-join.
+        let target_reactions: Vec<i32> = reactions::table
+            .select(reactions::message_id)
+            .filter(reactions::author.eq(dest_id))
+            .load(db)?;
+        // Delete duplicates from source.
+        // We're not going to merge based on receive time,
+        // although that would be the "right" thing to do.
+        // Let's hope we never really take this path.
+        let deleted_reactions = diesel::delete(reactions::table)
+            .filter(
+                reactions::author
+                    .eq(source_id)
+                    .and(reactions::message_id.eq_any(target_reactions)),
+            )
+            .execute(db)?;
+        log::log!(
+            if deleted_reactions > 0 {
+                log::Level::Warn
+            } else {
+                log::Level::Trace
+            },
+            "Deleted {} reactions. Please file an issue if > 0",
+            deleted_reactions
+        );
+        let updated_reactions = diesel::update(reactions::table)
+            .filter(reactions::author.eq(source_id))
+            .set(reactions::author.eq(dest_id))
+            .execute(db)?;
+        log::trace!("Updated {} reactions", updated_reactions);
+
+        // 5. Update receipts
+        //    Merge the duplicates into the receipts of dest_id, such that we still know whether
+        //    the message reached them, and delete them.  Then update the rest.
+        let target_receipts: Vec<orm::Receipt> = receipts::table
+            .filter(receipts::recipient_id.eq(dest_id))
+            .load(db)?;
+        let target_message_ids: Vec<i32> = target_receipts.iter().map(|r| r.message_id).collect();
+        let duplicate_receipts: Vec<orm::Receipt> = receipts::table
+            .filter(
+                receipts::recipient_id
+                    .eq(source_id)
+                    .and(receipts::message_id.eq_any(&target_message_ids)),
+            )
+            .load(db)?;
+        for duplicate in duplicate_receipts {
+            let target = target_receipts
+                .iter()
+                .find(|r| r.message_id == duplicate.message_id)
+                .expect("duplicate of a target receipt");
+            diesel::update(receipts::table)
+                .filter(
+                    receipts::recipient_id
+                        .eq(dest_id)
+                        .and(receipts::message_id.eq(duplicate.message_id)),
+                )
+                .set((
+                    receipts::delivered.eq(target.delivered.or(duplicate.delivered)),
+                    receipts::read.eq(target.read.or(duplicate.read)),
+                    receipts::viewed.eq(target.viewed.or(duplicate.viewed)),
+                    receipts::sent.eq(target.sent.or(duplicate.sent)),
+                    receipts::failed.eq(target.failed.or(duplicate.failed)),
+                ))
+                .execute(db)?;
+        }
+        let deleted_receipts = diesel::delete(receipts::table)
+            .filter(
+                receipts::recipient_id
+                    .eq(source_id)
+                    .and(receipts::message_id.eq_any(&target_message_ids)),
+            )
+            .execute(db)?;
+        log::trace!("Merged and deleted {} duplicate receipts", deleted_receipts);
+        let updated_receipts = diesel::update(receipts::table)
+            .filter(receipts::recipient_id.eq(source_id))
+            .set(receipts::recipient_id.eq(dest_id))
+            .execute(db)?;
+        log::trace!("Updated {} receipts", updated_receipts);
+
+        let deleted = diesel::delete(recipients::table)
+            .filter(recipients::id.eq(source_id))
+            .execute(db)?;
+        log::trace!("Deleted {} recipient", deleted);
+        assert_eq!(deleted, 1, "delete only one recipient");
+        Ok(dest_id)
+    }
+}
@@ -0,0 +1,250 @@
+//! Changing the password of the storage, and encrypting or decrypting an existing storage.
+//!
+//! The database and the files in `storage/identity` get keys derived from fresh salts.  The
+//! re-encrypted files are first written next to the originals, and only moved in place once the
+//! database has its new key.  When that is interrupted, [Storage::open] finishes the move if the
+//! database got its new key, and drops the staged files otherwise.
+
+use super::*;
+
+const REKEY_SUFFIX: &str = "rekey";
+
+impl Storage {
+    /// Changes the password of the storage.
+    ///
+    /// `old_password` has to match the current password of an encrypted storage, and is ignored
+    /// for an unencrypted one.  Setting `new_password` to `None` decrypts the storage, while
+    /// giving a password to an unencrypted storage encrypts it.  All clones of this storage keep
+    /// working with the new keys.
+    pub async fn change_password(
+        &self,
+        old_password: Option<&str>,
+        new_password: Option<&str>,
+    ) -> Result<(), anyhow::Error> {
+        let current = self.store_enc();
+        if let Some(current) = &current {
+            let old_password = old_password.context("The current password is required")?;
+            let storage_salt =
+                utils::read_salt_file(self.path.join("storage").join("salt")).await?;
+            let db_salt = utils::read_salt_file(self.path.join("db").join("salt")).await?;
+            let old =
+                encryption::StorageEncryption::new(old_password.to_string(), storage_salt, db_salt)
+                    .await?;
+            anyhow::ensure!(
+                old.get_database_key() == current.get_database_key(),
+                "Wrong password"
+            );
+        }
+        if let Some(new_password) = new_password {
+            anyhow::ensure!(!new_password.is_empty(), "The new password is empty");
+        } else if current.is_none() {
+            log::info!("Storage is not encrypted, nothing to change");
+            return Ok(());
+        }
+
+        let salts = new_password.map(|_| Self::generate_salts());
+        let new = match (new_password, salts) {
+            (Some(password), Some((storage_salt, db_salt))) => Some(
+                encryption::StorageEncryption::new(password.to_string(), storage_salt, db_salt)
+                    .await?,
+            ),
+            _ => None,
+        };
+
+        // 1. Write the re-encrypted identity files and the new salts next to the old ones.
+        let mut staged = Vec::new();
+        let identity_dir = self.path.join("storage").join("identity");
+        for entry in std::fs::read_dir(&identity_dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            // Files with an extension are leftovers of an interrupted write.
+            if !entry.metadata()?.is_file() || path.extension().is_some() {
+                continue;
+            }
+            let contents = utils::read_file_async_encrypted(&path, current.as_ref())
+                .await
+                .with_context(|| format!("Could not decrypt {}", path.display()))?;
+            let rekeyed = rekeyed_path(&path);
+            utils::write_file_async_encrypted(&rekeyed, contents, new.as_ref()).await?;
+            staged.push((rekeyed, path));
+        }
+
+        let salt_paths = [
+            self.path.join("storage").join("salt"),
+            self.path.join("db").join("salt"),
+        ];
+        if let Some((storage_salt, db_salt)) = salts {
+            for (path, salt) in salt_paths.iter().zip(vec![storage_salt, db_salt]) {
+                let rekeyed = rekeyed_path(path);
+                utils::write_file_async(&rekeyed, &salt).await?;
+                staged.push((rekeyed, path.clone()));
+            }
+        }
+
+        // 2. Re-key the database.  The lock is held until the new keys are in place, so that no
+        //    one reads or writes in between.
+        let mut db = self.db();
+        let database_key = new.as_ref().map(|x| x.get_database_key());
+        match (&current, database_key) {
+            (Some(_), Some(database_key)) => {
+                log::info!("Re-keying the database");
+                db.batch_execute(&format!(
+                    "PRAGMA rekey = \"x'{}'\";",
+                    hex::encode(database_key)
+                ))
+                .context("re-keying the database")?;
+            }
+            _ => {
+                // SQLCipher can only re-key between two keys, so the database is copied.
+                log::info!(
+                    "{} the database",
+                    if new.is_some() {
+                        "Encrypting"
+                    } else {
+                        "Decrypting"
+                    }
+                );
+                let db_file = self.path.join("db").join("harbour-whisperfish.db");
+                let rekeyed = rekeyed_path(&db_file);
+                if rekeyed.exists() {
+                    std::fs::remove_file(&rekeyed)?;
+                }
+                backup::sqlcipher_export(&mut db, &rekeyed, database_key)?;
+                std::fs::rename(&rekeyed, &db_file)?;
+                *db = Self::connect(&StorageLocation::Path(&self.path), database_key)
+                    .context("Opening the re-keyed database")?;
+            }
+        }
+
+        // 3. Move the files in place.
+        for (rekeyed, path) in &staged {
+            std::fs::rename(rekeyed, path)
+                .with_context(|| format!("Could not replace {}", path.display()))?;
+        }
+        if new.is_none() {
+            for path in &salt_paths {
+                std::fs::remove_file(path).ok();
+            }
+        }
+
+        *self.store_enc.write().expect("storage encryption lock") = new;
+        drop(db);
+
+        log::info!(
+            "Changed the storage password, the storage is {}",
+            if self.is_encrypted() {
+                "encrypted"
+            } else {
+                "not encrypted"
+            }
+        );
+        Ok(())
+    }
+
+    /// Finishes or rolls back a [Storage::change_password] that was interrupted.
+    ///
+    /// Whether the database got its new key decides.  Encrypting or decrypting shows in the
+    /// header of the database, a new password only by trying the keys.  When `password` opens
+    /// neither, the staged files are left alone; opening will fail on the wrong password anyway.
+    pub(super) async fn recover_rekey(
+        path: &Path,
+        password: Option<&str>,
+    ) -> Result<(), anyhow::Error> {
+        let mut staged = Vec::new();
+        let identity_dir = path.join("storage").join("identity");
+        if identity_dir.exists() {
+            for entry in std::fs::read_dir(&identity_dir)? {
+                let rekeyed = entry?.path();
+                if rekeyed.extension().map_or(false, |ext| ext == REKEY_SUFFIX) {
+                    staged.push((rekeyed.clone(), rekeyed.with_extension("")));
+                }
+            }
+        }
+        let storage_salt = path.join("storage").join("salt");
+        let db_salt = path.join("db").join("salt");
+        let db_file = path.join("db").join("harbour-whisperfish.db");
+        for file in &[&storage_salt, &db_salt, &db_file] {
+            let rekeyed = rekeyed_path(file);
+            if rekeyed.exists() {
+                staged.push((rekeyed, file.to_path_buf()));
+            }
+        }
+        if staged.is_empty() {
+            return Ok(());
+        }
+
+        let new_db_salt = rekeyed_path(&db_salt);
+        let rekeyed = match (new_db_salt.exists(), db_salt.exists()) {
+            // A new password
+            (true, true) => {
+                let password = match password {
+                    Some(password) => password,
+                    None => return Ok(()),
+                };
+                let opens = |storage_salt: PathBuf, db_salt: PathBuf| async move {
+                    let store_enc = encryption::StorageEncryption::new(
+                        password.to_string(),
+                        utils::read_salt_file(storage_salt).await?,
+                        utils::read_salt_file(db_salt).await?,
+                    )
+                    .await?;
+                    let location = StorageLocation::Path(path);
+                    Ok::<_, anyhow::Error>(
+                        Self::connect(&location, Some(store_enc.get_database_key())).is_ok(),
+                    )
+                };
+                // The storage salt may have been moved in place already.
+                let new_storage_salt = Some(rekeyed_path(&storage_salt))
+                    .filter(|p| p.exists())
+                    .unwrap_or_else(|| storage_salt.clone());
+                if opens(new_storage_salt, new_db_salt).await? {
+                    true
+                } else if opens(storage_salt.clone(), db_salt.clone()).await? {
+                    false
+                } else {
+                    return Ok(());
+                }
+            }
+            // Encrypting
+            (true, false) => !is_plaintext_db(&db_file)?,
+            // Decrypting
+            (false, true) => is_plaintext_db(&db_file)?,
+            (false, false) => false,
+        };
+
+        if rekeyed {
+            log::warn!("Finishing an interrupted password change");
+            for (rekeyed, path) in &staged {
+                std::fs::rename(rekeyed, path)
+                    .with_context(|| format!("Could not replace {}", path.display()))?;
+            }
+            if is_plaintext_db(&db_file)? {
+                std::fs::remove_file(&storage_salt).ok();
+                std::fs::remove_file(&db_salt).ok();
+            }
+        } else {
+            log::warn!("Rolling back an interrupted password change");
+            for (rekeyed, _) in &staged {
+                std::fs::remove_file(rekeyed)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the database file is unencrypted, by its SQLite header.
+fn is_plaintext_db(db_file: &Path) -> Result<bool, anyhow::Error> {
+    let mut header = [0u8; 16];
+    let mut file = File::open(db_file)?;
+    Ok(
+        std::io::Read::read_exact(&mut file, &mut header).is_ok()
+            && &header == b"SQLite format 3\0",
+    )
+}
+
+fn rekeyed_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().expect("file name").to_owned();
+    name.push(".");
+    name.push(REKEY_SUFFIX);
+    path.with_file_name(name)
+}
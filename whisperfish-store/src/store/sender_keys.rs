@@ -0,0 +1,132 @@
+//! Our sender keys in groups: their distribution ids, and which devices already got them.
+//!
+//! A message to a group is encrypted once with our sender key for that group, instead of once
+//! per device.  Every device of every member first needs the key, which it gets in a sender key
+//! distribution message.  When a member leaves, the group gets a new distribution id, and with
+//! it a new key, so that they can no longer read along.
+
+use super::*;
+
+impl Storage {
+    pub fn fetch_distribution_id(&self, group_v2_id: &str) -> Option<Uuid> {
+        use schema::sender_key_distributions;
+
+        let id: Option<String> = sender_key_distributions::table
+            .select(sender_key_distributions::distribution_id)
+            .filter(sender_key_distributions::group_v2_id.eq(group_v2_id))
+            .first(&mut *self.db())
+            .optional()
+            .expect("db");
+        id.map(|id| Uuid::parse_str(&id).expect("valid distribution id in db"))
+    }
+
+    /// The distribution id of our sender key in the group, created on first use.
+    pub fn fetch_or_insert_distribution_id(&self, group_v2_id: &str) -> Uuid {
+        use schema::sender_key_distributions;
+
+        if let Some(id) = self.fetch_distribution_id(group_v2_id) {
+            return id;
+        }
+
+        let id = Uuid::new_v4();
+        log::info!(
+            "New sender key distribution {} for group {}",
+            id,
+            group_v2_id
+        );
+        diesel::insert_into(sender_key_distributions::table)
+            .values((
+                sender_key_distributions::group_v2_id.eq(group_v2_id),
+                sender_key_distributions::distribution_id.eq(id.to_string()),
+                sender_key_distributions::created_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(&mut *self.db())
+            .expect("db");
+        id
+    }
+
+    /// Forgets our sender key for the group, such that the next message starts a new one.
+    ///
+    /// Returns whether there was a key to forget.
+    pub fn rotate_sender_key(&self, group_v2_id: &str) -> bool {
+        let id = match self.fetch_distribution_id(group_v2_id) {
+            Some(id) => id,
+            None => return false,
+        };
+        log::info!("Rotating sender key {} of group {}", id, group_v2_id);
+
+        let id = id.to_string();
+        diesel::delete(schema::sender_key_shared::table)
+            .filter(schema::sender_key_shared::distribution_id.eq(&id))
+            .execute(&mut *self.db())
+            .expect("db");
+        // Distribution ids are random, so only our own key uses this one.
+        diesel::delete(schema::sender_key_records::table)
+            .filter(schema::sender_key_records::distribution_id.eq(&id))
+            .execute(&mut *self.db())
+            .expect("db");
+        diesel::delete(schema::sender_key_distributions::table)
+            .filter(schema::sender_key_distributions::group_v2_id.eq(group_v2_id))
+            .execute(&mut *self.db())
+            .expect("db");
+        true
+    }
+
+    /// The devices that got our sender key for `distribution_id`.
+    pub fn fetch_sender_key_shared(&self, distribution_id: Uuid) -> Vec<ProtocolAddress> {
+        use schema::sender_key_shared;
+
+        let shared: Vec<(String, i32)> = sender_key_shared::table
+            .select((sender_key_shared::address, sender_key_shared::device))
+            .filter(sender_key_shared::distribution_id.eq(distribution_id.to_string()))
+            .load(&mut *self.db())
+            .expect("db");
+        shared
+            .into_iter()
+            .map(|(address, device)| ProtocolAddress::new(address, (device as u32).into()))
+            .collect()
+    }
+
+    /// Records that these devices got our sender key for `distribution_id`.
+    pub fn mark_sender_key_shared(&self, distribution_id: Uuid, addresses: &[ProtocolAddress]) {
+        use schema::sender_key_shared;
+
+        let now = Utc::now().naive_utc();
+        for addr in addresses {
+            diesel::replace_into(sender_key_shared::table)
+                .values((
+                    sender_key_shared::distribution_id.eq(distribution_id.to_string()),
+                    sender_key_shared::address.eq(addr.name()),
+                    sender_key_shared::device.eq(u32::from(addr.device_id()) as i32),
+                    sender_key_shared::shared_at.eq(now),
+                ))
+                .execute(&mut *self.db())
+                .expect("db");
+        }
+    }
+
+    /// Forgets that these devices got any of our sender keys, because they may have lost them.
+    pub fn clear_sender_key_shared(&self, addresses: &[ProtocolAddress]) {
+        use schema::sender_key_shared;
+
+        for addr in addresses {
+            diesel::delete(sender_key_shared::table)
+                .filter(
+                    sender_key_shared::address
+                        .eq(addr.name())
+                        .and(sender_key_shared::device.eq(u32::from(addr.device_id()) as i32)),
+                )
+                .execute(&mut *self.db())
+                .expect("db");
+        }
+    }
+
+    /// Forgets that any device of `address` got any of our sender keys, e.g. after they
+    /// reinstalled.
+    pub fn clear_sender_key_shared_with(&self, address: &str) -> usize {
+        diesel::delete(schema::sender_key_shared::table)
+            .filter(schema::sender_key_shared::address.eq(address))
+            .execute(&mut *self.db())
+            .expect("db")
+    }
+}
@@ -0,0 +1,123 @@
+//! A short-lived log of the content we sent, by timestamp and recipient device.
+//!
+//! When a device cannot decrypt a message, it asks for it again with a retry request, which
+//! only tells the timestamp of the message.  The log keeps the content around long enough to
+//! answer such requests.  A device is struck from an entry when it confirms delivery, and
+//! entries are dropped altogether after [SENT_CONTENT_LIFETIME].
+
+use super::*;
+
+/// How long sent content can be asked for again.
+pub const SENT_CONTENT_LIFETIME: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+/// Content sent before this moment is no longer kept.
+fn sent_content_cutoff() -> NaiveDateTime {
+    Utc::now().naive_utc()
+        - chrono::Duration::from_std(SENT_CONTENT_LIFETIME)
+            .expect("lifetime fits in chrono::Duration")
+}
+
+impl Storage {
+    /// Logs `content` (an encoded `Content`) with the given timestamp as sent to `recipients`, a
+    /// list of devices.
+    pub fn log_sent_content(
+        &self,
+        timestamp: NaiveDateTime,
+        content: &[u8],
+        recipients: &[(Uuid, u32)],
+    ) {
+        use schema::{sent_content_log, sent_content_recipients};
+
+        if recipients.is_empty() {
+            return;
+        }
+        self.prune_sent_content();
+
+        self.db()
+            .transaction::<_, diesel::result::Error, _>(|db| {
+                diesel::insert_into(sent_content_log::table)
+                    .values((
+                        sent_content_log::timestamp.eq(timestamp),
+                        sent_content_log::content.eq(content),
+                        sent_content_log::sent_at.eq(Utc::now().naive_utc()),
+                    ))
+                    .execute(db)?;
+                let id: i32 = diesel::select(last_insert_rowid()).get_result(db)?;
+
+                let recipients: Vec<_> = recipients
+                    .iter()
+                    .unique()
+                    .map(|(uuid, device_id)| {
+                        (
+                            sent_content_recipients::sent_content_id.eq(id),
+                            sent_content_recipients::address.eq(uuid.to_string()),
+                            sent_content_recipients::device_id.eq(*device_id as i32),
+                        )
+                    })
+                    .collect();
+                diesel::insert_into(sent_content_recipients::table)
+                    .values(recipients)
+                    .execute(db)?;
+                Ok(())
+            })
+            .expect("db");
+    }
+
+    /// The content with this timestamp that was sent to device `device_id` of `recipient`, unless
+    /// it got too old or was delivered already.
+    pub fn fetch_sent_content(
+        &self,
+        recipient: Uuid,
+        device_id: u32,
+        timestamp: NaiveDateTime,
+    ) -> Option<Vec<u8>> {
+        use schema::{sent_content_log, sent_content_recipients};
+
+        let cutoff = sent_content_cutoff();
+        sent_content_log::table
+            .inner_join(sent_content_recipients::table)
+            .select(sent_content_log::content)
+            .filter(sent_content_log::timestamp.eq(timestamp))
+            .filter(sent_content_log::sent_at.ge(cutoff))
+            .filter(sent_content_recipients::address.eq(recipient.to_string()))
+            .filter(sent_content_recipients::device_id.eq(device_id as i32))
+            .order_by(sent_content_log::id.desc())
+            .first(&mut *self.db())
+            .optional()
+            .expect("db")
+    }
+
+    /// Strikes device `device_id` of `recipient` from the content with this timestamp, because it
+    /// got it.
+    pub fn forget_sent_content(&self, recipient: Uuid, device_id: u32, timestamp: NaiveDateTime) {
+        use schema::{sent_content_log, sent_content_recipients};
+
+        let ids = sent_content_log::table
+            .select(sent_content_log::id)
+            .filter(sent_content_log::timestamp.eq(timestamp));
+        diesel::delete(sent_content_recipients::table)
+            .filter(sent_content_recipients::sent_content_id.eq_any(ids))
+            .filter(sent_content_recipients::address.eq(recipient.to_string()))
+            .filter(sent_content_recipients::device_id.eq(device_id as i32))
+            .execute(&mut *self.db())
+            .expect("db");
+    }
+
+    /// Drops the content that got too old, or that every recipient got.  Returns how many
+    /// entries were dropped.
+    pub fn prune_sent_content(&self) -> usize {
+        use schema::{sent_content_log, sent_content_recipients};
+
+        let cutoff = sent_content_cutoff();
+        let pending =
+            sent_content_recipients::table.select(sent_content_recipients::sent_content_id);
+        diesel::delete(sent_content_log::table)
+            .filter(
+                sent_content_log::sent_at
+                    .lt(cutoff)
+                    .or(sent_content_log::id.ne_all(pending)),
+            )
+            .execute(&mut *self.db())
+            .expect("db")
+    }
+}
@@ -0,0 +1,168 @@
+mod common;
+
+use self::common::*;
+use ::phonenumber::PhoneNumber;
+use rstest::rstest;
+use std::collections::HashMap;
+use std::future::Future;
+use uuid::{uuid, Uuid};
+use whisperfish_store::{ContactDiscoveryService, DiscoveredContact};
+
+const ACI: Uuid = uuid!("dc6bf7f6-9946-4e01-89f6-dc3abdb2f71b");
+const ACI2: Uuid = uuid!("c25f3e9a-2cfd-4eb0-8a53-b22eb025667d");
+const PNI: Uuid = uuid!("0e8d3cbc-d3a5-4b4d-9a61-5c4f1d1e6f20");
+const PNI2: Uuid = uuid!("5a3e7d0c-8f14-4b6b-b2c1-7e9d3f5a1c48");
+
+fn e164(number: &str) -> PhoneNumber {
+    ::phonenumber::parse(None, number).unwrap()
+}
+
+/// Stands in for the contact discovery service of Signal.
+#[derive(Default)]
+struct LocalDirectory {
+    registered: HashMap<String, (Option<Uuid>, Option<Uuid>)>,
+    lookups: Vec<Vec<PhoneNumber>>,
+}
+
+impl LocalDirectory {
+    fn with(mut self, number: &str, aci: Option<Uuid>, pni: Option<Uuid>) -> Self {
+        self.registered.insert(number.into(), (aci, pni));
+        self
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl ContactDiscoveryService for LocalDirectory {
+    async fn discover(
+        &mut self,
+        e164s: &[PhoneNumber],
+    ) -> Result<Vec<DiscoveredContact>, anyhow::Error> {
+        self.lookups.push(e164s.to_vec());
+        Ok(e164s
+            .iter()
+            .filter_map(|e164| {
+                let (aci, pni) = self.registered.get(&e164.to_string())?;
+                Some(DiscoveredContact {
+                    e164: e164.clone(),
+                    aci: *aci,
+                    pni: *pni,
+                })
+            })
+            .collect())
+    }
+}
+
+struct Unreachable;
+
+#[async_trait::async_trait(?Send)]
+impl ContactDiscoveryService for Unreachable {
+    async fn discover(
+        &mut self,
+        _e164s: &[PhoneNumber],
+    ) -> Result<Vec<DiscoveredContact>, anyhow::Error> {
+        anyhow::bail!("no network")
+    }
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn discovered_contacts_are_merged(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, _temp_dir) = storage.await;
+
+    // Known by UUID only, e.g. from a group.
+    storage.fetch_or_insert_recipient_by_uuid(ACI);
+
+    let mut directory = LocalDirectory::default()
+        .with("+32474000000", Some(ACI), Some(PNI))
+        .with("+32474000001", None, Some(PNI2));
+    let report = storage
+        .discover_contacts(
+            &mut directory,
+            vec![
+                e164("+32474000000"),
+                e164("+32474000001"),
+                e164("+32474000002"),
+            ],
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(report.queried, 3);
+    assert_eq!(report.registered, 2);
+    assert_eq!(report.unregistered, 0);
+
+    let recipients = storage.fetch_recipients();
+    assert_eq!(recipients.len(), 2);
+
+    let merged = storage.fetch_recipient_by_uuid(ACI).unwrap();
+    assert_eq!(merged.e164, Some(e164("+32474000000")));
+    assert_eq!(merged.pni, Some(PNI));
+    assert!(merged.is_registered);
+
+    let without_aci = storage
+        .fetch_recipient_by_phonenumber(&e164("+32474000001"))
+        .unwrap();
+    assert_eq!(without_aci.uuid, None);
+    assert_eq!(without_aci.pni, Some(PNI2));
+    assert!(without_aci.is_registered);
+
+    // No recipient for a number that does not use Signal.
+    assert!(storage
+        .fetch_recipient_by_phonenumber(&e164("+32474000002"))
+        .is_none());
+    assert_eq!(storage.count_registered_contacts(), 2);
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn known_recipients_are_refreshed(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, _temp_dir) = storage.await;
+
+    storage.fetch_or_insert_recipient_by_phonenumber(&e164("+32474000000"));
+    storage.fetch_or_insert_recipient_by_phonenumber(&e164("+32474000001"));
+    assert_eq!(storage.count_registered_contacts(), 2);
+
+    // The known numbers are looked up too, and only once.
+    let mut directory = LocalDirectory::default().with("+32474000001", Some(ACI), None);
+    let report = storage
+        .discover_contacts(&mut directory, vec![e164("+32474000001")])
+        .await
+        .unwrap();
+    assert_eq!(directory.lookups.len(), 1);
+    assert_eq!(directory.lookups[0].len(), 2);
+
+    assert_eq!(report.registered, 1);
+    assert_eq!(report.unregistered, 1);
+    assert!(
+        !storage
+            .fetch_recipient_by_phonenumber(&e164("+32474000000"))
+            .unwrap()
+            .is_registered
+    );
+    assert_eq!(storage.count_registered_contacts(), 1);
+
+    // Registering again flips the flag back.
+    let mut directory = directory.with("+32474000000", Some(ACI2), None);
+    let report = storage
+        .discover_contacts(&mut directory, Vec::new())
+        .await
+        .unwrap();
+    assert_eq!(report.registered, 2);
+    assert_eq!(report.unregistered, 0);
+    assert_eq!(storage.count_registered_contacts(), 2);
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn failed_discovery_changes_nothing(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, _temp_dir) = storage.await;
+
+    storage.fetch_or_insert_recipient_by_phonenumber(&e164("+32474000000"));
+
+    let result = storage
+        .discover_contacts(&mut Unreachable, vec![e164("+32474000001")])
+        .await;
+    assert!(result.is_err());
+    assert_eq!(storage.fetch_recipients().len(), 1);
+    assert_eq!(storage.count_registered_contacts(), 1);
+}
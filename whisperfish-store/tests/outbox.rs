@@ -0,0 +1,173 @@
+mod common;
+
+use self::common::*;
+use chrono::prelude::*;
+use chrono::Duration;
+use rstest::rstest;
+use std::future::Future;
+use whisperfish_store::NewMessage;
+
+fn outgoing(session_id: i32, text: &str) -> NewMessage {
+    NewMessage {
+        session_id,
+        source_e164: None,
+        source_uuid: None,
+        text: text.into(),
+        timestamp: Utc::now().naive_utc(),
+        sent: false,
+        received: false,
+        is_read: true,
+        flags: 0,
+        attachment: None,
+        mime_type: None,
+        has_attachment: false,
+        outgoing: true,
+        is_unidentified: false,
+        quote_timestamp: None,
+        expires_in: None,
+    }
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn retry_schedule(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, _temp_dir) = storage.await;
+    let pn = phonenumber::parse(None, "+32474000000").unwrap();
+    let session = storage.fetch_or_insert_session_by_phonenumber(&pn);
+    let msg = storage.create_message(&outgoing(session.id, "Hello"));
+
+    storage.enqueue_outgoing(msg.id);
+    let now = Utc::now().naive_utc();
+    let entry = storage.fetch_outbox_entry(msg.id).unwrap();
+    assert_eq!(entry.attempts, 0);
+    assert_eq!(storage.fetch_due_outbox_entries(now).len(), 1);
+
+    // A transient failure postpones the message.
+    let later = now + Duration::minutes(1);
+    let entry = storage
+        .reschedule_outgoing(msg.id, later, "timeout")
+        .unwrap();
+    assert_eq!(entry.attempts, 1);
+    assert_eq!(entry.last_error.as_deref(), Some("timeout"));
+    assert!(storage.fetch_due_outbox_entries(now).is_empty());
+    assert_eq!(storage.fetch_due_outbox_entries(later).len(), 1);
+
+    // Until the connection comes back.
+    assert_eq!(storage.retry_outbox_now(), 1);
+    assert_eq!(
+        storage
+            .fetch_due_outbox_entries(Utc::now().naive_utc())
+            .len(),
+        1
+    );
+
+    storage.dequeue_message(msg.id, Utc::now().naive_utc(), false);
+    assert!(storage.fetch_outbox_entry(msg.id).is_none());
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn manual_retry_after_failure(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, _temp_dir) = storage.await;
+    let pn = phonenumber::parse(None, "+32474000000").unwrap();
+    let session = storage.fetch_or_insert_session_by_phonenumber(&pn);
+    let msg = storage.create_message(&outgoing(session.id, "Hello"));
+
+    storage.enqueue_outgoing(msg.id);
+    storage.reschedule_outgoing(msg.id, Utc::now().naive_utc(), "timeout");
+    storage.fail_message(msg.id);
+    assert!(storage.fetch_outbox_entry(msg.id).is_none());
+    assert!(
+        storage
+            .fetch_message_by_id(msg.id)
+            .unwrap()
+            .sending_has_failed
+    );
+
+    storage.enqueue_outgoing(msg.id);
+    assert!(
+        !storage
+            .fetch_message_by_id(msg.id)
+            .unwrap()
+            .sending_has_failed
+    );
+    let entry = storage.fetch_outbox_entry(msg.id).unwrap();
+    assert_eq!(entry.attempts, 0);
+    assert_eq!(entry.last_error, None);
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn partial_group_send(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, _temp_dir) = storage.await;
+    let pn = phonenumber::parse(None, "+32474000000").unwrap();
+    let session = storage.fetch_or_insert_session_by_phonenumber(&pn);
+    let msg = storage.create_message(&outgoing(session.id, "Hello"));
+    let alice = storage.fetch_or_insert_recipient_by_phonenumber(&pn);
+    let bob = storage.fetch_or_insert_recipient_by_phonenumber(
+        &phonenumber::parse(None, "+32474000001").unwrap(),
+    );
+
+    assert!(storage.fetch_message_sent_to(msg.id).is_empty());
+    storage.mark_message_sent_to(msg.id, alice.id, Utc::now().naive_utc());
+    assert_eq!(storage.fetch_message_sent_to(msg.id), vec![alice.id]);
+
+    // Being sent is not being delivered.
+    let augmented = storage.fetch_augmented_message(msg.id).unwrap();
+    assert_eq!(augmented.delivered(), 0);
+    assert_eq!(augmented.receipts.len(), 1);
+
+    storage.mark_message_sent_to(msg.id, bob.id, Utc::now().naive_utc());
+    let mut sent_to = storage.fetch_message_sent_to(msg.id);
+    sent_to.sort_unstable();
+    assert_eq!(sent_to, vec![alice.id, bob.id]);
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn requeue_pending_on_startup(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, _temp_dir) = storage.await;
+    let pn = phonenumber::parse(None, "+32474000000").unwrap();
+    let session = storage.fetch_or_insert_session_by_phonenumber(&pn);
+    let pending = storage.create_message(&outgoing(session.id, "Pending"));
+    let queued = storage.create_message(&outgoing(session.id, "Queued"));
+    let failed = storage.create_message(&outgoing(session.id, "Failed"));
+    let sent = storage.create_message(&outgoing(session.id, "Sent"));
+    storage.enqueue_outgoing(queued.id);
+    storage.fail_message(failed.id);
+    storage.dequeue_message(sent.id, Utc::now().naive_utc(), false);
+
+    assert_eq!(storage.requeue_pending_messages(), 1);
+    assert!(storage.fetch_outbox_entry(pending.id).is_some());
+    assert!(storage.fetch_outbox_entry(failed.id).is_none());
+    assert!(storage.fetch_outbox_entry(sent.id).is_none());
+    assert_eq!(storage.requeue_pending_messages(), 0);
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn failed_recipients(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, _temp_dir) = storage.await;
+    let pn = phonenumber::parse(None, "+32474000000").unwrap();
+    let session = storage.fetch_or_insert_session_by_phonenumber(&pn);
+    let msg = storage.create_message(&outgoing(session.id, "Hello"));
+    let alice = storage.fetch_or_insert_recipient_by_phonenumber(&pn);
+
+    let intended = storage.fetch_message_intended_recipients(msg.id);
+    assert_eq!(intended.len(), 1);
+    assert_eq!(intended[0].id, alice.id);
+
+    assert_eq!(storage.mark_unsent_recipients_failed(msg.id), 1);
+    assert_eq!(storage.fetch_message_failed_for(msg.id), vec![alice.id]);
+    let receipts = storage.fetch_message_receipts(msg.id);
+    assert_eq!(receipts.len(), 1);
+    assert_eq!(receipts[0].0.status(), "failed");
+
+    // The retry got through.
+    storage.mark_message_sent_to(msg.id, alice.id, Utc::now().naive_utc());
+    assert!(storage.fetch_message_failed_for(msg.id).is_empty());
+    let receipts = storage.fetch_message_receipts(msg.id);
+    assert_eq!(receipts[0].0.status(), "sent");
+    assert_eq!(receipts[0].0.failed, None);
+    assert_eq!(storage.mark_unsent_recipients_failed(msg.id), 0);
+}
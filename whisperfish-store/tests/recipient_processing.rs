@@ -81,22 +81,26 @@ mod merge_and_fetch {
     async fn trusted_pair(storage: impl Future<Output = InMemoryDb>, phonenumber: PhoneNumber) {
         let (storage, _temp_dir) = storage.await;
 
-        let recipient = storage.merge_and_fetch_recipient(
-            Some(phonenumber.clone()),
-            Some(UUID),
-            None,
-            TrustLevel::Certain,
-        );
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Certain,
+            )
+            .unwrap();
         assert_eq!(recipient.e164.as_ref(), Some(&phonenumber));
         assert_eq!(recipient.uuid, Some(UUID));
 
         // Second call should be a no-op
-        let recipient_check = storage.merge_and_fetch_recipient(
-            Some(phonenumber.clone()),
-            Some(UUID),
-            None,
-            TrustLevel::Certain,
-        );
+        let recipient_check = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Certain,
+            )
+            .unwrap();
         assert_eq!(recipient.e164.as_ref(), Some(&phonenumber));
         assert_eq!(recipient.uuid, Some(UUID));
         assert_eq!(recipient_check.id, recipient.id);
@@ -107,12 +111,14 @@ mod merge_and_fetch {
     async fn untrusted_pair(storage: impl Future<Output = InMemoryDb>, phonenumber: PhoneNumber) {
         let (storage, _temp_dir) = storage.await;
 
-        let recipient = storage.merge_and_fetch_recipient(
-            Some(phonenumber.clone()),
-            Some(UUID),
-            None,
-            TrustLevel::Uncertain,
-        );
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Uncertain,
+            )
+            .unwrap();
         assert_eq!(recipient.e164, None);
         assert_eq!(recipient.uuid, Some(UUID));
     }
@@ -124,12 +130,14 @@ mod merge_and_fetch {
     ) {
         let ((storage, _temp_dir), phonenumber) = storage_with_e164_recipient.await;
 
-        let recipient = storage.merge_and_fetch_recipient(
-            Some(phonenumber.clone()),
-            Some(UUID),
-            None,
-            TrustLevel::Certain,
-        );
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Certain,
+            )
+            .unwrap();
         assert_eq!(recipient.e164.as_ref(), Some(&phonenumber));
         assert_eq!(recipient.uuid, Some(UUID));
 
@@ -143,12 +151,14 @@ mod merge_and_fetch {
     ) {
         let ((storage, _temp_dir), phonenumber) = storage_with_e164_recipient.await;
 
-        let recipient = storage.merge_and_fetch_recipient(
-            Some(phonenumber.clone()),
-            Some(UUID),
-            None,
-            TrustLevel::Uncertain,
-        );
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Uncertain,
+            )
+            .unwrap();
         assert_eq!(recipient.e164, None);
         assert_eq!(recipient.uuid, Some(UUID));
 
@@ -175,12 +185,14 @@ mod merge_and_fetch {
     ) {
         let (storage, _temp_dir) = storage_with_uuid_recipient.await;
 
-        let recipient = storage.merge_and_fetch_recipient(
-            Some(phonenumber.clone()),
-            Some(UUID),
-            None,
-            TrustLevel::Certain,
-        );
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Certain,
+            )
+            .unwrap();
         assert_eq!(recipient.e164.as_ref(), Some(&phonenumber));
         assert_eq!(recipient.uuid, Some(UUID));
 
@@ -195,12 +207,14 @@ mod merge_and_fetch {
     ) {
         let (storage, _temp_dir) = storage_with_uuid_recipient.await;
 
-        let recipient = storage.merge_and_fetch_recipient(
-            Some(phonenumber.clone()),
-            Some(UUID),
-            None,
-            TrustLevel::Uncertain,
-        );
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Uncertain,
+            )
+            .unwrap();
         assert_eq!(recipient.e164.as_ref(), None);
         assert_eq!(recipient.uuid, Some(UUID));
 
@@ -232,12 +246,14 @@ mod merge_and_fetch_conflicting_recipients {
         // If we now fetch the recipient based on both e164 and uuid, with certainty of their
         // relation,
         // we trigger their merger.
-        let recipient = storage.merge_and_fetch_recipient(
-            Some(phonenumber.clone()),
-            Some(UUID),
-            None,
-            TrustLevel::Certain,
-        );
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Certain,
+            )
+            .unwrap();
         assert_eq!(recipient.e164.as_ref(), Some(&phonenumber));
         assert_eq!(recipient.uuid, Some(UUID));
 
@@ -262,12 +278,14 @@ mod merge_and_fetch_conflicting_recipients {
         // If we now fetch the recipient based on both e164 and uuid, with certainty of their
         // relation,
         // we trigger their merger.
-        let recipient = storage.merge_and_fetch_recipient(
-            Some(phonenumber.clone()),
-            Some(UUID),
-            None,
-            TrustLevel::Uncertain,
-        );
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Uncertain,
+            )
+            .unwrap();
         assert_eq!(recipient.e164.as_ref(), None);
         assert_eq!(recipient.id, r2.id);
         assert_eq!(recipient.uuid, Some(UUID));
@@ -284,12 +302,14 @@ mod merge_and_fetch_conflicting_recipients {
     ) {
         let (storage, _temp_dir) = storage.await;
 
-        let r1 = storage.merge_and_fetch_recipient(
-            Some(phonenumber.clone()),
-            Some(UUID),
-            None,
-            TrustLevel::Certain,
-        );
+        let r1 = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Certain,
+            )
+            .unwrap();
         let r2 = storage.fetch_or_insert_recipient_by_uuid(UUID2);
         // We have two separate recipients.
         assert_ne!(r1.id, r2.id);
@@ -303,12 +323,14 @@ mod merge_and_fetch_conflicting_recipients {
         // XXX Signal Android then marks the former as "needing refresh". Still need to figure out what
         // that is, but it probably checks with the server than indeed the former UUID doesn't
         // exist anymore, and that the data needs to be moved.
-        let recipient = storage.merge_and_fetch_recipient(
-            Some(phonenumber.clone()),
-            Some(UUID2),
-            None,
-            TrustLevel::Certain,
-        );
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID2),
+                None,
+                TrustLevel::Certain,
+            )
+            .unwrap();
         assert_eq!(recipient.e164.as_ref(), Some(&phonenumber));
         assert_eq!(recipient.uuid, Some(UUID2));
 
@@ -330,12 +352,14 @@ mod merge_and_fetch_conflicting_recipients {
     ) {
         let (storage, _temp_dir) = storage.await;
 
-        let r1 = storage.merge_and_fetch_recipient(
-            Some(phonenumber.clone()),
-            Some(UUID),
-            None,
-            TrustLevel::Certain,
-        );
+        let r1 = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Certain,
+            )
+            .unwrap();
         let r2 = storage.fetch_or_insert_recipient_by_uuid(UUID2);
         // We have two separate recipients.
         assert_ne!(r1.id, r2.id);
@@ -346,12 +370,14 @@ mod merge_and_fetch_conflicting_recipients {
         // If we now fetch the recipient based on both e164 and uuid2, with uncertainty of their
         // relation,
         // we should get the uuid2 recipient without any other action.
-        let recipient = storage.merge_and_fetch_recipient(
-            Some(phonenumber.clone()),
-            Some(UUID2),
-            None,
-            TrustLevel::Uncertain,
-        );
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID2),
+                None,
+                TrustLevel::Uncertain,
+            )
+            .unwrap();
         assert_eq!(recipient.e164.as_ref(), None);
         assert_eq!(recipient.uuid, Some(UUID2));
 
@@ -365,3 +391,737 @@ mod merge_and_fetch_conflicting_recipients {
         assert_eq!(recipient.e164.as_ref(), Some(&phonenumber));
     }
 }
+
+mod merge_and_fetch_pni {
+    use super::*;
+    use libsignal_service::protocol::{
+        DeviceId, IdentityKeyPair, IdentityKeyStore, ProtocolAddress,
+    };
+    use whisperfish_store::TrustLevel;
+
+    const PNI: Uuid = uuid!("0e8d3cbc-d3a5-4b4d-9a61-5c4f1d1e6f20");
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn insert_then_fetch_by_pni(storage: impl Future<Output = InMemoryDb>) {
+        let (storage, _temp_dir) = storage.await;
+
+        let recipient1 = storage
+            .merge_and_fetch_recipient(None, None, Some(PNI), TrustLevel::Certain)
+            .unwrap();
+        let recipient2 = storage
+            .merge_and_fetch_recipient(None, None, Some(PNI), TrustLevel::Certain)
+            .unwrap();
+        assert_eq!(recipient1.id, recipient2.id);
+        assert_eq!(recipient1.pni, Some(PNI));
+        assert_eq!(recipient1.uuid, None);
+        assert_eq!(
+            storage.fetch_recipient_by_pni(PNI).unwrap().id,
+            recipient1.id
+        );
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn trusted_triple(storage: impl Future<Output = InMemoryDb>, phonenumber: PhoneNumber) {
+        let (storage, _temp_dir) = storage.await;
+
+        // First known by phone number and PNI, e.g. from contact discovery.
+        let r1 = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                None,
+                Some(PNI),
+                TrustLevel::Certain,
+            )
+            .unwrap();
+        assert_eq!(r1.pni, Some(PNI));
+
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                Some(PNI),
+                TrustLevel::Certain,
+            )
+            .unwrap();
+        assert_eq!(recipient.id, r1.id);
+        assert_eq!(recipient.e164.as_ref(), Some(&phonenumber));
+        assert_eq!(recipient.uuid, Some(UUID));
+        assert_eq!(recipient.pni, Some(PNI));
+        assert_eq!(storage.fetch_recipients().len(), 1);
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn trusted_disjunct_pni(
+        storage_with_uuid_recipient: impl Future<Output = InMemoryDb>,
+        phonenumber: PhoneNumber,
+    ) {
+        let (storage, _temp_dir) = storage_with_uuid_recipient.await;
+
+        let by_pni = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                None,
+                Some(PNI),
+                TrustLevel::Certain,
+            )
+            .unwrap();
+        assert_eq!(storage.fetch_recipients().len(), 2);
+
+        // Learning the ACI behind the PNI merges both.
+        let recipient = storage
+            .merge_and_fetch_recipient(None, Some(UUID), Some(PNI), TrustLevel::Certain)
+            .unwrap();
+        assert_ne!(recipient.id, by_pni.id);
+        assert_eq!(recipient.uuid, Some(UUID));
+        assert_eq!(recipient.pni, Some(PNI));
+        assert_eq!(recipient.e164.as_ref(), Some(&phonenumber));
+        assert_eq!(storage.fetch_recipients().len(), 1);
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn untrusted_disjunct_pni(storage_with_uuid_recipient: impl Future<Output = InMemoryDb>) {
+        let (storage, _temp_dir) = storage_with_uuid_recipient.await;
+
+        let by_pni = storage.fetch_or_insert_recipient_by_pni(PNI);
+        let recipient = storage
+            .merge_and_fetch_recipient(None, Some(UUID), Some(PNI), TrustLevel::Uncertain)
+            .unwrap();
+        assert_eq!(recipient.pni, None);
+        assert_eq!(storage.fetch_recipient_by_pni(PNI).unwrap().id, by_pni.id);
+        assert_eq!(storage.fetch_recipients().len(), 2);
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn trusted_pni_moves(storage: impl Future<Output = InMemoryDb>) {
+        let (storage, _temp_dir) = storage.await;
+
+        let r1 = storage
+            .merge_and_fetch_recipient(None, Some(UUID), Some(PNI), TrustLevel::Certain)
+            .unwrap();
+        assert_eq!(r1.pni, Some(PNI));
+
+        // The phone number, and with it the PNI, went to another account.
+        let r2 = storage
+            .merge_and_fetch_recipient(None, Some(UUID2), Some(PNI), TrustLevel::Certain)
+            .unwrap();
+        assert_ne!(r1.id, r2.id);
+        assert_eq!(r2.pni, Some(PNI));
+        assert_eq!(storage.fetch_recipient_by_id(r1.id).unwrap().pni, None);
+        assert_eq!(storage.fetch_recipients().len(), 2);
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn pni_signature_links_pni(storage: impl Future<Output = InMemoryDb>) {
+        let (mut storage, _temp_dir) = storage.await;
+        let mut rng = rand::thread_rng();
+
+        let aci_identity = IdentityKeyPair::generate(&mut rng);
+        let pni_identity = IdentityKeyPair::generate(&mut rng);
+        let signature = pni_identity
+            .sign_alternate_identity(aci_identity.identity_key(), &mut rng)
+            .unwrap();
+
+        storage.fetch_or_insert_recipient_by_uuid(UUID);
+        storage.fetch_or_insert_recipient_by_pni(PNI);
+
+        // Without the identities, nothing can be verified.
+        assert!(storage
+            .process_pni_signature(UUID, PNI, &signature)
+            .is_none());
+
+        for (uuid, identity) in vec![(UUID, aci_identity), (PNI, pni_identity)] {
+            let addr = ProtocolAddress::new(uuid.to_string(), DeviceId::from(1));
+            storage
+                .save_identity(&addr, identity.identity_key(), None)
+                .await
+                .unwrap();
+        }
+        assert!(storage
+            .process_pni_signature(UUID, PNI, b"forged")
+            .is_none());
+        assert_eq!(storage.fetch_recipients().len(), 2);
+
+        let recipient = storage
+            .process_pni_signature(UUID, PNI, &signature)
+            .unwrap();
+        assert_eq!(recipient.uuid, Some(UUID));
+        assert_eq!(recipient.pni, Some(PNI));
+        assert_eq!(storage.fetch_recipients().len(), 1);
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn own_pni_signature_verifies(storage: impl Future<Output = InMemoryDb>) {
+        let (storage, _temp_dir) = storage.await;
+
+        let aci_identity = storage.get_identity_key_pair(None).await.unwrap();
+        let pni_identity = storage
+            .pni_store()
+            .get_identity_key_pair(None)
+            .await
+            .unwrap();
+        assert_ne!(aci_identity.public_key(), pni_identity.public_key());
+        assert_eq!(
+            storage
+                .get_pni_identity_key_pair()
+                .await
+                .unwrap()
+                .public_key(),
+            pni_identity.public_key()
+        );
+
+        let signature = storage.pni_signature().await.unwrap();
+        assert!(pni_identity
+            .identity_key()
+            .verify_alternate_identity(aci_identity.identity_key(), &signature)
+            .unwrap());
+
+        // A new PNI identity, e.g. after changing our number, is picked up by all clones.
+        let new_identity = IdentityKeyPair::generate(&mut rand::thread_rng());
+        let pni_store = storage.pni_store();
+        storage
+            .set_pni_identity(new_identity, Some(4321))
+            .await
+            .unwrap();
+        assert_eq!(
+            pni_store
+                .get_identity_key_pair(None)
+                .await
+                .unwrap()
+                .public_key(),
+            new_identity.public_key()
+        );
+        assert_eq!(
+            pni_store.get_local_registration_id(None).await.unwrap(),
+            4321
+        );
+    }
+}
+
+mod resolve_recipient {
+    use super::*;
+    use chrono::prelude::*;
+    use whisperfish_store::{NewMessage, RecipientEvent, RecipientIdentifiers, TrustLevel};
+
+    const PNI: Uuid = uuid!("6f3f4a0e-5b1c-4d8e-9c2a-0b7e3d1f2a90");
+    const PNI2: Uuid = uuid!("a1d2c3b4-e5f6-4a7b-8c9d-0e1f2a3b4c5d");
+
+    fn other_phonenumber() -> PhoneNumber {
+        ::phonenumber::parse(None, "+32474999999").unwrap()
+    }
+
+    fn ids(
+        e164: Option<&PhoneNumber>,
+        aci: Option<Uuid>,
+        pni: Option<Uuid>,
+    ) -> RecipientIdentifiers {
+        RecipientIdentifiers {
+            e164: e164.cloned(),
+            aci,
+            pni,
+        }
+    }
+
+    fn new_message(session_id: i32, text: &str, timestamp: i64) -> NewMessage {
+        NewMessage {
+            session_id,
+            source_e164: None,
+            source_uuid: None,
+            text: text.into(),
+            timestamp: Utc.timestamp_opt(timestamp, 0).unwrap().naive_utc(),
+            sent: false,
+            received: true,
+            is_read: true,
+            flags: 0,
+            attachment: None,
+            mime_type: None,
+            has_attachment: false,
+            outgoing: false,
+            is_unidentified: false,
+            quote_timestamp: None,
+            expires_in: None,
+        }
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn nothing_to_resolve(storage: impl Future<Output = InMemoryDb>) {
+        let (storage, _temp_dir) = storage.await;
+
+        for trust_level in vec![TrustLevel::Certain, TrustLevel::Uncertain] {
+            assert!(storage
+                .resolve_recipient(RecipientIdentifiers::default(), trust_level)
+                .is_none());
+            assert!(storage
+                .merge_and_fetch_recipient(None, None, None, trust_level)
+                .is_err());
+        }
+        assert!(storage.fetch_recipients().is_empty());
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn insert_reports_event(
+        storage: impl Future<Output = InMemoryDb>,
+        phonenumber: PhoneNumber,
+    ) {
+        let (storage, _temp_dir) = storage.await;
+
+        let resolution = storage
+            .resolve_recipient(
+                ids(Some(&phonenumber), Some(UUID), Some(PNI)),
+                TrustLevel::Certain,
+            )
+            .unwrap();
+        assert_eq!(
+            resolution.events,
+            vec![RecipientEvent::Inserted(resolution.recipient.id)]
+        );
+        assert_eq!(resolution.recipient.e164.as_ref(), Some(&phonenumber));
+        assert_eq!(resolution.recipient.uuid, Some(UUID));
+        assert_eq!(resolution.recipient.pni, Some(PNI));
+
+        // Resolving again changes nothing.
+        let resolution = storage
+            .resolve_recipient(
+                ids(Some(&phonenumber), Some(UUID), Some(PNI)),
+                TrustLevel::Certain,
+            )
+            .unwrap();
+        assert!(resolution.events.is_empty());
+        assert_eq!(storage.fetch_recipients().len(), 1);
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn untrusted_insert_without_aci(
+        storage: impl Future<Output = InMemoryDb>,
+        phonenumber: PhoneNumber,
+    ) {
+        let (storage, _temp_dir) = storage.await;
+
+        // Without an ACI, the phone number and PNI are stored together.
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                None,
+                Some(PNI),
+                TrustLevel::Uncertain,
+            )
+            .unwrap();
+        assert_eq!(recipient.e164.as_ref(), Some(&phonenumber));
+        assert_eq!(recipient.pni, Some(PNI));
+        assert_eq!(recipient.uuid, None);
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn untrusted_fills_in_free_pni(
+        storage_with_e164_recipient: impl Future<Output = (InMemoryDb, PhoneNumber)>,
+    ) {
+        let ((storage, _temp_dir), phonenumber) = storage_with_e164_recipient.await;
+        let r1 = storage
+            .fetch_recipient_by_phonenumber(&phonenumber)
+            .unwrap();
+
+        let resolution = storage
+            .resolve_recipient(
+                ids(Some(&phonenumber), None, Some(PNI)),
+                TrustLevel::Uncertain,
+            )
+            .unwrap();
+        assert_eq!(resolution.recipient.id, r1.id);
+        assert_eq!(resolution.recipient.pni, Some(PNI));
+        assert_eq!(resolution.events, vec![RecipientEvent::Updated(r1.id)]);
+        assert_eq!(storage.fetch_recipients().len(), 1);
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn untrusted_pni_and_e164_not_merged(
+        storage_with_e164_recipient: impl Future<Output = (InMemoryDb, PhoneNumber)>,
+    ) {
+        let ((storage, _temp_dir), phonenumber) = storage_with_e164_recipient.await;
+        let by_e164 = storage
+            .fetch_recipient_by_phonenumber(&phonenumber)
+            .unwrap();
+        let by_pni = storage.fetch_or_insert_recipient_by_pni(PNI);
+
+        let resolution = storage
+            .resolve_recipient(
+                ids(Some(&phonenumber), None, Some(PNI)),
+                TrustLevel::Uncertain,
+            )
+            .unwrap();
+        assert_eq!(resolution.recipient.id, by_pni.id);
+        assert_eq!(resolution.recipient.e164, None);
+        assert!(resolution.events.is_empty());
+
+        assert_eq!(storage.fetch_recipients().len(), 2);
+        assert_eq!(storage.fetch_recipient_by_id(by_e164.id).unwrap().pni, None);
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn trusted_pni_and_e164_merged(
+        storage_with_e164_recipient: impl Future<Output = (InMemoryDb, PhoneNumber)>,
+    ) {
+        let ((storage, _temp_dir), phonenumber) = storage_with_e164_recipient.await;
+        let by_e164 = storage
+            .fetch_recipient_by_phonenumber(&phonenumber)
+            .unwrap();
+        let by_pni = storage.fetch_or_insert_recipient_by_pni(PNI);
+
+        let resolution = storage
+            .resolve_recipient(
+                ids(Some(&phonenumber), None, Some(PNI)),
+                TrustLevel::Certain,
+            )
+            .unwrap();
+        assert_eq!(resolution.recipient.id, by_pni.id);
+        assert_eq!(resolution.recipient.e164.as_ref(), Some(&phonenumber));
+        assert_eq!(
+            resolution.events,
+            vec![
+                RecipientEvent::Merged {
+                    source: by_e164.id,
+                    dest: by_pni.id
+                },
+                RecipientEvent::Updated(by_pni.id),
+            ]
+        );
+        assert_eq!(storage.fetch_recipients().len(), 1);
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn trusted_three_way_merge(
+        storage_with_uuid_recipient: impl Future<Output = InMemoryDb>,
+        phonenumber: PhoneNumber,
+    ) {
+        let (storage, _temp_dir) = storage_with_uuid_recipient.await;
+        let by_aci = storage.fetch_recipient_by_uuid(UUID).unwrap();
+        let by_pni = storage.fetch_or_insert_recipient_by_pni(PNI);
+        let by_e164 = storage.fetch_or_insert_recipient_by_phonenumber(&phonenumber);
+        assert_eq!(storage.fetch_recipients().len(), 3);
+
+        let resolution = storage
+            .resolve_recipient(
+                ids(Some(&phonenumber), Some(UUID), Some(PNI)),
+                TrustLevel::Certain,
+            )
+            .unwrap();
+        let recipient = resolution.recipient;
+        assert_eq!(recipient.id, by_aci.id);
+        assert_eq!(recipient.uuid, Some(UUID));
+        assert_eq!(recipient.pni, Some(PNI));
+        assert_eq!(recipient.e164.as_ref(), Some(&phonenumber));
+        assert_eq!(
+            resolution.events,
+            vec![
+                RecipientEvent::Merged {
+                    source: by_pni.id,
+                    dest: by_aci.id
+                },
+                RecipientEvent::Merged {
+                    source: by_e164.id,
+                    dest: by_aci.id
+                },
+                RecipientEvent::Updated(by_aci.id),
+            ]
+        );
+        assert_eq!(storage.fetch_recipients().len(), 1);
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn trusted_merge_carries_identifiers(
+        storage_with_uuid_recipient: impl Future<Output = InMemoryDb>,
+        phonenumber: PhoneNumber,
+    ) {
+        let (storage, _temp_dir) = storage_with_uuid_recipient.await;
+        storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                None,
+                Some(PNI),
+                TrustLevel::Certain,
+            )
+            .unwrap();
+
+        // Only the phone number is given, the PNI comes along with the merge.
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Certain,
+            )
+            .unwrap();
+        assert_eq!(recipient.uuid, Some(UUID));
+        assert_eq!(recipient.pni, Some(PNI));
+        assert_eq!(recipient.e164.as_ref(), Some(&phonenumber));
+        assert_eq!(storage.fetch_recipients().len(), 1);
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn trusted_e164_and_pni_move_to_new_account(
+        storage: impl Future<Output = InMemoryDb>,
+        phonenumber: PhoneNumber,
+    ) {
+        let (storage, _temp_dir) = storage.await;
+        let r1 = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                Some(PNI),
+                TrustLevel::Certain,
+            )
+            .unwrap();
+
+        // The number was registered again, by someone else.
+        let resolution = storage
+            .resolve_recipient(
+                ids(Some(&phonenumber), Some(UUID2), Some(PNI)),
+                TrustLevel::Certain,
+            )
+            .unwrap();
+        let r2 = resolution.recipient;
+        assert_ne!(r1.id, r2.id);
+        assert_eq!(r2.e164.as_ref(), Some(&phonenumber));
+        assert_eq!(r2.pni, Some(PNI));
+        assert_eq!(
+            resolution.events,
+            vec![
+                RecipientEvent::Stripped(r1.id),
+                RecipientEvent::Inserted(r2.id)
+            ]
+        );
+
+        let r1 = storage.fetch_recipient_by_id(r1.id).unwrap();
+        assert_eq!(r1.uuid, Some(UUID));
+        assert_eq!(r1.e164, None);
+        assert_eq!(r1.pni, None);
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn trusted_pni_claims_e164_without_aci(
+        storage: impl Future<Output = InMemoryDb>,
+        phonenumber: PhoneNumber,
+    ) {
+        let (storage, _temp_dir) = storage.await;
+        let r1 = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Certain,
+            )
+            .unwrap();
+        let r2 = storage
+            .merge_and_fetch_recipient(None, Some(UUID2), Some(PNI), TrustLevel::Certain)
+            .unwrap();
+
+        // Contact discovery says the number now belongs to the PNI of the second account.
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                None,
+                Some(PNI),
+                TrustLevel::Certain,
+            )
+            .unwrap();
+        assert_eq!(recipient.id, r2.id);
+        assert_eq!(recipient.e164.as_ref(), Some(&phonenumber));
+        assert_eq!(storage.fetch_recipient_by_id(r1.id).unwrap().e164, None);
+        assert_eq!(storage.fetch_recipients().len(), 2);
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn number_change_adds_message(
+        storage: impl Future<Output = InMemoryDb>,
+        phonenumber: PhoneNumber,
+    ) {
+        let (storage, _temp_dir) = storage.await;
+        let new_number = other_phonenumber();
+
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                Some(PNI),
+                TrustLevel::Certain,
+            )
+            .unwrap();
+        let session = storage.fetch_or_insert_session_by_recipient_id(recipient.id);
+
+        let resolution = storage
+            .resolve_recipient(
+                ids(Some(&new_number), Some(UUID), Some(PNI2)),
+                TrustLevel::Certain,
+            )
+            .unwrap();
+        assert_eq!(resolution.recipient.id, recipient.id);
+        assert_eq!(resolution.recipient.e164.as_ref(), Some(&new_number));
+        assert_eq!(resolution.recipient.pni, Some(PNI2));
+        assert_eq!(
+            resolution.events,
+            vec![
+                RecipientEvent::NumberChanged {
+                    id: recipient.id,
+                    old: phonenumber,
+                    new: new_number,
+                },
+                RecipientEvent::Updated(recipient.id),
+            ]
+        );
+
+        let messages = storage.fetch_all_messages(session.id);
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0]
+            .text
+            .as_deref()
+            .unwrap()
+            .contains("changed their phone number"));
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn number_change_without_conversation(
+        storage: impl Future<Output = InMemoryDb>,
+        phonenumber: PhoneNumber,
+    ) {
+        let (storage, _temp_dir) = storage.await;
+
+        storage
+            .merge_and_fetch_recipient(Some(phonenumber), Some(UUID), None, TrustLevel::Certain)
+            .unwrap();
+        storage
+            .merge_and_fetch_recipient(
+                Some(other_phonenumber()),
+                Some(UUID),
+                None,
+                TrustLevel::Certain,
+            )
+            .unwrap();
+        assert!(storage.fetch_sessions().is_empty());
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn untrusted_number_change_ignored(
+        storage: impl Future<Output = InMemoryDb>,
+        phonenumber: PhoneNumber,
+    ) {
+        let (storage, _temp_dir) = storage.await;
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Certain,
+            )
+            .unwrap();
+
+        let resolution = storage
+            .resolve_recipient(
+                ids(Some(&other_phonenumber()), Some(UUID), None),
+                TrustLevel::Uncertain,
+            )
+            .unwrap();
+        assert!(resolution.events.is_empty());
+        assert_eq!(resolution.recipient.id, recipient.id);
+        assert_eq!(resolution.recipient.e164.as_ref(), Some(&phonenumber));
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn merge_moves_conversation(
+        storage_with_uuid_recipient: impl Future<Output = InMemoryDb>,
+        phonenumber: PhoneNumber,
+    ) {
+        let (mut storage, _temp_dir) = storage_with_uuid_recipient.await;
+        let by_aci = storage.fetch_recipient_by_uuid(UUID).unwrap();
+        let by_e164 = storage.fetch_or_insert_recipient_by_phonenumber(&phonenumber);
+
+        let aci_session = storage.fetch_or_insert_session_by_recipient_id(by_aci.id);
+        let e164_session = storage.fetch_or_insert_session_by_recipient_id(by_e164.id);
+        let first = storage.create_message(&new_message(e164_session.id, "First", 1));
+        storage.create_message(&new_message(aci_session.id, "Second", 2));
+        storage.save_reaction(
+            first.id,
+            by_e164.id,
+            "👍".into(),
+            Utc.timestamp_opt(3, 0).unwrap().naive_utc(),
+        );
+        assert_eq!(storage.fetch_sessions().len(), 2);
+
+        let recipient = storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Certain,
+            )
+            .unwrap();
+        assert_eq!(recipient.id, by_aci.id);
+        assert!(storage.fetch_recipient_by_id(by_e164.id).is_none());
+
+        let sessions = storage.fetch_sessions();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, aci_session.id);
+        let texts: Vec<_> = storage
+            .fetch_all_messages(aci_session.id)
+            .into_iter()
+            .map(|message| message.text.unwrap())
+            .collect();
+        assert_eq!(texts, vec!["Second", "First"]);
+
+        let reactions = storage.fetch_reactions_for_message(first.id);
+        assert_eq!(reactions.len(), 1);
+        assert_eq!(reactions[0].1.id, by_aci.id);
+    }
+
+    #[rstest]
+    #[actix_rt::test]
+    async fn merge_keeps_receipt_state(
+        storage_with_uuid_recipient: impl Future<Output = InMemoryDb>,
+        phonenumber: PhoneNumber,
+    ) {
+        let (storage, _temp_dir) = storage_with_uuid_recipient.await;
+        let by_aci = storage.fetch_recipient_by_uuid(UUID).unwrap();
+        let by_e164 = storage.fetch_or_insert_recipient_by_phonenumber(&phonenumber);
+
+        let session = storage.fetch_or_insert_session_by_recipient_id(by_aci.id);
+        let message = storage.create_message(&new_message(session.id, "Hello", 1));
+        let sent_at = Utc.timestamp_opt(2, 0).unwrap().naive_utc();
+        let failed_at = Utc.timestamp_opt(3, 0).unwrap().naive_utc();
+        storage.mark_message_sent_to(message.id, by_e164.id, sent_at);
+        storage.mark_message_failed_for(message.id, by_aci.id, failed_at);
+
+        storage
+            .merge_and_fetch_recipient(
+                Some(phonenumber.clone()),
+                Some(UUID),
+                None,
+                TrustLevel::Certain,
+            )
+            .unwrap();
+
+        let receipts = storage.fetch_message_receipts(message.id);
+        assert_eq!(receipts.len(), 1);
+        let (receipt, recipient) = &receipts[0];
+        assert_eq!(recipient.id, by_aci.id);
+        assert_eq!(receipt.sent, Some(sent_at));
+        assert_eq!(receipt.failed, Some(failed_at));
+    }
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use self::common::*;
+use libsignal_service::protocol::{
+    create_sender_key_distribution_message, group_encrypt, DeviceId, ProtocolAddress,
+    SenderKeyStore,
+};
+use libsignal_service::zkgroup::api::groups::{GroupMasterKey, GroupSecretParams};
+use rstest::rstest;
+use std::future::Future;
+use whisperfish_store::GroupV2;
+
+fn group() -> GroupV2 {
+    GroupV2 {
+        secret: GroupSecretParams::derive_from_master_key(GroupMasterKey::new([1; 32])),
+        revision: 0,
+    }
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn distribution_id_per_group(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, _temp_dir) = storage.await;
+    let session = storage.fetch_or_insert_session_by_group_v2(&group());
+    let group_id = session.unwrap_group_v2().id.clone();
+
+    assert_eq!(storage.fetch_distribution_id(&group_id), None);
+    let id = storage.fetch_or_insert_distribution_id(&group_id);
+    assert_eq!(storage.fetch_or_insert_distribution_id(&group_id), id);
+
+    assert!(storage.rotate_sender_key(&group_id));
+    assert_eq!(storage.fetch_distribution_id(&group_id), None);
+    assert_ne!(storage.fetch_or_insert_distribution_id(&group_id), id);
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn shared_with_devices(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, _temp_dir) = storage.await;
+    let session = storage.fetch_or_insert_session_by_group_v2(&group());
+    let group_id = session.unwrap_group_v2().id.clone();
+    let id = storage.fetch_or_insert_distribution_id(&group_id);
+
+    let alice = uuid::Uuid::new_v4().to_string();
+    let alice_1 = ProtocolAddress::new(alice.clone(), DeviceId::from(1));
+    let alice_2 = ProtocolAddress::new(alice.clone(), DeviceId::from(2));
+    let bob_1 = ProtocolAddress::new(uuid::Uuid::new_v4().to_string(), DeviceId::from(1));
+
+    storage.mark_sender_key_shared(id, &[alice_1.clone(), alice_2.clone(), bob_1.clone()]);
+    // Marking twice is fine.
+    storage.mark_sender_key_shared(id, &[bob_1.clone()]);
+    assert_eq!(storage.fetch_sender_key_shared(id).len(), 3);
+
+    storage.clear_sender_key_shared(&[alice_2.clone()]);
+    let shared = storage.fetch_sender_key_shared(id);
+    assert!(shared.contains(&alice_1) && !shared.contains(&alice_2));
+
+    assert_eq!(storage.clear_sender_key_shared_with(&alice), 1);
+    assert_eq!(storage.fetch_sender_key_shared(id), vec![bob_1]);
+
+    // A new key is shared with nobody.
+    storage.rotate_sender_key(&group_id);
+    let id = storage.fetch_or_insert_distribution_id(&group_id);
+    assert!(storage.fetch_sender_key_shared(id).is_empty());
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn rotation_forgets_our_key(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, _temp_dir) = storage.await;
+    let session = storage.fetch_or_insert_session_by_group_v2(&group());
+    let group_id = session.unwrap_group_v2().id.clone();
+    let id = storage.fetch_or_insert_distribution_id(&group_id);
+    let local = ProtocolAddress::new(uuid::Uuid::new_v4().to_string(), DeviceId::from(1));
+
+    let mut store = storage.clone();
+    let mut rng = rand::thread_rng();
+    create_sender_key_distribution_message(&local, id, &mut store, &mut rng, None)
+        .await
+        .unwrap();
+    // Every message advances our key, which overwrites the stored one.
+    for _ in 0..2 {
+        group_encrypt(&mut store, &local, id, b"hello", &mut rng, None)
+            .await
+            .unwrap();
+    }
+    assert!(store
+        .load_sender_key(&local, id, None)
+        .await
+        .unwrap()
+        .is_some());
+
+    storage.rotate_sender_key(&group_id);
+    assert!(store
+        .load_sender_key(&local, id, None)
+        .await
+        .unwrap()
+        .is_none());
+}
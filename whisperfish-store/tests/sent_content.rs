@@ -0,0 +1,58 @@
+mod common;
+
+use self::common::*;
+use chrono::prelude::*;
+use rstest::rstest;
+use std::future::Future;
+use uuid::Uuid;
+
+#[rstest]
+#[actix_rt::test]
+async fn resend_until_delivered(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, _temp_dir) = storage.await;
+    let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    let ts = whisperfish_store::millis_to_naive_chrono(1692000000123);
+
+    storage.log_sent_content(ts, b"hello", &[(alice, 1), (alice, 2), (bob, 1)]);
+    assert_eq!(
+        storage.fetch_sent_content(alice, 1, ts),
+        Some(b"hello".to_vec())
+    );
+    assert_eq!(
+        storage.fetch_sent_content(bob, 1, ts),
+        Some(b"hello".to_vec())
+    );
+    // Only the devices it was sent to can ask for it.
+    assert_eq!(storage.fetch_sent_content(carol, 1, ts), None);
+    assert_eq!(storage.fetch_sent_content(bob, 2, ts), None);
+    let other_ts = whisperfish_store::millis_to_naive_chrono(1692000000124);
+    assert_eq!(storage.fetch_sent_content(alice, 1, other_ts), None);
+
+    // Every device confirms delivery by itself.
+    storage.forget_sent_content(alice, 1, ts);
+    assert_eq!(storage.fetch_sent_content(alice, 1, ts), None);
+    assert_eq!(
+        storage.fetch_sent_content(alice, 2, ts),
+        Some(b"hello".to_vec())
+    );
+    assert_eq!(
+        storage.fetch_sent_content(bob, 1, ts),
+        Some(b"hello".to_vec())
+    );
+
+    // Once every device got it, the content goes.
+    storage.forget_sent_content(alice, 2, ts);
+    assert_eq!(storage.prune_sent_content(), 0);
+    storage.forget_sent_content(bob, 1, ts);
+    assert_eq!(storage.prune_sent_content(), 1);
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn nothing_to_log(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, _temp_dir) = storage.await;
+    let ts = Utc::now().naive_utc();
+
+    storage.log_sent_content(ts, b"hello", &[]);
+    assert_eq!(storage.prune_sent_content(), 0);
+}
@@ -10,7 +10,7 @@ use std::future::Future;
 use std::sync::Arc;
 use whisperfish_store::config::SignalConfig;
 use whisperfish_store::orm::UnidentifiedAccessMode;
-use whisperfish_store::{GroupV1, NewMessage, Storage};
+use whisperfish_store::{ExportFormat, GroupV1, NewAttachment, NewMessage, Storage};
 
 #[rstest]
 #[actix_rt::test]
@@ -147,6 +147,215 @@ async fn process_message_exists_session_source(storage: impl Future<Output = InM
     }
 }
 
+#[rstest]
+#[actix_rt::test]
+async fn mark_voice_note_viewed(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, temp_dir) = storage.await;
+
+    let pn1 = phonenumber::parse(None, "+358501234567").unwrap();
+    let sess1 = storage.fetch_or_insert_session_by_phonenumber(&pn1);
+
+    let path = temp_dir.join("voice.aac");
+    std::fs::write(&path, [0xFF, 0xF1, 0x60, 0x40, 0x00, 0xFF, 0xFC]).unwrap();
+
+    let msg = storage.create_message(&NewMessage {
+        session_id: sess1.id,
+        source_e164: Some(pn1),
+        source_uuid: None,
+        text: String::new(),
+        timestamp: Utc.timestamp_opt(1, 0).unwrap().naive_utc(),
+        sent: false,
+        received: true,
+        is_read: true,
+        flags: 0,
+        attachment: Some(path.to_str().unwrap().into()),
+        mime_type: Some("audio/aac".into()),
+        has_attachment: true,
+        outgoing: false,
+        is_unidentified: false,
+        quote_timestamp: None,
+        expires_in: None,
+    });
+    assert_eq!(msg.viewed_timestamp, None);
+
+    let attachment = &storage.fetch_attachments_for_message(msg.id)[0];
+    assert!(!attachment.is_voice_note);
+    storage.store_voice_note_summary(
+        attachment.id,
+        Some(std::time::Duration::from_millis(1500)),
+        Some(&[0, 128, 255]),
+    );
+    let attachment = storage.fetch_attachment(attachment.id).unwrap();
+    assert!(attachment.is_voice_note);
+    assert_eq!(attachment.audio_duration, Some(1500));
+    assert_eq!(attachment.audio_waveform, Some(vec![0, 128, 255]));
+
+    // Only the first view yields the message, such that only one receipt is sent.
+    let viewed = storage.mark_message_viewed(msg.id).unwrap();
+    assert!(viewed.viewed_timestamp.is_some());
+    assert!(storage.mark_message_viewed(msg.id).is_none());
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn erase_view_once_media(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, temp_dir) = storage.await;
+
+    let pn1 = phonenumber::parse(None, "+358501234567").unwrap();
+    let sess1 = storage.fetch_or_insert_session_by_phonenumber(&pn1);
+
+    let path = temp_dir.join("view-once.bin");
+    std::fs::write(&path, b"encrypted media").unwrap();
+
+    let msg = storage.create_message(&NewMessage {
+        session_id: sess1.id,
+        source_e164: Some(pn1),
+        source_uuid: None,
+        text: String::new(),
+        timestamp: Utc.timestamp_opt(1, 0).unwrap().naive_utc(),
+        sent: false,
+        received: true,
+        is_read: true,
+        flags: 0,
+        attachment: Some(path.to_str().unwrap().into()),
+        mime_type: Some("image/jpeg".into()),
+        has_attachment: true,
+        outgoing: false,
+        is_unidentified: false,
+        quote_timestamp: None,
+        expires_in: None,
+    });
+    assert!(!msg.is_view_once);
+
+    // Regular media is never erased.
+    assert_eq!(storage.erase_view_once_attachments(msg.id), 0);
+    assert!(path.exists());
+
+    storage.mark_message_view_once(msg.id);
+    assert!(storage.fetch_message_by_id(msg.id).unwrap().is_view_once);
+
+    assert_eq!(storage.erase_view_once_attachments(msg.id), 1);
+    assert!(!path.exists());
+    let attachments = storage.fetch_attachments_for_message(msg.id);
+    assert_eq!(attachments.len(), 1);
+    assert_eq!(attachments[0].attachment_path, None);
+
+    // Erasing twice is harmless.
+    assert_eq!(storage.erase_view_once_attachments(msg.id), 0);
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn album_attachments_keep_order(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, temp_dir) = storage.await;
+
+    let pn1 = phonenumber::parse(None, "+358501234567").unwrap();
+    let sess1 = storage.fetch_or_insert_session_by_phonenumber(&pn1);
+
+    let msg = storage.create_message(&NewMessage {
+        session_id: sess1.id,
+        source_e164: None,
+        source_uuid: None,
+        text: "Holiday pictures".into(),
+        timestamp: Utc.timestamp_opt(1, 0).unwrap().naive_utc(),
+        sent: false,
+        received: false,
+        is_read: true,
+        flags: 0,
+        attachment: None,
+        mime_type: None,
+        has_attachment: true,
+        outgoing: true,
+        is_unidentified: false,
+        quote_timestamp: None,
+        expires_in: None,
+    });
+
+    // Insert in reverse, the display order should win.
+    for &(order, name) in &[(2, "c.jpg"), (1, "b.png"), (0, "a.jpg")] {
+        let path = temp_dir.join(name);
+        std::fs::write(&path, name).unwrap();
+        storage
+            .insert_local_attachment(
+                msg.id,
+                order,
+                &NewAttachment {
+                    path: path.to_str().unwrap().into(),
+                    mime_type: "image/jpeg".into(),
+                    caption: Some(format!("Caption {}", order)),
+                    is_borderless: order == 1,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+    }
+
+    // A file that went away is refused.
+    assert!(storage
+        .insert_local_attachment(
+            msg.id,
+            3,
+            &NewAttachment {
+                path: temp_dir.join("gone.jpg").to_str().unwrap().into(),
+                mime_type: "image/jpeg".into(),
+                ..Default::default()
+            },
+        )
+        .is_err());
+
+    let attachments = storage.fetch_attachments_for_message(msg.id);
+    let names: Vec<_> = attachments
+        .iter()
+        .map(|a| a.file_name.as_deref().unwrap())
+        .collect();
+    assert_eq!(names, ["a.jpg", "b.png", "c.jpg"]);
+    assert_eq!(attachments[1].caption.as_deref(), Some("Caption 1"));
+    assert!(attachments[1].is_borderless);
+    assert!(!attachments[0].is_borderless);
+    assert_eq!(attachments[2].size, Some(5));
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn sender_certificates_expire(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, _temp_dir) = storage.await;
+
+    let tomorrow = Utc::now().naive_utc() + chrono::Duration::days(1);
+    let yesterday = Utc::now().naive_utc() - chrono::Duration::days(1);
+
+    storage.save_sender_certificate("complete", &[1, 2, 3], tomorrow);
+    storage.save_sender_certificate("uuid_only", &[4, 5, 6], yesterday);
+
+    let certs = storage.fetch_sender_certificates();
+    assert_eq!(certs.len(), 1);
+    assert_eq!(certs[0].kind, "complete");
+    assert_eq!(certs[0].certificate, vec![1, 2, 3]);
+
+    // A new certificate replaces the old one.
+    storage.save_sender_certificate("complete", &[7, 8, 9], tomorrow);
+    let certs = storage.fetch_sender_certificates();
+    assert_eq!(certs.len(), 1);
+    assert_eq!(certs[0].certificate, vec![7, 8, 9]);
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn recipient_by_username(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, _temp_dir) = storage.await;
+
+    let uuid = uuid::Uuid::new_v4();
+    let recipient = storage.fetch_or_insert_recipient_by_uuid(uuid);
+    assert!(storage.fetch_recipient_by_username("alice.42").is_none());
+
+    assert!(storage.set_recipient_username(recipient.id, Some("Alice.42")));
+    let found = storage.fetch_recipient_by_username("ALICE.42").unwrap();
+    assert_eq!(found.id, recipient.id);
+    assert_eq!(found.username.as_deref(), Some("alice.42"));
+
+    storage.set_recipient_username(recipient.id, None);
+    assert!(storage.fetch_recipient_by_username("alice.42").is_none());
+}
+
 /// This tests code that may potentially be removed after release
 /// but it's important as long as we receive messages without ACK
 #[rstest]
@@ -512,6 +721,137 @@ async fn test_create_and_open_storage(
     Ok(())
 }
 
+#[rstest(
+    old_password,
+    new_password,
+    case(Some("old password"), Some("new password")),
+    case(None, Some("new password")),
+    case(Some("old password"), None)
+)]
+#[actix_rt::test]
+async fn change_storage_password(old_password: Option<&str>, new_password: Option<&str>) {
+    env_logger::try_init().ok();
+
+    let location = whisperfish_store::temp();
+    let config = Arc::new(SignalConfig::default());
+    let storage = Storage::new(
+        config.clone(),
+        &location,
+        old_password,
+        12345,
+        12346,
+        "Some Password",
+        [0; 52],
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    let pn = phonenumber::parse(None, "+358501234567").unwrap();
+    let session = storage.fetch_or_insert_session_by_phonenumber(&pn);
+
+    if old_password.is_some() {
+        assert!(storage
+            .change_password(Some("wrong password"), new_password)
+            .await
+            .is_err());
+        assert!(storage.change_password(None, new_password).await.is_err());
+    }
+
+    // A clone shares the keys
+    let clone = storage.clone();
+    storage
+        .change_password(old_password, new_password)
+        .await
+        .unwrap();
+    assert_eq!(clone.is_encrypted(), new_password.is_some());
+    assert_eq!(clone.signal_password().await.unwrap(), "Some Password");
+    assert!(clone.fetch_session_by_id(session.id).is_some());
+    drop(clone);
+    drop(storage);
+
+    if old_password.is_some() {
+        assert!(
+            Storage::open(config.clone(), &location, old_password.map(String::from))
+                .await
+                .is_err(),
+            "Storage still opens with the old password"
+        );
+    }
+
+    let storage = Storage::open(config, &location, new_password.map(String::from))
+        .await
+        .unwrap();
+    assert_eq!(storage.signal_password().await.unwrap(), "Some Password");
+    assert_eq!(storage.signaling_key().await.unwrap(), [0; 52]);
+    assert!(storage.fetch_session_by_id(session.id).is_some());
+}
+
+#[actix_rt::test]
+async fn interrupted_password_change() {
+    env_logger::try_init().ok();
+
+    let location = whisperfish_store::temp();
+    let config = Arc::new(SignalConfig::default());
+    let storage = Storage::new(
+        config.clone(),
+        &location,
+        Some("old password"),
+        12345,
+        12346,
+        "Some Password",
+        [0; 52],
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+
+    let identity_dir = location.join("storage").join("identity");
+    let mut files = vec![
+        location.join("storage").join("salt"),
+        location.join("db").join("salt"),
+    ];
+    for entry in std::fs::read_dir(&identity_dir).unwrap() {
+        files.push(entry.unwrap().path());
+    }
+    let rekeyed = |path: &std::path::Path| {
+        let mut name = path.file_name().unwrap().to_owned();
+        name.push(".rekey");
+        path.with_file_name(name)
+    };
+
+    // Interrupted before the database got its new key: the staged files are dropped.
+    std::fs::write(rekeyed(&files[1]), &[1u8; 8]).unwrap();
+    std::fs::write(identity_dir.join("http_password.rekey"), b"garbage").unwrap();
+    drop(storage);
+    let storage = Storage::open(config.clone(), &location, Some("old password".into()))
+        .await
+        .unwrap();
+    assert_eq!(storage.signal_password().await.unwrap(), "Some Password");
+    assert!(!rekeyed(&files[1]).exists());
+    assert!(!identity_dir.join("http_password.rekey").exists());
+
+    // Interrupted after the database got its new key: the staged files are moved in place.
+    let old_contents: Vec<_> = files.iter().map(|f| std::fs::read(f).unwrap()).collect();
+    storage
+        .change_password(Some("old password"), Some("new password"))
+        .await
+        .unwrap();
+    drop(storage);
+    for (file, contents) in files.iter().zip(old_contents) {
+        std::fs::rename(file, rekeyed(file)).unwrap();
+        std::fs::write(file, contents).unwrap();
+    }
+
+    let storage = Storage::open(config, &location, Some("new password".into()))
+        .await
+        .unwrap();
+    assert_eq!(storage.signal_password().await.unwrap(), "Some Password");
+    assert_eq!(storage.signaling_key().await.unwrap(), [0; 52]);
+    assert!(files.iter().all(|file| !rekeyed(file).exists()));
+}
+
 #[actix_rt::test]
 async fn test_recipient_actions() {
     use rand::distributions::Alphanumeric;
@@ -664,8 +1004,6 @@ async fn test_recipient_actions() {
     let msg = storage.fetch_message_by_id(msg.id).unwrap();
     assert!(msg.sending_has_failed);
 
-    assert_eq!(storage.mark_pending_messages_failed(), 0);
-
     assert!(storage.fetch_group_sessions().is_empty());
     assert!(storage
         .fetch_session_by_id_augmented(session.id + 1)
@@ -883,7 +1221,7 @@ fn test_remove_attachment_filenames() {
     let regex = SignalConfig::default().attachments_regex();
 
     // List of known good and bad locations, feel free to add samples.
-    let test_data: [(bool, &str); 21]= [
+    let test_data: [(bool, &str); 23]= [
         // defaultuser, new
         (true, "/home/defaultuser/.local/share/be.rubdos/harbour-whisperfish/storage/attachments/5da77b73f271bd460956d3807643f6b8.png"),
         (true, "/home/defaultuser/.local/share/be.rubdos/harbour-whisperfish/storage/attachments/Photo_20220417_233207.jpg"),
@@ -897,6 +1235,9 @@ fn test_remove_attachment_filenames() {
         // nemo, old
         (true, "/home/nemo/.local/share/harbour-whisperfish/storage/attachments/bd09cdd805f5aa07aa3ee950a9b1fef9.pdf"),
         (true, "/home/nemo/.local/share/harbour-whisperfish/storage/camera/Photo_20221108_202942.jpg"),
+        // additional account
+        (true, "/home/defaultuser/.local/share/be.rubdos/harbour-whisperfish/accounts/work/storage/attachments/0f1a2ab0e4a4c5d1d1b2c1c7f4b0e6a1.jpg"),
+        (true, "/home/defaultuser/.local/share/be.rubdos/harbour-whisperfish/accounts/work/storage/camera/Photo_20231012_101010.jpg"),
         // Android storage
         (false, "/home/defaultuser/android_storage/Download/cat-meme.jpg"),
         // Downloads
@@ -927,3 +1268,417 @@ fn test_remove_attachment_filenames() {
         );
     });
 }
+
+#[rstest]
+#[actix_rt::test]
+async fn collect_orphaned_files(storage: impl Future<Output = InMemoryDb>) {
+    use std::time::Duration;
+    use whisperfish_store::{OrphanAction, TrustLevel};
+
+    let (storage, temp_dir) = storage.await;
+    let attachment_dir = temp_dir.join("attachments");
+    let avatar_dir = temp_dir.join("avatars");
+    std::fs::create_dir_all(&attachment_dir).unwrap();
+    std::fs::create_dir_all(&avatar_dir).unwrap();
+
+    let pn1 = phonenumber::parse(None, "+358501234567").unwrap();
+    let sess1 = storage.fetch_or_insert_session_by_phonenumber(&pn1);
+    let uuid = uuid::Uuid::new_v4();
+    storage
+        .merge_and_fetch_recipient(None, Some(uuid), None, TrustLevel::Certain)
+        .unwrap();
+
+    let referenced = attachment_dir.join("referenced.jpg");
+    std::fs::write(&referenced, b"picture").unwrap();
+    let scaled = attachment_dir.join("scaled.jpg");
+    std::fs::write(&scaled, b"pic").unwrap();
+    let orphan = attachment_dir.join("orphan.jpg");
+    std::fs::write(&orphan, b"lost").unwrap();
+    let avatar = avatar_dir.join(uuid.to_string());
+    std::fs::write(&avatar, b"avatar").unwrap();
+    let orphan_avatar = avatar_dir.join(uuid::Uuid::new_v4().to_string());
+    std::fs::write(&orphan_avatar, b"gone").unwrap();
+    let drafted = attachment_dir.join("drafted.jpg");
+    std::fs::write(&drafted, b"draft").unwrap();
+    let queued = attachment_dir.join("queued.jpg");
+    std::fs::write(&queued, b"queue").unwrap();
+
+    let msg = storage.create_message(&NewMessage {
+        session_id: sess1.id,
+        source_e164: Some(pn1),
+        source_uuid: None,
+        text: String::new(),
+        timestamp: Utc.timestamp_opt(1, 0).unwrap().naive_utc(),
+        sent: false,
+        received: true,
+        is_read: true,
+        flags: 0,
+        attachment: Some(referenced.to_str().unwrap().into()),
+        mime_type: Some("image/jpeg".into()),
+        has_attachment: true,
+        outgoing: false,
+        is_unidentified: false,
+        quote_timestamp: None,
+        expires_in: None,
+    });
+
+    let attachment = &storage.fetch_attachments_for_message(msg.id)[0];
+    let properties = format!(
+        r#"{{"skipTransform":false,"transformedPath":"{}"}}"#,
+        scaled.display()
+    );
+    storage.store_attachment_transform(attachment.id, &properties);
+    // The attachment keeps describing the original file.
+    let transformed = storage.fetch_attachment(attachment.id).unwrap();
+    assert_eq!(
+        transformed.transform_properties.as_deref(),
+        Some(properties.as_str())
+    );
+    assert_eq!(
+        (transformed.width, transformed.height),
+        (attachment.width, attachment.height)
+    );
+
+    let usage = storage.fetch_session_disk_usage();
+    assert_eq!(usage.len(), 1);
+    assert_eq!(usage[0].session_id, sess1.id);
+    assert_eq!(usage[0].attachment_count, 1);
+    assert_eq!(usage[0].bytes, 7);
+
+    // Files of messages that are about to be sent, or still being written, stay.
+    let queued_msg = storage.create_message(&NewMessage {
+        session_id: sess1.id,
+        source_e164: None,
+        source_uuid: None,
+        text: String::new(),
+        timestamp: Utc.timestamp_opt(2, 0).unwrap().naive_utc(),
+        sent: false,
+        received: false,
+        is_read: true,
+        flags: 0,
+        attachment: Some(queued.to_str().unwrap().into()),
+        mime_type: Some("image/jpeg".into()),
+        has_attachment: true,
+        outgoing: true,
+        is_unidentified: false,
+        quote_timestamp: None,
+        expires_in: None,
+    });
+    storage.enqueue_outgoing(queued_msg.id);
+    storage.save_draft(sess1.id, format!("Look at {}", drafted.display()));
+
+    let dirs = [attachment_dir.clone()];
+
+    // Fresh files are left alone.
+    let report = storage
+        .collect_garbage(
+            &dirs,
+            Some(&avatar_dir),
+            &OrphanAction::Report,
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+    assert!(report.orphans.is_empty());
+
+    let report = storage
+        .collect_garbage(
+            &dirs,
+            Some(&avatar_dir),
+            &OrphanAction::Report,
+            Duration::from_secs(0),
+        )
+        .unwrap();
+    let mut orphans = report.orphans.clone();
+    orphans.sort();
+    let mut expected = vec![orphan.clone(), orphan_avatar.clone()];
+    expected.sort();
+    assert_eq!(orphans, expected);
+    assert_eq!(report.orphan_bytes, 8);
+    assert!(report.missing_attachments.is_empty());
+    assert!(orphan.exists());
+
+    let quarantine = temp_dir.join("quarantine");
+    storage
+        .collect_garbage(
+            &dirs,
+            Some(&avatar_dir),
+            &OrphanAction::Quarantine(quarantine.clone()),
+            Duration::from_secs(0),
+        )
+        .unwrap();
+    assert!(!orphan.exists());
+    assert!(quarantine.join("orphan.jpg").exists());
+    assert!(referenced.exists());
+    assert!(scaled.exists());
+    assert!(avatar.exists());
+    assert!(queued.exists());
+    assert!(drafted.exists());
+
+    std::fs::remove_file(&referenced).unwrap();
+    let report = storage
+        .collect_garbage(&dirs, None, &OrphanAction::Delete, Duration::from_secs(0))
+        .unwrap();
+    assert!(report.orphans.is_empty());
+    assert_eq!(report.missing_attachments, vec![attachment.id]);
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn backup_and_restore(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, temp_dir) = storage.await;
+    let attachment_dir = temp_dir.join("attachments");
+    let avatar_dir = temp_dir.join("avatars");
+    std::fs::create_dir_all(&attachment_dir).unwrap();
+    std::fs::create_dir_all(&avatar_dir).unwrap();
+
+    let pn1 = phonenumber::parse(None, "+358501234567").unwrap();
+    let sess1 = storage.fetch_or_insert_session_by_phonenumber(&pn1);
+    let picture = attachment_dir.join("picture.jpg");
+    // Larger than a chunk of the backup.
+    let picture_contents = vec![7u8; 3 * 1024 * 1024 / 2];
+    std::fs::write(&picture, &picture_contents).unwrap();
+    std::fs::write(avatar_dir.join("avatar"), b"avatar").unwrap();
+
+    storage.create_message(&NewMessage {
+        session_id: sess1.id,
+        source_e164: Some(pn1),
+        source_uuid: None,
+        text: "Moving phones".into(),
+        timestamp: Utc.timestamp_opt(1, 0).unwrap().naive_utc(),
+        sent: false,
+        received: true,
+        is_read: true,
+        flags: 0,
+        attachment: Some(picture.to_str().unwrap().into()),
+        mime_type: Some("image/jpeg".into()),
+        has_attachment: true,
+        outgoing: false,
+        is_unidentified: false,
+        quote_timestamp: None,
+        expires_in: None,
+    });
+
+    let backup = temp_dir.join("account.wfbackup");
+    assert!(storage.export_backup(&backup, "horse", None).is_err());
+    let summary = storage
+        .export_backup(&backup, "correct horse", Some(&avatar_dir))
+        .unwrap();
+    assert!(summary.bytes > 0);
+    assert!(!temp_dir.join("account.part").exists());
+
+    assert!(Storage::check_backup_passphrase(&backup, "correct horse")
+        .await
+        .unwrap());
+    assert!(!Storage::check_backup_passphrase(&backup, "battery staple")
+        .await
+        .unwrap());
+
+    let restored_dir = whisperfish_store::temp();
+    let config = Arc::new(SignalConfig::default());
+    assert!(Storage::restore_backup(
+        config.clone(),
+        &restored_dir,
+        &backup,
+        "battery staple",
+        None
+    )
+    .await
+    .is_err());
+    // A wrong passphrase leaves the storage alone.
+    assert!(!restored_dir.join("storage").exists());
+
+    let restored = Storage::restore_backup(
+        config.clone(),
+        &restored_dir,
+        &backup,
+        "correct horse",
+        None,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(restored.signal_password().await.unwrap(), "Some Password");
+    assert_eq!(restored.signaling_key().await.unwrap(), [0; 52]);
+
+    let session = restored.fetch_session_by_id(sess1.id).unwrap();
+    let message = restored
+        .fetch_last_message_by_session_id(session.id)
+        .unwrap();
+    assert_eq!(message.text.as_deref(), Some("Moving phones"));
+
+    let attachment = &restored.fetch_attachments_for_message(message.id)[0];
+    let restored_picture = restored_dir
+        .join("storage")
+        .join("attachments")
+        .join("picture.jpg");
+    assert_eq!(
+        attachment.attachment_path.as_deref(),
+        restored_picture.to_str()
+    );
+    assert_eq!(std::fs::read(&restored_picture).unwrap(), picture_contents);
+    assert!(restored_dir
+        .join("storage")
+        .join("avatars")
+        .join("avatar")
+        .exists());
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn restore_corrupt_backup(storage: impl Future<Output = InMemoryDb>) {
+    let (storage, temp_dir) = storage.await;
+    let backup = temp_dir.join("account.wfbackup");
+    storage
+        .export_backup(&backup, "correct horse", None)
+        .unwrap();
+
+    // Claim a frame of 4 GiB right after the header; this must not be allocated.
+    let original = std::fs::read(&backup).unwrap();
+    let mut contents = original.clone();
+    contents[8 + 1 + 16..8 + 1 + 16 + 4].copy_from_slice(&[0xff; 4]);
+    std::fs::write(&backup, &contents).unwrap();
+
+    assert!(Storage::check_backup_passphrase(&backup, "correct horse")
+        .await
+        .is_err());
+    let restored_dir = whisperfish_store::temp();
+    assert!(Storage::restore_backup(
+        Arc::new(SignalConfig::default()),
+        &restored_dir,
+        &backup,
+        "correct horse",
+        None
+    )
+    .await
+    .is_err());
+
+    // A cut off backup is refused too.
+    std::fs::write(&backup, &original[..original.len() / 2]).unwrap();
+    assert!(Storage::restore_backup(
+        Arc::new(SignalConfig::default()),
+        &restored_dir,
+        &backup,
+        "correct horse",
+        None
+    )
+    .await
+    .is_err());
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn export_session(storage: impl Future<Output = InMemoryDb>) {
+    let (mut storage, temp_dir) = storage.await;
+    let attachment_dir = temp_dir.join("attachments");
+    std::fs::create_dir_all(&attachment_dir).unwrap();
+
+    let pn1 = phonenumber::parse(None, "+358501234567").unwrap();
+    let sess1 = storage.fetch_or_insert_session_by_phonenumber(&pn1);
+    let picture = attachment_dir.join("picture.jpg");
+    std::fs::write(&picture, b"picture").unwrap();
+
+    for (second, text, attachment) in vec![(1, "<b>First</b>", Some(&picture)), (2, "Second", None)]
+    {
+        storage.create_message(&NewMessage {
+            session_id: sess1.id,
+            source_e164: Some(pn1.clone()),
+            source_uuid: None,
+            text: text.into(),
+            timestamp: Utc.timestamp_opt(second, 0).unwrap().naive_utc(),
+            sent: false,
+            received: true,
+            is_read: true,
+            flags: 0,
+            attachment: attachment.map(|p| p.to_str().unwrap().into()),
+            mime_type: attachment.map(|_| "image/jpeg".into()),
+            has_attachment: attachment.is_some(),
+            outgoing: false,
+            is_unidentified: false,
+            quote_timestamp: None,
+            expires_in: None,
+        });
+    }
+    let first = storage
+        .fetch_message_by_timestamp(Utc.timestamp_opt(1, 0).unwrap().naive_utc())
+        .unwrap();
+    storage.save_reaction(
+        first.id,
+        sess1.unwrap_dm().id,
+        "👍".into(),
+        Utc.timestamp_opt(3, 0).unwrap().naive_utc(),
+    );
+
+    let export_dir = temp_dir.join("export");
+    let summary = storage
+        .export_session(sess1.id, ExportFormat::Json, &export_dir)
+        .unwrap();
+    assert_eq!(summary.messages, 2);
+    assert_eq!(summary.attachments, 1);
+    let json: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&summary.path).unwrap()).unwrap();
+    let messages = json["messages"].as_array().unwrap();
+    assert_eq!(messages[0]["text"], "<b>First</b>");
+    assert_eq!(messages[0]["reactions"][0]["emoji"], "👍");
+    assert_eq!(messages[1]["text"], "Second");
+    let copied = export_dir.join(messages[0]["attachments"][0]["file"].as_str().unwrap());
+    assert_eq!(std::fs::read(copied).unwrap(), b"picture");
+
+    let summary = storage
+        .export_session(sess1.id, ExportFormat::Html, &export_dir)
+        .unwrap();
+    let html = std::fs::read_to_string(&summary.path).unwrap();
+    assert!(html.contains("&lt;b&gt;First&lt;/b&gt;"));
+    assert!(html.find("First").unwrap() < html.find("Second").unwrap());
+
+    assert!(storage
+        .export_session(sess1.id + 1, ExportFormat::Text, &export_dir)
+        .is_err());
+}
+
+#[rstest]
+#[actix_rt::test]
+async fn unread_count(storage: impl Future<Output = InMemoryDb>) {
+    let (mut storage, _temp_dir) = storage.await;
+
+    let pn1 = phonenumber::parse(None, "+358501234567").unwrap();
+    let sess1 = storage.fetch_or_insert_session_by_phonenumber(&pn1);
+    assert_eq!(storage.unread_count(sess1.id), 0);
+
+    for (second, is_read) in vec![(1, true), (2, false), (3, false)] {
+        storage.create_message(&NewMessage {
+            session_id: sess1.id,
+            source_e164: Some(pn1.clone()),
+            source_uuid: None,
+            text: "Hello".into(),
+            timestamp: Utc.timestamp_opt(second, 0).unwrap().naive_utc(),
+            sent: false,
+            received: true,
+            is_read,
+            flags: 0,
+            attachment: None,
+            mime_type: None,
+            has_attachment: false,
+            outgoing: false,
+            is_unidentified: false,
+            quote_timestamp: None,
+            expires_in: None,
+        });
+    }
+    assert_eq!(storage.unread_count(sess1.id), 2);
+    assert_eq!(storage.unread_count(sess1.id + 1), 0);
+
+    storage.mark_session_read(sess1.id);
+    assert_eq!(storage.unread_count(sess1.id), 0);
+}
+
+#[test]
+fn account_locations() {
+    let default = whisperfish_store::default_location().unwrap();
+    let work = whisperfish_store::account_location(Some("work_2")).unwrap();
+    assert_eq!(work.to_path_buf(), default.join("accounts").join("work_2"));
+
+    for invalid in &["", "../default", "Work", "work account", "wörk"] {
+        assert!(!whisperfish_store::is_valid_account_name(invalid));
+        assert!(whisperfish_store::account_location(Some(invalid)).is_err());
+    }
+}
@@ -1,6 +1,9 @@
 #![allow(non_snake_case)]
 
-use crate::worker::{DeleteMessageForAll, ExportAttachment, QueueMessage};
+use crate::worker::{
+    DeleteMessageForAll, ExportAttachment, MarkMessageViewed, OpenViewOnce, QueueAttachment,
+    QueueMessage,
+};
 
 use super::*;
 use futures::prelude::*;
@@ -15,6 +18,9 @@ pub struct MessageMethods {
     createMessage: qt_method!(
         fn(&self, session_id: i32, message: QString, attachment: QString, quote: i32, add: bool)
     ),
+    createMultiMessage:
+        qt_method!(fn(&self, session_id: i32, message: QString, attachments: QString, quote: i32)),
+    createVoiceNote: qt_method!(fn(&self, session_id: i32, attachment: QString, quote: i32)),
 
     sendMessage: qt_method!(fn(&self, mid: i32)),
     sendReaction:
@@ -25,6 +31,8 @@ pub struct MessageMethods {
     removeForAll: qt_method!(fn(&self, id: i32)),
 
     exportAttachment: qt_method!(fn(&self, attachment_id: i32)),
+    markViewed: qt_method!(fn(&self, id: i32)),
+    openViewOnce: qt_method!(fn(&self, id: i32)),
 }
 
 impl MessageMethods {
@@ -39,6 +47,11 @@ impl MessageMethods {
     ) {
         let message = message.to_string();
         let attachment = attachment.to_string();
+        let attachments = if attachment.is_empty() {
+            Vec::new()
+        } else {
+            vec![QueueAttachment::new(attachment)]
+        };
 
         actix::spawn(
             self.client_actor
@@ -47,21 +60,79 @@ impl MessageMethods {
                 .send(QueueMessage {
                     session_id,
                     message,
-                    attachment,
+                    attachments,
                     quote,
                 })
-                .map(Result::unwrap),
+                .map(log_queue_result),
+        );
+    }
+
+    /// Queue a message with an album of attachments.
+    ///
+    /// `attachments` is a JSON array of objects with a `data` (or `path`) field, and optionally
+    /// `caption`, `voiceNote` and `borderless`.  The array order is the display order.
+    #[with_executor]
+    fn createMultiMessage(
+        &mut self,
+        session_id: i32,
+        message: QString,
+        attachments: QString,
+        quote: i32,
+    ) {
+        let message = message.to_string();
+        let attachments = attachments.to_string();
+        let attachments: Vec<QueueAttachment> = match serde_json::from_str(&attachments) {
+            Ok(attachments) => attachments,
+            Err(e) => {
+                log::error!("Could not parse attachments to queue: {}", e);
+                return;
+            }
+        };
+
+        actix::spawn(
+            self.client_actor
+                .as_ref()
+                .unwrap()
+                .send(QueueMessage {
+                    session_id,
+                    message,
+                    attachments,
+                    quote,
+                })
+                .map(log_queue_result),
+        );
+    }
+
+    /// Queue a recorded Opus or AAC file as voice note.
+    #[with_executor]
+    fn createVoiceNote(&mut self, session_id: i32, attachment: QString, quote: i32) {
+        let attachment = attachment.to_string();
+
+        actix::spawn(
+            self.client_actor
+                .as_ref()
+                .unwrap()
+                .send(QueueMessage {
+                    session_id,
+                    message: String::new(),
+                    attachments: vec![QueueAttachment {
+                        is_voice_note: true,
+                        ..QueueAttachment::new(attachment)
+                    }],
+                    quote,
+                })
+                .map(log_queue_result),
         );
     }
 
-    /// Called when a message should be queued to be sent to OWS
+    /// Called when a failed or waiting message should be sent again right away
     #[with_executor]
     fn sendMessage(&mut self, mid: i32) {
         actix::spawn(
             self.client_actor
                 .as_mut()
                 .unwrap()
-                .send(crate::worker::SendMessage(mid))
+                .send(crate::worker::RetryMessage(mid))
                 .map(Result::unwrap),
         );
     }
@@ -135,4 +206,39 @@ impl MessageMethods {
 
         log::trace!("Dispatched ExportAttachment({})", attachment_id);
     }
+
+    /// Mark a message as viewed, e.g. after playing a voice note.
+    #[with_executor]
+    pub fn markViewed(&self, id: i32) {
+        actix::spawn(
+            self.client_actor
+                .as_ref()
+                .unwrap()
+                .send(MarkMessageViewed(id))
+                .map(Result::unwrap),
+        );
+
+        log::trace!("Dispatched MarkMessageViewed({})", id);
+    }
+
+    /// Open the media of a view-once message. This works exactly once.
+    #[with_executor]
+    pub fn openViewOnce(&self, id: i32) {
+        actix::spawn(
+            self.client_actor
+                .as_ref()
+                .unwrap()
+                .send(OpenViewOnce(id))
+                .map(Result::unwrap),
+        );
+
+        log::trace!("Dispatched OpenViewOnce({})", id);
+    }
+}
+
+/// Logs why a message could not be queued.
+fn log_queue_result(result: Result<Result<i32, anyhow::Error>, MailboxError>) {
+    if let Err(e) = result.unwrap() {
+        log::error!("Could not queue message: {:#}", e);
+    }
 }
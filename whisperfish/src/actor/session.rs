@@ -7,7 +7,7 @@ use methods::*;
 
 use crate::gui::StorageReady;
 use crate::platform::QmlApp;
-use crate::store::{orm, Storage};
+use crate::store::{orm, ExportFormat, Storage};
 use actix::prelude::*;
 use libsignal_protocol::{DeviceId, ProtocolAddress};
 use qmetaobject::prelude::*;
@@ -80,6 +80,13 @@ pub struct SaveDraft {
     pub draft: String,
 }
 
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+pub struct ExportSession {
+    pub sid: i32,
+    pub format: ExportFormat,
+}
+
 pub struct SessionActor {
     inner: QObjectBox<SessionMethods>,
     storage: Option<Storage>,
@@ -89,8 +96,14 @@ pub struct SessionActor {
 
 impl SessionActor {
     pub fn new(app: &mut QmlApp) -> Self {
+        let actor = Self::headless();
+        app.set_object_property("SessionModel".into(), actor.inner.pinned());
+        actor
+    }
+
+    /// Creates a `SessionActor` that is not exposed to QML, for use by command line tools.
+    pub fn headless() -> Self {
         let inner = QObjectBox::new(SessionMethods::default());
-        app.set_object_property("SessionModel".into(), inner.pinned());
 
         Self {
             inner,
@@ -198,6 +211,44 @@ impl Handler<SaveDraft> for SessionActor {
     }
 }
 
+impl Handler<ExportSession> for SessionActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(
+        &mut self,
+        ExportSession { sid, format }: ExportSession,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let storage = self.storage.clone().unwrap();
+
+        Box::pin(
+            async move {
+                use anyhow::Context;
+                let target_dir = dirs::document_dir()
+                    .or_else(dirs::download_dir)
+                    .context("No documents or downloads directory to export to")?
+                    .join("Whisperfish");
+                tokio::task::spawn_blocking(move || {
+                    storage.export_session(sid, format, &target_dir)
+                })
+                .await?
+            }
+            .into_actor(self)
+            .map(move |result, act, _ctx| match result {
+                Ok(summary) => act
+                    .inner
+                    .pinned()
+                    .borrow()
+                    .sessionExported(sid, summary.path.to_string_lossy().into_owned().into()),
+                Err(e) => {
+                    log::error!("Exporting session {} failed: {:?}", sid, e);
+                    act.inner.pinned().borrow().sessionExportFailed(sid);
+                }
+            }),
+        )
+    }
+}
+
 impl Handler<RemoveIdentities> for SessionActor {
     type Result = ();
 
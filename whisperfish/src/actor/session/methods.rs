@@ -21,6 +21,10 @@ pub struct SessionMethods {
     removeIdentities: qt_method!(fn(&self, recipients_id: i32)),
 
     saveDraft: qt_method!(fn(&self, sid: i32, draft: String)),
+
+    exportSession: qt_method!(fn(&self, sid: i32, format: String)),
+    sessionExported: qt_signal!(sid: i32, path: QString),
+    sessionExportFailed: qt_signal!(sid: i32),
 }
 
 impl SessionMethods {
@@ -107,4 +111,25 @@ impl SessionMethods {
         );
         log::trace!("Dispatched SafeDraft for {}", sid);
     }
+
+    /// Exports the conversation as `html`, `json` or `text`.
+    #[with_executor]
+    fn exportSession(&self, sid: i32, format: String) {
+        let format = match format.parse::<ExportFormat>() {
+            Ok(format) => format,
+            Err(e) => {
+                log::error!("{}", e);
+                self.sessionExportFailed(sid);
+                return;
+            }
+        };
+        actix::spawn(
+            self.actor
+                .as_ref()
+                .unwrap()
+                .send(ExportSession { sid, format })
+                .map(Result::unwrap),
+        );
+        log::trace!("Dispatched ExportSession({}, {:?})", sid, format);
+    }
 }
@@ -105,7 +105,7 @@ impl Handler<UpdateTypingNotifications> for SessionActor {
                     Some(typing.sender.uuid),
                     None,
                     crate::store::TrustLevel::Certain,
-                );
+                )?;
                 let group_id = typing.inner.group_id.as_ref().map(hex::encode);
                 let session = match &group_id {
                     // Group V1
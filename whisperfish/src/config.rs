@@ -1,4 +1,4 @@
 mod settings;
 
 pub use self::settings::SettingsBridge;
-pub use whisperfish_store::config::SignalConfig;
+pub use whisperfish_store::config::{config_dir, SignalConfig};
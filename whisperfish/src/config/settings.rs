@@ -15,6 +15,8 @@ pub struct SettingsBridge {
     avatarExists: qt_method!(fn(&self, key: String) -> bool),
 
     inner: *mut QSettings,
+    /// The account these settings belong to, `None` for the default account.
+    account: Option<String>,
 
     debug_mode: qt_property!(bool; READ get_debug_mode WRITE set_debug_mode NOTIFY debug_mode_changed),
     enable_typing_indicators: qt_property!(bool; READ get_enable_typing_indicators WRITE set_enable_typing_indicators NOTIFY enable_typing_indicators_changed),
@@ -62,21 +64,27 @@ pub struct SettingsBridge {
 
 impl Default for SettingsBridge {
     fn default() -> Self {
+        Self::for_account(None)
+    }
+}
+
+impl SettingsBridge {
+    /// The settings of an account, `None` being the default account.
+    pub fn for_account(account: Option<&str>) -> Self {
         Self {
             base: Default::default(),
 
             avatarExists: Default::default(),
 
             inner: QSettings::from_path(
-                dirs::config_dir()
-                    .context("Could not get xdg config directory path")
+                crate::config::config_dir(account)
+                    .context("Could not get config directory path")
                     .unwrap()
-                    .join("be.rubdos")
-                    .join("harbour-whisperfish")
                     .join("harbour-whisperfish.conf")
                     .to_str()
                     .unwrap(),
             ),
+            account: account.map(Into::into),
 
             debug_mode: false,
             enable_typing_indicators: false,
@@ -375,27 +383,18 @@ impl SettingsBridge {
         self.set_bool_if_unset("attachment_log", false);
         self.set_bool_if_unset("quit_on_ui_close", true);
         self.set_string_if_unset("country_code", "");
-        self.set_string_if_unset(
-            "avatar_dir",
-            // XXX this has to be adapted to current config struct
-            &crate::config::SignalConfig::default()
-                .get_avatar_dir()
-                .to_string_lossy(),
-        );
+        let config = self.signal_config();
+        self.set_string_if_unset("avatar_dir", &config.get_avatar_dir().to_string_lossy());
         self.set_string_if_unset(
             "attachment_dir",
-            // XXX this has to be adapted to current config struct
-            &crate::config::SignalConfig::default()
-                .default_attachment_dir()
-                .to_string_lossy(),
-        );
-        self.set_string_if_unset(
-            "camera_dir",
-            // XXX this has to be adapted to current config struct
-            &crate::config::SignalConfig::default()
-                .default_camera_dir()
-                .to_string_lossy(),
+            &config.default_attachment_dir().to_string_lossy(),
         );
+        self.set_string_if_unset("camera_dir", &config.default_camera_dir().to_string_lossy());
+    }
+
+    /// The default config of the account, which knows where its files belong.
+    fn signal_config(&self) -> crate::config::SignalConfig {
+        crate::config::SignalConfig::for_account(self.account.clone()).expect("valid account name")
     }
 
     pub fn get_string(&self, key: impl AsRef<str>) -> String {
@@ -407,7 +406,7 @@ impl SettingsBridge {
     }
 
     pub fn avatar_exists(&self, uuid: impl AsRef<str>) -> bool {
-        crate::config::SignalConfig::default()
+        self.signal_config()
             .get_avatar_dir()
             .join(uuid.as_ref())
             .exists()
@@ -1,9 +1,10 @@
 use crate::platform::{is_harbour, MayExit, QmlApp};
 use crate::store::Storage;
-use crate::{actor, config::SettingsBridge, model, worker};
+use crate::{actor, config::SettingsBridge, jsonrpc, model, worker};
 use actix::prelude::*;
 use qmeta_async::with_executor;
 use qmetaobject::prelude::*;
+use qmetaobject::QVariantList;
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -36,6 +37,11 @@ pub struct AppState {
     recipientCount: qt_method!(fn(&self) -> i32),
     unsentCount: qt_method!(fn(&self) -> i32),
 
+    account: Option<String>,
+    accountName: qt_method!(fn(&self) -> QString),
+    accounts: qt_method!(fn(&self) -> QVariantList),
+    switchAccount: qt_method!(fn(&self, account: QString) -> bool),
+
     pub storage: RefCell<Option<Storage>>,
 }
 
@@ -115,8 +121,75 @@ impl AppState {
         self.storage.borrow().as_ref().unwrap().unsent_count()
     }
 
+    /// The account of this instance, or an empty string for the default account.
+    #[allow(non_snake_case)]
+    #[with_executor]
+    fn accountName(&self) -> QString {
+        self.account.as_deref().unwrap_or_default().into()
+    }
+
+    /// All accounts on this device, the default account being the empty string.
+    #[with_executor]
+    fn accounts(&self) -> QVariantList {
+        let mut accounts = QVariantList::default();
+        accounts.push(QVariant::from(QString::default()));
+        match crate::store::list_accounts() {
+            Ok(names) => {
+                for name in names {
+                    accounts.push(QVariant::from(QString::from(name)));
+                }
+            }
+            Err(e) => log::error!("Could not list accounts: {}", e),
+        }
+        accounts
+    }
+
+    /// Starts Whisperfish for another account, or brings it to the front when it already runs.
+    ///
+    /// Accounts run in separate processes, so this one keeps running.  An account that does not
+    /// exist yet is created, and will be registered or linked like a fresh install.
+    #[allow(non_snake_case)]
     #[with_executor]
-    fn new() -> Self {
+    fn switchAccount(&self, account: QString) -> bool {
+        let account = account.to_string();
+        let account = account.trim();
+        if account == self.account.as_deref().unwrap_or_default() {
+            return true;
+        }
+
+        let mut command = match std::env::current_exe() {
+            Ok(exe) => std::process::Command::new(exe),
+            Err(e) => {
+                log::error!("Could not find the Whisperfish executable: {}", e);
+                return false;
+            }
+        };
+        if !account.is_empty() {
+            if !crate::store::is_valid_account_name(account) {
+                log::warn!("Invalid account name {:?}", account);
+                return false;
+            }
+            command.args(&["--account", account]);
+        }
+
+        match command.spawn() {
+            Ok(_child) => {
+                log::info!("Started Whisperfish for account {:?}", account);
+                true
+            }
+            Err(e) => {
+                log::error!(
+                    "Could not start Whisperfish for account {:?}: {}",
+                    account,
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    #[with_executor]
+    fn new(account: Option<String>) -> Self {
         Self {
             base: Default::default(),
             closed: false,
@@ -136,6 +209,11 @@ impl AppState {
             sessionCount: Default::default(),
             recipientCount: Default::default(),
             unsentCount: Default::default(),
+
+            account,
+            accountName: Default::default(),
+            accounts: Default::default(),
+            switchAccount: Default::default(),
         }
     }
 }
@@ -148,9 +226,11 @@ pub struct WhisperfishApp {
     pub prompt: QObjectBox<model::Prompt>,
 
     pub client_actor: Addr<worker::ClientActor>,
+    pub jsonrpc_server: Option<Addr<jsonrpc::JsonRpcServer>>,
     pub setup_worker: QObjectBox<worker::SetupWorker>,
 
     pub settings_bridge: QObjectBox<SettingsBridge>,
+    pub dbus_api: QObjectBox<worker::DBusApi>,
 }
 
 impl WhisperfishApp {
@@ -164,6 +244,11 @@ impl WhisperfishApp {
             .as_ref()
             .unwrap()
             .clone();
+        self.dbus_api.pinned().borrow_mut().storage = Some(storage.clone());
+        self.contact_model
+            .pinned()
+            .borrow_mut()
+            .set_storage(storage.clone());
         let msg = StorageReady { storage };
 
         futures::join! {
@@ -184,6 +269,13 @@ impl WhisperfishApp {
                     .send(msg.clone()).await {
                     log::error!("Error handling StorageReady: {}", e);
                 }
+            },
+            async {
+                if let Some(jsonrpc_server) = &self.jsonrpc_server {
+                    if let Err(e) = jsonrpc_server.send(msg.clone()).await {
+                        log::error!("Error handling StorageReady: {}", e);
+                    }
+                }
             }
         };
     }
@@ -243,6 +335,8 @@ pub fn run(config: crate::config::SignalConfig) -> Result<(), anyhow::Error> {
                 qml_register_type::<model::Group>(uri, 1, 0, cstr!("Group"));
                 qml_register_type::<model::Attachment>(uri, 1, 0, cstr!("Attachment"));
                 qml_register_type::<model::Reactions>(uri, 1, 0, cstr!("Reactions"));
+                qml_register_type::<model::Receipts>(uri, 1, 0, cstr!("Receipts"));
+                qml_register_type::<model::StorageUsage>(uri, 1, 0, cstr!("StorageUsage"));
             }
 
             let mut app = QmlApp::application("harbour-whisperfish".into());
@@ -263,18 +357,26 @@ pub fn run(config: crate::config::SignalConfig) -> Result<(), anyhow::Error> {
             )?
             .start();
             let message_actor = actor::MessageActor::new(&mut app, client_actor.clone()).start();
+            let jsonrpc_server = config
+                .jsonrpc_socket
+                .clone()
+                .map(|socket| jsonrpc::JsonRpcServer::new(socket, client_actor.clone()).start());
+            let dbus_api = worker::DBusApi::new(client_actor.clone(), session_actor.clone());
+            let account = config.account().map(String::from);
 
             let whisperfish = Rc::new(WhisperfishApp {
-                app_state: QObjectBox::new(AppState::new()),
+                app_state: QObjectBox::new(AppState::new(account.clone())),
                 session_actor,
                 message_actor,
                 client_actor,
-                contact_model: QObjectBox::new(model::ContactModel::default()),
+                jsonrpc_server,
+                contact_model: QObjectBox::new(model::ContactModel::for_account(account.clone())),
                 prompt: QObjectBox::new(model::Prompt::default()),
 
                 setup_worker: QObjectBox::new(worker::SetupWorker::default()),
 
-                settings_bridge: QObjectBox::new(SettingsBridge::default()),
+                settings_bridge: QObjectBox::new(SettingsBridge::for_account(account.as_deref())),
+                dbus_api: QObjectBox::new(dbus_api),
             });
 
             app.set_property("AppVersion".into(), version.into());
@@ -282,6 +384,8 @@ pub fn run(config: crate::config::SignalConfig) -> Result<(), anyhow::Error> {
             let ci_job_url: Option<QString> = option_env!("CI_JOB_URL").map(Into::into);
             let ci_job_url = ci_job_url.map(Into::into).unwrap_or_else(|| false.into());
             app.set_property("CiJobUrl".into(), ci_job_url);
+            let dbus_service_name: QString = crate::dbus_service_name(account.as_deref()).into();
+            app.set_property("DBusServiceName".into(), dbus_service_name.into());
 
             app.set_object_property("Prompt".into(), whisperfish.prompt.pinned());
             app.set_object_property(
@@ -291,6 +395,7 @@ pub fn run(config: crate::config::SignalConfig) -> Result<(), anyhow::Error> {
             app.set_object_property("ContactModel".into(), whisperfish.contact_model.pinned());
             app.set_object_property("SetupWorker".into(), whisperfish.setup_worker.pinned());
             app.set_object_property("AppState".into(), whisperfish.app_state.pinned());
+            app.set_object_property("DBusApi".into(), whisperfish.dbus_api.pinned());
 
             // We need to decied when to close the app based on the current setup state and
             // background service configuration. We do that in QML in the lastWindowClosed signal
@@ -0,0 +1,291 @@
+//! Local JSON-RPC interface, compatible with the daemon mode of signal-cli.
+//!
+//! Clients connect to a Unix socket and exchange newline-delimited JSON-RPC 2.0 messages, like with
+//! `signal-cli daemon --socket`.  Only the methods that map onto Whisperfish are implemented, see
+//! [methods].  New incoming messages are pushed as `receive` notifications to the clients that
+//! called `subscribeReceive`.
+//!
+//! The server only runs when `jsonrpcSocket` is set in the config file, or `--jsonrpc-socket` is
+//! given on the command line.
+
+mod methods;
+
+use crate::gui::StorageReady;
+use crate::store::observer::{Event, Interest};
+use crate::store::{schema, Storage};
+use crate::worker::ClientActor;
+use actix::prelude::*;
+use futures::future::LocalBoxFuture;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const INTERNAL_ERROR: i32 = -32603;
+
+/// The lines to be written to one connected client.
+type Peer = mpsc::UnboundedSender<String>;
+
+type RpcResult = Result<Value, RpcError>;
+
+#[derive(Debug)]
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+
+    fn internal(message: impl std::fmt::Display) -> Self {
+        Self::new(INTERNAL_ERROR, message.to_string())
+    }
+}
+
+pub struct JsonRpcServer {
+    socket: PathBuf,
+    storage: Option<Storage>,
+    client_actor: Addr<ClientActor>,
+
+    subscriptions: HashMap<u32, Peer>,
+    next_subscription: u32,
+}
+
+impl JsonRpcServer {
+    pub fn new(socket: PathBuf, client_actor: Addr<ClientActor>) -> Self {
+        Self {
+            socket,
+            storage: None,
+            client_actor,
+            subscriptions: HashMap::new(),
+            next_subscription: 0,
+        }
+    }
+
+    fn bind(&self) -> Result<UnixListener, anyhow::Error> {
+        // A socket left behind by a previous run would make binding fail.
+        if self.socket.exists() {
+            std::fs::remove_file(&self.socket)?;
+        }
+        // Anyone who can connect can read and send messages.  The socket is bound in a private
+        // directory, and only moved in place once nobody else can connect to it.
+        let parent = match self.socket.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let private = tempfile::Builder::new()
+            .prefix(".jsonrpc")
+            .tempdir_in(parent)?;
+        std::fs::set_permissions(private.path(), std::fs::Permissions::from_mode(0o700))?;
+        let staged = private.path().join("socket");
+
+        let listener = UnixListener::bind(&staged)?;
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, &self.socket)?;
+        Ok(listener)
+    }
+}
+
+impl Actor for JsonRpcServer {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let listener = match self.bind() {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!(
+                    "Could not serve JSON-RPC on {}: {}",
+                    self.socket.display(),
+                    e
+                );
+                ctx.stop();
+                return;
+            }
+        };
+        log::info!("Serving JSON-RPC on {}", self.socket.display());
+
+        let addr = ctx.address();
+        ctx.spawn(
+            async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            actix::spawn(serve(addr.clone(), stream));
+                        }
+                        Err(e) => {
+                            log::error!("Accepting JSON-RPC client: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+            .into_actor(self),
+        );
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        std::fs::remove_file(&self.socket).ok();
+    }
+}
+
+impl Handler<StorageReady> for JsonRpcServer {
+    type Result = ();
+
+    fn handle(&mut self, storageready: StorageReady, ctx: &mut Self::Context) -> Self::Result {
+        let mut storage = storageready.storage;
+        storage.register_observer(
+            vec![Interest::whole_table(schema::messages::table)],
+            ctx.address().downgrade().recipient(),
+        );
+        self.storage = Some(storage);
+        log::trace!("JsonRpcServer has a registered storage");
+    }
+}
+
+impl Handler<Event> for JsonRpcServer {
+    type Result = Vec<Interest>;
+
+    fn handle(&mut self, event: Event, _ctx: &mut Self::Context) -> Self::Result {
+        if event.is_insert()
+            && event.for_table(schema::messages::table)
+            && !self.subscriptions.is_empty()
+        {
+            if let Some(mid) = event.key().as_i32() {
+                self.notify_received(mid);
+            }
+        }
+        vec![Interest::whole_table(schema::messages::table)]
+    }
+}
+
+/// One line, i.e. one request, from a client.
+#[derive(Message)]
+#[rtype(result = "Option<String>")]
+struct Call {
+    line: String,
+    peer: Peer,
+}
+
+impl Handler<Call> for JsonRpcServer {
+    type Result = ResponseActFuture<Self, Option<String>>;
+
+    fn handle(&mut self, Call { line, peer }: Call, _ctx: &mut Self::Context) -> Self::Result {
+        let request: Value = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = RpcError::new(PARSE_ERROR, e.to_string());
+                return Box::pin(fut::ready(Some(response(Value::Null, Err(error)))));
+            }
+        };
+
+        let id = request.get("id").cloned();
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+        let result: LocalBoxFuture<'static, RpcResult> =
+            match request.get("method").and_then(Value::as_str) {
+                Some(method) => {
+                    log::trace!("JSON-RPC call {}", method);
+                    self.dispatch(method, params, &peer)
+                }
+                None => Box::pin(futures::future::ready(Err(RpcError::new(
+                    INVALID_REQUEST,
+                    "Missing method",
+                )))),
+            };
+
+        Box::pin(result.into_actor(self).map(move |result, _act, _ctx| {
+            // Requests without id are notifications, which get no response.
+            id.map(|id| response(id, result))
+        }))
+    }
+}
+
+fn response(id: Value, result: RpcResult) -> String {
+    match result {
+        Ok(result) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "result": result,
+        }),
+        Err(error) => {
+            log::warn!("JSON-RPC error {}: {}", error.code, error.message);
+            json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {
+                    "code": error.code,
+                    "message": error.message,
+                },
+            })
+        }
+    }
+    .to_string()
+}
+
+/// Reads requests from a client and writes back the responses and notifications.
+async fn serve(server: Addr<JsonRpcServer>, stream: UnixStream) {
+    log::info!("JSON-RPC client connected");
+    let (read, mut write) = stream.into_split();
+    let (peer, mut outgoing) = mpsc::unbounded_channel::<String>();
+
+    let writer = actix::spawn(async move {
+        while let Some(mut line) = outgoing.recv().await {
+            line.push('\n');
+            if let Err(e) = write.write_all(line.as_bytes()).await {
+                log::warn!("Writing to JSON-RPC client: {}", e);
+                break;
+            }
+        }
+    });
+
+    let mut lines = BufReader::new(read).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                log::warn!("Reading from JSON-RPC client: {}", e);
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // Requests are handled concurrently, since sending a message takes a while.
+        let server = server.clone();
+        let peer = peer.clone();
+        actix::spawn(async move {
+            let call = Call {
+                line,
+                peer: peer.clone(),
+            };
+            match server.send(call).await {
+                Ok(Some(response)) => {
+                    peer.send(response).ok();
+                }
+                Ok(None) => {}
+                Err(e) => log::error!("JSON-RPC server is gone: {}", e),
+            }
+        });
+    }
+
+    // Dropping the receiving end ends the subscriptions of this client.
+    writer.abort();
+    log::info!("JSON-RPC client disconnected");
+}
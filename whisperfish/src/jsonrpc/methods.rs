@@ -0,0 +1,605 @@
+//! The signal-cli methods, on top of [Storage] and [ClientActor].
+//!
+//! As in signal-cli, recipients are phone numbers or UUIDs, groups are identified by their base64
+//! encoded ID, and timestamps are in milliseconds.  Parameters that only make sense for signal-cli,
+//! like `account`, are ignored.
+
+use super::*;
+use crate::actor::SendReaction;
+use crate::store::orm::{self, SessionType};
+use crate::worker::{IsConnected, QueueAttachment, QueueMessage, SendReceipt};
+use chrono::NaiveDateTime;
+use futures::prelude::*;
+use libsignal_service::proto::receipt_message;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// How long `send` waits for a message to leave, before reporting it as queued.
+const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A parameter that may be given as single value or as list.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> Default for OneOrMany<T> {
+    fn default() -> Self {
+        Self::Many(Vec::new())
+    }
+}
+
+impl<T> OneOrMany<T> {
+    fn into_vec(self) -> Vec<T> {
+        match self {
+            Self::One(value) => vec![value],
+            Self::Many(values) => values,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SendParams {
+    #[serde(default)]
+    recipient: OneOrMany<String>,
+    group_id: Option<String>,
+    #[serde(default)]
+    message: String,
+    #[serde(default, alias = "attachment")]
+    attachments: OneOrMany<String>,
+    quote_timestamp: Option<i64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SendReactionParams {
+    #[serde(default)]
+    recipient: OneOrMany<String>,
+    group_id: Option<String>,
+    emoji: String,
+    target_author: Option<String>,
+    target_timestamp: i64,
+    #[serde(default)]
+    remove: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SendReceiptParams {
+    recipient: String,
+    target_timestamp: OneOrMany<u64>,
+    #[serde(default, rename = "type")]
+    receipt_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UnsubscribeParams {
+    subscription: u32,
+}
+
+fn parse_params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::invalid_params(e.to_string()))
+}
+
+fn ready(result: RpcResult) -> LocalBoxFuture<'static, RpcResult> {
+    Box::pin(future::ready(result))
+}
+
+impl JsonRpcServer {
+    pub(super) fn dispatch(
+        &mut self,
+        method: &str,
+        params: Value,
+        peer: &Peer,
+    ) -> LocalBoxFuture<'static, RpcResult> {
+        let storage = match &self.storage {
+            Some(storage) => storage.clone(),
+            None => return ready(Err(RpcError::internal("Storage is not opened yet"))),
+        };
+        let client = self.client_actor.clone();
+
+        match method {
+            "send" => Box::pin(send(storage, client, params)),
+            "sendReaction" => Box::pin(send_reaction(storage, client, params)),
+            "sendReceipt" => ready(send_receipt(&storage, &client, params)),
+            "listGroups" => ready(Ok(list_groups(&storage))),
+            "listContacts" => ready(Ok(list_contacts(&storage))),
+            "subscribeReceive" => ready(Ok(self.subscribe(peer.clone()))),
+            "unsubscribeReceive" => ready(self.unsubscribe(params)),
+            "version" => ready(Ok(json!({ "version": env!("CARGO_PKG_VERSION") }))),
+            _ => ready(Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Method not implemented: {}", method),
+            ))),
+        }
+    }
+
+    fn subscribe(&mut self, peer: Peer) -> Value {
+        let subscription = self.next_subscription;
+        self.next_subscription += 1;
+        self.subscriptions.insert(subscription, peer);
+        subscription.into()
+    }
+
+    fn unsubscribe(&mut self, params: Value) -> RpcResult {
+        let UnsubscribeParams { subscription } = parse_params(params)?;
+        match self.subscriptions.remove(&subscription) {
+            Some(_) => Ok(Value::Null),
+            None => Err(RpcError::invalid_params(format!(
+                "Unknown subscription {}",
+                subscription
+            ))),
+        }
+    }
+
+    /// Pushes a new incoming message to the subscribed clients.
+    pub(super) fn notify_received(&mut self, mid: i32) {
+        let storage = self.storage.as_ref().unwrap();
+        let result = match storage
+            .fetch_message_by_id(mid)
+            .and_then(|message| envelope(storage, &message))
+        {
+            Some(result) => result,
+            None => return,
+        };
+
+        // Clients that went away are dropped here.
+        self.subscriptions.retain(|subscription, peer| {
+            let notification = json!({
+                "jsonrpc": "2.0",
+                "method": "receive",
+                "params": {
+                    "subscription": subscription,
+                    "result": result,
+                },
+            });
+            peer.send(notification.to_string()).is_ok()
+        });
+    }
+}
+
+async fn send(storage: Storage, client: Addr<ClientActor>, params: Value) -> RpcResult {
+    let params: SendParams = parse_params(params)?;
+    let sessions = target_sessions(
+        &storage,
+        params.recipient.into_vec(),
+        params.group_id.as_deref(),
+    )?;
+    let quote = match params.quote_timestamp {
+        Some(timestamp) => message_by_timestamp(&storage, timestamp)?.id,
+        None => -1,
+    };
+    let attachments: Vec<_> = params
+        .attachments
+        .into_vec()
+        .into_iter()
+        .map(QueueAttachment::new)
+        .collect();
+    for attachment in &attachments {
+        if !std::path::Path::new(&attachment.path).is_file() {
+            return Err(RpcError::invalid_params(format!(
+                "Attachment {} is not a file",
+                attachment.path
+            )));
+        }
+    }
+
+    let mut timestamp = None;
+    let mut results = Vec::new();
+    for session in sessions {
+        let mid = client
+            .send(QueueMessage {
+                session_id: session.id,
+                message: params.message.clone(),
+                attachments: attachments.clone(),
+                quote,
+            })
+            .await
+            .map_err(RpcError::internal)?
+            .map_err(|e| RpcError::internal(format!("{:#}", e)))?;
+        let message = storage
+            .fetch_message_by_id(mid)
+            .ok_or_else(|| RpcError::internal("Queued message not found"))?;
+        timestamp.get_or_insert(message.server_timestamp.timestamp_millis());
+
+        // Without a connection the message waits in the outbox, so there is nothing to wait for.
+        let connected = client.send(IsConnected).await.map_err(RpcError::internal)?;
+        let r#type = if connected {
+            wait_until_sent(&storage, message.id).await
+        } else {
+            log::info!("Message {} over JSON-RPC is queued until connected", mid);
+            "QUEUED"
+        };
+        let mut result = session_address(&session);
+        result["type"] = r#type.into();
+        results.push(result);
+    }
+
+    Ok(json!({
+        "timestamp": timestamp,
+        "results": results,
+    }))
+}
+
+/// Waits for the message to leave, and returns the `type` of its send result.
+///
+/// Whisperfish does not know why sending failed, so all failures look like network errors. A
+/// message that is still in the outbox when the timeout passes is reported as queued.
+async fn wait_until_sent(storage: &Storage, mid: i32) -> &'static str {
+    let deadline = Instant::now() + SEND_TIMEOUT;
+    while Instant::now() < deadline {
+        match storage.fetch_message_by_id(mid) {
+            Some(message) if message.sent_timestamp.is_some() => return "SUCCESS",
+            Some(message) if !message.sending_has_failed => {}
+            _ => return "NETWORK_FAILURE",
+        }
+        actix::clock::sleep(Duration::from_millis(250)).await;
+    }
+    log::warn!("Timed out sending message {} over JSON-RPC", mid);
+    "QUEUED"
+}
+
+/// Reacts to the message by `targetAuthor` with `targetTimestamp`.
+///
+/// Timestamps are not unique, so the message is looked for in the conversation given by
+/// `recipient` or `groupId` too, when given.
+async fn send_reaction(storage: Storage, client: Addr<ClientActor>, params: Value) -> RpcResult {
+    let params: SendReactionParams = parse_params(params)?;
+    let author = params
+        .target_author
+        .as_deref()
+        .map(|author| find_recipient(&storage, author))
+        .transpose()?;
+    let recipients = params.recipient.into_vec();
+    let sessions = if params.group_id.is_some() || !recipients.is_empty() {
+        Some(target_sessions(
+            &storage,
+            recipients,
+            params.group_id.as_deref(),
+        )?)
+    } else {
+        None
+    };
+    let self_id = storage.fetch_self_recipient().map(|r| r.id);
+
+    let mut candidates: Vec<_> = NaiveDateTime::from_timestamp_millis(params.target_timestamp)
+        .map(|ts| storage.fetch_messages_by_timestamp(ts))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|message| match &author {
+            Some(author) if Some(author.id) == self_id => message.is_outbound,
+            Some(author) => !message.is_outbound && message.sender_recipient_id == Some(author.id),
+            None => true,
+        })
+        .filter(|message| {
+            sessions.as_ref().map_or(true, |sessions| {
+                sessions.iter().any(|s| s.id == message.session_id)
+            })
+        })
+        .collect();
+    let message = match candidates.len() {
+        0 => {
+            return Err(RpcError::invalid_params(format!(
+                "No message with timestamp {}",
+                params.target_timestamp
+            )))
+        }
+        1 => candidates.remove(0),
+        _ => {
+            return Err(RpcError::invalid_params(format!(
+                "Several messages with timestamp {}; give targetAuthor, recipient or groupId",
+                params.target_timestamp
+            )))
+        }
+    };
+    if !message.is_outbound && message.sender_recipient_id.is_none() {
+        return Err(RpcError::invalid_params(
+            "The target message has no known author",
+        ));
+    }
+
+    client
+        .send(SendReaction {
+            message_id: message.id,
+            sender_id: message.sender_recipient_id.unwrap_or(0),
+            emoji: params.emoji,
+            remove: params.remove,
+        })
+        .await
+        .map_err(RpcError::internal)?;
+
+    Ok(json!({ "results": [] }))
+}
+
+fn send_receipt(storage: &Storage, client: &Addr<ClientActor>, params: Value) -> RpcResult {
+    let params: SendReceiptParams = parse_params(params)?;
+    let r#type = match params.receipt_type.as_deref() {
+        None | Some("read") => receipt_message::Type::Read,
+        Some("viewed") => receipt_message::Type::Viewed,
+        Some(other) => {
+            return Err(RpcError::invalid_params(format!(
+                "Unknown receipt type {}",
+                other
+            )))
+        }
+    };
+    let recipient = find_recipient(storage, &params.recipient)?;
+
+    client.do_send(SendReceipt {
+        recipient_id: recipient.id,
+        r#type,
+        timestamps: params.target_timestamp.into_vec(),
+    });
+
+    Ok(json!({ "results": [] }))
+}
+
+fn list_groups(storage: &Storage) -> Value {
+    let self_id = storage.fetch_self_recipient().map(|r| r.id);
+
+    let groups: Vec<_> = storage
+        .fetch_sessions()
+        .into_iter()
+        .filter_map(|session| {
+            let (id, name, description, members, admins) = match &session.r#type {
+                SessionType::GroupV1(group) => {
+                    let members: Vec<_> = storage
+                        .fetch_group_members_by_group_v1_id(&group.id)
+                        .into_iter()
+                        .map(|(_, recipient)| recipient)
+                        .collect();
+                    (&group.id, &group.name, None, members, Vec::new())
+                }
+                SessionType::GroupV2(group) => {
+                    let (admins, members): (Vec<_>, Vec<_>) = storage
+                        .fetch_group_members_by_group_v2_id(&group.id)
+                        .into_iter()
+                        // Role 2 is administrator in the Signal protocol.
+                        .partition(|(member, _)| member.role == 2);
+                    let admins: Vec<_> = admins.into_iter().map(|(_, r)| r).collect();
+                    let members = members.into_iter().map(|(_, r)| r).chain(admins.clone());
+                    (
+                        &group.id,
+                        &group.name,
+                        group.description.as_ref(),
+                        members.collect(),
+                        admins,
+                    )
+                }
+                SessionType::DirectMessage(_) => return None,
+            };
+
+            Some(json!({
+                "id": group_id_to_base64(id),
+                "name": name,
+                "description": description,
+                "isMember": members.iter().any(|r| Some(r.id) == self_id),
+                "isBlocked": false,
+                "members": members.iter().map(address).collect::<Vec<_>>(),
+                "admins": admins.iter().map(address).collect::<Vec<_>>(),
+            }))
+        })
+        .collect();
+
+    groups.into()
+}
+
+fn list_contacts(storage: &Storage) -> Value {
+    let contacts: Vec<_> = storage
+        .fetch_recipients()
+        .iter()
+        .filter(|recipient| recipient.e164.is_some() || recipient.uuid.is_some())
+        .map(|recipient| {
+            let mut contact = address(recipient);
+            contact["username"] = recipient.username.clone().into();
+            contact["name"] = recipient.profile_joined_name.clone().into();
+            contact["isBlocked"] = recipient.blocked.into();
+            contact["profile"] = json!({
+                "givenName": recipient.profile_given_name,
+                "familyName": recipient.profile_family_name,
+                "about": recipient.about,
+                "aboutEmoji": recipient.about_emoji,
+            });
+            contact
+        })
+        .collect();
+
+    contacts.into()
+}
+
+/// The `receive` notification for an incoming message, in the format of signal-cli.
+fn envelope(storage: &Storage, message: &orm::Message) -> Option<Value> {
+    if message.is_outbound {
+        return None;
+    }
+
+    let session = storage.fetch_session_by_id(message.session_id)?;
+    let sender = match message
+        .sender_recipient_id
+        .and_then(|id| storage.fetch_recipient_by_id(id))
+    {
+        Some(sender) => sender,
+        None if session.is_dm() => session.unwrap_dm().clone(),
+        None => return None,
+    };
+
+    let group_info = match &session.r#type {
+        SessionType::GroupV1(group) => Some(&group.id),
+        SessionType::GroupV2(group) => Some(&group.id),
+        SessionType::DirectMessage(_) => None,
+    }
+    .map(|id| {
+        json!({
+            "groupId": group_id_to_base64(id),
+            "type": "DELIVER",
+        })
+    });
+
+    let quote = message
+        .quote_id
+        .and_then(|id| storage.fetch_message_by_id(id))
+        .map(|quoted| {
+            json!({
+                "id": quoted.server_timestamp.timestamp_millis(),
+                "text": quoted.text,
+            })
+        });
+
+    let attachments: Vec<_> = storage
+        .fetch_attachments_for_message(message.id)
+        .iter()
+        .map(|attachment| {
+            json!({
+                "contentType": attachment.content_type,
+                "filename": attachment.file_name,
+                "id": attachment.id.to_string(),
+                "size": attachment.size,
+            })
+        })
+        .collect();
+
+    let timestamp = message.server_timestamp.timestamp_millis();
+    let account = storage
+        .fetch_self_recipient()
+        .and_then(|r| r.e164)
+        .map(|e164| e164.to_string());
+
+    Some(json!({
+        "envelope": {
+            "source": sender.e164_or_uuid(),
+            "sourceNumber": sender.e164.as_ref().map(|e164| e164.to_string()),
+            "sourceUuid": sender.uuid.map(|uuid| uuid.to_string()),
+            "sourceName": sender.name(),
+            "timestamp": timestamp,
+            "dataMessage": {
+                "timestamp": timestamp,
+                "message": message.text,
+                "expiresInSeconds": message.expires_in.unwrap_or(0),
+                "viewOnce": message.is_view_once,
+                "groupInfo": group_info,
+                "quote": quote,
+                "attachments": attachments,
+            },
+        },
+        "account": account,
+    }))
+}
+
+/// The sessions to send to: either the group, or the conversations with the recipients.
+fn target_sessions(
+    storage: &Storage,
+    recipients: Vec<String>,
+    group_id: Option<&str>,
+) -> Result<Vec<orm::Session>, RpcError> {
+    if let Some(group_id) = group_id {
+        let hex_id = base64::decode(group_id)
+            .map(hex::encode)
+            .map_err(|_| RpcError::invalid_params("Invalid group ID"))?;
+        let session = storage
+            .fetch_session_by_group_v2_id(&hex_id)
+            .or_else(|| storage.fetch_session_by_group_v1_id(&hex_id))
+            .ok_or_else(|| RpcError::invalid_params(format!("Unknown group {}", group_id)))?;
+        return Ok(vec![session]);
+    }
+
+    if recipients.is_empty() {
+        return Err(RpcError::invalid_params("No recipient or group given"));
+    }
+    recipients
+        .iter()
+        .map(|recipient| {
+            crate::worker::resolve_session(storage, recipient)
+                .map_err(|e| RpcError::invalid_params(e.to_string()))
+        })
+        .collect()
+}
+
+fn find_recipient(storage: &Storage, recipient: &str) -> Result<orm::Recipient, RpcError> {
+    let found = if let Ok(uuid) = uuid::Uuid::parse_str(recipient) {
+        storage.fetch_recipient_by_uuid(uuid)
+    } else {
+        phonenumber::parse(None, recipient)
+            .ok()
+            .and_then(|e164| storage.fetch_recipient_by_phonenumber(&e164))
+    };
+    found.ok_or_else(|| RpcError::invalid_params(format!("Unknown recipient {}", recipient)))
+}
+
+fn message_by_timestamp(storage: &Storage, timestamp: i64) -> Result<orm::Message, RpcError> {
+    NaiveDateTime::from_timestamp_millis(timestamp)
+        .and_then(|ts| storage.fetch_message_by_timestamp(ts))
+        .ok_or_else(|| RpcError::invalid_params(format!("No message with timestamp {}", timestamp)))
+}
+
+fn session_address(session: &orm::Session) -> Value {
+    match &session.r#type {
+        SessionType::DirectMessage(recipient) => json!({ "recipientAddress": address(recipient) }),
+        SessionType::GroupV1(group) => json!({ "groupId": group_id_to_base64(&group.id) }),
+        SessionType::GroupV2(group) => json!({ "groupId": group_id_to_base64(&group.id) }),
+    }
+}
+
+fn address(recipient: &orm::Recipient) -> Value {
+    json!({
+        "number": recipient.e164.as_ref().map(|e164| e164.to_string()),
+        "uuid": recipient.uuid.map(|uuid| uuid.to_string()),
+    })
+}
+
+/// Whisperfish stores group IDs in hex, signal-cli shows them in base64.
+fn group_id_to_base64(hex_id: &str) -> String {
+    hex::decode(hex_id)
+        .map(base64::encode)
+        .unwrap_or_else(|_| hex_id.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_params_like_signal_cli() {
+        let params: SendParams = parse_params(json!({
+            "account": "+32474000000",
+            "recipient": "+32474123456",
+            "message": "Hello",
+            "attachment": "/tmp/picture.jpg",
+        }))
+        .unwrap();
+        assert_eq!(params.recipient.into_vec(), vec!["+32474123456"]);
+        assert_eq!(params.attachments.into_vec(), vec!["/tmp/picture.jpg"]);
+        assert_eq!(params.group_id, None);
+
+        let params: SendParams = parse_params(json!({
+            "recipient": ["+32474123456", "4ce3a0e4-2c9e-4a55-8a7e-6b2dbb0ad5e1"],
+            "message": "Hello",
+        }))
+        .unwrap();
+        assert_eq!(params.recipient.into_vec().len(), 2);
+        assert!(params.attachments.into_vec().is_empty());
+
+        assert!(parse_params::<SendReceiptParams>(json!({ "recipient": "+32474123456" })).is_err());
+
+        let params: SendReactionParams = parse_params(json!({
+            "groupId": "ABEiM0RVZneImaq7zN3u/w==",
+            "emoji": "👍",
+            "targetAuthor": "+32474123456",
+            "targetTimestamp": 1234,
+        }))
+        .unwrap();
+        assert_eq!(params.target_author.as_deref(), Some("+32474123456"));
+        assert!(params.recipient.into_vec().is_empty());
+        assert!(!params.remove);
+    }
+
+    #[test]
+    fn group_ids_in_base64() {
+        let hex_id = "00112233445566778899aabbccddeeff";
+        let base64_id = group_id_to_base64(hex_id);
+        assert_eq!(base64_id, "ABEiM0RVZneImaq7zN3u/w==");
+        assert_eq!(hex::encode(base64::decode(base64_id).unwrap()), hex_id);
+    }
+}
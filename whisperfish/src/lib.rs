@@ -3,6 +3,8 @@
 pub mod actor;
 pub mod config;
 pub mod gui;
+pub mod jsonrpc;
+pub mod media;
 pub mod model;
 pub mod platform;
 pub mod qblurhashimageprovider;
@@ -27,3 +29,13 @@ pub fn conf_dir() -> std::path::PathBuf {
 
     conf_dir
 }
+
+/// The D-Bus service name of a running instance.
+///
+/// Every account runs in its own process, so additional accounts get their own name.
+pub fn dbus_service_name(account: Option<&str>) -> String {
+    match account {
+        None => "be.rubdos.whisperfish".into(),
+        Some(account) => format!("be.rubdos.whisperfish.account_{}", account),
+    }
+}
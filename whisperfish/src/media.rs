@@ -0,0 +1,461 @@
+//! Preprocessing of outgoing media attachments.
+//!
+//! Images are resized to fit the sent-media limits, re-encoded, and stripped of their metadata
+//! (EXIF, which commonly contains the GPS location of the picture) before they are uploaded.
+
+pub mod voice_note;
+
+use anyhow::Context;
+use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageFormat};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Size constraints for an image that is about to be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageLimits {
+    /// Maximum width and height in pixels.
+    pub max_dimension: u32,
+    /// Target maximum size in bytes of the encoded file.
+    pub max_size: usize,
+}
+
+/// Limits used when `scale_image_attachments` is enabled.
+///
+/// These mirror the "standard" sent-media quality of Signal-Android.
+pub const STANDARD_QUALITY: ImageLimits = ImageLimits {
+    max_dimension: 1600,
+    max_size: 1024 * 1024,
+};
+
+/// Limits used when `scale_image_attachments` is disabled.
+///
+/// Images within these limits are only stripped of their metadata, never re-encoded.
+pub const HIGH_QUALITY: ImageLimits = ImageLimits {
+    max_dimension: 4096,
+    max_size: 6 * 1024 * 1024,
+};
+
+/// JPEG qualities that are tried in order, until the encoded image fits in `max_size`.
+const JPEG_QUALITIES: [u8; 4] = [85, 75, 65, 50];
+
+/// Sent-media quality level, as stored in the transform properties.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SentMediaQuality {
+    Standard,
+    High,
+}
+
+/// The transformation that has been applied to an attachment before upload.
+///
+/// This is serialized as JSON into `attachments.transform_properties`, such that a retry of a
+/// failed message does not transform the attachment again.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct TransformProperties {
+    /// True if the original file was already fit for upload.
+    pub skip_transform: bool,
+    pub sent_media_quality: SentMediaQuality,
+    /// The file that should be uploaded instead of the original, if any.
+    pub transformed_path: Option<String>,
+    pub transformed_size: Option<usize>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl TransformProperties {
+    pub fn from_json(json: &str) -> Option<Self> {
+        match serde_json::from_str(json) {
+            Ok(props) => Some(props),
+            Err(e) => {
+                log::warn!("Invalid attachment transform properties: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("serializable transform properties")
+    }
+
+    /// The path of the file that should be uploaded, given the path of the original.
+    ///
+    /// Returns `None` if the transformed file has gone missing.
+    pub fn upload_path(&self, original: &str) -> Option<String> {
+        match &self.transformed_path {
+            Some(path) if Path::new(path).exists() => Some(path.clone()),
+            Some(_) => None,
+            None => Some(original.to_string()),
+        }
+    }
+}
+
+/// Whether `transform_image` knows how to handle a file of this MIME type.
+pub fn is_transformable_image(mime_type: &str) -> bool {
+    matches!(mime_type, "image/jpeg" | "image/jpg" | "image/png")
+}
+
+/// Prepare an image for upload.
+///
+/// If the image needs to be modified, the result is written into `dest_dir`, under a random file
+/// name.  Returns `None` if the file is not a JPEG or PNG image.
+pub fn transform_image(
+    path: &Path,
+    dest_dir: &Path,
+    scale: bool,
+) -> Result<Option<TransformProperties>, anyhow::Error> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("Could not read image {}", path.display()))?;
+    let format = match image::guess_format(&bytes) {
+        Ok(f @ ImageFormat::Jpeg) | Ok(f @ ImageFormat::Png) => f,
+        _ => return Ok(None),
+    };
+
+    let (limits, quality) = if scale {
+        (STANDARD_QUALITY, SentMediaQuality::Standard)
+    } else {
+        (HIGH_QUALITY, SentMediaQuality::High)
+    };
+
+    let orientation = if format == ImageFormat::Jpeg {
+        jpeg_exif_orientation(&bytes).unwrap_or(1)
+    } else {
+        1
+    };
+    let (width, height) = image::io::Reader::with_format(Cursor::new(&bytes), format)
+        .into_dimensions()
+        .context("Could not read image dimensions")?;
+
+    let needs_reencode = scale
+        || width.max(height) > limits.max_dimension
+        || bytes.len() > limits.max_size
+        || orientation != 1;
+
+    let (encoded, format, width, height) = if needs_reencode {
+        let img = image::load_from_memory_with_format(&bytes, format)
+            .context("Could not decode image")?;
+        let img = apply_orientation(img, orientation);
+        let img = if img.width().max(img.height()) > limits.max_dimension {
+            img.resize(
+                limits.max_dimension,
+                limits.max_dimension,
+                FilterType::Lanczos3,
+            )
+        } else {
+            img
+        };
+        let (encoded, format) = encode_image(&img, format, limits)?;
+        (encoded, format, img.width(), img.height())
+    } else {
+        let stripped = strip_metadata(&bytes, format);
+        if stripped.len() == bytes.len() {
+            log::trace!("Image {} can be sent as is", path.display());
+            return Ok(Some(TransformProperties {
+                skip_transform: true,
+                sent_media_quality: quality,
+                transformed_path: None,
+                transformed_size: None,
+                width,
+                height,
+            }));
+        }
+        (stripped, format, width, height)
+    };
+
+    let ext = match format {
+        ImageFormat::Png => "png",
+        _ => "jpg",
+    };
+    let dest = dest_dir.join(format!("{}.{}", Uuid::new_v4().as_simple(), ext));
+    std::fs::write(&dest, &encoded)
+        .with_context(|| format!("Could not write transformed image {}", dest.display()))?;
+    log::debug!(
+        "Transformed image {} ({} bytes) into {} ({} bytes, {}x{})",
+        path.display(),
+        bytes.len(),
+        dest.display(),
+        encoded.len(),
+        width,
+        height
+    );
+
+    Ok(Some(TransformProperties {
+        skip_transform: false,
+        sent_media_quality: quality,
+        transformed_path: Some(path_to_string(dest)?),
+        transformed_size: Some(encoded.len()),
+        width,
+        height,
+    }))
+}
+
+fn path_to_string(path: PathBuf) -> Result<String, anyhow::Error> {
+    path.into_os_string()
+        .into_string()
+        .map_err(|p| anyhow::anyhow!("Non-UTF8 path {:?}", p))
+}
+
+/// Encode the image, trying to stay below `limits.max_size`.
+///
+/// PNG images without transparency that are too large get converted to JPEG.
+fn encode_image(
+    img: &DynamicImage,
+    format: ImageFormat,
+    limits: ImageLimits,
+) -> Result<(Vec<u8>, ImageFormat), anyhow::Error> {
+    if format == ImageFormat::Png {
+        let mut buf = Vec::new();
+        img.write_to(&mut buf, image::ImageOutputFormat::Png)
+            .context("Could not encode PNG")?;
+        if buf.len() <= limits.max_size || img.color().has_alpha() {
+            return Ok((buf, ImageFormat::Png));
+        }
+    }
+
+    let rgb = DynamicImage::ImageRgb8(img.to_rgb8());
+    let mut buf = Vec::new();
+    for quality in JPEG_QUALITIES.iter() {
+        buf.clear();
+        rgb.write_to(&mut buf, image::ImageOutputFormat::Jpeg(*quality))
+            .context("Could not encode JPEG")?;
+        if buf.len() <= limits.max_size {
+            break;
+        }
+    }
+    Ok((buf, ImageFormat::Jpeg))
+}
+
+/// Rotate and flip the image according to its EXIF orientation tag.
+fn apply_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Iterate over the header segments of a JPEG file, up to the start of the image data.
+///
+/// Yields the marker and the range of the full segment (including marker and length).
+fn jpeg_segments(bytes: &[u8]) -> impl Iterator<Item = (u8, std::ops::Range<usize>)> + '_ {
+    let mut offset = 2; // Skip SOI
+    std::iter::from_fn(move || {
+        // Skip fill bytes
+        while bytes.get(offset) == Some(&0xFF) && bytes.get(offset + 1) == Some(&0xFF) {
+            offset += 1;
+        }
+        if bytes.get(offset) != Some(&0xFF) {
+            return None;
+        }
+        let marker = *bytes.get(offset + 1)?;
+        // Start of scan or end of image: the rest is image data.
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let len = u16::from_be_bytes([*bytes.get(offset + 2)?, *bytes.get(offset + 3)?]) as usize;
+        let segment = offset..offset + 2 + len;
+        if segment.end > bytes.len() {
+            return None;
+        }
+        offset = segment.end;
+        Some((marker, segment))
+    })
+}
+
+/// Read the orientation tag from the EXIF data of a JPEG file.
+fn jpeg_exif_orientation(bytes: &[u8]) -> Option<u16> {
+    let (_, segment) = jpeg_segments(bytes).find(|(marker, segment)| {
+        *marker == 0xE1
+            && bytes
+                .get(segment.start + 4..segment.end)
+                .map_or(false, |s| s.starts_with(b"Exif\0\0"))
+    })?;
+    let tiff = &bytes[segment.start + 10..segment.end];
+
+    let big_endian = match tiff.get(0..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let read_u16 = |at: usize| -> Option<u16> {
+        let b = [*tiff.get(at)?, *tiff.get(at + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    };
+    let read_u32 = |at: usize| -> Option<u32> {
+        let b = [
+            *tiff.get(at)?,
+            *tiff.get(at + 1)?,
+            *tiff.get(at + 2)?,
+            *tiff.get(at + 3)?,
+        ];
+        Some(if big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    };
+
+    let ifd0 = read_u32(4)? as usize;
+    let entries = read_u16(ifd0)? as usize;
+    (0..entries)
+        .map(|i| ifd0 + 2 + i * 12)
+        .find(|&entry| read_u16(entry) == Some(0x0112))
+        .and_then(|entry| read_u16(entry + 8))
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// Remove metadata from an image without re-encoding it.
+///
+/// For JPEG, this drops the APP1 (EXIF, XMP) and APP13 (IPTC) segments.
+/// For PNG, this drops the `eXIf` and textual chunks.
+pub fn strip_metadata(bytes: &[u8], format: ImageFormat) -> Vec<u8> {
+    match format {
+        ImageFormat::Jpeg => {
+            let mut out = Vec::with_capacity(bytes.len());
+            out.extend_from_slice(&bytes[..2]);
+            let mut offset = 2;
+            for (marker, segment) in jpeg_segments(bytes) {
+                offset = segment.end;
+                if marker != 0xE1 && marker != 0xED {
+                    out.extend_from_slice(&bytes[segment]);
+                }
+            }
+            out.extend_from_slice(&bytes[offset..]);
+            out
+        }
+        ImageFormat::Png => {
+            const SIGNATURE_LEN: usize = 8;
+            let mut out = Vec::with_capacity(bytes.len());
+            out.extend_from_slice(&bytes[..SIGNATURE_LEN.min(bytes.len())]);
+            let mut offset = SIGNATURE_LEN;
+            while offset + 8 <= bytes.len() {
+                let len = u32::from_be_bytes([
+                    bytes[offset],
+                    bytes[offset + 1],
+                    bytes[offset + 2],
+                    bytes[offset + 3],
+                ]) as usize;
+                let end = (offset + 12 + len).min(bytes.len());
+                let chunk_type = &bytes[offset + 4..offset + 8];
+                if !matches!(chunk_type, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt") {
+                    out.extend_from_slice(&bytes[offset..end]);
+                }
+                offset = end;
+            }
+            out.extend_from_slice(&bytes[offset..]);
+            out
+        }
+        _ => bytes.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minimal JPEG header with an EXIF segment containing only an orientation tag, followed by
+    /// a comment segment and the start of the scan.
+    fn jpeg_with_orientation(orientation: u16) -> Vec<u8> {
+        let mut tiff = b"II\x2A\x00\x08\x00\x00\x00".to_vec();
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        tiff.extend_from_slice(&0x0112u16.to_le_bytes());
+        tiff.extend_from_slice(&3u16.to_le_bytes());
+        tiff.extend_from_slice(&1u32.to_le_bytes());
+        tiff.extend_from_slice(&orientation.to_le_bytes());
+        tiff.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend_from_slice(&tiff);
+
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        jpeg.extend_from_slice(&((app1.len() + 2) as u16).to_be_bytes());
+        jpeg.extend_from_slice(&app1);
+        jpeg.extend_from_slice(&[0xFF, 0xFE, 0x00, 0x04, b'h', b'i']);
+        jpeg.extend_from_slice(&[0xFF, 0xDA, 0x12, 0x34, 0xFF, 0xD9]);
+        jpeg
+    }
+
+    #[test]
+    fn reads_exif_orientation() {
+        for orientation in 1..=8 {
+            let jpeg = jpeg_with_orientation(orientation);
+            assert_eq!(jpeg_exif_orientation(&jpeg), Some(orientation));
+        }
+        assert_eq!(jpeg_exif_orientation(&[0xFF, 0xD8, 0xFF, 0xD9]), None);
+    }
+
+    #[test]
+    fn truncated_app1_segment() {
+        for len in 0..=1u8 {
+            let jpeg = [0xFF, 0xD8, 0xFF, 0xE1, 0x00, len, 0xFF, 0xD9];
+            assert_eq!(jpeg_exif_orientation(&jpeg), None);
+        }
+    }
+
+    #[test]
+    fn strips_jpeg_exif() {
+        let jpeg = jpeg_with_orientation(6);
+        let stripped = strip_metadata(&jpeg, ImageFormat::Jpeg);
+        assert_eq!(
+            stripped,
+            vec![
+                0xFF, 0xD8, 0xFF, 0xFE, 0x00, 0x04, b'h', b'i', 0xFF, 0xDA, 0x12, 0x34, 0xFF, 0xD9
+            ]
+        );
+        assert_eq!(jpeg_exif_orientation(&stripped), None);
+    }
+
+    #[test]
+    fn scales_and_reencodes() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("big.png");
+        DynamicImage::new_rgb8(3200, 800).save(&src).unwrap();
+
+        let props = transform_image(&src, dir.path(), true).unwrap().unwrap();
+        assert!(!props.skip_transform);
+        assert_eq!(props.sent_media_quality, SentMediaQuality::Standard);
+        assert_eq!((props.width, props.height), (1600, 400));
+
+        let out = props.transformed_path.as_deref().unwrap();
+        assert!(out.ends_with(".png"));
+        let out = image::open(out).unwrap();
+        assert_eq!(out.dimensions(), (1600, 400));
+
+        let parsed = TransformProperties::from_json(&props.to_json()).unwrap();
+        assert_eq!(parsed, props);
+    }
+
+    #[test]
+    fn skips_clean_small_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("small.png");
+        DynamicImage::new_rgb8(16, 16).save(&src).unwrap();
+
+        let props = transform_image(&src, dir.path(), false).unwrap().unwrap();
+        assert!(props.skip_transform);
+        assert_eq!(props.transformed_path, None);
+        assert_eq!(
+            props.upload_path(src.to_str().unwrap()).as_deref(),
+            src.to_str()
+        );
+    }
+
+    #[test]
+    fn ignores_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("notes.txt");
+        std::fs::write(&src, b"not an image").unwrap();
+
+        assert_eq!(transform_image(&src, dir.path(), true).unwrap(), None);
+    }
+}
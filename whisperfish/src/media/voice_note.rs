@@ -0,0 +1,548 @@
+//! Duration and waveform summaries of voice notes.
+//!
+//! Whisperfish does not ship audio decoders, so the waveform is approximated from the size of the
+//! compressed audio packets: both Opus and AAC are encoded with a variable bitrate, and spend
+//! considerably more bits on speech than on silence.
+
+use anyhow::Context;
+use std::path::Path;
+use std::time::Duration;
+
+/// Number of bars in a waveform summary.
+pub const WAVEFORM_BARS: usize = 46;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoiceNoteSummary {
+    pub duration: Duration,
+    /// Relative loudness per bar, scaled such that the loudest bar is 255.
+    pub waveform: Vec<u8>,
+}
+
+/// A chunk of compressed audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Packet {
+    /// Duration of the packet, in samples at the stream's sample rate.
+    samples: u64,
+    /// Compressed size of the packet.
+    bytes: usize,
+}
+
+struct AudioStream {
+    sample_rate: u32,
+    packets: Vec<Packet>,
+    /// Exact duration in samples, if the container provides one.
+    total_samples: Option<u64>,
+}
+
+/// Compute the duration and waveform of an Ogg/Opus, MP4/AAC or ADTS/AAC voice note.
+pub fn summarize(path: &Path) -> Result<VoiceNoteSummary, anyhow::Error> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("Could not read voice note {}", path.display()))?;
+    summarize_bytes(&data)
+}
+
+pub fn summarize_bytes(data: &[u8]) -> Result<VoiceNoteSummary, anyhow::Error> {
+    let stream = if data.starts_with(b"OggS") {
+        parse_ogg_opus(data)?
+    } else if data.get(4..8) == Some(b"ftyp") {
+        parse_mp4(data)?
+    } else {
+        parse_adts(data)?
+    };
+
+    Ok(summarize_stream(&stream))
+}
+
+fn summarize_stream(stream: &AudioStream) -> VoiceNoteSummary {
+    let packet_samples: u64 = stream.packets.iter().map(|p| p.samples).sum();
+    let total_samples = stream.total_samples.unwrap_or(packet_samples);
+    let duration = Duration::from_millis(total_samples * 1000 / stream.sample_rate.max(1) as u64);
+
+    if packet_samples == 0 {
+        return VoiceNoteSummary {
+            duration,
+            waveform: vec![0; WAVEFORM_BARS],
+        };
+    }
+
+    // Spread every packet over the bars it overlaps with.
+    let bar_len = packet_samples as f64 / WAVEFORM_BARS as f64;
+    let mut bytes = [0f64; WAVEFORM_BARS];
+    let mut samples = [0f64; WAVEFORM_BARS];
+    let mut position = 0f64;
+    for packet in stream.packets.iter().filter(|p| p.samples > 0) {
+        let start = position;
+        let end = start + packet.samples as f64;
+        let first = (start / bar_len) as usize;
+        let last = ((end / bar_len).ceil() as usize).min(WAVEFORM_BARS);
+        for bar in first..last {
+            let overlap = end.min((bar + 1) as f64 * bar_len) - start.max(bar as f64 * bar_len);
+            if overlap > 0. {
+                bytes[bar] += packet.bytes as f64 * overlap / packet.samples as f64;
+                samples[bar] += overlap;
+            }
+        }
+        position = end;
+    }
+
+    // Bitrate per bar, as a proxy for the loudness.
+    let rates: Vec<f64> = bytes
+        .iter()
+        .zip(samples.iter())
+        .map(|(&b, &s)| if s > 0. { b / s } else { 0. })
+        .collect();
+    let max = rates.iter().cloned().fold(0., f64::max);
+    let waveform = rates
+        .iter()
+        .map(|rate| {
+            if max > 0. {
+                (rate / max * 255.).round() as u8
+            } else {
+                0
+            }
+        })
+        .collect();
+
+    VoiceNoteSummary { duration, waveform }
+}
+
+/// Parse the first logical stream of an Ogg/Opus file.
+fn parse_ogg_opus(data: &[u8]) -> Result<AudioStream, anyhow::Error> {
+    let mut packets = Vec::new();
+    let mut current = Vec::new();
+    let mut pre_skip = 0u64;
+    let mut last_granule = None;
+    let mut headers_seen = 0;
+    let mut stream_serial = None;
+
+    let mut offset = 0;
+    while offset + 27 <= data.len() {
+        anyhow::ensure!(&data[offset..offset + 4] == b"OggS", "Invalid Ogg page");
+        let granule = i64::from_le_bytes(read_array(data, offset + 6)?);
+        let serial = u32::from_le_bytes(read_array(data, offset + 14)?);
+        let segments = data[offset + 26] as usize;
+        let lacing = data
+            .get(offset + 27..offset + 27 + segments)
+            .context("Truncated Ogg page")?;
+        let mut body = offset + 27 + segments;
+
+        let is_our_stream = *stream_serial.get_or_insert(serial) == serial;
+
+        for &lace in lacing {
+            let segment = data
+                .get(body..body + lace as usize)
+                .context("Truncated Ogg segment")?;
+            body += lace as usize;
+            if !is_our_stream {
+                continue;
+            }
+            current.extend_from_slice(segment);
+            if lace < 255 {
+                match headers_seen {
+                    0 => {
+                        anyhow::ensure!(current.starts_with(b"OpusHead"), "Not an Opus stream");
+                        pre_skip = u16::from_le_bytes(read_array(&current, 10)?) as u64;
+                    }
+                    1 => {
+                        anyhow::ensure!(current.starts_with(b"OpusTags"), "Missing Opus tags");
+                    }
+                    _ => {
+                        if let Some(samples) = opus_packet_samples(&current) {
+                            packets.push(Packet {
+                                samples,
+                                bytes: current.len(),
+                            });
+                        }
+                    }
+                }
+                headers_seen += 1;
+                current.clear();
+            }
+        }
+
+        if is_our_stream && granule >= 0 {
+            last_granule = Some(granule as u64);
+        }
+        offset = body;
+    }
+
+    anyhow::ensure!(headers_seen >= 2, "Incomplete Opus stream");
+
+    Ok(AudioStream {
+        // Opus always uses 48kHz granule positions, regardless of the input rate.
+        sample_rate: 48000,
+        packets,
+        total_samples: last_granule.map(|g| g.saturating_sub(pre_skip)),
+    })
+}
+
+/// Number of samples (at 48kHz) in an Opus packet, following RFC 6716 section 3.1.
+fn opus_packet_samples(packet: &[u8]) -> Option<u64> {
+    let toc = *packet.first()?;
+    let config = (toc >> 3) as usize;
+    let frame_size = match config {
+        // SILK: 10, 20, 40, 60 ms
+        0..=11 => [480, 960, 1920, 2880][config % 4],
+        // Hybrid: 10, 20 ms
+        12..=15 => [480, 960][config % 2],
+        // CELT: 2.5, 5, 10, 20 ms
+        _ => [120, 240, 480, 960][config % 4],
+    };
+    let frames = match toc & 0x3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => (*packet.get(1)? & 0x3F) as u64,
+    };
+    Some(frame_size * frames)
+}
+
+/// Parse a raw AAC stream with ADTS headers, optionally prefixed with an ID3v2 tag.
+fn parse_adts(data: &[u8]) -> Result<AudioStream, anyhow::Error> {
+    const SAMPLE_RATES: [u32; 13] = [
+        96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+    ];
+
+    let mut offset = 0;
+    if data.starts_with(b"ID3") && data.len() >= 10 {
+        // Syncsafe integer: 7 bits per byte
+        let size = data[6..10]
+            .iter()
+            .fold(0usize, |acc, &b| (acc << 7) | (b & 0x7F) as usize);
+        offset = 10 + size;
+    }
+
+    let mut sample_rate = None;
+    let mut packets = Vec::new();
+    while offset + 7 <= data.len() {
+        let header = &data[offset..offset + 7];
+        anyhow::ensure!(
+            header[0] == 0xFF && header[1] & 0xF0 == 0xF0,
+            "Unsupported voice note format"
+        );
+        let rate = *SAMPLE_RATES
+            .get(((header[2] >> 2) & 0xF) as usize)
+            .context("Invalid ADTS sample rate")?;
+        sample_rate.get_or_insert(rate);
+
+        let frame_len = ((header[3] & 0x3) as usize) << 11
+            | (header[4] as usize) << 3
+            | (header[5] >> 5) as usize;
+        anyhow::ensure!(frame_len >= 7, "Invalid ADTS frame length");
+        let blocks = (header[6] & 0x3) as u64 + 1;
+
+        packets.push(Packet {
+            samples: 1024 * blocks,
+            bytes: frame_len,
+        });
+        offset += frame_len;
+    }
+
+    Ok(AudioStream {
+        sample_rate: sample_rate.context("No ADTS frames found")?,
+        packets,
+        total_samples: None,
+    })
+}
+
+/// Iterate over the boxes in an MP4 box body, yielding their type and contents.
+fn mp4_boxes(data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> + '_ {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let size = u32::from_be_bytes(read_array(data, offset).ok()?) as usize;
+        let kind: [u8; 4] = read_array(data, offset + 4).ok()?;
+        let (header, size) = match size {
+            0 => (8, data.len() - offset),
+            1 => (
+                16,
+                u64::from_be_bytes(read_array(data, offset + 8).ok()?) as usize,
+            ),
+            size => (8, size),
+        };
+        let body = data.get(offset + header..offset + size)?;
+        offset += size;
+        Some((kind, body))
+    })
+}
+
+fn mp4_find<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    mp4_boxes(data)
+        .find(|(k, _)| k == kind)
+        .map(|(_, body)| body)
+}
+
+/// Upper bound on the number of samples in an MP4 sound track, about six hours of AAC.
+///
+/// The sample tables come from the file, so they are checked against it before anything is
+/// allocated for them.
+const MAX_MP4_SAMPLES: usize = 1 << 20;
+
+/// Parse the first sound track of an MP4/M4A file.
+fn parse_mp4(data: &[u8]) -> Result<AudioStream, anyhow::Error> {
+    let moov = mp4_find(data, b"moov").context("No moov box")?;
+    let mdia = mp4_boxes(moov)
+        .filter(|(k, _)| k == b"trak")
+        .filter_map(|(_, trak)| mp4_find(trak, b"mdia"))
+        .find(|mdia| {
+            // hdlr: version/flags (4), pre-defined (4), handler type (4)
+            mp4_find(mdia, b"hdlr").and_then(|hdlr| hdlr.get(8..12)) == Some(b"soun")
+        })
+        .context("No sound track")?;
+
+    let mdhd = mp4_find(mdia, b"mdhd").context("No mdhd box")?;
+    let (timescale, duration) = if mdhd.first() == Some(&1) {
+        (
+            u32::from_be_bytes(read_array(mdhd, 20)?),
+            u64::from_be_bytes(read_array(mdhd, 24)?),
+        )
+    } else {
+        (
+            u32::from_be_bytes(read_array(mdhd, 12)?),
+            u32::from_be_bytes(read_array(mdhd, 16)?) as u64,
+        )
+    };
+
+    let stbl = mp4_find(mdia, b"minf")
+        .and_then(|minf| mp4_find(minf, b"stbl"))
+        .context("No sample table")?;
+
+    // Sample sizes
+    let stsz = mp4_find(stbl, b"stsz").context("No stsz box")?;
+    let uniform_size = u32::from_be_bytes(read_array(stsz, 4)?) as usize;
+    let sample_count = u32::from_be_bytes(read_array(stsz, 8)?) as usize;
+    anyhow::ensure!(
+        sample_count <= MAX_MP4_SAMPLES,
+        "Too many samples: {}",
+        sample_count
+    );
+    if uniform_size == 0 {
+        anyhow::ensure!(
+            12 + 4 * sample_count <= stsz.len(),
+            "stsz box too short for {} samples",
+            sample_count
+        );
+    } else {
+        anyhow::ensure!(
+            sample_count
+                .checked_mul(uniform_size)
+                .map_or(false, |total| total <= data.len()),
+            "Samples larger than the file"
+        );
+    }
+    let sizes = (0..sample_count)
+        .map(|i| {
+            if uniform_size != 0 {
+                Ok(uniform_size)
+            } else {
+                Ok(u32::from_be_bytes(read_array(stsz, 12 + 4 * i)?) as usize)
+            }
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+    // Sample durations, run-length encoded
+    let stts = mp4_find(stbl, b"stts").context("No stts box")?;
+    let entries = u32::from_be_bytes(read_array(stts, 4)?) as usize;
+    anyhow::ensure!(
+        entries <= stts.len().saturating_sub(8) / 8,
+        "stts box too short for {} entries",
+        entries
+    );
+    let mut durations = Vec::with_capacity(sample_count);
+    for i in 0..entries {
+        let count = u32::from_be_bytes(read_array(stts, 8 + 8 * i)?) as usize;
+        let delta = u32::from_be_bytes(read_array(stts, 12 + 8 * i)?) as u64;
+        // Durations beyond the last sample have nothing to describe.
+        let count = count.min(sample_count - durations.len());
+        durations.resize(durations.len() + count, delta);
+    }
+
+    let packets = sizes
+        .into_iter()
+        .zip(durations)
+        .map(|(bytes, samples)| Packet { samples, bytes })
+        .collect();
+
+    Ok(AudioStream {
+        sample_rate: timescale,
+        packets,
+        total_samples: Some(duration),
+    })
+}
+
+fn read_array<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], anyhow::Error> {
+    let mut out = [0u8; N];
+    out.copy_from_slice(
+        data.get(offset..offset + N)
+            .context("Unexpected end of audio file")?,
+    );
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adts_frame(len: usize) -> Vec<u8> {
+        // MPEG-4, no CRC, AAC LC, 16kHz (index 8), mono, one raw data block.
+        let mut frame = vec![0xFF, 0xF1, 0x60, 0x40, 0, 0, 0xFC];
+        frame[3] |= ((len >> 11) & 0x3) as u8;
+        frame[4] = ((len >> 3) & 0xFF) as u8;
+        frame[5] = (((len & 0x7) << 5) | 0x1F) as u8;
+        frame.resize(len, 0);
+        frame
+    }
+
+    fn ogg_page(serial: u32, granule: i64, packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut lacing = Vec::new();
+        for packet in packets {
+            let mut len = packet.len();
+            while len >= 255 {
+                lacing.push(255);
+                len -= 255;
+            }
+            lacing.push(len as u8);
+        }
+        let mut page = b"OggS\0\0".to_vec();
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&serial.to_le_bytes());
+        page.extend_from_slice(&[0; 8]); // sequence number and CRC
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(&lacing);
+        for packet in packets {
+            page.extend_from_slice(packet);
+        }
+        page
+    }
+
+    #[test]
+    fn adts_duration_and_waveform() {
+        // One second of audio: 16000 / 1024 = 15.6 frames, so 16 frames.
+        // The second half is "loud".
+        let mut data = Vec::new();
+        for i in 0..16 {
+            data.extend(adts_frame(if i < 8 { 20 } else { 200 }));
+        }
+        let summary = summarize_bytes(&data).unwrap();
+        assert_eq!(summary.duration, Duration::from_millis(1024));
+        assert_eq!(summary.waveform.len(), WAVEFORM_BARS);
+        assert_eq!(summary.waveform[WAVEFORM_BARS - 1], 255);
+        assert_eq!(summary.waveform[0], 26);
+    }
+
+    #[test]
+    fn opus_duration_and_waveform() {
+        let mut head = b"OpusHead".to_vec();
+        head.extend_from_slice(&[1, 1]); // version, channels
+        head.extend_from_slice(&312u16.to_le_bytes()); // pre-skip
+        head.extend_from_slice(&[0; 7]);
+
+        // 20 ms CELT frames (config 31, code 0), the first half silent.
+        let packets: Vec<Vec<u8>> = (0..100)
+            .map(|i| {
+                let mut packet = vec![31 << 3];
+                packet.resize(if i < 50 { 3 } else { 300 }, 0);
+                packet
+            })
+            .collect();
+
+        let mut data = ogg_page(7, 0, &[head]);
+        data.extend(ogg_page(7, 0, &[b"OpusTags".to_vec()]));
+        data.extend(ogg_page(7, 960 * 50, &packets[..50]));
+        data.extend(ogg_page(7, 960 * 100 + 312, &packets[50..]));
+
+        let summary = summarize_bytes(&data).unwrap();
+        assert_eq!(summary.duration, Duration::from_secs(2));
+        assert_eq!(summary.waveform[0], 3);
+        assert_eq!(summary.waveform[WAVEFORM_BARS - 1], 255);
+    }
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut b = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        b.extend_from_slice(kind);
+        b.extend_from_slice(body);
+        b
+    }
+
+    /// An M4A file with a single sound track, described by the given sample tables.
+    fn mp4_file(stsz: &[u8], stts: &[u8]) -> Vec<u8> {
+        let mut mdhd = vec![0; 12];
+        mdhd.extend_from_slice(&16000u32.to_be_bytes());
+        mdhd.extend_from_slice(&16000u32.to_be_bytes());
+        mdhd.extend_from_slice(&[0; 4]);
+
+        let mut hdlr = vec![0; 8];
+        hdlr.extend_from_slice(b"soun");
+        hdlr.extend_from_slice(&[0; 13]);
+
+        let stbl = mp4_box(
+            b"stbl",
+            &[mp4_box(b"stsz", stsz), mp4_box(b"stts", stts)].concat(),
+        );
+        let minf = mp4_box(b"minf", &stbl);
+        let mdia = mp4_box(
+            b"mdia",
+            &[mp4_box(b"mdhd", &mdhd), mp4_box(b"hdlr", &hdlr), minf].concat(),
+        );
+        let moov = mp4_box(b"moov", &mp4_box(b"trak", &mdia));
+        [mp4_box(b"ftyp", b"M4A \0\0\0\0"), moov].concat()
+    }
+
+    /// A sample table box: version/flags, then the given 32-bit fields.
+    fn table(fields: &[u32]) -> Vec<u8> {
+        let mut b = vec![0; 4];
+        for field in fields {
+            b.extend_from_slice(&field.to_be_bytes());
+        }
+        b
+    }
+
+    #[test]
+    fn mp4_duration_and_waveform() {
+        let mut stsz = vec![0, 16];
+        for i in 0..16u32 {
+            stsz.push(if i < 8 { 20u32 } else { 200 });
+        }
+        let stts = table(&[1, 16, 1024]);
+
+        let data = mp4_file(&table(&stsz), &stts);
+        let summary = summarize_bytes(&data).unwrap();
+        assert_eq!(summary.duration, Duration::from_secs(1));
+        assert_eq!(summary.waveform[0], 26);
+        assert_eq!(summary.waveform[WAVEFORM_BARS - 1], 255);
+    }
+
+    #[test]
+    fn mp4_hostile_sample_tables() {
+        let stts = table(&[1, 16, 1024]);
+        // A uniform size for more samples than the file can hold
+        let data = mp4_file(&table(&[1, 100_000]), &stts);
+        assert!(summarize_bytes(&data).is_err());
+        // More sizes than the box holds
+        let data = mp4_file(&table(&[0, 100_000, 20]), &stts);
+        assert!(summarize_bytes(&data).is_err());
+
+        let stsz = table(&[1, 16]);
+        // More entries than the box holds
+        let data = mp4_file(&stsz, &table(&[u32::MAX, 16, 1024]));
+        assert!(summarize_bytes(&data).is_err());
+        // A run much longer than the track
+        let data = mp4_file(&stsz, &table(&[1, u32::MAX, 1024]));
+        let summary = summarize_bytes(&data).unwrap();
+        assert_eq!(summary.duration, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn opus_frame_sizes() {
+        // SILK 60 ms, single frame
+        assert_eq!(opus_packet_samples(&[(3 << 3)]), Some(2880));
+        // CELT 2.5 ms, two frames
+        assert_eq!(opus_packet_samples(&[(16 << 3) | 1]), Some(240));
+        // Hybrid 20 ms, arbitrary number of frames
+        assert_eq!(opus_packet_samples(&[(13 << 3) | 3, 3]), Some(2880));
+        assert_eq!(opus_packet_samples(&[]), None);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(summarize_bytes(b"definitely not audio").is_err());
+    }
+}
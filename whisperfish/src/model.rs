@@ -58,8 +58,10 @@ pub mod device;
 pub mod group;
 pub mod messages;
 pub mod reactions;
+pub mod receipts;
 pub mod recipient;
 pub mod sessions;
+pub mod storage_usage;
 
 pub mod prompt;
 
@@ -72,8 +74,10 @@ pub use self::group::*;
 pub use self::messages::*;
 pub use self::prompt::*;
 pub use self::reactions::*;
+pub use self::receipts::*;
 pub use self::recipient::*;
 pub use self::sessions::*;
+pub use self::storage_usage::*;
 
 use chrono::prelude::*;
 use qmetaobject::prelude::*;
@@ -132,6 +136,40 @@ where
     }
 }
 
+/// Renders `data` as a QR code, in a PNG `data:` URI that QML can show as image.
+pub(crate) fn qr_code_data_uri(data: &str) -> String {
+    let code = qrcode::QrCode::new(data).expect("to generate qrcode");
+    let image_buf = code.render::<image::Luma<u8>>().build();
+
+    let mut image_uri = String::from("data:image/png;base64,");
+    {
+        let mut image_b64enc =
+            base64::write::EncoderStringWriter::from(&mut image_uri, base64::STANDARD);
+        image::png::PngEncoder::new(&mut image_b64enc)
+            .encode(
+                &image_buf,
+                image_buf.width(),
+                image_buf.height(),
+                <image::Luma<u8> as image::Pixel>::COLOR_TYPE,
+            )
+            .expect("to write QR code image to data:-URI");
+    }
+    image_uri
+}
+
+fn qvariantlist_from_bytes(bytes: Option<Vec<u8>>) -> QVariant {
+    match bytes {
+        Some(bytes) => {
+            let mut list = qmetaobject::QVariantList::default();
+            for b in bytes {
+                list.push(QVariant::from(b as i32));
+            }
+            list.into()
+        }
+        None => QVariant::default(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -21,6 +21,9 @@ crate::observing_model! {
         r#type MimeType,
         data Data,
         visual_hash VisualHash,
+        is_voice_note IsVoiceNote,
+        audio_duration AudioDuration,
+        audio_waveform AudioWaveform,
     }
 }
 
@@ -76,6 +79,9 @@ define_model_roles! {
         Data(attachment_path via qstring_from_option):  "data",
         OriginalName(file_name via qstring_from_option): "original_name",
         VisualHash(visual_hash via qstring_from_option):  "visual_hash",
+        IsVoiceNote(is_voice_note):                     "is_voice_note",
+        AudioDuration(audio_duration via qvariant_from_option): "audio_duration",
+        AudioWaveform(audio_waveform via qvariantlist_from_bytes): "audio_waveform",
     }
 }
 
@@ -83,6 +89,8 @@ define_model_roles! {
 pub struct AttachmentListModel {
     base: qt_base_class!(trait QAbstractListModel),
     pub(super) attachments: Vec<orm::Attachment>,
+    /// View-once media can only be opened through the message methods.
+    pub(super) view_once: bool,
 
     count: qt_property!(i32; NOTIFY rowCountChanged READ row_count),
 
@@ -145,6 +153,13 @@ impl AttachmentListModel {
     }
 
     fn open(&mut self, idx: i32) {
+        if self.view_once {
+            log::error!(
+                "[attachment] Refusing to open view-once media (idx {})",
+                idx
+            );
+            return;
+        }
         let attachment = if let Some(attachment) = self.attachments.get(idx as usize) {
             attachment
         } else {
@@ -1,4 +1,5 @@
 use crate::config::SettingsBridge;
+use crate::store::Storage;
 use phonenumber::Mode;
 use qmeta_async::with_executor;
 use qmetaobject::prelude::*;
@@ -13,13 +14,23 @@ pub struct ContactModel {
     total: qt_property!(i32; NOTIFY contacts_changed READ total),
 
     contacts_changed: qt_signal!(),
+
+    account: Option<String>,
+    storage: Option<Storage>,
 }
 
 impl ContactModel {
+    pub fn for_account(account: Option<String>) -> Self {
+        Self {
+            account,
+            ..Default::default()
+        }
+    }
+
     // The default formatter expected by QML
     #[with_executor]
     fn format(&self, number: QString) -> QString {
-        let settings = SettingsBridge::default();
+        let settings = SettingsBridge::for_account(self.account.as_deref());
         let country_code = settings.get_string("country_code");
 
         format_with_country(&number.to_string(), &country_code)
@@ -27,9 +38,17 @@ impl ContactModel {
             .into()
     }
 
+    /// The number of contacts that use Signal.
     fn total(&self) -> i32 {
-        // XXX: this should in fact be the amount of *registered* contacts.
-        0
+        self.storage
+            .as_ref()
+            .map(|storage| storage.count_registered_contacts() as i32)
+            .unwrap_or(0)
+    }
+
+    pub fn set_storage(&mut self, storage: Storage) {
+        self.storage = Some(storage);
+        self.contacts_changed();
     }
 }
 
@@ -51,7 +70,7 @@ fn format_with_country_helper(number: &str, mode: Mode, country_code: &str) -> O
     Some(number.format().mode(mode).to_string())
 }
 
-fn format_with_country(number: &str, country_code: &str) -> Option<String> {
+pub(crate) fn format_with_country(number: &str, country_code: &str) -> Option<String> {
     let number = number.trim();
     if number.is_empty() {
         return None;
@@ -6,14 +6,18 @@ use crate::store::{schema, Storage};
 use phonenumber::PhoneNumber;
 use qmetaobject::prelude::*;
 
-/// QML-constructable object that queries a session based on e164 or uuid, and creates it if
-/// necessary.
+/// QML-constructable object that queries a session based on e164, uuid or username, and creates
+/// it if necessary.
+///
+/// A username is only resolved once the recipient is known, i.e. after
+/// `ClientWorker.find_username` found it.
 #[derive(Default, QObject)]
 pub struct CreateConversationImpl {
     base: qt_base_class!(trait QObject),
     session_id: Option<i32>,
     uuid: Option<uuid::Uuid>,
     e164: Option<phonenumber::PhoneNumber>,
+    username: Option<String>,
     name: Option<String>,
 }
 
@@ -22,6 +26,7 @@ crate::observing_model! {
         sessionId: i32; READ get_session_id,
         uuid: QString; READ get_uuid WRITE set_uuid,
         e164: QString; READ get_e164 WRITE set_e164,
+        username: QString; READ get_username WRITE set_username,
         ready: bool; READ get_ready,
         invalid: bool; READ get_invalid,
         hasName: bool; READ has_name,
@@ -40,7 +45,10 @@ impl EventObserving for CreateConversationImpl {
     }
 
     fn interests(&self) -> Vec<Interest> {
-        vec![Interest::whole_table(schema::sessions::table)]
+        vec![
+            Interest::whole_table(schema::sessions::table),
+            Interest::whole_table(schema::recipients::table),
+        ]
     }
 }
 
@@ -59,7 +67,7 @@ impl CreateConversationImpl {
 
     fn get_invalid(&self) -> bool {
         // XXX Also invalid when lookup failed
-        self.e164.is_none() && self.uuid.is_none()
+        self.e164.is_none() && self.uuid.is_none() && self.username.is_none()
     }
 
     fn fetch(&mut self, storage: Storage) {
@@ -67,8 +75,10 @@ impl CreateConversationImpl {
             storage.fetch_recipient_by_uuid(uuid)
         } else if let Some(e164) = &self.e164 {
             storage.fetch_recipient_by_phonenumber(e164)
+        } else if let Some(username) = &self.username {
+            storage.fetch_recipient_by_username(username)
         } else {
-            log::trace!("Neither e164 nor uuid set; not fetching.");
+            log::trace!("Neither e164, uuid nor username set; not fetching.");
             return;
         };
 
@@ -77,9 +87,14 @@ impl CreateConversationImpl {
                 self.name = Some(name.clone());
             } else if let Some(e164) = &recipient.e164 {
                 self.name = Some(e164.to_string());
+            } else if let Some(username) = &recipient.username {
+                self.name = Some(username.clone());
             }
 
             storage.fetch_or_insert_session_by_recipient_id(recipient.id)
+        } else if self.username.is_some() {
+            log::trace!("Username not looked up yet.");
+            return;
         } else {
             // XXX This most probably requires interaction.
             log::warn!("Not creating new recipients through this method.");
@@ -105,6 +120,7 @@ impl CreateConversationImpl {
             })
             .ok();
         self.e164 = None;
+        self.username = None;
         if let Some(ctx) = ctx {
             self.fetch(ctx.storage());
         }
@@ -119,11 +135,26 @@ impl CreateConversationImpl {
             })
             .ok();
         self.uuid = None;
+        self.username = None;
+        if let Some(ctx) = ctx {
+            self.fetch(ctx.storage());
+        }
+    }
+
+    fn set_username(&mut self, ctx: Option<ModelContext<Self>>, username: QString) {
+        let username = username.to_string();
+        self.username = Some(username.trim().to_lowercase()).filter(|u| !u.is_empty());
+        self.uuid = None;
+        self.e164 = None;
         if let Some(ctx) = ctx {
             self.fetch(ctx.storage());
         }
     }
 
+    fn get_username(&self) -> QString {
+        self.username.as_deref().unwrap_or_default().into()
+    }
+
     fn get_e164(&self) -> QString {
         self.e164
             .as_ref()
@@ -137,7 +168,7 @@ impl CreateConversationImpl {
     }
 
     fn init(&mut self, ctx: ModelContext<Self>) {
-        if self.e164.is_some() || self.uuid.is_some() {
+        if self.e164.is_some() || self.uuid.is_some() || self.username.is_some() {
             self.fetch(ctx.storage());
         }
     }
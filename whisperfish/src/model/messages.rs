@@ -35,6 +35,8 @@ crate::observing_model! {
         delivered Delivered,
         read Read,
         viewed Viewed,
+        viewedTimestamp ViewedTimestamp,
+        viewOnce ViewOnce,
 
         sent Sent,
         flags Flags,
@@ -111,6 +113,15 @@ impl MessageImpl {
 
     fn fetch_attachments(&mut self, storage: Storage, id: i32) {
         let attachments = storage.fetch_attachments_for_message(id);
+        let view_once = self.message.as_ref().map_or(false, |m| m.is_view_once);
+        for container in &[
+            &self.attachments,
+            &self.visual_attachments,
+            &self.detail_attachments,
+        ] {
+            container.pinned().borrow_mut().view_once = view_once;
+        }
+
         self.attachments
             .pinned()
             .borrow_mut()
@@ -314,6 +325,8 @@ define_model_roles! {
         Delivered(fn delivered(&self)):                       "delivered",
         Read(fn read(&self)):                                 "read",
         Viewed(fn viewed(&self)):                             "viewed",
+        ViewedTimestamp(viewed_timestamp via qdatetime_from_naive_option): "viewedTimestamp",
+        ViewOnce(is_view_once):                               "viewOnce",
 
         Sent(fn sent(&self)):                                 "sent",
         Flags(flags):                                         "flags",
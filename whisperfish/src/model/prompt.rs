@@ -14,6 +14,7 @@ pub struct Prompt {
     promptVerificationCode: qt_signal!(),
     promptPassword: qt_signal!(),
     promptCaptcha: qt_signal!(),
+    promptBackupPassphrase: qt_signal!(),
     showLinkQR: qt_signal!(),
 
     linkingQR: qt_property!(QString; NOTIFY qrChanged),
@@ -25,6 +26,7 @@ pub struct Prompt {
     verificationCode: qt_method!(fn(&self, code: QString)),
     password: qt_method!(fn(&self, password: QString)),
     captcha: qt_method!(fn(&self, captcha: QString)),
+    backupPassphrase: qt_method!(fn(&self, passphrase: QString)),
     resetPeerIdentity: qt_method!(fn(&self, confirm: QString)),
 
     startCaptcha: qt_method!(fn(&self)),
@@ -34,6 +36,7 @@ pub struct Prompt {
     code_listeners: Vec<futures::channel::oneshot::Sender<QString>>,
     phone_number_listeners: Vec<futures::channel::oneshot::Sender<QString>>,
     captcha_listeners: Vec<futures::channel::oneshot::Sender<QString>>,
+    backup_passphrase_listeners: Vec<futures::channel::oneshot::Sender<QString>>,
 }
 
 impl Prompt {
@@ -77,6 +80,16 @@ impl Prompt {
         }
     }
 
+    #[allow(non_snake_case)]
+    #[with_executor]
+    fn backupPassphrase(&mut self, passphrase: QString) {
+        for listener in self.backup_passphrase_listeners.drain(..) {
+            if listener.send(passphrase.clone()).is_err() {
+                log::warn!("Request for backup passphrase fulfilled, but nobody listens.");
+            }
+        }
+    }
+
     #[allow(non_snake_case)]
     #[with_executor]
     fn resetPeerIdentity(&self, _confirm: QString) {}
@@ -127,24 +140,27 @@ impl Prompt {
         }
     }
 
-    pub fn show_link_qr(&mut self, url: String) {
-        let code = qrcode::QrCode::new(url.as_str()).expect("to generate qrcode for linking URI");
-        let image_buf = code.render::<image::Luma<u8>>().build();
-
-        // Export generate QR code pixmap data into a PNG data:-URI string
-        let mut image_uri = String::from("data:image/png;base64,");
-        {
-            let mut image_b64enc =
-                base64::write::EncoderStringWriter::from(&mut image_uri, base64::STANDARD);
-            image::png::PngEncoder::new(&mut image_b64enc)
-                .encode(
-                    &image_buf,
-                    image_buf.width(),
-                    image_buf.height(),
-                    <image::Luma<u8> as image::Pixel>::COLOR_TYPE,
-                )
-                .expect("to write QR code image to data:-URI");
+    /// Asks for the passphrase of the backup to restore. An empty passphrase skips the restore.
+    pub fn ask_backup_passphrase(&mut self) -> impl Future<Output = Option<QString>> {
+        self.promptBackupPassphrase();
+
+        let (sender, receiver) = futures::channel::oneshot::channel();
+
+        self.backup_passphrase_listeners.push(sender);
+
+        async {
+            match receiver.await {
+                Ok(pwd) => Some(pwd),
+                Err(_e) => {
+                    log::error!("Backup passphrase prompt was canceled");
+                    None
+                }
+            }
         }
+    }
+
+    pub fn show_link_qr(&mut self, url: String) {
+        let image_uri = super::qr_code_data_uri(&url);
 
         self.linkingQR = QString::from(image_uri);
         self.qrChanged();
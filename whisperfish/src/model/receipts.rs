@@ -0,0 +1,171 @@
+#![allow(non_snake_case)]
+
+use std::collections::HashMap;
+
+use crate::model::*;
+use crate::store::observer::{EventObserving, Interest};
+use crate::store::{orm, schema, Storage};
+use qmetaobject::prelude::*;
+
+/// QML-constructable object that lists how far a single message got with each recipient.
+#[derive(Default, QObject)]
+pub struct ReceiptsImpl {
+    base: qt_base_class!(trait QObject),
+    message_id: Option<i32>,
+
+    receipt_list: QObjectBox<ReceiptListModel>,
+}
+
+crate::observing_model! {
+    pub struct Receipts(ReceiptsImpl) {
+        messageId: i32; READ get_message_id WRITE set_message_id,
+        valid: bool; READ get_valid,
+        receipts: QVariant; READ receipts,
+        count: i32; READ receipt_count,
+        failedCount: i32; READ failed_count,
+    }
+}
+
+impl EventObserving for ReceiptsImpl {
+    type Context = ModelContext<Self>;
+
+    fn observe(&mut self, ctx: Self::Context, event: crate::store::observer::Event) {
+        if let Some(message_id) = self.message_id {
+            self.receipt_list
+                .pinned()
+                .borrow_mut()
+                .observe(ctx, message_id, event);
+        }
+    }
+
+    fn interests(&self) -> Vec<Interest> {
+        self.message_id
+            .into_iter()
+            .map(|id| {
+                Interest::whole_table_with_relation(
+                    schema::receipts::table,
+                    schema::messages::table,
+                    id,
+                )
+            })
+            .chain(
+                self.receipt_list
+                    .pinned()
+                    .borrow()
+                    .receipts
+                    .iter()
+                    .flat_map(|(_receipt, recipient)| recipient.interests()),
+            )
+            .collect()
+    }
+}
+
+define_model_roles! {
+    pub(super) enum ReceiptRoles for orm::Receipt [with offset 100] {
+        MessageId(message_id): "messageId",
+        RecipientId(recipient_id): "recipientId",
+        Status(fn status(&self) via QString::from): "status",
+        Sent(sent via qdatetime_from_naive_option): "sentTime",
+        Delivered(delivered via qdatetime_from_naive_option): "deliveredTime",
+        Read(read via qdatetime_from_naive_option): "readTime",
+        Viewed(viewed via qdatetime_from_naive_option): "viewedTime",
+        Failed(failed via qdatetime_from_naive_option): "failedTime",
+    }
+}
+
+impl ReceiptsImpl {
+    fn get_message_id(&self) -> i32 {
+        self.message_id.unwrap_or(-1)
+    }
+
+    fn get_valid(&self) -> bool {
+        self.message_id.is_some()
+    }
+
+    fn receipt_count(&self) -> i32 {
+        self.receipt_list.pinned().borrow().row_count()
+    }
+
+    fn failed_count(&self) -> i32 {
+        self.receipt_list
+            .pinned()
+            .borrow()
+            .receipts
+            .iter()
+            .filter(|(receipt, _)| receipt.status() == "failed")
+            .count() as _
+    }
+
+    fn fetch(&mut self, storage: Storage, id: i32) {
+        self.receipt_list
+            .pinned()
+            .borrow_mut()
+            .load_all(storage, id);
+    }
+
+    fn set_message_id(&mut self, ctx: Option<ModelContext<Self>>, id: i32) {
+        self.message_id = Some(id);
+        if let Some(ctx) = ctx {
+            self.fetch(ctx.storage(), id);
+        }
+    }
+
+    fn init(&mut self, ctx: ModelContext<Self>) {
+        if let Some(id) = self.message_id {
+            self.fetch(ctx.storage(), id);
+        }
+    }
+
+    fn receipts(&self) -> QVariant {
+        self.receipt_list.pinned().into()
+    }
+}
+
+#[derive(QObject, Default)]
+pub struct ReceiptListModel {
+    base: qt_base_class!(trait QAbstractListModel),
+    receipts: Vec<(orm::Receipt, orm::Recipient)>,
+}
+
+impl ReceiptListModel {
+    fn load_all(&mut self, storage: Storage, message_id: i32) {
+        self.begin_reset_model();
+        self.receipts = storage.fetch_message_receipts(message_id);
+        self.receipts
+            .sort_by(|(_, a), (_, b)| a.name().cmp(&b.name()));
+        self.end_reset_model();
+    }
+
+    fn observe(
+        &mut self,
+        ctx: ModelContext<ReceiptsImpl>,
+        message_id: i32,
+        _event: crate::store::observer::Event,
+    ) {
+        self.load_all(ctx.storage(), message_id);
+    }
+}
+
+impl QAbstractListModel for ReceiptListModel {
+    fn row_count(&self) -> i32 {
+        self.receipts.len() as i32
+    }
+
+    fn data(&self, index: QModelIndex, role: i32) -> QVariant {
+        const OFFSET: i32 = 100;
+        if role >= OFFSET {
+            let role = ReceiptRoles::from(role - OFFSET);
+            role.get(&self.receipts[index.row() as usize].0)
+        } else {
+            let role = RecipientRoles::from(role);
+            role.get(&self.receipts[index.row() as usize].1)
+        }
+    }
+
+    fn role_names(&self) -> HashMap<i32, QByteArray> {
+        ReceiptRoles::role_names()
+            .into_iter()
+            .chain(RecipientRoles::role_names())
+            .collect()
+    }
+}
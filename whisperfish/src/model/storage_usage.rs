@@ -0,0 +1,117 @@
+#![allow(non_snake_case)]
+
+use crate::model::*;
+use crate::store::observer::{EventObserving, Interest};
+use crate::store::{schema, SessionDiskUsage, Storage};
+use qmetaobject::prelude::*;
+use std::collections::HashMap;
+
+/// QML-constructable object that reports the disk space taken by attachments, per session.
+///
+/// The sessions are ordered by the space they take, largest first.
+#[derive(Default, QObject)]
+pub struct StorageUsageImpl {
+    base: qt_base_class!(trait QObject),
+    usage_list: QObjectBox<StorageUsageListModel>,
+}
+
+crate::observing_model! {
+    pub struct StorageUsage(StorageUsageImpl) {
+        sessions: QVariant; READ sessions,
+
+        totalBytes: i64; READ total_bytes,
+        totalAttachments: i64; READ total_attachments,
+    }
+}
+
+impl StorageUsageImpl {
+    fn init(&mut self, ctx: ModelContext<Self>) {
+        self.usage_list
+            .pinned()
+            .borrow_mut()
+            .load_all(ctx.storage());
+    }
+
+    fn sessions(&self) -> QVariant {
+        self.usage_list.pinned().into()
+    }
+
+    fn total_bytes(&self) -> i64 {
+        self.usage_list
+            .pinned()
+            .borrow()
+            .content
+            .iter()
+            .map(|usage| usage.bytes)
+            .sum()
+    }
+
+    fn total_attachments(&self) -> i64 {
+        self.usage_list
+            .pinned()
+            .borrow()
+            .content
+            .iter()
+            .map(|usage| usage.attachment_count)
+            .sum()
+    }
+}
+
+impl EventObserving for StorageUsageImpl {
+    type Context = ModelContext<Self>;
+
+    fn observe(&mut self, ctx: Self::Context, _event: crate::store::observer::Event) {
+        self.usage_list
+            .pinned()
+            .borrow_mut()
+            .load_all(ctx.storage());
+    }
+
+    fn interests(&self) -> Vec<Interest> {
+        vec![
+            Interest::whole_table(schema::attachments::table),
+            Interest::whole_table(schema::messages::table),
+        ]
+    }
+}
+
+define_model_roles! {
+    enum StorageUsageRoles for SessionDiskUsage {
+        SessionId(session_id):               "sessionId",
+        AttachmentCount(attachment_count):   "attachmentCount",
+        Bytes(bytes):                        "bytes",
+    }
+}
+
+#[derive(QObject, Default)]
+pub struct StorageUsageListModel {
+    base: qt_base_class!(trait QAbstractListModel),
+    content: Vec<SessionDiskUsage>,
+
+    count: qt_property!(i32; NOTIFY countChanged READ row_count),
+    countChanged: qt_signal!(),
+}
+
+impl StorageUsageListModel {
+    fn load_all(&mut self, storage: Storage) {
+        self.begin_reset_model();
+        self.content = storage.fetch_session_disk_usage();
+        self.end_reset_model();
+        self.countChanged();
+    }
+}
+
+impl QAbstractListModel for StorageUsageListModel {
+    fn row_count(&self) -> i32 {
+        self.content.len() as i32
+    }
+
+    fn data(&self, index: QModelIndex, role: i32) -> QVariant {
+        let role = StorageUsageRoles::from(role);
+        role.get(&self.content[index.row() as usize])
+    }
+
+    fn role_names(&self) -> HashMap<i32, QByteArray> {
+        StorageUsageRoles::role_names()
+    }
+}
@@ -1,7 +1,9 @@
 pub mod client;
 
+mod dbus_api;
 mod profile_refresh;
 mod setup;
 
 pub use self::client::*;
+pub use self::dbus_api::*;
 pub use self::setup::*;
@@ -1,18 +1,30 @@
 // XXX maybe the session-to-db migration should move into the store module.
 pub mod migrations;
 
+mod connection;
+mod decryption_error;
+mod events;
 mod groupv2;
 mod linked_devices;
+mod outbox;
+mod pni;
 mod profile;
 mod profile_upload;
+mod sender_key;
 mod unidentified;
+mod username;
 
+pub use self::connection::*;
+pub use self::events::*;
 pub use self::groupv2::*;
 pub use self::linked_devices::*;
 use self::migrations::MigrationCondVar;
+pub use self::outbox::*;
+pub use self::pni::*;
 pub use self::profile::*;
 pub use self::profile_upload::*;
 use self::unidentified::UnidentifiedCertificates;
+pub use self::username::*;
 use libsignal_service::proto::data_message::{Delete, Quote};
 use libsignal_service::proto::sync_message::Sent;
 use libsignal_service::push_service::RegistrationMethod;
@@ -45,11 +57,11 @@ use libsignal_service::content::{
 };
 use libsignal_service::prelude::*;
 use libsignal_service::proto::typing_message::Action;
-use libsignal_service::proto::{receipt_message, ReceiptMessage};
+use libsignal_service::proto::{receipt_message, ReceiptMessage, SyncMessage};
 use libsignal_service::protocol::*;
 use libsignal_service::push_service::{
     AccountAttributes, DeviceCapabilities, DeviceId, RegistrationSessionMetadataResponse,
-    ServiceIds, VerificationTransport, VerifyAccountResponse,
+    ServiceIds, VerificationTransport, VerifyAccountResponse, DEFAULT_DEVICE_ID,
 };
 use libsignal_service::sender::AttachmentSpec;
 use libsignal_service::websocket::SignalWebSocket;
@@ -65,6 +77,7 @@ use std::fmt::{Display, Error, Formatter};
 use std::fs::remove_file;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::str::FromStr;
 use std::time::Duration;
 
@@ -74,24 +87,52 @@ const TM_MAX_RATE: f32 = 30.0; // messages per minute
 const TM_CACHE_CAPACITY: f32 = 5.0; // 5 min
 const TM_CACHE_TRESHOLD: f32 = 4.5; // 4 min 30 sec
 
+// How long the decrypted copy of view-once media is kept around for the viewer.
+const VIEW_ONCE_LINGER: Duration = Duration::from_secs(60);
+
+/// Queues a new outgoing message.  Returns the id of the message.
 #[derive(actix::Message, Debug)]
-#[rtype(result = "()")]
+#[rtype(result = "Result<i32, anyhow::Error>")]
 pub struct QueueMessage {
     pub session_id: i32,
     pub message: String,
-    pub attachment: String,
+    /// Attachments, in the order they should be displayed.
+    pub attachments: Vec<QueueAttachment>,
     pub quote: i32,
 }
 
+/// A local file to be sent along with a [QueueMessage].
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct QueueAttachment {
+    /// Path to the file. QML calls this `data`.
+    #[serde(alias = "data")]
+    pub path: String,
+    pub caption: Option<String>,
+    #[serde(alias = "voiceNote")]
+    pub is_voice_note: bool,
+    #[serde(alias = "borderless")]
+    pub is_borderless: bool,
+}
+
+impl QueueAttachment {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            ..Default::default()
+        }
+    }
+}
+
 impl Display for QueueMessage {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
         write!(
             f,
-            "QueueMessage {{ session_id: {}, message: \"{}\", quote: {}, attachment: \"{}\" }}",
+            "QueueMessage {{ session_id: {}, message: \"{}\", quote: {}, attachments: {} }}",
             &self.session_id,
             shorten(&self.message, 9),
             &self.quote,
-            &self.attachment,
+            &self.attachments.len(),
         )
     }
 }
@@ -114,6 +155,8 @@ struct DeliverMessage<T> {
     online: bool,
     for_story: bool,
     session: orm::Session,
+    /// Group members that already got the message, and are skipped.
+    sent_to: Vec<i32>,
 }
 
 #[derive(actix::Message)]
@@ -145,11 +188,64 @@ struct AttachmentDownloaded {
 #[rtype(result = "usize")]
 pub struct CompactDb(usize);
 
+#[derive(Message)]
+#[rtype(result = "()")]
+/// Reconcile the attachment and avatar directories with the database.
+///
+/// Orphaned files are moved into `storage/quarantine` when `quarantine` is set, and deleted
+/// otherwise.
+pub struct CollectGarbage {
+    pub quarantine: bool,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+/// Write a passphrase-encrypted backup of the whole account into the Downloads directory.
+///
+/// The backup can be restored when setting up Whisperfish on another device.
+pub struct ExportBackup {
+    pub passphrase: String,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+/// Change the password of the storage.
+///
+/// Without a new password the storage is decrypted; an unencrypted storage is encrypted when it
+/// gets one.
+pub struct ChangeStoragePassword {
+    pub old_password: Option<String>,
+    pub new_password: Option<String>,
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 /// Reset a session with a certain recipient
 pub struct EndSession(pub i32);
 
+#[derive(Message)]
+#[rtype(result = "()")]
+/// Mark an incoming message as viewed, and send a viewed receipt to its sender.
+///
+/// A voice note counts as viewed once it has been played.
+pub struct MarkMessageViewed(pub i32);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+/// Send a receipt of type `r#type` for the messages with `timestamps` to a recipient.
+pub struct SendReceipt {
+    pub recipient_id: i32,
+    pub r#type: receipt_message::Type,
+    pub timestamps: Vec<u64>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+/// Open the media of an incoming view-once message.
+///
+/// The decrypted copy is handed to the system viewer, after which the media is removed for good.
+pub struct OpenViewOnce(pub i32);
+
 #[derive(QObject, Default)]
 #[allow(non_snake_case)]
 pub struct ClientWorker {
@@ -174,15 +270,32 @@ pub struct ClientWorker {
     // FIXME: Rust "r#type" to Qt "type" doesn't work
     proofRequested: qt_signal!(token: QString, r#type: QString),
     proofCaptchaResult: qt_signal!(success: bool),
+    garbageCollected: qt_signal!(orphans: i32, bytes: i64),
+    backupExported: qt_signal!(path: QString),
+    backupFailed: qt_signal!(),
+    storagePasswordChanged: qt_signal!(encrypted: bool),
+    storagePasswordFailed: qt_signal!(),
+    usernameReserved: qt_signal!(username: QString),
+    usernameChanged: qt_signal!(),
+    usernameFailed: qt_signal!(),
+    usernameFound: qt_signal!(username: QString, uuid: QString),
+    usernameNotFound: qt_signal!(username: QString),
+    numberChangeCodeRequested: qt_signal!(),
+    numberChanged: qt_signal!(number: QString),
+    numberChangeFailed: qt_signal!(),
 
     send_typing_notification: qt_method!(fn(&self, id: i32, is_start: bool)),
     submit_proof_captcha: qt_method!(fn(&self, token: String, response: String)),
 
     connected: qt_property!(bool; NOTIFY connectedChanged),
     connectedChanged: qt_signal!(),
+    /// See [ConnectionState::as_str].
+    connectionState: qt_property!(QString; NOTIFY connectionStateChanged),
+    connectionStateChanged: qt_signal!(),
+    set_network_available: qt_method!(fn(&self, available: bool)),
+    reconnect: qt_method!(fn(&self)),
 
     actor: Option<Addr<ClientActor>>,
-    session_actor: Option<Addr<SessionActor>>,
     device_model: Option<QObjectBox<DeviceModel>>,
 
     // Linked device management
@@ -190,6 +303,21 @@ pub struct ClientWorker {
     unlink_device: qt_method!(fn(&self, id: i64)),
     reload_linked_devices: qt_method!(fn(&self)),
     compact_db: qt_method!(fn(&self)),
+    collect_garbage: qt_method!(fn(&self, quarantine: bool)),
+    export_backup: qt_method!(fn(&self, passphrase: String)),
+    change_storage_password: qt_method!(fn(&self, old_password: String, new_password: String)),
+
+    /// Our own username, empty if there is none.
+    username: qt_property!(QString; NOTIFY usernameChanged),
+    reserve_username: qt_method!(fn(&self, nickname: String)),
+    confirm_username: qt_method!(fn(&self)),
+    delete_username: qt_method!(fn(&self)),
+    find_username: qt_method!(fn(&self, username: String)),
+    username_link: qt_method!(fn(&self, username: String) -> QString),
+    username_qr: qt_method!(fn(&self, username: String) -> QString),
+
+    change_number: qt_method!(fn(&self, number: String, voice: bool)),
+    confirm_number_change: qt_method!(fn(&self, code: String)),
 
     refresh_group_v2: qt_method!(fn(&self, session_id: usize)),
 
@@ -203,7 +331,9 @@ pub struct ClientWorker {
 
 /// ClientActor keeps track of the connection state.
 pub struct ClientActor {
-    inner: QObjectBox<ClientWorker>,
+    events: Box<dyn ClientEvents>,
+    session_actor: Addr<SessionActor>,
+    connection: Connection,
 
     migration_state: MigrationCondVar,
 
@@ -212,6 +342,8 @@ pub struct ClientActor {
     local_addr: Option<ServiceAddress>,
     storage: Option<Storage>,
     ws: Option<SignalWebSocket>,
+    /// The websocket for sealed sender messages, opened along with the message pipe.
+    u_ws: Option<SignalWebSocket>,
     // XXX The cipher should be behind a Mutex.
     // By considering the session that needs to be accessed,
     // we could lock only a single session to enforce serialized access.
@@ -221,15 +353,26 @@ pub struct ClientActor {
     // Putting in behind a Mutex is a lot of work now though,
     // especially considering this should be *internal* to ServiceCipher.
     cipher: Option<ServiceCipher<crate::store::Storage, rand::rngs::ThreadRng>>,
+    /// Decrypts the messages to our phone number, see [crate::store::Storage::pni_store].
+    pni_cipher: Option<ServiceCipher<crate::store::Storage, rand::rngs::ThreadRng>>,
     config: std::sync::Arc<crate::config::SignalConfig>,
 
     transient_timestamps: HashSet<u64>,
+    /// Messages that are being sent right now, so that the outbox does not send them twice.
+    sending: HashSet<i32>,
+    /// Retry requests for messages that did not decrypt, see [decryption_error::RetryRequests].
+    retry_requests: decryption_error::RetryRequests,
 
     start_time: DateTime<Local>,
 
     outdated_profile_stream_handle: Option<SpawnHandle>,
 
+    /// Username reserved by [ReserveUsername], waiting for [ConfirmUsername].
+    reserved_username: Option<String>,
+
     registration_session: Option<RegistrationSessionMetadataResponse>,
+    /// The new phone number, between [RequestNumberChange] and [ConfirmNumberChange].
+    number_change: Option<(PhoneNumber, RegistrationSessionMetadataResponse)>,
 }
 
 fn whisperfish_device_capabilities() -> DeviceCapabilities {
@@ -256,30 +399,48 @@ impl ClientActor {
         app.set_object_property("ClientWorker".into(), inner.pinned());
         app.set_object_property("DeviceModel".into(), device_model.pinned());
 
-        inner.pinned().borrow_mut().session_actor = Some(session_actor);
         inner.pinned().borrow_mut().device_model = Some(device_model);
 
+        Self::with_events(Box::new(inner), session_actor, config)
+    }
+
+    /// Creates a `ClientActor` that reports to `events` instead of to QML, for instance for
+    /// command line tools.
+    pub fn with_events(
+        events: Box<dyn ClientEvents>,
+        session_actor: Addr<SessionActor>,
+        config: std::sync::Arc<crate::config::SignalConfig>,
+    ) -> Result<Self, anyhow::Error> {
         let transient_timestamps: HashSet<u64> =
             HashSet::with_capacity((TM_CACHE_CAPACITY * TM_MAX_RATE) as _);
 
         Ok(Self {
-            inner,
+            events,
+            session_actor,
+            connection: Connection::default(),
             migration_state: MigrationCondVar::new(),
             unidentified_certificates: UnidentifiedCertificates::default(),
             credentials: None,
             local_addr: None,
             storage: None,
             cipher: None,
+            pni_cipher: None,
             ws: None,
+            u_ws: None,
             config,
 
             transient_timestamps,
+            sending: HashSet::new(),
+            retry_requests: Default::default(),
 
             start_time: Local::now(),
 
             outdated_profile_stream_handle: None,
 
+            reserved_username: None,
+
             registration_session: None,
+            number_change: None,
         })
     }
 
@@ -313,6 +474,11 @@ impl ClientActor {
         self.authenticated_service_with_credentials(self.credentials.clone().unwrap())
     }
 
+    /// The settings of the account this actor runs for.
+    fn settings(&self) -> crate::config::SettingsBridge {
+        crate::config::SettingsBridge::for_account(self.config.account())
+    }
+
     fn message_sender(
         &self,
     ) -> impl Future<
@@ -320,17 +486,41 @@ impl ClientActor {
             MessageSender<AwcPushService, crate::store::Storage, rand::rngs::ThreadRng>,
             ServiceError,
         >,
+    > {
+        self.message_sender_as(self.local_addr.unwrap(), self.config.get_device_id())
+    }
+
+    /// A [MessageSender] that sends no transcripts to our other devices, because it does not
+    /// know about them.  For messages of which the transcript is sent separately.
+    fn message_sender_without_transcripts(
+        &self,
+    ) -> impl Future<
+        Output = Result<
+            MessageSender<AwcPushService, crate::store::Storage, rand::rngs::ThreadRng>,
+            ServiceError,
+        >,
+    > {
+        let nobody = ServiceAddress { uuid: Uuid::nil() };
+        self.message_sender_as(nobody, DEFAULT_DEVICE_ID.into())
+    }
+
+    fn message_sender_as(
+        &self,
+        local_addr: ServiceAddress,
+        device_id: libsignal_service::protocol::DeviceId,
+    ) -> impl Future<
+        Output = Result<
+            MessageSender<AwcPushService, crate::store::Storage, rand::rngs::ThreadRng>,
+            ServiceError,
+        >,
     > {
         let storage = self.storage.clone().unwrap();
         let service = self.authenticated_service();
-        let mut u_service = self.unauthenticated_service();
 
         let ws = self.ws.clone().unwrap();
+        let u_ws = self.u_ws.clone().unwrap();
         let cipher = self.cipher.clone().unwrap();
-        let local_addr = self.local_addr.unwrap();
-        let device_id = self.config.get_device_id();
         async move {
-            let u_ws = u_service.ws("/v1/websocket/", None, false).await?;
             Ok(MessageSender::new(
                 ws,
                 u_ws,
@@ -388,6 +578,7 @@ impl ClientActor {
             session,
             online: false,
             for_story: false,
+            sent_to: Vec::new(),
         });
 
         Some(())
@@ -409,20 +600,19 @@ impl ClientActor {
         metadata: &Metadata,
     ) -> Option<i32> {
         let timestamp = metadata.timestamp;
-        let settings = crate::config::SettingsBridge::default();
+        let settings = self.settings();
         let is_sync_sent = sync_sent.is_some();
 
         let mut storage = self.storage.clone().expect("storage");
-        let sender_recipient = if source_phonenumber.is_some() || source_uuid.is_some() {
-            Some(storage.merge_and_fetch_recipient(
+        // None for messages without a sender, e.g. our own synced messages.
+        let sender_recipient = storage
+            .merge_and_fetch_recipient(
                 source_phonenumber.clone(),
                 source_uuid,
                 None,
                 crate::store::TrustLevel::Certain,
-            ))
-        } else {
-            None
-        };
+            )
+            .ok();
 
         if msg.flags() & DataMessageFlags::EndSession as u32 != 0 {
             let storage = storage.clone();
@@ -442,15 +632,18 @@ impl ClientActor {
 
         if (source_phonenumber.is_some() || source_uuid.is_some()) && !is_sync_sent {
             if let Some(key) = msg.profile_key.as_deref() {
-                let (recipient, was_updated) = storage.update_profile_key(
+                match storage.update_profile_key(
                     source_phonenumber.clone(),
                     source_uuid,
                     None,
                     key,
                     crate::store::TrustLevel::Certain,
-                );
-                if was_updated {
-                    ctx.notify(RefreshProfile::ByRecipientId(recipient.id));
+                ) {
+                    Ok((recipient, true)) => {
+                        ctx.notify(RefreshProfile::ByRecipientId(recipient.id));
+                    }
+                    Ok((_, false)) => {}
+                    Err(e) => log::warn!("Could not update profile key: {:?}", e),
                 }
             }
         }
@@ -469,10 +662,8 @@ impl ClientActor {
                 reaction,
             ) {
                 log::info!("Reaction saved for message {}/{}", session.id, message.id);
-                self.inner
-                    .pinned()
-                    .borrow_mut()
-                    .messageReactionReceived(session.id, message.id);
+                self.events
+                    .message_reaction_received(session.id, message.id);
             } else {
                 log::error!("Could not find a message for this reaction. Dropping.");
                 log::warn!(
@@ -582,15 +773,16 @@ impl ClientActor {
             None
         };
 
-        let session = group.unwrap_or_else(|| {
-            let recipient = storage.merge_and_fetch_recipient(
-                source_phonenumber.clone(),
-                source_uuid,
-                None,
-                TrustLevel::Certain,
-            );
-            storage.fetch_or_insert_session_by_recipient_id(recipient.id)
-        });
+        let session = match group {
+            Some(group) => group,
+            None => match sender_recipient.as_ref() {
+                Some(recipient) => storage.fetch_or_insert_session_by_recipient_id(recipient.id),
+                None => {
+                    log::warn!("Message without sender nor group; dropping.");
+                    return None;
+                }
+            },
+        };
 
         if msg.flags() & DataMessageFlags::ExpirationTimerUpdate as u32 != 0 {
             storage.update_expiration_timer(session.id, msg.expire_timer);
@@ -620,6 +812,9 @@ impl ClientActor {
         };
 
         let message = storage.create_message(&new_message);
+        if msg.is_view_once() {
+            storage.mark_message_view_once(message.id);
+        }
 
         if settings.get_bool("attachment_log") && !msg.attachments.is_empty() {
             log::trace!("Logging message to the attachment log");
@@ -646,10 +841,7 @@ impl ClientActor {
             }
         }
 
-        self.inner
-            .pinned()
-            .borrow_mut()
-            .messageReceived(session.id, message.id);
+        self.events.message_received(session.id, message.id);
 
         // XXX If from ourselves, skip
         if !is_sync_sent && !session.is_muted {
@@ -659,24 +851,22 @@ impl ClientActor {
                 orm::SessionType::DirectMessage(recipient) => recipient.name(),
             };
 
-            self.inner.pinned().borrow_mut().notifyMessage(
-                session.id,
-                message.id,
-                session_name.as_ref().into(),
-                sender_recipient
+            self.events.notify_message(MessageNotification {
+                session_id: session.id,
+                message_id: message.id,
+                session_name: session_name.into_owned(),
+                sender_name: sender_recipient
                     .as_ref()
-                    .map(|x| x.name().as_ref().into())
-                    .unwrap_or_else(|| "".into()),
-                sender_recipient
+                    .map(|x| x.name().into_owned())
+                    .unwrap_or_default(),
+                sender_identifier: sender_recipient
                     .as_ref()
-                    .map(|x| x.e164_or_uuid().into())
-                    .unwrap_or_else(|| "".into()),
-                sender_recipient
-                    .map(|x| x.uuid().into())
-                    .unwrap_or_else(|| "".into()),
-                message.text.as_deref().unwrap_or("").into(),
-                session.is_group(),
-            );
+                    .map(|x| x.e164_or_uuid())
+                    .unwrap_or_default(),
+                sender_uuid: sender_recipient.map(|x| x.uuid()).unwrap_or_default(),
+                message: message.text.clone().unwrap_or_default(),
+                is_group: session.is_group(),
+            });
         }
         Some(message.id)
     }
@@ -688,6 +878,7 @@ impl ClientActor {
         let local_addr = self.local_addr.unwrap();
         let storage = self.storage.clone().unwrap();
         let sender = self.message_sender();
+        let is_primary = u32::from(self.config.get_device_id()) == DEFAULT_DEVICE_ID;
 
         actix::spawn(async move {
             let mut sender = sender.await?;
@@ -751,7 +942,9 @@ impl ClientActor {
                     anyhow::bail!("Unimplemented {:?}", req.r#type());
                 }
                 Type::PniIdentity => {
-                    anyhow::bail!("Unimplemented {:?}", req.r#type());
+                    // The PNI identity is the primary device's to hand out.
+                    anyhow::ensure!(is_primary, "PNI identity requested from a linked device");
+                    Self::send_pni_identity(&storage, &mut sender, &local_addr).await?;
                 },
             };
 
@@ -783,10 +976,7 @@ impl ClientActor {
             millis
         );
         if let Some((sess, msg)) = storage.mark_message_received(source.uuid, timestamp, None) {
-            self.inner
-                .pinned()
-                .borrow_mut()
-                .messageReceipt(sess.id, msg.id)
+            self.events.message_receipt(sess.id, msg.id)
         }
     }
 
@@ -882,15 +1072,49 @@ impl ClientActor {
                             read.timestamp()
                         );
                         if let Some((sess, msg)) = storage.mark_message_read(ts) {
-                            self.inner
-                                .pinned()
-                                .borrow_mut()
-                                .messageReceipt(sess.id, msg.id)
+                            self.events.message_receipt(sess.id, msg.id)
                         } else {
                             log::warn!("Could not mark as received!");
                         }
                     }
                 }
+                if let Some(view_once_open) = &message.view_once_open {
+                    handled = true;
+                    log::trace!("Sync view once open message");
+                    let ts = millis_to_naive_chrono(view_once_open.timestamp());
+                    // Timestamps are not unique, so the sender has to match too.
+                    let author = Uuid::parse_str(view_once_open.sender_uuid())
+                        .ok()
+                        .and_then(|uuid| storage.fetch_recipient_by_uuid(uuid));
+                    let self_id = storage.fetch_self_recipient().map(|r| r.id);
+                    let message = author.and_then(|author| {
+                        storage
+                            .fetch_messages_by_timestamp(ts)
+                            .into_iter()
+                            .find(|msg| {
+                                if Some(author.id) == self_id {
+                                    msg.is_outbound
+                                } else {
+                                    !msg.is_outbound && msg.sender_recipient_id == Some(author.id)
+                                }
+                            })
+                    });
+                    if let Some(msg) = message {
+                        storage.erase_view_once_attachments(msg.id);
+                        storage.mark_message_viewed(msg.id);
+                    } else {
+                        log::warn!(
+                            "Could not find view-once message of {} at {}",
+                            view_once_open.sender_uuid(),
+                            ts
+                        );
+                    }
+                }
+                if let Some(pni_identity) = &message.pni_identity {
+                    handled = true;
+                    log::trace!("Sync PNI identity");
+                    self.process_pni_identity(pni_identity, ctx);
+                }
                 if let Some(fetch) = message.fetch_latest {
                     handled = true;
                     match fetch.r#type() {
@@ -919,12 +1143,7 @@ impl ClientActor {
             ContentBody::TypingMessage(typing) => {
                 log::info!("{:?} is typing.", metadata.sender);
                 let res = self
-                    .inner
-                    .pinned()
-                    .borrow()
                     .session_actor
-                    .as_ref()
-                    .expect("session actor running")
                     .try_send(crate::actor::TypingNotification {
                         typing,
                         sender: metadata.sender,
@@ -937,16 +1156,19 @@ impl ClientActor {
                 log::info!("{:?} received a message.", metadata.sender);
                 // XXX dispatch on receipt.type
                 for &ts in &receipt.timestamp {
+                    // They have it, so they will not ask for it again.
+                    storage.forget_sent_content(
+                        metadata.sender.uuid,
+                        metadata.sender_device,
+                        millis_to_naive_chrono(ts),
+                    );
                     // Signal uses timestamps in milliseconds, chrono has nanoseconds
                     if let Some((sess, msg)) = storage.mark_message_received(
                         metadata.sender.uuid,
                         millis_to_naive_chrono(ts),
                         None,
                     ) {
-                        self.inner
-                            .pinned()
-                            .borrow_mut()
-                            .messageReceipt(sess.id, msg.id)
+                        self.events.message_receipt(sess.id, msg.id)
                     } else {
                         log::warn!("Could not mark {} as received!", ts);
                     }
@@ -974,14 +1196,11 @@ impl Actor for ClientActor {
     type Context = actix::Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        self.inner.pinned().borrow_mut().actor = Some(ctx.address());
+        self.events.started(ctx.address());
     }
 
-    fn stopped(&mut self, ctx: &mut Self::Context) {
-        self.inner.pinned().borrow_mut().actor = Some(ctx.address());
-
-        self.inner.pinned().borrow_mut().connected = false;
-        self.inner.pinned().borrow().connectedChanged();
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.set_connection_state(ConnectionState::Offline);
     }
 }
 
@@ -1032,7 +1251,7 @@ impl Handler<FetchAttachment> for ClientActor {
         // in this method, as well as the generated path.
         // We have this function that returns a filesystem path, so we can
         // set it ourselves.
-        let settings = crate::config::SettingsBridge::default();
+        let settings = self.settings();
         let dir = settings.get_string("attachment_dir");
         let dest = PathBuf::from(dir);
 
@@ -1051,14 +1270,20 @@ impl Handler<FetchAttachment> for ClientActor {
                 .unwrap(),
         };
 
+        // View-once media stays encrypted with the attachment key until it is opened.
+        let is_view_once = message.is_view_once;
+        if is_view_once {
+            ext = "bin";
+        }
+
         let ptr2 = attachment.clone();
         let attachment_id = attachment.id;
+        let is_voice_note = attachment.is_voice_note;
         let session_id = session.id;
         let message_id = message.id;
         Box::pin(
             async move {
                 use futures::io::AsyncReadExt;
-                use libsignal_service::attachment_cipher::*;
 
                 let mut stream = loop {
                     let r = service.get_attachment(&ptr).await;
@@ -1075,30 +1300,13 @@ impl Handler<FetchAttachment> for ClientActor {
                 // We need the whole file for the crypto to check out 😢
                 let actual_len = ptr.size.unwrap();
                 let mut ciphertext = Vec::with_capacity(actual_len as usize);
-                let stream_len = stream
+                stream
                     .read_to_end(&mut ciphertext)
                     .await
-                    .expect("streamed attachment") as u32;
+                    .expect("streamed attachment");
 
-                let key_material = ptr.key();
-                assert_eq!(
-                    key_material.len(),
-                    64,
-                    "key material for attachments is ought to be 64 bytes"
-                );
-                let mut key = [0u8; 64];
-                key.copy_from_slice(key_material);
-                decrypt_in_place(key, &mut ciphertext).expect("attachment decryption");
-
-                // Signal puts exponentially increasing padding at the end
-                // to prevent some distinguishing attacks, so it has to be truncated.
-                if stream_len > actual_len {
-                    log::info!(
-                        "The attachment contains {} bytes of padding",
-                        (stream_len - actual_len)
-                    );
-                    log::info!("Truncating from {} to {} bytes", stream_len, actual_len);
-                    ciphertext.truncate(actual_len as usize);
+                if !is_view_once {
+                    decrypt_attachment(&ptr, &mut ciphertext)?;
                 }
 
                 // Signal Desktop sometimes sends a JPEG image with .png extension,
@@ -1123,6 +1331,12 @@ impl Handler<FetchAttachment> for ClientActor {
                     .save_attachment(attachment_id, &dest, ext, &ciphertext)
                     .await?;
 
+                if is_voice_note {
+                    if let Some(attachment) = storage.fetch_attachment(attachment_id) {
+                        store_voice_note_summary(storage.clone(), attachment).await;
+                    }
+                }
+
                 client_addr
                     .send(AttachmentDownloaded {
                         session_id,
@@ -1151,13 +1365,21 @@ impl Handler<FetchAttachment> for ClientActor {
 }
 
 impl Handler<QueueMessage> for ClientActor {
-    type Result = ();
+    type Result = Result<i32, anyhow::Error>;
 
     fn handle(&mut self, msg: QueueMessage, ctx: &mut Self::Context) -> Self::Result {
         log::trace!("MessageActor::handle({})", msg);
         let storage = self.storage.as_mut().unwrap();
 
-        let has_attachment = !msg.attachment.is_empty();
+        for attachment in &msg.attachments {
+            anyhow::ensure!(
+                Path::new(&attachment.path).is_file(),
+                "Attachment {} is not a file",
+                attachment.path
+            );
+        }
+
+        let has_attachment = !msg.attachments.is_empty();
         let self_recipient = storage
             .fetch_self_recipient()
             .expect("self recipient set when sending");
@@ -1175,6 +1397,7 @@ impl Handler<QueueMessage> for ClientActor {
             None
         };
 
+        let attachments = msg.attachments;
         let msg = storage.create_message(&crate::store::NewMessage {
             session_id: msg.session_id,
             source_e164: self_recipient.e164,
@@ -1182,21 +1405,9 @@ impl Handler<QueueMessage> for ClientActor {
             text: msg.message,
             timestamp: chrono::Utc::now().naive_utc(),
             has_attachment,
-            mime_type: if has_attachment {
-                Some(
-                    mime_guess::from_path(&msg.attachment)
-                        .first_or_octet_stream()
-                        .essence_str()
-                        .into(),
-                )
-            } else {
-                None
-            },
-            attachment: if has_attachment {
-                Some(msg.attachment)
-            } else {
-                None
-            },
+            // Attachments are inserted below, such that they keep their order and captions.
+            mime_type: None,
+            attachment: None,
             flags: 0,
             outgoing: true,
             received: false,
@@ -1207,7 +1418,53 @@ impl Handler<QueueMessage> for ClientActor {
             expires_in: session.expiring_message_timeout,
         });
 
-        ctx.notify(SendMessage(msg.id));
+        let mut voice_notes = Vec::new();
+        for (order, attachment) in attachments.into_iter().enumerate() {
+            let mime_type = mime_guess::from_path(&attachment.path)
+                .first_or_octet_stream()
+                .essence_str()
+                .into();
+            let inserted = storage.insert_local_attachment(
+                msg.id,
+                order as i32,
+                &crate::store::NewAttachment {
+                    path: attachment.path,
+                    mime_type,
+                    caption: attachment.caption.filter(|c| !c.is_empty()),
+                    is_voice_note: attachment.is_voice_note,
+                    is_borderless: attachment.is_borderless,
+                },
+            );
+            let inserted = match inserted {
+                Ok(inserted) => inserted,
+                Err(e) => {
+                    // Do not send half an album; the user can retry once the file is back.
+                    storage.fail_message(msg.id);
+                    return Err(e);
+                }
+            };
+            if inserted.is_voice_note {
+                voice_notes.push(store_voice_note_summary(storage.clone(), inserted));
+            }
+        }
+
+        if voice_notes.is_empty() {
+            storage.enqueue_outgoing(msg.id);
+            ctx.notify(SendMessage(msg.id));
+        } else {
+            // The recipients should get the duration and waveform along with the voice note.
+            let storage = storage.clone();
+            let mid = msg.id;
+            ctx.spawn(
+                future::join_all(voice_notes)
+                    .into_actor(self)
+                    .map(move |_, _act, ctx| {
+                        storage.enqueue_outgoing(mid);
+                        ctx.notify(SendMessage(mid));
+                    }),
+            );
+        }
+        Ok(msg.id)
     }
 }
 
@@ -1217,6 +1474,15 @@ impl Handler<SendMessage> for ClientActor {
     // Equiv of worker/send.go
     fn handle(&mut self, SendMessage(mid): SendMessage, ctx: &mut Self::Context) -> Self::Result {
         log::info!("ClientActor::SendMessage({:?})", mid);
+        if self.sending.contains(&mid) {
+            log::debug!("Message {} is being sent already", mid);
+            return Box::pin(async {}.into_actor(self).map(|_, _, _| ()));
+        }
+        if !self.connection.state().is_connected() {
+            log::info!("Not connected; message {} waits in the outbox", mid);
+            return Box::pin(async {}.into_actor(self).map(|_, _, _| ()));
+        }
+
         let sender = self.message_sender();
         let storage = self.storage.as_mut().unwrap();
         let msg = storage.fetch_augmented_message(mid).unwrap();
@@ -1225,8 +1491,11 @@ impl Handler<SendMessage> for ClientActor {
 
         if msg.sent_timestamp.is_some() {
             log::warn!("Message already sent, refusing to retransmit.");
+            storage.remove_from_outbox(mid);
             return Box::pin(async {}.into_actor(self).map(|_, _, _| ()));
         }
+        // After a partial failure, only the remaining recipients get the message.
+        let sent_to = storage.fetch_message_sent_to(mid);
 
         let self_recipient = storage.fetch_self_recipient();
         log::trace!("Sending for session: {}", session);
@@ -1234,6 +1503,12 @@ impl Handler<SendMessage> for ClientActor {
 
         let storage = storage.clone();
         let addr = ctx.address();
+        self.sending.insert(mid);
+
+        let settings = self.settings();
+        let scale_images = settings.get_bool("scale_image_attachments");
+        let transform_dir = PathBuf::from(settings.get_string("attachment_dir"));
+
         Box::pin(
             async move {
                 let mut sender = sender.await?;
@@ -1286,16 +1561,36 @@ impl Handler<SendMessage> for ClientActor {
                 let attachments = storage.fetch_attachments_for_message(msg.id);
 
                 for attachment in &attachments {
-                    let attachment_path = attachment
-                        .attachment_path
-                        .clone() // Clone for the spawn_blocking below
-                        .expect("attachment path when uploading");
-                    let contents =
+                    // An earlier attempt to send this message may have uploaded it already.
+                    if let Some(ptr) = attachment.pointer.as_deref() {
+                        match AttachmentPointer::decode(ptr) {
+                            Ok(ptr) => {
+                                content.attachments.push(ptr);
+                                continue;
+                            }
+                            Err(e) => log::warn!(
+                                "Uploading attachment {} again, its pointer is invalid: {}",
+                                attachment.id,
+                                e
+                            ),
+                        }
+                    }
+                    let (attachment_path, width, height) = prepare_attachment_upload(
+                        &storage,
+                        attachment,
+                        scale_images,
+                        &transform_dir,
+                    )
+                    .await?;
+                    let contents = {
+                        // Clone for the spawn_blocking below
+                        let attachment_path = attachment_path.clone();
                         tokio::task::spawn_blocking(move || std::fs::read(attachment_path))
                             .await
                             .context("threadpool")?
-                            .context("reading attachment")?;
-                    let attachment_path = attachment.attachment_path.as_deref().unwrap();
+                            .context("reading attachment")?
+                    };
+                    let attachment_path = attachment_path.as_str();
                     let spec = AttachmentSpec {
                         content_type: match mime_guess::from_path(attachment_path).first() {
                             Some(mime) => mime.essence_str().into(),
@@ -1308,17 +1603,15 @@ impl Handler<SendMessage> for ClientActor {
                         preview: None,
                         voice_note: Some(attachment.is_voice_note),
                         borderless: Some(attachment.is_borderless),
-                        width: attachment.width.map(|x| x as u32),
-                        height: attachment.height.map(|x| x as u32),
-                        caption: None,
+                        width,
+                        height,
+                        caption: attachment.caption.clone(),
                         blur_hash: None,
                     };
-                    let ptr = match sender.upload_attachment(spec, contents).await {
-                        Ok(v) => v,
-                        Err(e) => {
-                            anyhow::bail!("Failed to upload attachment: {}", e);
-                        }
-                    };
+                    let ptr = sender
+                        .upload_attachment(spec, contents)
+                        .await
+                        .context("Failed to upload attachment")?;
                     storage.store_attachment_pointer(attachment.id, &ptr);
                     content.attachments.push(ptr);
                 }
@@ -1330,6 +1623,7 @@ impl Handler<SendMessage> for ClientActor {
                         timestamp,
                         session,
                         for_story: false,
+                        sent_to,
                     })
                     .await?;
 
@@ -1341,11 +1635,13 @@ impl Handler<SendMessage> for ClientActor {
                         });
 
                         // Look for Ok recipients that couldn't deliver on unidentified.
+                        let sent_at = chrono::Utc::now().naive_utc();
                         for result in results.iter().filter_map(|res| res.as_ref().ok()) {
                             // Look up recipient to check the current state
                             let recipient = storage
                                 .fetch_recipient_by_uuid(result.recipient.uuid)
                                 .expect("sent recipient in db");
+                            storage.mark_message_sent_to(mid, recipient.id, sent_at);
                             let target_state = if result.unidentified {
                                 // Unrestricted and success; keep unrestricted
                                 if recipient.unidentified_access_mode
@@ -1381,8 +1677,13 @@ impl Handler<SendMessage> for ClientActor {
 
                             Ok((session_id, mid, msg.inner.text))
                         } else {
-                            storage.fail_message(mid);
+                            let failed = storage.mark_unsent_recipients_failed(mid);
+                            log::debug!("Message {} failed for {} recipient(s)", mid, failed);
                             let result_count = results.len();
+                            let transient = results
+                                .iter()
+                                .filter_map(|res| res.as_ref().err())
+                                .all(is_transient_sender_error);
                             for error in results.into_iter().filter_map(Result::err) {
                                 log::error!("Could not deliver message: {}", error);
                                 match error {
@@ -1414,34 +1715,39 @@ impl Handler<SendMessage> for ClientActor {
                                 };
                             }
                             log::error!("Successfully delivered message to {} out of {} recipients", successes, result_count);
+                            if transient {
+                                return Err(TransientSendFailure {
+                                    failed: result_count - successes,
+                                    total: result_count,
+                                }
+                                .into());
+                            }
                             anyhow::bail!("Could not deliver message.")
                         }
                     }
-                    Err(e) => {
-                        storage.fail_message(mid);
-                        Err(e)
-                    }
+                    Err(e) => Err(e),
                 }
             }
             .into_actor(self)
-            .map(move |res, act, _ctx| {
+            .map(move |res, act, ctx| {
+                act.sending.remove(&mid);
                 match res {
                     Ok((sid, mid, message)) => {
-                        act.inner.pinned().borrow().messageSent(
-                            sid,
-                            mid,
-                            message.unwrap_or_default().into(),
-                        );
+                        act.events
+                            .message_sent(sid, mid, message.as_deref().unwrap_or_default());
                     }
                     Err(e) => {
                         log::error!("Sending message: {}", e);
-                        act.inner.pinned().borrow().messageNotSent(session_id, mid);
+                        if act.schedule_retry(mid, &e) {
+                            return;
+                        }
+                        let storage = act.storage.as_ref().unwrap();
+                        storage.mark_unsent_recipients_failed(mid);
+                        storage.fail_message(mid);
+                        act.events.message_not_sent(session_id, mid);
                         if let Some(MessageSenderError::NotFound { .. }) = e.downcast_ref() {
                             // Handles session-is-not-a-group ok
-                            act.inner
-                                .pinned()
-                                .borrow()
-                                .refresh_group_v2(session_id as _);
+                            ctx.notify(RequestGroupV2InfoBySessionId(session_id));
                         }
                     }
                 };
@@ -1450,6 +1756,356 @@ impl Handler<SendMessage> for ClientActor {
     }
 }
 
+/// Decrypts a downloaded attachment in place, and strips the padding Signal appends.
+fn decrypt_attachment(ptr: &AttachmentPointer, data: &mut Vec<u8>) -> anyhow::Result<()> {
+    use libsignal_service::attachment_cipher::decrypt_in_place;
+
+    let key_material = ptr.key();
+    assert_eq!(
+        key_material.len(),
+        64,
+        "key material for attachments is ought to be 64 bytes"
+    );
+    let mut key = [0u8; 64];
+    key.copy_from_slice(key_material);
+    decrypt_in_place(key, data).map_err(|e| anyhow::anyhow!("attachment decryption: {:?}", e))?;
+
+    // Signal puts exponentially increasing padding at the end
+    // to prevent some distinguishing attacks, so it has to be truncated.
+    let actual_len = ptr.size() as usize;
+    if data.len() > actual_len {
+        log::info!(
+            "The attachment contains {} bytes of padding",
+            data.len() - actual_len
+        );
+        log::info!("Truncating from {} to {} bytes", data.len(), actual_len);
+        data.truncate(actual_len);
+    }
+    Ok(())
+}
+
+/// Decrypts the stored media of a view-once message into a temporary file.
+fn decrypt_view_once(attachment: &orm::Attachment) -> anyhow::Result<PathBuf> {
+    let path = attachment
+        .attachment_path
+        .as_deref()
+        .context("view-once attachment without path")?;
+    let ptr = AttachmentPointer::decode(
+        attachment
+            .pointer
+            .as_deref()
+            .context("view-once attachment without pointer")?,
+    )?;
+
+    let mut data =
+        std::fs::read(path).with_context(|| format!("Could not read view-once media {}", path))?;
+    decrypt_attachment(&ptr, &mut data)?;
+
+    let mime = if attachment.content_type == "application/x-signal-view-once" {
+        MimeClassifier::new()
+            .classify(
+                LoadContext::Browsing,
+                NoSniffFlag::Off,
+                ApacheBugFlag::Off,
+                &None,
+                &data as &[u8],
+            )
+            .to_string()
+    } else {
+        attachment.content_type.clone()
+    };
+    let ext = mime_guess::get_mime_extensions_str(&mime)
+        .and_then(|exts| exts.first())
+        .copied()
+        .unwrap_or("bin");
+
+    let dir = dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("harbour-whisperfish");
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Could not create directory {}", dir.display()))?;
+    let mut target = dir.join(Uuid::new_v4().as_simple().to_string());
+    target.set_extension(ext);
+    std::fs::write(&target, &data)
+        .with_context(|| format!("Could not write view-once media {}", target.display()))?;
+
+    Ok(target)
+}
+
+/// Compute the duration and waveform of a voice note attachment, and store them.
+///
+/// The attachment is marked as voice note, even if the summary could not be computed.
+async fn store_voice_note_summary(storage: Storage, attachment: orm::Attachment) {
+    let summary = match attachment.attachment_path.clone() {
+        Some(path) => tokio::task::spawn_blocking(move || {
+            crate::media::voice_note::summarize(Path::new(&path))
+        })
+        .await
+        .context("threadpool")
+        .and_then(|summary| summary)
+        .map(Some),
+        None => Ok(None),
+    };
+    match summary {
+        Ok(Some(summary)) => storage.store_voice_note_summary(
+            attachment.id,
+            Some(summary.duration),
+            Some(&summary.waveform),
+        ),
+        Ok(None) => storage.store_voice_note_summary(attachment.id, None, None),
+        Err(e) => {
+            log::warn!(
+                "Could not compute voice note summary of attachment {}: {}",
+                attachment.id,
+                e
+            );
+            storage.store_voice_note_summary(attachment.id, None, None);
+        }
+    }
+}
+
+/// Apply the media preprocessing to an outgoing attachment, if applicable.
+///
+/// Returns the path of the file to upload, and its dimensions.
+/// The transformation is recorded in the database, such that a retry reuses the transformed file.
+async fn prepare_attachment_upload(
+    storage: &Storage,
+    attachment: &orm::Attachment,
+    scale_images: bool,
+    transform_dir: &Path,
+) -> Result<(String, Option<u32>, Option<u32>), anyhow::Error> {
+    let original = attachment
+        .attachment_path
+        .clone()
+        .expect("attachment path when uploading");
+    let dimensions = (
+        attachment.width.map(|x| x as u32),
+        attachment.height.map(|x| x as u32),
+    );
+
+    if let Some((path, props)) = attachment
+        .transform_properties
+        .as_deref()
+        .and_then(crate::media::TransformProperties::from_json)
+        .and_then(|props| Some((props.upload_path(&original)?, props)))
+    {
+        log::trace!("Reusing earlier transform of attachment {}", attachment.id);
+        return Ok((path, Some(props.width), Some(props.height)));
+    }
+
+    let is_image = !attachment.is_voice_note
+        && mime_guess::from_path(&original)
+            .first()
+            .map(|mime| crate::media::is_transformable_image(mime.essence_str()))
+            .unwrap_or(false);
+    if !is_image {
+        return Ok((original, dimensions.0, dimensions.1));
+    }
+
+    let props = {
+        let original = PathBuf::from(&original);
+        let transform_dir = transform_dir.to_owned();
+        tokio::task::spawn_blocking(move || {
+            crate::media::transform_image(&original, &transform_dir, scale_images)
+        })
+        .await
+        .context("threadpool")?
+        .context("preprocessing image attachment")?
+    };
+
+    match props {
+        Some(props) => {
+            storage.store_attachment_transform(attachment.id, &props.to_json());
+            let path = props.transformed_path.unwrap_or(original);
+            Ok((path, Some(props.width), Some(props.height)))
+        }
+        None => Ok((original, dimensions.0, dimensions.1)),
+    }
+}
+
+impl Handler<MarkMessageViewed> for ClientActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        MarkMessageViewed(mid): MarkMessageViewed,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("ClientActor::MarkMessageViewed({})", mid);
+        let storage = self.storage.as_ref().unwrap();
+
+        let message = match storage.mark_message_viewed(mid) {
+            Some(message) => message,
+            None => {
+                log::trace!("Message {} was already viewed", mid);
+                return;
+            }
+        };
+        if message.is_outbound {
+            return;
+        }
+
+        let sender = match message
+            .sender_recipient_id
+            .and_then(|id| storage.fetch_recipient_by_id(id))
+        {
+            Some(sender) => sender,
+            None => {
+                log::warn!("Viewed message {} has no sender, not sending receipt", mid);
+                return;
+            }
+        };
+        let session = storage.fetch_or_insert_session_by_recipient_id(sender.id);
+
+        let content = ReceiptMessage {
+            r#type: Some(receipt_message::Type::Viewed as _),
+            timestamp: vec![message.server_timestamp.timestamp_millis() as u64],
+        };
+
+        ctx.notify(DeliverMessage {
+            content,
+            timestamp: Utc::now().timestamp_millis() as u64,
+            session,
+            online: false,
+            for_story: false,
+            sent_to: Vec::new(),
+        });
+    }
+}
+
+impl Handler<SendReceipt> for ClientActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        SendReceipt {
+            recipient_id,
+            r#type,
+            timestamps,
+        }: SendReceipt,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!(
+            "ClientActor::SendReceipt({}, {:?}, {:?})",
+            recipient_id,
+            r#type,
+            timestamps
+        );
+        let storage = self.storage.as_ref().unwrap();
+        let session = storage.fetch_or_insert_session_by_recipient_id(recipient_id);
+
+        let content = ReceiptMessage {
+            r#type: Some(r#type as _),
+            timestamp: timestamps,
+        };
+
+        ctx.notify(DeliverMessage {
+            content,
+            timestamp: Utc::now().timestamp_millis() as u64,
+            session,
+            online: false,
+            for_story: false,
+            sent_to: Vec::new(),
+        });
+    }
+}
+
+impl Handler<OpenViewOnce> for ClientActor {
+    type Result = ();
+
+    fn handle(&mut self, OpenViewOnce(mid): OpenViewOnce, ctx: &mut Self::Context) -> Self::Result {
+        log::trace!("ClientActor::OpenViewOnce({})", mid);
+        let storage = self.storage.clone().unwrap();
+
+        let message = match storage.fetch_message_by_id(mid) {
+            Some(message) if message.is_view_once => message,
+            _ => {
+                log::error!("Message {} is not a view-once message", mid);
+                return;
+            }
+        };
+        if message.viewed_timestamp.is_some() {
+            log::warn!("View-once message {} has already been opened", mid);
+            return;
+        }
+
+        let attachment = storage
+            .fetch_attachments_for_message(mid)
+            .into_iter()
+            .find(|attachment| attachment.attachment_path.is_some());
+        let opened = match attachment {
+            Some(attachment) => decrypt_view_once(&attachment),
+            None => Err(anyhow::anyhow!("view-once media has not been downloaded")),
+        };
+
+        // Whether or not decryption worked out, this was the one chance to open the media.
+        storage.erase_view_once_attachments(mid);
+        ctx.notify(MarkMessageViewed(mid));
+
+        match opened {
+            Ok(path) => {
+                // Waiting for the viewer would block the actor.
+                match Command::new("xdg-open").arg(&path).spawn() {
+                    Ok(mut child) => {
+                        tokio::task::spawn_blocking(move || child.wait());
+                    }
+                    Err(e) => log::error!("Could not open view-once media: {}", e),
+                }
+                // Give the viewer some time to load the file, then remove the plain text copy.
+                ctx.run_later(VIEW_ONCE_LINGER, move |_act, _ctx| {
+                    if let Err(e) = remove_file(&path) {
+                        log::warn!("Could not remove {}: {}", path.display(), e);
+                    }
+                });
+            }
+            Err(e) => log::error!("Could not open view-once message {}: {:?}", mid, e),
+        }
+
+        // Let our other devices know the media has been consumed.
+        let sender_uuid = if message.is_outbound {
+            storage.fetch_self_recipient().and_then(|r| r.uuid)
+        } else {
+            message
+                .sender_recipient_id
+                .and_then(|id| storage.fetch_recipient_by_id(id))
+                .and_then(|r| r.uuid)
+        };
+        let sender_uuid = match sender_uuid {
+            Some(uuid) => uuid,
+            None => {
+                log::warn!("View-once message {} has no sender UUID, not syncing", mid);
+                return;
+            }
+        };
+
+        let content = SyncMessage {
+            view_once_open: Some(sync_message::ViewOnceOpen {
+                sender_uuid: Some(sender_uuid.to_string()),
+                timestamp: Some(message.server_timestamp.timestamp_millis() as u64),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let local_addr = self.local_addr.unwrap();
+        let sender = self.message_sender();
+        actix::spawn(
+            async move {
+                let mut sender = sender.await?;
+                let now = Utc::now().timestamp_millis() as u64;
+                sender
+                    .send_message(&local_addr, None, content, now, false)
+                    .await?;
+                Ok::<_, anyhow::Error>(())
+            }
+            .map(|v| {
+                if let Err(e) = v {
+                    log::error!("{:?} in OpenViewOnce", e)
+                }
+            }),
+        );
+    }
+}
+
 impl Handler<EndSession> for ClientActor {
     type Result = ();
 
@@ -1480,6 +2136,7 @@ impl Handler<EndSession> for ClientActor {
             quote_timestamp: None,
             expires_in: session.expiring_message_timeout,
         });
+        storage.enqueue_outgoing(msg.id);
         ctx.notify(SendMessage(msg.id));
     }
 }
@@ -1544,6 +2201,7 @@ impl Handler<SendTypingNotification> for ClientActor {
                     timestamp: now,
                     session,
                     for_story: false,
+                    sent_to: Vec::new(),
                 })
                 .await?
                 .map(|_unidentified| session_id)
@@ -1647,6 +2305,7 @@ impl Handler<SendReaction> for ClientActor {
                     timestamp: now.timestamp_millis() as u64,
                     session,
                     for_story: false,
+                    sent_to: Vec::new(),
                 })
                 .await?
                 .map(|_| (emoji, now, self_recipient.id))
@@ -1699,23 +2358,40 @@ impl Handler<ReactionSent> for ClientActor {
 impl<T: Into<ContentBody>> Handler<DeliverMessage<T>> for ClientActor {
     type Result = ResponseFuture<Result<Vec<SendMessageResult>, anyhow::Error>>;
 
-    fn handle(&mut self, msg: DeliverMessage<T>, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: DeliverMessage<T>, ctx: &mut Self::Context) -> Self::Result {
         let DeliverMessage {
             content,
             timestamp,
             online,
             session,
             for_story,
+            sent_to,
         } = msg;
         let content = content.into();
 
         log::trace!("Transmitting {:?} with timestamp {}", content, timestamp);
+        // Kept around in case a recipient cannot decrypt it, see decryption_error.rs.
+        let resendable = match &content {
+            ContentBody::DataMessage(_) => Some(content.clone().into_proto().encode_to_vec()),
+            _ => None,
+        };
 
         let storage = self.storage.clone().unwrap();
         let sender = self.message_sender();
+        let quiet_sender = self.message_sender_without_transcripts();
         let local_addr = self.local_addr.unwrap();
+        let local_device =
+            ProtocolAddress::new(local_addr.uuid.to_string(), self.config.get_device_id());
+        let is_primary = u32::from(self.config.get_device_id()) == DEFAULT_DEVICE_ID;
+        let ws = self.ws.clone();
+        let u_ws = self.u_ws.clone();
+        let local_pni = self.config.get_pni();
 
         let certs = self.unidentified_certificates.clone();
+        if certs.get(CertType::Complete).is_none() {
+            log::warn!("No valid sender certificate, sending without sealed sender");
+            ctx.notify(unidentified::RotateUnidentifiedCertificates);
+        }
 
         Box::pin(async move {
             let mut sender = sender.await?;
@@ -1732,13 +2408,18 @@ impl<T: Into<ContentBody>> Handler<DeliverMessage<T>> for ClientActor {
                         .filter_map(|(_member, recipient)| {
                             let member = recipient.to_service_address();
 
-                            if !recipient.is_registered || Some(local_addr) == member {
+                            if !recipient.is_registered
+                                || Some(local_addr) == member
+                                || sent_to.contains(&recipient.id)
+                            {
                                 None
                             } else if let Some(member) = member {
                                 // XXX change the cert type when we want to introduce E164 privacy.
                                 let access =
                                     certs.access_for(CertType::Complete, recipient, for_story);
-                                Some((member, access))
+                                let sender_key_access =
+                                    sender_key::sender_key_access(&certs, recipient, for_story);
+                                Some((member, access, sender_key_access))
                             } else {
                                 log::warn!(
                                     "No known UUID for {}; will not deliver this message.",
@@ -1748,10 +2429,99 @@ impl<T: Into<ContentBody>> Handler<DeliverMessage<T>> for ClientActor {
                             }
                         })
                         .collect::<Vec<_>>();
-                    // Clone + async closure means we can use an immutable borrow.
-                    sender
-                        .send_message_to_group(&members, content, timestamp, online)
-                        .await
+
+                    let sender_key_members = members
+                        .iter()
+                        .filter_map(|(member, _, key)| key.map(|key| (*member, key)))
+                        .collect::<Vec<_>>();
+                    let mut results = Vec::new();
+                    // Set when some members got the message with our sender key.
+                    let mut sender_key_message = None;
+                    let mut one_by_one: Vec<ServiceAddress> =
+                        members.iter().map(|(member, _, _)| *member).collect();
+                    if let (Some(certificate), ContentBody::DataMessage(message)) =
+                        (certs.get(CertType::Complete), &content)
+                    {
+                        if sender_key_members.len() >= sender_key::MIN_SENDER_KEY_RECIPIENTS {
+                            let sent = async {
+                                sender_key::send_with_sender_key(
+                                    storage.clone(),
+                                    u_ws.clone().context("Not connected")?,
+                                    certificate.clone(),
+                                    local_device,
+                                    &group.id,
+                                    sender_key_members,
+                                    &content,
+                                    timestamp,
+                                    online,
+                                )
+                                .await
+                            };
+                            match sent.await {
+                                Ok((sent, remaining)) => {
+                                    log::debug!(
+                                        "Sent to {} member(s) with sender key, {} remaining",
+                                        sent.len(),
+                                        remaining.len()
+                                    );
+                                    if !sent.is_empty() {
+                                        sender_key_message = Some(message.clone());
+                                    }
+                                    results = sent;
+                                    one_by_one = remaining;
+                                }
+                                Err(e) => {
+                                    log::warn!(
+                                        "Could not send with sender key, sending one by one: {:#}",
+                                        e
+                                    );
+                                }
+                            }
+                        }
+                    }
+
+                    let members = members
+                        .into_iter()
+                        .filter(|(member, _, _)| one_by_one.contains(member))
+                        .map(|(member, access, _)| (member, access))
+                        .collect::<Vec<_>>();
+                    if !members.is_empty() {
+                        let sent = if sender_key_message.is_some() {
+                            // Our other devices get a single transcript for the whole group below.
+                            let mut quiet_sender = quiet_sender.await?;
+                            quiet_sender
+                                .send_message_to_group(&members, content, timestamp, online)
+                                .await
+                        } else {
+                            sender
+                                .send_message_to_group(&members, content, timestamp, online)
+                                .await
+                        };
+                        results.extend(sent);
+                    }
+                    if let Some(message) = sender_key_message {
+                        // The library does not know about the members that got it with our sender
+                        // key, so our other devices get the transcript from here.
+                        match storage.get_sub_device_sessions(&local_addr).await {
+                            Ok(devices) if is_primary && devices.is_empty() => {}
+                            Ok(_) => {
+                                let transcript =
+                                    sender_key::sent_transcript(&message, timestamp, &results);
+                                if let Err(e) = sender
+                                    .send_message(&local_addr, None, transcript, timestamp, false)
+                                    .await
+                                {
+                                    log::error!(
+                                        "Could not send the transcript of message {} to our other devices: {}",
+                                        timestamp,
+                                        e
+                                    );
+                                }
+                            }
+                            Err(e) => log::error!("Could not look up our other devices: {}", e),
+                        }
+                    }
+                    results
                 }
                 orm::SessionType::DirectMessage(recipient) => {
                     let svc = recipient.to_service_address();
@@ -1763,16 +2533,62 @@ impl<T: Into<ContentBody>> Handler<DeliverMessage<T>> for ClientActor {
                             anyhow::bail!("Unregistered recipient {}", svc.uuid.to_string());
                         }
 
-                        vec![
-                            sender
-                                .send_message(&svc, access, content.clone(), timestamp, online)
-                                .await,
-                        ]
+                        let result = sender
+                            .send_message(&svc, access, content.clone(), timestamp, online)
+                            .await;
+                        if result.is_ok()
+                            && recipient.needs_pni_signature
+                            && matches!(content, ContentBody::DataMessage(_))
+                        {
+                            // They only know our phone number.
+                            let sealing = certs
+                                .get(CertType::Complete)
+                                .cloned()
+                                .zip(sender_key::sender_key_access(&certs, recipient, false));
+                            let sent = async {
+                                let local_pni = local_pni.context("Our PNI is unknown")?;
+                                let ws = match &sealing {
+                                    Some(_) => u_ws.context("Not connected")?,
+                                    None => ws.context("Not connected")?,
+                                };
+                                pni::send_pni_signature(&storage, ws, sealing, svc, local_pni).await
+                            };
+                            match sent.await {
+                                Ok(()) => {
+                                    storage.set_recipient_needs_pni_signature(recipient.id, false);
+                                }
+                                Err(e) => log::warn!(
+                                    "Could not send our PNI signature to {}: {:#}",
+                                    svc.uuid,
+                                    e
+                                ),
+                            }
+                        }
+                        vec![result]
                     } else {
                         anyhow::bail!("Recipient id {} has no UUID", recipient.id);
                     }
                 }
             };
+            if let Some(resendable) = resendable {
+                // Sending set up a session with every device that got it.
+                let mut recipients = Vec::new();
+                for sent in results.iter().filter_map(|result| result.as_ref().ok()) {
+                    let uuid = sent.recipient.uuid;
+                    recipients.push((uuid, DEFAULT_DEVICE_ID));
+                    match storage.get_sub_device_sessions(&sent.recipient).await {
+                        Ok(devices) => {
+                            recipients.extend(devices.into_iter().map(|device| (uuid, device)))
+                        }
+                        Err(e) => log::warn!("Could not look up the devices of {}: {}", uuid, e),
+                    }
+                }
+                storage.log_sent_content(
+                    millis_to_naive_chrono(timestamp),
+                    &resendable,
+                    &recipients,
+                );
+            }
             Ok(results)
         })
     }
@@ -1790,7 +2606,7 @@ impl Handler<AttachmentDownloaded> for ClientActor {
         _ctx: &mut Self::Context,
     ) {
         log::info!("Attachment downloaded for message {}", mid);
-        self.inner.pinned().borrow().attachmentDownloaded(sid, mid);
+        self.events.attachment_downloaded(sid, mid);
     }
 }
 
@@ -1799,6 +2615,7 @@ impl Handler<StorageReady> for ClientActor {
 
     fn handle(&mut self, storageready: StorageReady, _ctx: &mut Self::Context) -> Self::Result {
         self.storage = Some(storageready.storage.clone());
+        self.unidentified_certificates = UnidentifiedCertificates::load(&storageready.storage);
         let phonenumber = self
             .config
             .get_tel()
@@ -1806,7 +2623,11 @@ impl Handler<StorageReady> for ClientActor {
         let uuid = self.config.get_uuid();
         let device_id = self.config.get_device_id();
 
-        storageready.storage.mark_pending_messages_failed();
+        let username = storageready
+            .storage
+            .fetch_self_recipient()
+            .and_then(|recipient| recipient.username);
+        self.events.username_changed(username.as_deref());
 
         let storage_for_password = storageready.storage;
         let request_password = async move {
@@ -1847,74 +2668,25 @@ impl Handler<StorageReady> for ClientActor {
                 );
                 // end signal service context
                 act.cipher = Some(cipher);
+                act.pni_cipher = act.new_pni_cipher();
                 act.local_addr = Some(ServiceAddress { uuid });
 
                 Self::queue_migrations(ctx);
 
-                ctx.notify(Restart);
+                act.start_outbox(ctx);
+                ctx.notify(Connect);
 
                 ctx.notify(RefreshPreKeys);
+                ctx.notify(RefreshPniPreKeys);
+
+                ctx.run_interval(unidentified::ROTATION_CHECK_INTERVAL, |_act, ctx| {
+                    ctx.notify(unidentified::RotateUnidentifiedCertificates)
+                });
             },
         ))
     }
 }
 
-#[derive(Message)]
-#[rtype(result = "()")]
-struct Restart;
-
-impl Handler<Restart> for ClientActor {
-    type Result = ResponseActFuture<Self, ()>;
-
-    fn handle(&mut self, _: Restart, _ctx: &mut Self::Context) -> Self::Result {
-        let service = self.authenticated_service();
-        let credentials = self.credentials.clone().unwrap();
-        let migrations_ready = self.migration_state.ready();
-
-        self.inner.pinned().borrow_mut().connected = false;
-        self.inner.pinned().borrow().connectedChanged();
-        Box::pin(
-            async move {
-                migrations_ready.await;
-                let mut receiver = MessageReceiver::new(service.clone());
-
-                let pipe = receiver.create_message_pipe(credentials).await?;
-                let ws = pipe.ws();
-                Result::<_, ServiceError>::Ok((pipe, ws))
-            }
-            .into_actor(self)
-            .map(move |pipe, act, ctx| match pipe {
-                Ok((pipe, ws)) => {
-                    ctx.notify(unidentified::RotateUnidentifiedCertificates);
-                    ctx.add_stream(pipe.stream());
-
-                    ctx.set_mailbox_capacity(1);
-                    act.inner.pinned().borrow_mut().connected = true;
-                    act.ws = Some(ws);
-                    act.inner.pinned().borrow().connectedChanged();
-
-                    // If profile stream was running, restart.
-                    if let Some(handle) = act.outdated_profile_stream_handle.take() {
-                        ctx.cancel_future(handle);
-                    }
-                    act.outdated_profile_stream_handle = Some(
-                        ctx.add_stream(OutdatedProfileStream::new(act.storage.clone().unwrap())),
-                    );
-                }
-                Err(e) => {
-                    log::error!("Error starting stream: {}", e);
-                    log::info!("Retrying in 10");
-                    let addr = ctx.address();
-                    actix::spawn(async move {
-                        actix::clock::sleep(Duration::from_secs(10)).await;
-                        addr.send(Restart).await.expect("retry restart");
-                    });
-                }
-            }),
-        )
-    }
-}
-
 /// Queue a force-refresh of a profile fetch
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -1975,12 +2747,20 @@ impl StreamHandler<Result<Envelope, ServiceError>> for ClientActor {
             }
         };
 
-        let mut cipher = self.cipher.clone().expect("cipher initialized");
+        let (mut cipher, to_pni) = self.cipher_for(&msg);
 
         if msg.is_receipt() {
             self.process_receipt(&msg);
         }
 
+        if decryption_error::is_plaintext_content(&msg) {
+            ctx.notify(decryption_error::ProcessUndecrypted {
+                envelope: msg,
+                to_pni,
+            });
+            return;
+        }
+
         if !(msg.is_prekey_signal_message()
             || msg.is_signal_message()
             || msg.is_unidentified_sender()
@@ -1990,6 +2770,7 @@ impl StreamHandler<Result<Envelope, ServiceError>> for ClientActor {
         }
 
         let storage = self.storage.clone().expect("initialized storage");
+        let client = ctx.address();
 
         ctx.spawn(
             async move {
@@ -2039,6 +2820,14 @@ impl StreamHandler<Result<Envelope, ServiceError>> for ClientActor {
                                 return None;
                             }
                         }
+                        Err(e) if decryption_error::is_decryption_error(&e) => {
+                            log::error!("Could not decrypt envelope: {:?}", e);
+                            client.do_send(decryption_error::ProcessUndecrypted {
+                                envelope: msg,
+                                to_pni,
+                            });
+                            return None;
+                        }
                         Err(e) => {
                             log::error!("Error opening envelope: {:?}", e);
                             return None;
@@ -2051,8 +2840,14 @@ impl StreamHandler<Result<Envelope, ServiceError>> for ClientActor {
                 Some(content)
             }
             .into_actor(self)
-            .map(|content, act, ctx| {
+            .map(move |content, act, ctx| {
                 if let Some(content) = content {
+                    // Whoever writes to our phone number should get our PNI signature, which
+                    // links it to our ACI.  Writing to our ACI shows they know it already.
+                    let storage = act.storage.as_ref().unwrap();
+                    let sender =
+                        storage.fetch_or_insert_recipient_by_uuid(content.metadata.sender.uuid);
+                    storage.set_recipient_needs_pni_signature(sender.id, to_pni);
                     act.process_envelope(content, ctx);
                 }
             }),
@@ -2061,12 +2856,7 @@ impl StreamHandler<Result<Envelope, ServiceError>> for ClientActor {
 
     /// Called when the WebSocket somehow has disconnected.
     fn finished(&mut self, ctx: &mut Self::Context) {
-        log::debug!("Attempting reconnect");
-
-        self.inner.pinned().borrow_mut().connected = false;
-        self.inner.pinned().borrow().connectedChanged();
-
-        ctx.notify(Restart);
+        self.connection_lost(ctx);
     }
 }
 
@@ -2421,6 +3211,42 @@ impl ClientWorker {
         });
     }
 
+    #[with_executor]
+    pub fn collect_garbage(&self, quarantine: bool) {
+        let actor = self.actor.clone().unwrap();
+        actix::spawn(async move {
+            if let Err(e) = actor.send(CollectGarbage { quarantine }).await {
+                log::error!("{:?} in collect_garbage()", e);
+            }
+        });
+    }
+
+    #[with_executor]
+    pub fn export_backup(&self, passphrase: String) {
+        let actor = self.actor.clone().unwrap();
+        actix::spawn(async move {
+            if let Err(e) = actor.send(ExportBackup { passphrase }).await {
+                log::error!("{:?} in export_backup()", e);
+            }
+        });
+    }
+
+    /// Empty passwords stand for an unencrypted storage.
+    #[with_executor]
+    pub fn change_storage_password(&self, old_password: String, new_password: String) {
+        let actor = self.actor.clone().unwrap();
+        let non_empty = |password: String| Some(password).filter(|p| !p.is_empty());
+        let msg = ChangeStoragePassword {
+            old_password: non_empty(old_password),
+            new_password: non_empty(new_password),
+        };
+        actix::spawn(async move {
+            if let Err(e) = actor.send(msg).await {
+                log::error!("{:?} in change_storage_password()", e);
+            }
+        });
+    }
+
     #[with_executor]
     pub fn delete_file(&self, file_name: String) {
         let result = remove_file(&file_name);
@@ -2519,6 +3345,127 @@ impl Handler<CompactDb> for ClientActor {
     }
 }
 
+impl Handler<CollectGarbage> for ClientActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(
+        &mut self,
+        CollectGarbage { quarantine }: CollectGarbage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("handle(CollectGarbage)");
+        let storage = self.storage.clone().unwrap();
+        let settings = self.settings();
+
+        // Pictures taken with the camera belong to the user, not to us.
+        let attachment_dirs = [PathBuf::from(settings.get_string("attachment_dir"))];
+        let avatar_dir = PathBuf::from(settings.get_string("avatar_dir"));
+        let action = if quarantine {
+            let quarantine = self
+                .config
+                .get_share_dir()
+                .join("storage")
+                .join("quarantine");
+            crate::store::OrphanAction::Quarantine(quarantine)
+        } else {
+            crate::store::OrphanAction::Delete
+        };
+
+        Box::pin(
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    storage.collect_garbage(
+                        &attachment_dirs,
+                        Some(&avatar_dir),
+                        &action,
+                        crate::store::GARBAGE_GRACE_PERIOD,
+                    )
+                })
+                .await?
+            }
+            .into_actor(self)
+            .map(|result, act, _ctx| match result {
+                Ok(report) => act
+                    .events
+                    .garbage_collected(report.orphans.len(), report.orphan_bytes),
+                Err(e) => log::error!("Collecting garbage failed: {:?}", e),
+            }),
+        )
+    }
+}
+
+impl Handler<ExportBackup> for ClientActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(
+        &mut self,
+        ExportBackup { passphrase }: ExportBackup,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("handle(ExportBackup)");
+        let storage = self.storage.clone().unwrap();
+        let settings = self.settings();
+        let avatar_dir = PathBuf::from(settings.get_string("avatar_dir"));
+        let target = dirs::download_dir()
+            .unwrap_or_else(|| self.config.get_share_dir())
+            .join(crate::store::account_backup_file_name(
+                self.config.account(),
+            ));
+
+        Box::pin(
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    storage
+                        .export_backup(&target, &passphrase, Some(&avatar_dir))
+                        .map(|_| target)
+                })
+                .await?
+            }
+            .into_actor(self)
+            .map(|result, act, _ctx| match result {
+                Ok(target) => act.events.backup_exported(&target),
+                Err(e) => {
+                    log::error!("Exporting backup failed: {:?}", e);
+                    act.events.backup_failed();
+                }
+            }),
+        )
+    }
+}
+
+impl Handler<ChangeStoragePassword> for ClientActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(
+        &mut self,
+        ChangeStoragePassword {
+            old_password,
+            new_password,
+        }: ChangeStoragePassword,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("handle(ChangeStoragePassword)");
+        let storage = self.storage.clone().unwrap();
+
+        Box::pin(
+            async move {
+                storage
+                    .change_password(old_password.as_deref(), new_password.as_deref())
+                    .await
+                    .map(|()| storage.is_encrypted())
+            }
+            .into_actor(self)
+            .map(|result, act, _ctx| match result {
+                Ok(encrypted) => act.events.storage_password_changed(encrypted),
+                Err(e) => {
+                    log::error!("Changing the storage password failed: {:?}", e);
+                    act.events.storage_password_failed();
+                }
+            }),
+        )
+    }
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct ProofRequired {
@@ -2530,10 +3477,7 @@ impl Handler<ProofRequired> for ClientActor {
     type Result = ();
 
     fn handle(&mut self, proof: ProofRequired, _ctx: &mut Self::Context) -> Self::Result {
-        self.inner
-            .pinned()
-            .borrow()
-            .proofRequested(proof.token.into(), proof.r#type.into());
+        self.events.proof_requested(&proof.token, &proof.r#type);
     }
 }
 
@@ -2595,10 +3539,7 @@ impl Handler<ProofAccepted> for ClientActor {
     type Result = ();
 
     fn handle(&mut self, accepted: ProofAccepted, _ctx: &mut Self::Context) {
-        self.inner
-            .pinned()
-            .borrow_mut()
-            .proofCaptchaResult(accepted.result);
+        self.events.proof_captcha_result(accepted.result);
     }
 }
 
@@ -2641,6 +3582,7 @@ impl Handler<DeleteMessageForAll> for ClientActor {
                 ..Default::default()
             },
             for_story: false,
+            sent_to: Vec::new(),
             timestamp: now,
             online: false,
             session,
@@ -2780,11 +3722,78 @@ mod tests {
     #[test]
     fn queue_message() {
         let q = QueueMessage {
-            attachment: "Attachment!".into(),
+            attachments: vec![QueueAttachment::new("Attachment!")],
             session_id: 8,
             message: "Lorem ipsum dolor sit amet".into(),
             quote: 12,
         };
-        assert_eq!(format!("{}", q), "QueueMessage { session_id: 8, message: \"Lorem ips...\", quote: 12, attachment: \"Attachment!\" }");
+        assert_eq!(
+            format!("{}", q),
+            "QueueMessage { session_id: 8, message: \"Lorem ips...\", quote: 12, attachments: 1 }"
+        );
+    }
+
+    #[test]
+    fn queue_attachments_from_qml() {
+        let json = r#"[
+            {"data": "/tmp/a.jpg", "type": "image/jpeg", "caption": "Look!"},
+            {"path": "/tmp/b.aac", "voiceNote": true}
+        ]"#;
+        let attachments: Vec<QueueAttachment> = serde_json::from_str(json).unwrap();
+        assert_eq!(attachments.len(), 2);
+        assert_eq!(attachments[0].path, "/tmp/a.jpg");
+        assert_eq!(attachments[0].caption.as_deref(), Some("Look!"));
+        assert!(!attachments[0].is_voice_note);
+        assert_eq!(attachments[1].path, "/tmp/b.aac");
+        assert!(attachments[1].is_voice_note);
+        assert!(!attachments[1].is_borderless);
+    }
+
+    /// Records the events it receives.
+    #[derive(Clone, Default)]
+    struct RecordedEvents(std::rc::Rc<std::cell::RefCell<Vec<String>>>);
+
+    impl ClientEvents for RecordedEvents {
+        fn proof_requested(&self, token: &str, r#type: &str) {
+            self.0
+                .borrow_mut()
+                .push(format!("proof_requested {} {}", token, r#type));
+        }
+
+        fn proof_captcha_result(&self, success: bool) {
+            self.0
+                .borrow_mut()
+                .push(format!("proof_captcha_result {}", success));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn events_without_qml() {
+        let events = RecordedEvents::default();
+        let client = ClientActor::with_events(
+            Box::new(events.clone()),
+            SessionActor::headless().start(),
+            std::sync::Arc::new(crate::config::SignalConfig::default()),
+        )
+        .unwrap()
+        .start();
+
+        client
+            .send(ProofRequired {
+                token: "token".into(),
+                r#type: "recaptcha".into(),
+            })
+            .await
+            .unwrap();
+        client.send(ProofAccepted { result: true }).await.unwrap();
+        assert!(!client.send(IsConnected).await.unwrap());
+
+        assert_eq!(
+            *events.0.borrow(),
+            vec![
+                "proof_requested token recaptcha",
+                "proof_captcha_result true"
+            ]
+        );
     }
 }
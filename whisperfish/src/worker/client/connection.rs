@@ -0,0 +1,484 @@
+//! The connection to the Signal servers, as a state machine.
+//!
+//! [ClientActor] keeps one message pipe open, and next to it the unidentified websocket that sealed
+//! sender messages go out on.  When either breaks, or stops answering keep-alives,
+//! the actor waits before connecting again, doubling the wait after every failed attempt up to
+//! [MAX_BACKOFF].  While the network is down nothing is attempted, and when it comes back the
+//! actor connects right away.  When the server refuses our credentials, retrying does not help,
+//! so the actor stops until it is asked to [Reconnect].  When the account, or this device, is no
+//! longer registered, not even [Reconnect] helps: the actor stays [ConnectionState::Deregistered]
+//! until Whisperfish is registered or linked again.
+
+use super::*;
+use libsignal_service::messagepipe::MessagePipe;
+use libsignal_service::proto::WebSocketRequestMessage;
+use rand::Rng;
+
+/// Delay before the first reconnect.
+const MIN_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// How often to check that the message pipe is still alive.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+/// A keep-alive that is not answered within this time counts as a broken pipe.
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(20);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Not connected and not trying, e.g. because the network is down.
+    Offline,
+    Connecting,
+    Connected,
+    /// The last attempt failed, the next one starts after a delay.
+    BackingOff,
+    /// The server refused our credentials.
+    AuthFailed,
+    /// The account, or this device, is no longer registered.
+    Deregistered,
+}
+
+impl ConnectionState {
+    /// The name under which QML knows this state.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Offline => "offline",
+            Self::Connecting => "connecting",
+            Self::Connected => "connected",
+            Self::BackingOff => "backingOff",
+            Self::AuthFailed => "authFailed",
+            Self::Deregistered => "deregistered",
+        }
+    }
+
+    pub fn is_connected(self) -> bool {
+        self == Self::Connected
+    }
+
+    /// The state a failed connection attempt leads to.
+    fn after_failure(e: &ServiceError) -> Self {
+        match e {
+            ServiceError::Unauthorized | ServiceError::UnhandledResponseCode { http_code: 401 } => {
+                Self::AuthFailed
+            }
+            ServiceError::UnhandledResponseCode { http_code: 403 } => Self::Deregistered,
+            _ => Self::BackingOff,
+        }
+    }
+}
+
+/// Exponential backoff, with some jitter so that clients do not all reconnect at once after an
+/// outage.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    /// Counts a failure, and returns how long to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = (0..self.failures.min(16))
+            .fold(MIN_BACKOFF, |delay, _| delay * 2)
+            .min(MAX_BACKOFF);
+        self.failures += 1;
+
+        let millis = delay.as_millis() as u64;
+        let jitter = rand::thread_rng().gen_range(0, millis / 4 + 1);
+        Duration::from_millis(millis - jitter)
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// The connection part of [ClientActor].
+pub struct Connection {
+    state: ConnectionState,
+    backoff: Backoff,
+    network_available: bool,
+    /// Counts the message pipes, so that late answers to a keep-alive on an old pipe are ignored.
+    generation: u32,
+
+    attempt: Option<SpawnHandle>,
+    pipe: Option<SpawnHandle>,
+    reconnect: Option<SpawnHandle>,
+    keepalive: Option<SpawnHandle>,
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Self {
+            state: ConnectionState::Offline,
+            backoff: Backoff::default(),
+            // Until the platform tells otherwise.
+            network_available: true,
+            generation: 0,
+
+            attempt: None,
+            pipe: None,
+            reconnect: None,
+            keepalive: None,
+        }
+    }
+}
+
+impl Connection {
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Cancels everything that is running or scheduled for this connection.
+    fn cancel(&mut self, ctx: &mut <ClientActor as Actor>::Context) {
+        for handle in vec![
+            self.attempt.take(),
+            self.pipe.take(),
+            self.reconnect.take(),
+            self.keepalive.take(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            ctx.cancel_future(handle);
+        }
+    }
+}
+
+impl ClientActor {
+    pub(super) fn set_connection_state(&mut self, state: ConnectionState) {
+        if self.connection.state == state {
+            return;
+        }
+        log::info!(
+            "Connection state: {} -> {}",
+            self.connection.state.as_str(),
+            state.as_str()
+        );
+        self.connection.state = state;
+        self.events.connection_state_changed(state);
+    }
+
+    /// Called when the message pipe broke, or stopped answering keep-alives.
+    pub(super) fn connection_lost(&mut self, ctx: &mut <Self as Actor>::Context) {
+        log::warn!("Lost the connection to the Signal servers.");
+        self.connection.cancel(ctx);
+        self.schedule_reconnect(ctx);
+    }
+
+    fn schedule_reconnect(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if !self.connection.network_available {
+            self.set_connection_state(ConnectionState::Offline);
+            return;
+        }
+
+        let delay = self.connection.backoff.next_delay();
+        log::info!(
+            "Reconnecting in {:.1}s (attempt {}).",
+            delay.as_secs_f32(),
+            self.connection.backoff.failures()
+        );
+        self.set_connection_state(ConnectionState::BackingOff);
+        if let Some(handle) = self.connection.reconnect.take() {
+            ctx.cancel_future(handle);
+        }
+        self.connection.reconnect = Some(ctx.run_later(delay, |act, ctx| {
+            act.connection.reconnect = None;
+            ctx.notify(Connect);
+        }));
+    }
+
+    fn pipe_opened(
+        &mut self,
+        pipe: MessagePipe,
+        ws: SignalWebSocket,
+        u_ws: SignalWebSocket,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        self.connection.backoff.reset();
+        self.connection.generation += 1;
+
+        ctx.notify(unidentified::RotateUnidentifiedCertificates);
+        self.connection.pipe = Some(ctx.add_stream(pipe.stream()));
+
+        ctx.set_mailbox_capacity(1);
+        self.ws = Some(ws);
+        self.u_ws = Some(u_ws);
+        self.set_connection_state(ConnectionState::Connected);
+
+        self.connection.keepalive =
+            Some(ctx.run_interval(KEEPALIVE_INTERVAL, |act, ctx| act.send_keepalive(ctx)));
+
+        // If profile stream was running, restart.
+        if let Some(handle) = self.outdated_profile_stream_handle.take() {
+            ctx.cancel_future(handle);
+        }
+        self.outdated_profile_stream_handle =
+            Some(ctx.add_stream(OutdatedProfileStream::new(self.storage.clone().unwrap())));
+
+        // Whatever waited for the connection can go out now.
+        self.storage.as_ref().unwrap().retry_outbox_now();
+        ctx.notify(ProcessOutbox);
+    }
+
+    fn send_keepalive(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let (ws, u_ws) = match (self.connection.state, self.ws.clone(), self.u_ws.clone()) {
+            (ConnectionState::Connected, Some(ws), Some(u_ws)) => (ws, u_ws),
+            _ => return,
+        };
+        let generation = self.connection.generation;
+
+        let keepalive = |mut ws: SignalWebSocket| async move {
+            let request = WebSocketRequestMessage {
+                verb: Some("GET".into()),
+                path: Some("/v1/keepalive".into()),
+                ..Default::default()
+            };
+            actix::clock::timeout(KEEPALIVE_TIMEOUT, ws.request(request)).await
+        };
+        ctx.spawn(
+            future::join(keepalive(ws), keepalive(u_ws))
+                .into_actor(self)
+                .map(move |(pipe, unidentified), act, ctx| {
+                    if generation != act.connection.generation
+                        || act.connection.state != ConnectionState::Connected
+                    {
+                        return;
+                    }
+                    let mut alive = true;
+                    for (name, result) in vec![("pipe", pipe), ("unidentified", unidentified)] {
+                        match result {
+                            Ok(Ok(response)) if response.status() == 200 => {
+                                log::trace!("Keep-alive ({}) answered.", name);
+                                continue;
+                            }
+                            Ok(Ok(response)) => log::warn!(
+                                "Keep-alive ({}) answered with status {}.",
+                                name,
+                                response.status()
+                            ),
+                            Ok(Err(e)) => log::warn!("Keep-alive ({}) failed: {}", name, e),
+                            Err(_) => log::warn!("Keep-alive ({}) not answered in time.", name),
+                        }
+                        alive = false;
+                    }
+                    if !alive {
+                        act.connection_lost(ctx);
+                    }
+                }),
+        );
+    }
+}
+
+/// Opens the message pipe, unless it is open or opening already.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub(super) struct Connect;
+
+impl Handler<Connect> for ClientActor {
+    type Result = ();
+
+    fn handle(&mut self, _: Connect, ctx: &mut Self::Context) {
+        if let Some(handle) = self.connection.reconnect.take() {
+            ctx.cancel_future(handle);
+        }
+        match self.connection.state {
+            ConnectionState::Connecting | ConnectionState::Connected => return,
+            ConnectionState::Deregistered => {
+                log::warn!("Not connecting, this device is no longer registered.");
+                return;
+            }
+            _ => {}
+        }
+        if !self.connection.network_available {
+            log::info!("Not connecting, the network is down.");
+            self.set_connection_state(ConnectionState::Offline);
+            return;
+        }
+
+        let service = self.authenticated_service();
+        let mut u_service = self.unauthenticated_service();
+        let credentials = self.credentials.clone().unwrap();
+        let migrations_ready = self.migration_state.ready();
+
+        self.set_connection_state(ConnectionState::Connecting);
+        let attempt = ctx.spawn(
+            async move {
+                migrations_ready.await;
+                let mut receiver = MessageReceiver::new(service.clone());
+
+                let pipe = receiver.create_message_pipe(credentials).await?;
+                let ws = pipe.ws();
+                let u_ws = u_service.ws("/v1/websocket/", None, false).await?;
+                Result::<_, ServiceError>::Ok((pipe, ws, u_ws))
+            }
+            .into_actor(self)
+            .map(move |pipe, act, ctx| {
+                act.connection.attempt = None;
+                match pipe {
+                    Ok((pipe, ws, u_ws)) => act.pipe_opened(pipe, ws, u_ws, ctx),
+                    Err(e) => {
+                        log::error!("Error starting stream: {}", e);
+                        match ConnectionState::after_failure(&e) {
+                            ConnectionState::BackingOff => act.schedule_reconnect(ctx),
+                            state => act.set_connection_state(state),
+                        }
+                    }
+                }
+            }),
+        );
+        self.connection.attempt = Some(attempt);
+    }
+}
+
+/// Connects now, instead of waiting for the next attempt.  Also retries after an authentication
+/// failure, but not once the device is deregistered.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Reconnect;
+
+impl Handler<Reconnect> for ClientActor {
+    type Result = ();
+
+    fn handle(&mut self, _: Reconnect, ctx: &mut Self::Context) {
+        self.connection.backoff.reset();
+        ctx.notify(Connect);
+    }
+}
+
+/// The network came up or went down, as reported by the platform.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct NetworkAvailability {
+    pub available: bool,
+}
+
+impl Handler<NetworkAvailability> for ClientActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: NetworkAvailability, ctx: &mut Self::Context) {
+        if msg.available == self.connection.network_available {
+            return;
+        }
+        self.connection.network_available = msg.available;
+
+        if msg.available {
+            log::info!("The network is available.");
+            self.connection.backoff.reset();
+            if matches!(
+                self.connection.state,
+                ConnectionState::Offline | ConnectionState::BackingOff
+            ) && self.credentials.is_some()
+            {
+                ctx.notify(Connect);
+            }
+        } else {
+            log::info!("The network went down.");
+            self.connection.cancel(ctx);
+            if !matches!(
+                self.connection.state,
+                ConnectionState::AuthFailed | ConnectionState::Deregistered
+            ) {
+                self.set_connection_state(ConnectionState::Offline);
+            }
+        }
+    }
+}
+
+/// Asks whether the message pipe is connected, for clients that cannot watch the `connected`
+/// property.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct IsConnected;
+
+impl Handler<IsConnected> for ClientActor {
+    type Result = bool;
+
+    fn handle(&mut self, _: IsConnected, _ctx: &mut Self::Context) -> bool {
+        self.connection.state.is_connected()
+    }
+}
+
+/// Asks for the state of the connection.
+#[derive(Message)]
+#[rtype(result = "ConnectionState")]
+pub struct GetConnectionState;
+
+impl Handler<GetConnectionState> for ClientActor {
+    type Result = MessageResult<GetConnectionState>;
+
+    fn handle(&mut self, _: GetConnectionState, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.connection.state)
+    }
+}
+
+// methods called from Qt
+impl ClientWorker {
+    #[with_executor]
+    pub fn set_network_available(&self, available: bool) {
+        let actor = match self.actor.clone() {
+            Some(actor) => actor,
+            // Not started yet; it assumes the network is available.
+            None => return,
+        };
+        actix::spawn(async move {
+            if let Err(e) = actor.send(NetworkAvailability { available }).await {
+                log::error!("{:?} in set_network_available()", e);
+            }
+        });
+    }
+
+    #[with_executor]
+    pub fn reconnect(&self) {
+        let actor = self.actor.clone().unwrap();
+        actix::spawn(async move {
+            if let Err(e) = actor.send(Reconnect).await {
+                log::error!("{:?} in reconnect()", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut backoff = Backoff::default();
+        let mut previous = Duration::from_secs(0);
+        for attempt in 0..20 {
+            let delay = backoff.next_delay();
+            assert!(delay <= MAX_BACKOFF);
+            assert!(delay >= MIN_BACKOFF * 3 / 4);
+            if attempt < 4 {
+                assert!(delay > previous);
+            }
+            previous = delay;
+        }
+        assert!(previous >= MAX_BACKOFF * 3 / 4);
+        assert_eq!(backoff.failures(), 20);
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= MIN_BACKOFF);
+    }
+
+    #[test]
+    fn failures_that_stop_retrying() {
+        assert_eq!(
+            ConnectionState::after_failure(&ServiceError::Unauthorized),
+            ConnectionState::AuthFailed
+        );
+        assert_eq!(
+            ConnectionState::after_failure(&ServiceError::UnhandledResponseCode { http_code: 403 }),
+            ConnectionState::Deregistered
+        );
+        assert_eq!(
+            ConnectionState::after_failure(&ServiceError::Timeout {
+                reason: "keep-alive".into()
+            }),
+            ConnectionState::BackingOff
+        );
+    }
+}
@@ -0,0 +1,566 @@
+//! Messages that did not decrypt, and retry requests.
+//!
+//! When a message does not decrypt, we ask its sender to send it again with a retry request (a
+//! `DecryptionErrorMessage`), and leave a placeholder in the conversation.  The other way around,
+//! a retry request for something we sent is answered from the log of sent content, see
+//! [crate::store::Storage::log_sent_content].  When the request shows that our session with the
+//! device is broken, that session is reset first, so that the content goes out in a new one.
+//!
+//! Retry requests arrive as plaintext content, which the cipher does not open.
+
+use super::*;
+use libsignal_service::proto::{envelope, NullMessage};
+use libsignal_service::push_service::PushService;
+use libsignal_service::session_store::SessionStoreExt;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Instant, SystemTime};
+
+/// How long a device waits for our next retry request.
+const RETRY_REQUEST_INTERVAL: Duration = Duration::from_secs(60);
+/// How long we remember which messages we asked for again.
+const RETRY_REQUEST_MEMORY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Whether the message in an envelope that failed to open with `e` is lost, unless its sender
+/// sends it again.
+pub(super) fn is_decryption_error(e: &ServiceError) -> bool {
+    match e {
+        ServiceError::SignalProtocolError(e) => matches!(
+            e,
+            SignalProtocolError::InvalidMessage { .. }
+                | SignalProtocolError::SessionNotFound { .. }
+                | SignalProtocolError::InvalidSessionStructure { .. }
+                | SignalProtocolError::InvalidPreKeyId { .. }
+                | SignalProtocolError::InvalidSignedPreKeyId { .. }
+                | SignalProtocolError::InvalidKyberPreKeyId { .. }
+                | SignalProtocolError::NoSenderKeyState { .. }
+                | SignalProtocolError::InvalidSenderKeySession { .. }
+        ),
+        _ => false,
+    }
+}
+
+pub(super) fn is_plaintext_content(envelope: &Envelope) -> bool {
+    envelope.r#type() == envelope::Type::PlaintextContent
+}
+
+/// Strips the padding of [PlaintextContent]: a 0x80 byte, possibly followed by zeroes.
+fn unpad(body: &[u8]) -> &[u8] {
+    match body.iter().rposition(|b| *b != 0) {
+        Some(end) if body[end] == 0x80 => &body[..end],
+        _ => body,
+    }
+}
+
+/// An envelope that the cipher could not open, unsealed when it was sealed.
+struct Undecrypted {
+    sender: ServiceAddress,
+    device_id: u32,
+    r#type: CiphertextMessageType,
+    contents: Vec<u8>,
+    timestamp: u64,
+    content_hint: ContentHint,
+    /// The group the message was for, as far as the sealed sender envelope tells.
+    group_id: Option<Vec<u8>>,
+}
+
+impl Undecrypted {
+    async fn open(
+        envelope: &Envelope,
+        identity_store: &Storage,
+        trust_root: &PublicKey,
+    ) -> anyhow::Result<Self> {
+        if envelope.is_unidentified_sender() {
+            let usmc =
+                sealed_sender_decrypt_to_usmc(envelope.content(), identity_store, None).await?;
+            let certificate = usmc.sender()?;
+            if !certificate.validate(trust_root, envelope.server_timestamp())? {
+                anyhow::bail!("invalid sender certificate");
+            }
+            Ok(Self {
+                sender: ServiceAddress {
+                    uuid: Uuid::parse_str(certificate.sender_uuid()?)?,
+                },
+                device_id: certificate.sender_device_id()?.into(),
+                r#type: usmc.msg_type()?,
+                contents: usmc.contents()?.to_vec(),
+                timestamp: envelope.timestamp(),
+                content_hint: usmc.content_hint()?,
+                group_id: usmc.group_id()?.map(<[u8]>::to_vec),
+            })
+        } else {
+            let r#type = match envelope.r#type() {
+                envelope::Type::PrekeyBundle => CiphertextMessageType::PreKey,
+                envelope::Type::Ciphertext => CiphertextMessageType::Whisper,
+                envelope::Type::PlaintextContent => CiphertextMessageType::Plaintext,
+                other => anyhow::bail!("unexpected envelope type {:?}", other),
+            };
+            Ok(Self {
+                sender: ServiceAddress {
+                    uuid: Uuid::parse_str(envelope.source_uuid())?,
+                },
+                device_id: envelope.source_device(),
+                r#type,
+                contents: envelope.content().to_vec(),
+                timestamp: envelope.timestamp(),
+                content_hint: ContentHint::Default,
+                group_id: None,
+            })
+        }
+    }
+
+    /// The retry request in plaintext content; None for anything else.
+    fn retry_request(&self) -> anyhow::Result<Option<DecryptionErrorMessage>> {
+        if self.r#type != CiphertextMessageType::Plaintext {
+            return Ok(None);
+        }
+        let plaintext = PlaintextContent::try_from(&self.contents[..])?;
+        let content = libsignal_service::proto::Content::decode(unpad(plaintext.body()))?;
+        match content.decryption_error_message {
+            Some(message) => Ok(Some(DecryptionErrorMessage::try_from(&message[..])?)),
+            None => anyhow::bail!("plaintext content without a retry request"),
+        }
+    }
+}
+
+/// The retry requests we sent lately, such that a device whose messages keep failing to decrypt
+/// is not flooded with them.
+#[derive(Default)]
+pub(super) struct RetryRequests {
+    /// The messages we asked for again, by sender, device and timestamp.
+    requested: HashSet<(Uuid, u32, u64)>,
+    /// When each device got our last retry request.
+    last_request: HashMap<(Uuid, u32), Instant>,
+}
+
+impl RetryRequests {
+    /// Whether `message` was not asked for again yet; it counts as asked for from now on.
+    fn first_time(&mut self, message: &Undecrypted) -> bool {
+        let now = Utc::now().timestamp_millis() as u64;
+        let oldest = now - RETRY_REQUEST_MEMORY.as_millis() as u64;
+        self.requested
+            .retain(|(_, _, timestamp)| *timestamp > oldest);
+        self.requested
+            .insert((message.sender.uuid, message.device_id, message.timestamp))
+    }
+
+    /// Whether the device that sent `message` got a retry request less than
+    /// [RETRY_REQUEST_INTERVAL] before `now`.  If not, `now` counts as its last request.
+    fn throttled(&mut self, message: &Undecrypted, now: Instant) -> bool {
+        self.last_request
+            .retain(|_, last| now.saturating_duration_since(*last) < RETRY_REQUEST_INTERVAL);
+        let device = (message.sender.uuid, message.device_id);
+        if self.last_request.contains_key(&device) {
+            true
+        } else {
+            self.last_request.insert(device, now);
+            false
+        }
+    }
+}
+
+/// Sends a retry request for `message` to the device it came from, sealed when `sealing` has our
+/// certificate and their access key.
+async fn send_retry_request(
+    storage: &Storage,
+    mut ws: SignalWebSocket,
+    sealing: Option<(SenderCertificate, [u8; 16])>,
+    message: &Undecrypted,
+) -> anyhow::Result<()> {
+    let error = DecryptionErrorMessage::for_original(
+        &message.contents,
+        message.r#type,
+        message.timestamp,
+        message.device_id,
+    )?;
+    let plaintext = PlaintextContent::from(error);
+    let destination =
+        ProtocolAddress::new(message.sender.uuid.to_string(), message.device_id.into());
+    // Without a session, the server does not check the registration id.
+    let registration_id = match storage.load_session(&destination, None).await? {
+        Some(session) => session.remote_registration_id().unwrap_or(0),
+        None => 0,
+    };
+
+    let (r#type, content, access_key) = match sealing {
+        Some((certificate, access_key)) => {
+            let usmc = UnidentifiedSenderMessageContent::new(
+                CiphertextMessageType::Plaintext,
+                certificate,
+                plaintext.serialized().to_vec(),
+                ContentHint::Implicit,
+                message.group_id.clone(),
+            )?;
+            let ciphertext = sealed_sender_encrypt_from_usmc(
+                &destination,
+                &usmc,
+                storage,
+                None,
+                &mut rand::thread_rng(),
+            )
+            .await?;
+            (
+                envelope::Type::UnidentifiedSender,
+                ciphertext,
+                Some(access_key),
+            )
+        }
+        None => (
+            envelope::Type::PlaintextContent,
+            plaintext.serialized().to_vec(),
+            None,
+        ),
+    };
+    let messages = vec![sender_key::OutgoingPushMessage {
+        r#type: r#type as u32,
+        destination_device_id: message.device_id,
+        destination_registration_id: registration_id,
+        content: base64::encode(content),
+    }];
+    sender_key::put_messages(
+        storage,
+        &mut ws,
+        message.sender.uuid,
+        access_key,
+        messages,
+        Utc::now().timestamp_millis() as u64,
+        false,
+    )
+    .await
+}
+
+impl ClientActor {
+    /// Asks the sender of `message` to send it again, and tells the user a message went missing.
+    ///
+    /// Every message is asked for once, and a device gets at most one request per
+    /// [RETRY_REQUEST_INTERVAL]; the messages in between are lost.
+    fn request_retry(&mut self, message: Undecrypted, ctx: &mut <Self as Actor>::Context) {
+        if !self.retry_requests.first_time(&message) {
+            log::debug!(
+                "Message {} of {}.{} was asked for again already",
+                message.timestamp,
+                message.sender.uuid,
+                message.device_id
+            );
+            return;
+        }
+        log::warn!(
+            "Could not decrypt message {} of {}.{}; asking for it again",
+            message.timestamp,
+            message.sender.uuid,
+            message.device_id
+        );
+        let storage = self.storage.clone().unwrap();
+        let recipient = storage.fetch_or_insert_recipient_by_uuid(message.sender.uuid);
+
+        // Implicit content, like typing notifications and receipts, is not worth mentioning.
+        if message.content_hint != ContentHint::Implicit {
+            let session = message
+                .group_id
+                .as_ref()
+                .and_then(|id| storage.fetch_session_by_group_v2_id(&hex::encode(id)))
+                .unwrap_or_else(|| storage.fetch_or_insert_session_by_recipient_id(recipient.id));
+            storage.create_message(&crate::store::NewMessage {
+                session_id: session.id,
+                source_e164: None,
+                source_uuid: Some(message.sender.uuid),
+                text: "[Whisperfish] A message from this contact could not be decrypted. It was requested again.".into(), // XXX Translate
+                timestamp: millis_to_naive_chrono(message.timestamp),
+                sent: false,
+                received: true,
+                is_read: false,
+                flags: 0,
+                attachment: None,
+                mime_type: None,
+                has_attachment: false,
+                outgoing: false,
+                is_unidentified: false,
+                quote_timestamp: None,
+                expires_in: session.expiring_message_timeout,
+            });
+        }
+
+        if self.retry_requests.throttled(&message, Instant::now()) {
+            log::info!(
+                "{}.{} got a retry request less than {:?} ago; not asking for message {}",
+                message.sender.uuid,
+                message.device_id,
+                RETRY_REQUEST_INTERVAL,
+                message.timestamp
+            );
+            return;
+        }
+
+        // Only seal the request with an access key that is known to work.
+        let certs = &self.unidentified_certificates;
+        let sealing = certs
+            .get(CertType::Complete)
+            .cloned()
+            .zip(sender_key::sender_key_access(certs, &recipient, false));
+        let ws = self.ws.clone();
+        let u_ws = self.u_ws.clone();
+        ctx.spawn(
+            async move {
+                let ws = match &sealing {
+                    Some(_) => u_ws.context("not connected")?,
+                    None => ws.context("not connected")?,
+                };
+                send_retry_request(&storage, ws, sealing, &message).await
+            }
+            .into_actor(self)
+            .map(|res, _act, _ctx| {
+                if let Err(e) = res {
+                    log::error!("Could not send retry request: {:#}", e);
+                }
+            }),
+        );
+    }
+
+    /// Sends the content `request` asks for again, to `requester`.
+    fn answer_retry_request(
+        &mut self,
+        requester: ServiceAddress,
+        device_id: u32,
+        request: DecryptionErrorMessage,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        if request.device_id() != u32::from(self.config.get_device_id()) {
+            log::debug!("Ignoring retry request for another one of our devices");
+            return;
+        }
+        let timestamp = request.timestamp();
+        log::info!(
+            "{}.{} asks for message {} again",
+            requester.uuid,
+            device_id,
+            timestamp
+        );
+
+        let storage = self.storage.clone().unwrap();
+        let recipient = storage.fetch_or_insert_recipient_by_uuid(requester.uuid);
+        let certs = &self.unidentified_certificates;
+        let sealing = certs
+            .get(CertType::Complete)
+            .cloned()
+            .zip(sender_key::sender_key_access(certs, &recipient, false));
+        let mut service = self.authenticated_service();
+        let ws = self.ws.clone();
+        let u_ws = self.u_ws.clone();
+        ctx.spawn(
+            async move {
+                let address = ProtocolAddress::new(requester.uuid.to_string(), device_id.into());
+                let mut reset = false;
+                match request.ratchet_key() {
+                    Some(ratchet_key) => {
+                        let session = storage.load_session(&address, None).await?;
+                        if let Some(session) = session {
+                            if session.current_ratchet_key_matches(ratchet_key)? {
+                                log::info!("Resetting our broken session with {}", address);
+                                storage.delete_session(&address).await?;
+                                reset = true;
+                            }
+                        }
+                    }
+                    // It was a sender key message; they get our key again with the next one.
+                    None => storage.clear_sender_key_shared(&[address.clone()]),
+                }
+
+                let sent_at = millis_to_naive_chrono(timestamp);
+                let logged = storage.fetch_sent_content(requester.uuid, device_id, sent_at);
+                let (content, content_timestamp) = match logged {
+                    Some(content) => (content, timestamp),
+                    // Nothing to send again, but the new session should not wait for the next
+                    // message.
+                    None if reset => (
+                        libsignal_service::proto::Content {
+                            null_message: Some(NullMessage::default()),
+                            ..Default::default()
+                        }
+                        .encode_to_vec(),
+                        Utc::now().timestamp_millis() as u64,
+                    ),
+                    None => {
+                        log::info!("Message {} is no longer around to send again", timestamp);
+                        return Ok(());
+                    }
+                };
+
+                // Only the device that asked gets it again, in a new session if need be.
+                if storage.load_session(&address, None).await?.is_none() {
+                    let bundle = service.get_pre_key(&requester, device_id).await?;
+                    process_prekey_bundle(
+                        &address,
+                        &mut storage.clone(),
+                        &mut storage.clone(),
+                        &bundle,
+                        SystemTime::now(),
+                        &mut rand::thread_rng(),
+                        None,
+                    )
+                    .await?;
+                }
+                let certificate = sealing.as_ref().map(|(certificate, _)| certificate);
+                let messages = sender_key::encrypt_for_devices(
+                    &storage,
+                    certificate,
+                    &[address],
+                    &sender_key::pad(content),
+                )
+                .await?;
+                let mut ws = match &sealing {
+                    Some(_) => u_ws.context("not connected")?,
+                    None => ws.context("not connected")?,
+                };
+                sender_key::put_messages(
+                    &storage,
+                    &mut ws,
+                    requester.uuid,
+                    sealing.map(|(_, access_key)| access_key),
+                    messages,
+                    content_timestamp,
+                    false,
+                )
+                .await?;
+                storage.forget_sent_content(requester.uuid, device_id, sent_at);
+                Ok::<_, anyhow::Error>(())
+            }
+            .into_actor(self)
+            .map(|res, _act, _ctx| {
+                if let Err(e) = res {
+                    log::error!("Could not answer retry request: {:#}", e);
+                }
+            }),
+        );
+    }
+}
+
+/// An envelope the cipher could not open: a message that did not decrypt, or plaintext content,
+/// which is how retry requests arrive.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub(super) struct ProcessUndecrypted {
+    pub envelope: Envelope,
+    /// Whether the envelope is addressed to our phone number.
+    pub to_pni: bool,
+}
+
+impl Handler<ProcessUndecrypted> for ClientActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(
+        &mut self,
+        ProcessUndecrypted { envelope, to_pni }: ProcessUndecrypted,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let storage = self.storage.clone().unwrap();
+        let identity_store = if to_pni { storage.pni_store() } else { storage };
+        let trust_root = self.service_cfg().unidentified_sender_trust_root;
+
+        Box::pin(
+            async move { Undecrypted::open(&envelope, &identity_store, &trust_root).await }
+                .into_actor(self)
+                .map(|undecrypted, act, ctx| {
+                    let undecrypted = match undecrypted {
+                        Ok(undecrypted) => undecrypted,
+                        Err(e) => {
+                            log::error!(
+                                "Could not tell where an undecryptable envelope came from: {:#}",
+                                e
+                            );
+                            return;
+                        }
+                    };
+                    match undecrypted.retry_request() {
+                        Ok(Some(request)) => act.answer_retry_request(
+                            undecrypted.sender,
+                            undecrypted.device_id,
+                            request,
+                            ctx,
+                        ),
+                        Ok(None) => act.request_retry(undecrypted, ctx),
+                        Err(e) => log::warn!(
+                            "Unreadable plaintext content from {}: {:#}",
+                            undecrypted.sender.uuid,
+                            e
+                        ),
+                    }
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padding() {
+        assert_eq!(unpad(&[1, 2, 0x80]), &[1, 2]);
+        assert_eq!(unpad(&[1, 2, 0x80, 0, 0]), &[1, 2]);
+        assert_eq!(unpad(&[1, 2]), &[1, 2]);
+    }
+
+    #[test]
+    fn read_retry_request() {
+        let error =
+            DecryptionErrorMessage::for_original(&[], CiphertextMessageType::SenderKey, 1234, 2)
+                .unwrap();
+        let plaintext = PlaintextContent::from(error);
+        let mut message = Undecrypted {
+            sender: ServiceAddress {
+                uuid: Uuid::new_v4(),
+            },
+            device_id: 1,
+            r#type: CiphertextMessageType::Plaintext,
+            contents: plaintext.serialized().to_vec(),
+            timestamp: 5678,
+            content_hint: ContentHint::Default,
+            group_id: None,
+        };
+
+        let request = message.retry_request().unwrap().unwrap();
+        assert_eq!(request.timestamp(), 1234);
+        assert_eq!(request.device_id(), 2);
+        assert!(request.ratchet_key().is_none());
+
+        message.r#type = CiphertextMessageType::Whisper;
+        assert!(message.retry_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn limit_retry_requests() {
+        let undecrypted = |uuid, device_id, timestamp| Undecrypted {
+            sender: ServiceAddress { uuid },
+            device_id,
+            r#type: CiphertextMessageType::Whisper,
+            contents: Vec::new(),
+            timestamp,
+            content_hint: ContentHint::Default,
+            group_id: None,
+        };
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let timestamp = Utc::now().timestamp_millis() as u64;
+        let start = Instant::now();
+        let mut requests = RetryRequests::default();
+
+        let message = undecrypted(alice, 1, timestamp);
+        assert!(requests.first_time(&message));
+        assert!(!requests.first_time(&message));
+        assert!(!requests.throttled(&message, start));
+
+        let next = undecrypted(alice, 1, timestamp + 1);
+        assert!(requests.first_time(&next));
+        assert!(requests.throttled(&next, start + Duration::from_secs(1)));
+        assert!(!requests.throttled(&next, start + RETRY_REQUEST_INTERVAL));
+
+        let later = start + RETRY_REQUEST_INTERVAL;
+        assert!(requests.first_time(&undecrypted(alice, 2, timestamp)));
+        assert!(!requests.throttled(&undecrypted(alice, 2, timestamp), later));
+        assert!(requests.first_time(&undecrypted(bob, 1, timestamp)));
+        assert!(!requests.throttled(&undecrypted(bob, 1, timestamp), later));
+
+        let old = timestamp - RETRY_REQUEST_MEMORY.as_millis() as u64;
+        assert!(requests.first_time(&undecrypted(bob, 1, old)));
+        assert!(requests.first_time(&undecrypted(bob, 1, old)));
+    }
+}
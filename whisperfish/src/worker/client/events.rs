@@ -0,0 +1,202 @@
+//! Events through which the [ClientActor] reports to its front-end.
+//!
+//! The QML front-end is [ClientWorker], which turns every event into a Qt signal.  Command line
+//! tools and tests implement [ClientEvents] themselves, so the actor can run without a `QmlApp`.
+
+use super::*;
+use libsignal_service::push_service::DeviceInfo;
+
+/// A new message that should be brought to the attention of the user.
+#[derive(Clone, Debug, Default)]
+pub struct MessageNotification {
+    pub session_id: i32,
+    pub message_id: i32,
+    pub session_name: String,
+    pub sender_name: String,
+    /// The phone number of the sender, or their UUID if the number is unknown.
+    pub sender_identifier: String,
+    pub sender_uuid: String,
+    pub message: String,
+    pub is_group: bool,
+}
+
+/// Receiver of the events of a [ClientActor].
+///
+/// Every method does nothing by default, so front-ends only implement what they care about.
+pub trait ClientEvents {
+    /// The actor has started, and can be reached at `actor`.
+    fn started(&self, _actor: Addr<ClientActor>) {}
+
+    fn message_received(&self, _sid: i32, _mid: i32) {}
+    fn message_reaction_received(&self, _sid: i32, _mid: i32) {}
+    fn attachment_downloaded(&self, _sid: i32, _mid: i32) {}
+    /// A delivery or read receipt was processed for the message.
+    fn message_receipt(&self, _sid: i32, _mid: i32) {}
+    fn notify_message(&self, _notification: MessageNotification) {}
+
+    fn message_sent(&self, _sid: i32, _mid: i32, _message: &str) {}
+    fn message_not_sent(&self, _sid: i32, _mid: i32) {}
+
+    /// The connection to the Signal servers went up, down, or into another state in between.
+    fn connection_state_changed(&self, _state: ConnectionState) {}
+
+    /// The server asks to solve a captcha before sending more messages.
+    fn proof_requested(&self, _token: &str, _type: &str) {}
+    fn proof_captcha_result(&self, _success: bool) {}
+
+    fn linked_devices(&self, _devices: Vec<DeviceInfo>) {}
+    fn garbage_collected(&self, _orphans: usize, _bytes: u64) {}
+    fn backup_exported(&self, _path: &Path) {}
+    fn backup_failed(&self) {}
+    /// The storage password was changed; `encrypted` tells whether the storage is encrypted now.
+    fn storage_password_changed(&self, _encrypted: bool) {}
+    fn storage_password_failed(&self) {}
+
+    /// `username` is reserved, and can be confirmed.
+    fn username_reserved(&self, _username: &str) {}
+    /// Our own username was set, confirmed or deleted.
+    fn username_changed(&self, _username: Option<&str>) {}
+    fn username_failed(&self) {}
+    fn username_found(&self, _username: &str, _uuid: Uuid) {}
+    fn username_not_found(&self, _username: &str) {}
+
+    /// A verification code was sent to the new phone number.
+    fn number_change_code_requested(&self) {}
+    fn number_changed(&self, _number: &str) {}
+    fn number_change_failed(&self) {}
+}
+
+/// Drops all events.
+pub struct NoClientEvents;
+
+impl ClientEvents for NoClientEvents {}
+
+impl ClientEvents for QObjectBox<ClientWorker> {
+    fn started(&self, actor: Addr<ClientActor>) {
+        self.pinned().borrow_mut().actor = Some(actor);
+    }
+
+    fn message_received(&self, sid: i32, mid: i32) {
+        self.pinned().borrow().messageReceived(sid, mid);
+    }
+
+    fn message_reaction_received(&self, sid: i32, mid: i32) {
+        self.pinned().borrow().messageReactionReceived(sid, mid);
+    }
+
+    fn attachment_downloaded(&self, sid: i32, mid: i32) {
+        self.pinned().borrow().attachmentDownloaded(sid, mid);
+    }
+
+    fn message_receipt(&self, sid: i32, mid: i32) {
+        self.pinned().borrow().messageReceipt(sid, mid);
+    }
+
+    fn notify_message(&self, notification: MessageNotification) {
+        self.pinned().borrow().notifyMessage(
+            notification.session_id,
+            notification.message_id,
+            notification.session_name.into(),
+            notification.sender_name.into(),
+            notification.sender_identifier.into(),
+            notification.sender_uuid.into(),
+            notification.message.into(),
+            notification.is_group,
+        );
+    }
+
+    fn message_sent(&self, sid: i32, mid: i32, message: &str) {
+        self.pinned().borrow().messageSent(sid, mid, message.into());
+    }
+
+    fn message_not_sent(&self, sid: i32, mid: i32) {
+        self.pinned().borrow().messageNotSent(sid, mid);
+    }
+
+    fn connection_state_changed(&self, state: ConnectionState) {
+        let connected = state.is_connected();
+        let connected_changed = self.pinned().borrow().connected != connected;
+        self.pinned().borrow_mut().connected = connected;
+        self.pinned().borrow_mut().connectionState = state.as_str().into();
+        self.pinned().borrow().connectionStateChanged();
+        if connected_changed {
+            self.pinned().borrow().connectedChanged();
+        }
+    }
+
+    fn proof_requested(&self, token: &str, r#type: &str) {
+        self.pinned()
+            .borrow()
+            .proofRequested(token.into(), r#type.into());
+    }
+
+    fn proof_captcha_result(&self, success: bool) {
+        self.pinned().borrow().proofCaptchaResult(success);
+    }
+
+    fn linked_devices(&self, devices: Vec<DeviceInfo>) {
+        let client_worker = self.pinned();
+        let client_worker = client_worker.borrow();
+        let device_model = client_worker.device_model.as_ref().unwrap().pinned();
+        device_model.borrow_mut().set_devices(devices);
+    }
+
+    fn garbage_collected(&self, orphans: usize, bytes: u64) {
+        self.pinned()
+            .borrow()
+            .garbageCollected(orphans as i32, bytes as i64);
+    }
+
+    fn backup_exported(&self, path: &Path) {
+        self.pinned()
+            .borrow()
+            .backupExported(path.to_string_lossy().into_owned().into());
+    }
+
+    fn backup_failed(&self) {
+        self.pinned().borrow().backupFailed();
+    }
+
+    fn storage_password_changed(&self, encrypted: bool) {
+        self.pinned().borrow().storagePasswordChanged(encrypted);
+    }
+
+    fn storage_password_failed(&self) {
+        self.pinned().borrow().storagePasswordFailed();
+    }
+
+    fn username_reserved(&self, username: &str) {
+        self.pinned().borrow().usernameReserved(username.into());
+    }
+
+    fn username_changed(&self, username: Option<&str>) {
+        self.pinned().borrow_mut().username = username.unwrap_or_default().into();
+        self.pinned().borrow().usernameChanged();
+    }
+
+    fn username_failed(&self) {
+        self.pinned().borrow().usernameFailed();
+    }
+
+    fn username_found(&self, username: &str, uuid: Uuid) {
+        self.pinned()
+            .borrow()
+            .usernameFound(username.into(), uuid.to_string().into());
+    }
+
+    fn username_not_found(&self, username: &str) {
+        self.pinned().borrow().usernameNotFound(username.into());
+    }
+
+    fn number_change_code_requested(&self) {
+        self.pinned().borrow().numberChangeCodeRequested();
+    }
+
+    fn number_changed(&self, number: &str) {
+        self.pinned().borrow().numberChanged(number.into());
+    }
+
+    fn number_change_failed(&self) {
+        self.pinned().borrow().numberChangeFailed();
+    }
+}
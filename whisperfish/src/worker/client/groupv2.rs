@@ -129,7 +129,7 @@ impl Handler<RequestGroupV2Info> for ClientActor {
                 for (uuid, profile_key) in members_to_assert {
                     let recipient = storage.fetch_or_insert_recipient_by_uuid(*uuid);
                     if let Some(profile_key) = profile_key {
-                        let (recipient, _was_changed) = storage.update_profile_key(recipient.e164, recipient.uuid, None, &profile_key.get_bytes(), TrustLevel::Uncertain);
+                        let (recipient, _was_changed) = storage.update_profile_key(recipient.e164, recipient.uuid, None, &profile_key.get_bytes(), TrustLevel::Uncertain)?;
                         match recipient.profile_key {
                             Some(key) if key == profile_key.get_bytes() => {
                                 log::trace!("Profile key matches server-stored profile key");
@@ -152,7 +152,7 @@ impl Handler<RequestGroupV2Info> for ClientActor {
                 let uuids = group.members.iter().map(|member| {
                     member.uuid.to_string()
                 });
-                storage.db().transaction::<(), diesel::result::Error, _>(|db| {
+                let dropped = storage.db().transaction::<usize, diesel::result::Error, _>(|db| {
                     use crate::store::schema::{group_v2_members, recipients, group_v2s};
                     let stale_members: Vec<i32> = group_v2_members::table
                         .select(group_v2_members::recipient_id)
@@ -180,8 +180,12 @@ impl Handler<RequestGroupV2Info> for ClientActor {
                         storage.observe_delete(group_v2_members::table, PrimaryKey::Unknown)
                             .with_relation(group_v2s::table, group_id_hex.clone());
                     }
-                    Ok(())
+                    Ok(dropped)
                 }).expect("dropping stale members");
+                if dropped > 0 {
+                    // Whoever left should not be able to read our next messages.
+                    storage.rotate_sender_key(&group_id_hex);
+                }
 
                 {
                     use crate::store::schema::{group_v2_members, recipients, group_v2s};
@@ -372,9 +376,10 @@ impl Handler<GroupAvatarFetched> for ClientActor {
         _ctx: &mut Self::Context,
     ) -> Self::Result {
         let storage = self.storage.clone().unwrap();
+        let account = self.config.account().map(String::from);
         Box::pin(
             async move {
-                let settings = crate::config::SettingsBridge::default();
+                let settings = crate::config::SettingsBridge::for_account(account.as_deref());
                 let avatar_dir = settings.get_string("avatar_dir");
                 let avatar_dir = Path::new(&avatar_dir);
 
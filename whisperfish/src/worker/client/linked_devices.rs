@@ -70,12 +70,7 @@ impl Handler<ReloadLinkedDevices> for ClientActor {
                         }
                         Ok(devices) => {
                             log::trace!("Successfully refreshed linked devices: {:?}", devices);
-                            // A bunch bindings because of scope
-                            let client_worker = act.inner.pinned();
-                            let client_worker = client_worker.borrow_mut();
-                            let device_model =
-                                client_worker.device_model.as_ref().unwrap().pinned();
-                            device_model.borrow_mut().set_devices(devices);
+                            act.events.linked_devices(devices);
                         }
                     }
                 },
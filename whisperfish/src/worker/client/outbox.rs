@@ -0,0 +1,183 @@
+//! Sending the messages in the outbox, see [crate::store::Storage::enqueue_outgoing].
+//!
+//! A message is only sent while connected; otherwise it waits in the outbox until the message
+//! pipe opens.  When sending fails for a reason that may go away by itself (a timeout, a server
+//! error, a broken connection), the message is tried again later, waiting longer after every
+//! attempt.  Other failures, and too many attempts, mark the message as failed, and it is up to
+//! the user to [RetryMessage].
+
+use super::*;
+
+/// How often to look for messages whose next attempt is due.
+const OUTBOX_INTERVAL: Duration = Duration::from_secs(15);
+/// Delay before the first retry.
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+/// After this many failed attempts, the message is marked as failed.
+const MAX_ATTEMPTS: i32 = 12;
+
+/// How long to wait after the `attempts`th failed attempt.
+fn retry_delay(attempts: i32) -> Duration {
+    (1..attempts.min(16))
+        .fold(MIN_RETRY_DELAY, |delay, _| delay * 2)
+        .min(MAX_RETRY_DELAY)
+}
+
+fn is_transient_service_error(e: &ServiceError) -> bool {
+    match e {
+        ServiceError::Timeout { .. }
+        | ServiceError::SendError { .. }
+        | ServiceError::WsError { .. }
+        | ServiceError::WsClosing { .. }
+        | ServiceError::RateLimitExceeded => true,
+        ServiceError::UnhandledResponseCode { http_code } => *http_code >= 500,
+        _ => false,
+    }
+}
+
+pub(super) fn is_transient_sender_error(e: &MessageSenderError) -> bool {
+    match e {
+        MessageSenderError::ServiceError(e) => is_transient_service_error(e),
+        _ => false,
+    }
+}
+
+/// Whether trying again later may fix `e`.
+pub(super) fn is_transient(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<ServiceError>() {
+            is_transient_service_error(e)
+        } else if let Some(e) = cause.downcast_ref::<MessageSenderError>() {
+            is_transient_sender_error(e)
+        } else {
+            cause.is::<TransientSendFailure>()
+        }
+    })
+}
+
+/// Some recipients did not get the message, but only for reasons that retrying may fix.
+#[derive(Debug)]
+pub(super) struct TransientSendFailure {
+    pub failed: usize,
+    pub total: usize,
+}
+
+impl Display for TransientSendFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(
+            f,
+            "could not deliver message to {} out of {} recipients",
+            self.failed, self.total
+        )
+    }
+}
+
+impl std::error::Error for TransientSendFailure {}
+
+impl ClientActor {
+    /// Looks at the outbox every now and then.  Called once the storage is ready.
+    pub(super) fn start_outbox(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let requeued = self.storage.as_ref().unwrap().requeue_pending_messages();
+        if requeued > 0 {
+            log::info!("Put {} pending message(s) back in the outbox", requeued);
+        }
+        ctx.run_interval(OUTBOX_INTERVAL, |_act, ctx| ctx.notify(ProcessOutbox));
+    }
+
+    /// Schedules another attempt after `e`.  Returns false when the message should fail instead.
+    pub(super) fn schedule_retry(&mut self, mid: i32, e: &anyhow::Error) -> bool {
+        if !is_transient(e) {
+            return false;
+        }
+        let storage = self.storage.as_ref().unwrap();
+        let attempts = match storage.fetch_outbox_entry(mid) {
+            Some(entry) => entry.attempts + 1,
+            None => return false,
+        };
+        if attempts >= MAX_ATTEMPTS {
+            log::warn!("Giving up on message {} after {} attempts", mid, attempts);
+            return false;
+        }
+
+        let delay = retry_delay(attempts);
+        log::info!(
+            "Retrying message {} in {} seconds (attempt {})",
+            mid,
+            delay.as_secs(),
+            attempts + 1
+        );
+        let next_attempt = Utc::now().naive_utc()
+            + chrono::Duration::from_std(delay).expect("retry delay fits in chrono::Duration");
+        storage.reschedule_outgoing(mid, next_attempt, &e.to_string());
+        true
+    }
+}
+
+/// Sends the messages in the outbox whose next attempt is due.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub(super) struct ProcessOutbox;
+
+impl Handler<ProcessOutbox> for ClientActor {
+    type Result = ();
+
+    fn handle(&mut self, _: ProcessOutbox, ctx: &mut Self::Context) -> Self::Result {
+        if !self.connection.state().is_connected() {
+            return;
+        }
+        let storage = self.storage.as_ref().unwrap();
+        for entry in storage.fetch_due_outbox_entries(Utc::now().naive_utc()) {
+            if !self.sending.contains(&entry.message_id) {
+                log::trace!("Outbox: {}", entry);
+                ctx.notify(SendMessage(entry.message_id));
+            }
+        }
+    }
+}
+
+/// Puts a failed or waiting message back in the outbox, and tries to send it right away.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RetryMessage(pub i32);
+
+impl Handler<RetryMessage> for ClientActor {
+    type Result = ();
+
+    fn handle(&mut self, RetryMessage(mid): RetryMessage, ctx: &mut Self::Context) -> Self::Result {
+        log::trace!("ClientActor::RetryMessage({})", mid);
+        self.storage.as_ref().unwrap().enqueue_outgoing(mid);
+        ctx.notify(SendMessage(mid));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_grows_until_the_maximum() {
+        assert_eq!(retry_delay(1), MIN_RETRY_DELAY);
+        assert_eq!(retry_delay(2), MIN_RETRY_DELAY * 2);
+        assert_eq!(retry_delay(3), MIN_RETRY_DELAY * 4);
+        assert_eq!(retry_delay(MAX_ATTEMPTS), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn transient_errors() {
+        let timeout = ServiceError::Timeout {
+            reason: "test".into(),
+        };
+        assert!(is_transient(&anyhow::Error::from(timeout)));
+        assert!(is_transient(&anyhow::Error::from(
+            ServiceError::UnhandledResponseCode { http_code: 503 }
+        )));
+        assert!(is_transient(&anyhow::Error::new(TransientSendFailure {
+            failed: 1,
+            total: 2
+        })));
+        assert!(!is_transient(&anyhow::Error::from(
+            ServiceError::UnhandledResponseCode { http_code: 413 }
+        )));
+        assert!(!is_transient(&anyhow::anyhow!("Unregistered recipient")));
+    }
+}
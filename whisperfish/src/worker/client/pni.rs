@@ -0,0 +1,528 @@
+//! Phone number privacy: our second identity, the PNI.
+//!
+//! Contacts that only know our phone number write to our PNI, which has its own identity key,
+//! registration id and pre-keys.  Changing our phone number gives us a new PNI identity.
+//!
+//!
+//! Contacts that wrote to our phone number get our PNI signature with the next message we send
+//! them, which tells them that our PNI and ACI belong together, see [send_pni_signature].
+//!
+//! XXX: libsignal-service drops `Content.pniSignatureMessage` when it opens an envelope, so the
+//! PNI signatures of others never reach us.  [Storage::process_pni_signature] verifies them, once
+//! it does hand them out.
+
+use super::*;
+use libsignal_service::configuration::Endpoint;
+use libsignal_service::proto::PniSignatureMessage;
+use libsignal_service::provisioning::generate_registration_id;
+use libsignal_service::push_service::{HttpAuthOverride, PushService, DEFAULT_DEVICE_ID};
+use libsignal_service::session_store::SessionStoreExt;
+use std::collections::HashMap;
+
+/// One-time PNI pre-keys to keep on the server.
+const PNI_PRE_KEY_COUNT: u32 = 100;
+/// Below this amount, new one-time PNI pre-keys are uploaded.
+const PNI_PRE_KEY_MINIMUM: u32 = 10;
+
+fn encode_key(key: &[u8]) -> String {
+    base64::encode(key)
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PreKeyEntity {
+    key_id: u32,
+    public_key: String,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SignedPreKeyEntity {
+    key_id: u32,
+    public_key: String,
+    signature: String,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PreKeyState {
+    identity_key: String,
+    signed_pre_key: SignedPreKeyEntity,
+    pre_keys: Vec<PreKeyEntity>,
+}
+
+#[derive(serde::Deserialize)]
+struct PreKeyStatus {
+    count: u32,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ChangeNumberRequest {
+    session_id: String,
+    number: String,
+    pni_identity_key: String,
+    /// Sync messages for the linked devices, which we do not send yet.
+    device_messages: Vec<serde_json::Value>,
+    device_pni_signed_prekeys: HashMap<String, SignedPreKeyEntity>,
+    pni_registration_ids: HashMap<String, u32>,
+}
+
+#[derive(serde::Deserialize)]
+struct AccountIdentityResponse {
+    number: String,
+    pni: Uuid,
+}
+
+/// Generates a signed pre-key for `identity`, to be stored once the server took it.
+fn generate_signed_pre_key(
+    identity: &IdentityKeyPair,
+    id: u32,
+) -> Result<(SignedPreKeyRecord, SignedPreKeyEntity), anyhow::Error> {
+    let mut rng = rand::thread_rng();
+    let key_pair = KeyPair::generate(&mut rng);
+    let public_key = key_pair.public_key.serialize();
+    let signature = identity
+        .private_key()
+        .calculate_signature(&public_key, &mut rng)?;
+    let record = SignedPreKeyRecord::new(
+        id.into(),
+        Utc::now().timestamp_millis() as u64,
+        &key_pair,
+        &signature,
+    );
+    let entity = SignedPreKeyEntity {
+        key_id: id,
+        public_key: encode_key(&public_key),
+        signature: encode_key(&signature),
+    };
+    Ok((record, entity))
+}
+
+/// Sends our PNI signature for `pni` to every device of `recipient`, sealed when `sealing` has our
+/// certificate and their access key.
+///
+/// Signal clients add the signature to a data message, which libsignal-service cannot do, so it
+/// goes out in a content of its own.
+pub(super) async fn send_pni_signature(
+    storage: &Storage,
+    mut ws: SignalWebSocket,
+    sealing: Option<(SenderCertificate, [u8; 16])>,
+    recipient: ServiceAddress,
+    pni: Uuid,
+) -> anyhow::Result<()> {
+    let signature = storage.pni_signature().await?;
+    let content = sender_key::pad(
+        libsignal_service::proto::Content {
+            pni_signature_message: Some(PniSignatureMessage {
+                pni: Some(pni.as_bytes().to_vec()),
+                signature: Some(signature),
+            }),
+            ..Default::default()
+        }
+        .encode_to_vec(),
+    );
+
+    let mut device_ids = vec![DEFAULT_DEVICE_ID];
+    device_ids.extend(storage.get_sub_device_sessions(&recipient).await?);
+    let devices: Vec<_> = device_ids
+        .into_iter()
+        .map(|device_id| ProtocolAddress::new(recipient.uuid.to_string(), device_id.into()))
+        .collect();
+    let certificate = sealing.as_ref().map(|(certificate, _)| certificate);
+    let messages =
+        sender_key::encrypt_for_devices(storage, certificate, &devices, &content).await?;
+
+    sender_key::put_messages(
+        storage,
+        &mut ws,
+        recipient.uuid,
+        sealing.map(|(_, access_key)| access_key),
+        messages,
+        Utc::now().timestamp_millis() as u64,
+        false,
+    )
+    .await
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+/// Upload pre-keys for our PNI, when the server runs low on them.
+///
+/// A linked device waits for the PNI identity of the primary device; the primary device creates
+/// one if the storage predates PNI support.
+pub struct RefreshPniPreKeys;
+
+#[derive(Message)]
+#[rtype(result = "()")]
+/// Request a verification code for changing our phone number to `number`.
+///
+/// The number is as entered by the user; a number without country code is taken to be local.
+pub struct RequestNumberChange {
+    pub number: String,
+    pub transport: VerificationTransport,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+/// Change our phone number, after [RequestNumberChange].
+///
+/// This replaces our PNI identity.  Linked devices would need the new identity in a sync
+/// message, which we cannot send yet; the server refuses the change while devices are linked.
+pub struct ConfirmNumberChange {
+    pub confirm_code: String,
+}
+
+// methods called from Qt
+impl ClientWorker {
+    #[with_executor]
+    pub fn change_number(&self, number: String, voice: bool) {
+        let actor = self.actor.clone().unwrap();
+        let transport = if voice {
+            VerificationTransport::Voice
+        } else {
+            VerificationTransport::Sms
+        };
+        actix::spawn(async move {
+            if let Err(e) = actor.send(RequestNumberChange { number, transport }).await {
+                log::error!("{:?} in change_number()", e);
+            }
+        });
+    }
+
+    #[with_executor]
+    pub fn confirm_number_change(&self, code: String) {
+        let actor = self.actor.clone().unwrap();
+        actix::spawn(async move {
+            if let Err(e) = actor.send(ConfirmNumberChange { confirm_code: code }).await {
+                log::error!("{:?} in confirm_number_change()", e);
+            }
+        });
+    }
+}
+
+impl ClientActor {
+    /// A cipher that decrypts as our PNI, if we know it.
+    pub(super) fn new_pni_cipher(&self) -> Option<ServiceCipher<Storage, rand::rngs::ThreadRng>> {
+        let pni = self.config.get_pni()?;
+        let storage = self.storage.as_ref()?;
+        Some(ServiceCipher::new(
+            storage.pni_store(),
+            rand::thread_rng(),
+            self.service_cfg().unidentified_sender_trust_root,
+            pni,
+            self.config.get_device_id().into(),
+        ))
+    }
+
+    /// Chooses the cipher for `envelope`: the PNI cipher when it is addressed to our phone number.
+    ///
+    /// Returns whether the PNI cipher was chosen.
+    pub(super) fn cipher_for(
+        &self,
+        envelope: &Envelope,
+    ) -> (ServiceCipher<Storage, rand::rngs::ThreadRng>, bool) {
+        let to_pni = self.config.get_pni().is_some()
+            && Uuid::parse_str(envelope.destination_uuid()).ok() == self.config.get_pni();
+        match &self.pni_cipher {
+            Some(cipher) if to_pni => (cipher.clone(), true),
+            _ => (self.cipher.clone().expect("cipher initialized"), false),
+        }
+    }
+
+    /// Answers a PNI identity request of a linked device.
+    pub(super) async fn send_pni_identity(
+        storage: &Storage,
+        sender: &mut MessageSender<AwcPushService, Storage, rand::rngs::ThreadRng>,
+        local_addr: &ServiceAddress,
+    ) -> Result<(), anyhow::Error> {
+        let identity = storage.get_pni_identity_key_pair().await?;
+        let content = SyncMessage {
+            pni_identity: Some(sync_message::PniIdentity {
+                public_key: Some(identity.public_key().serialize().into()),
+                private_key: Some(identity.private_key().serialize()),
+            }),
+            ..Default::default()
+        };
+        let now = Utc::now().timestamp_millis() as u64;
+        sender
+            .send_message(local_addr, None, content, now, false)
+            .await?;
+        Ok(())
+    }
+
+    /// Stores the PNI identity shared by the primary device.
+    pub(super) fn process_pni_identity(
+        &self,
+        pni_identity: &sync_message::PniIdentity,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        use std::convert::TryFrom;
+
+        if u32::from(self.config.get_device_id()) == DEFAULT_DEVICE_ID {
+            log::warn!("Ignoring a PNI identity sync message on the primary device");
+            return;
+        }
+        let identity = IdentityKey::decode(pni_identity.public_key()).and_then(|public| {
+            let private = PrivateKey::try_from(pni_identity.private_key())?;
+            Ok(IdentityKeyPair::new(public, private))
+        });
+        let identity = match identity {
+            Ok(identity) => identity,
+            Err(e) => {
+                log::warn!("Invalid PNI identity in sync message: {}", e);
+                return;
+            }
+        };
+
+        let storage = self.storage.clone().unwrap();
+        ctx.spawn(
+            async move { storage.set_pni_identity(identity, None).await }
+                .into_actor(self)
+                .map(|result, _act, ctx| match result {
+                    Ok(()) => ctx.notify(RefreshPniPreKeys),
+                    Err(e) => log::error!("Storing the PNI identity failed: {:?}", e),
+                }),
+        );
+    }
+}
+
+impl Handler<RefreshPniPreKeys> for ClientActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, _: RefreshPniPreKeys, _ctx: &mut Self::Context) -> Self::Result {
+        log::trace!("handle(RefreshPniPreKeys)");
+        let mut service = self.authenticated_service();
+        let mut storage = self.storage.clone().unwrap();
+        let is_primary = u32::from(self.config.get_device_id()) == DEFAULT_DEVICE_ID;
+
+        let proc = async move {
+            let (identity, generated) = if storage.has_pni_identity().await? {
+                (storage.get_pni_identity_key_pair().await?, false)
+            } else if is_primary {
+                log::info!("Generating PNI identity key pair");
+                (IdentityKeyPair::generate(&mut rand::thread_rng()), true)
+            } else {
+                log::info!(
+                    "No PNI identity from the primary device yet; not uploading PNI pre-keys"
+                );
+                return Ok(false);
+            };
+
+            let status: PreKeyStatus = service
+                .get_json(
+                    Endpoint::Service,
+                    "/v2/keys?identity=pni",
+                    &[],
+                    HttpAuthOverride::NoOverride,
+                )
+                .await?;
+            // The keys of a new identity go up regardless.
+            if status.count >= PNI_PRE_KEY_MINIMUM && !generated {
+                log::trace!("{} PNI pre-keys left on the server", status.count);
+                return Ok(false);
+            }
+
+            // PNI pre-keys share their id space with the ACI ones.
+            let (signed_pre_key_id, _kyber_pre_key_id, pre_key_id) =
+                storage.next_pre_key_ids().await;
+            let (record, signed_pre_key) = generate_signed_pre_key(&identity, signed_pre_key_id)?;
+            storage
+                .save_signed_pre_key(signed_pre_key_id.into(), &record, None)
+                .await?;
+            let mut pre_keys = Vec::new();
+            for id in pre_key_id..pre_key_id + PNI_PRE_KEY_COUNT {
+                let key_pair = KeyPair::generate(&mut rand::thread_rng());
+                storage
+                    .save_pre_key(id.into(), &PreKeyRecord::new(id.into(), &key_pair), None)
+                    .await?;
+                pre_keys.push(PreKeyEntity {
+                    key_id: id,
+                    public_key: encode_key(&key_pair.public_key.serialize()),
+                });
+            }
+
+            let state = PreKeyState {
+                identity_key: encode_key(&identity.public_key().serialize()),
+                signed_pre_key,
+                pre_keys,
+            };
+            // The server answers with an empty body.
+            match service
+                .put_json::<(), _>(
+                    Endpoint::Service,
+                    "/v2/keys?identity=pni",
+                    &[],
+                    HttpAuthOverride::NoOverride,
+                    state,
+                )
+                .await
+            {
+                Ok(()) | Err(ServiceError::JsonDecodeError { .. }) => {}
+                Err(e) => return Err(e.into()),
+            }
+            // Only kept once the server knows it.
+            if generated {
+                storage.set_pni_identity(identity, None).await?;
+            }
+            Ok(true)
+        };
+
+        Box::pin(
+            proc.into_actor(self).map(
+                |result: Result<bool, anyhow::Error>, _act, _ctx| match result {
+                    Ok(true) => log::info!("Uploaded PNI pre-keys"),
+                    Ok(false) => {}
+                    Err(e) => log::error!("Refreshing PNI pre-keys failed: {:?}", e),
+                },
+            ),
+        )
+    }
+}
+
+impl Handler<RequestNumberChange> for ClientActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: RequestNumberChange, _ctx: &mut Self::Context) -> Self::Result {
+        log::trace!("handle(RequestNumberChange)");
+        let mut service = self.authenticated_service();
+        let is_primary = u32::from(self.config.get_device_id()) == DEFAULT_DEVICE_ID;
+        let country_code = self.settings().get_string("country_code");
+        let RequestNumberChange { number, transport } = msg;
+
+        Box::pin(
+            async move {
+                anyhow::ensure!(is_primary, "Only the primary device can change the number");
+                let phonenumber = crate::model::format_with_country(&number, &country_code)
+                    .and_then(|number| phonenumber::parse(None, number).ok())
+                    .with_context(|| format!("Invalid phone number {}", number))?;
+
+                let carrier = phonenumber.carrier();
+                let (mcc, mnc) = if let Some(carrier) = carrier {
+                    (Some(&carrier[0..3]), Some(&carrier[3..]))
+                } else {
+                    (None, None)
+                };
+                let session = service
+                    .create_verification_session(&phonenumber.to_string(), None, mcc, mnc)
+                    .await?;
+                // XXX: the captcha flow of the registration is not wired up here yet.
+                anyhow::ensure!(
+                    !session.captcha_required() && !session.push_challenge_required(),
+                    "Signal requires a challenge to change the number, which is not supported yet"
+                );
+                anyhow::ensure!(
+                    session.allowed_to_request_code,
+                    "Not allowed to request verification code: {:?}",
+                    session
+                );
+                let session = service
+                    .request_verification_code(&session.id, "whisperfish", transport)
+                    .await?;
+                Ok((phonenumber, session))
+            }
+            .into_actor(self)
+            .map(|result, act, _ctx| match result {
+                Ok((phonenumber, session)) => {
+                    log::info!("Verification code for {} requested", phonenumber);
+                    act.number_change = Some((phonenumber, session));
+                    act.events.number_change_code_requested();
+                }
+                Err(e) => {
+                    log::error!("Requesting a number change failed: {:?}", e);
+                    act.events.number_change_failed();
+                }
+            }),
+        )
+    }
+}
+
+impl Handler<ConfirmNumberChange> for ClientActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: ConfirmNumberChange, _ctx: &mut Self::Context) -> Self::Result {
+        log::trace!("handle(ConfirmNumberChange)");
+        let mut service = self.authenticated_service();
+        let mut storage = self.storage.clone().unwrap();
+        let number_change = self.number_change.clone();
+
+        Box::pin(
+            async move {
+                let (phonenumber, session) = number_change.context("No number change requested")?;
+                let session = service
+                    .submit_verification_code(&session.id, msg.confirm_code.trim())
+                    .await?;
+                anyhow::ensure!(session.verified, "Session is not verified");
+
+                let mut rng = rand::thread_rng();
+                let identity = IdentityKeyPair::generate(&mut rng);
+                let regid = generate_registration_id(&mut rng);
+                let (signed_pre_key_id, _, _) = storage.next_pre_key_ids().await;
+                let (record, signed_pre_key) =
+                    generate_signed_pre_key(&identity, signed_pre_key_id)?;
+
+                let device = DEFAULT_DEVICE_ID.to_string();
+                let request = ChangeNumberRequest {
+                    session_id: session.id,
+                    number: phonenumber.to_string(),
+                    pni_identity_key: encode_key(&identity.public_key().serialize()),
+                    device_messages: Vec::new(),
+                    device_pni_signed_prekeys: vec![(device.clone(), signed_pre_key)]
+                        .into_iter()
+                        .collect(),
+                    pni_registration_ids: vec![(device, regid)].into_iter().collect(),
+                };
+                let response: AccountIdentityResponse = service
+                    .put_json(
+                        Endpoint::Service,
+                        "/v2/accounts/number",
+                        &[],
+                        HttpAuthOverride::NoOverride,
+                        request,
+                    )
+                    .await?;
+                let phonenumber = phonenumber::parse(None, &response.number)?;
+
+                storage
+                    .save_signed_pre_key(signed_pre_key_id.into(), &record, None)
+                    .await?;
+                storage.set_pni_identity(identity, Some(regid)).await?;
+                Ok((phonenumber, response.pni))
+            }
+            .into_actor(self)
+            .map(
+                |result: Result<(PhoneNumber, Uuid), anyhow::Error>, act, ctx| match result {
+                    Ok((phonenumber, pni)) => {
+                        log::info!("Phone number changed to {}", phonenumber);
+                        act.number_change = None;
+                        act.config.set_tel(phonenumber.clone());
+                        act.config.set_pni(pni);
+                        if let Err(e) = act.config.write_to_file() {
+                            log::error!("Could not save the new phone number: {:?}", e);
+                        }
+                        if let Some(credentials) = &mut act.credentials {
+                            credentials.phonenumber = phonenumber.clone();
+                        }
+                        let storage = act.storage.as_ref().unwrap();
+                        if let Err(e) = storage.merge_and_fetch_recipient(
+                            Some(phonenumber.clone()),
+                            act.config.get_uuid(),
+                            Some(pni),
+                            TrustLevel::Certain,
+                        ) {
+                            log::error!("Could not store the new phone number: {:?}", e);
+                        }
+                        act.events.number_changed(&phonenumber.to_string());
+                        act.pni_cipher = act.new_pni_cipher();
+                        // The server dropped the pre-keys of the old PNI.
+                        ctx.notify(RefreshPniPreKeys);
+                    }
+                    Err(e) => {
+                        log::error!("Changing the phone number failed: {:?}", e);
+                        act.events.number_change_failed();
+                    }
+                },
+            ),
+        )
+    }
+}
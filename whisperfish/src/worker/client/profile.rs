@@ -127,9 +127,10 @@ impl Handler<ProfileAvatarFetched> for ClientActor {
         ProfileAvatarFetched(uuid, bytes): ProfileAvatarFetched,
         _ctx: &mut Self::Context,
     ) -> Self::Result {
+        let account = self.config.account().map(String::from);
         Box::pin(
             async move {
-                let settings = crate::config::SettingsBridge::default();
+                let settings = crate::config::SettingsBridge::for_account(account.as_deref());
                 let avatar_dir = settings.get_string("avatar_dir");
                 let avatar_dir = Path::new(&avatar_dir);
 
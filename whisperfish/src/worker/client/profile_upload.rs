@@ -248,13 +248,15 @@ impl Handler<UploadProfile> for ClientActor {
             }
 
             // Now also set the database
-            storage.update_profile_key(
+            if let Err(e) = storage.update_profile_key(
                 None,
                 Some(uuid),
                 None,
                 &profile_key.get_bytes(),
                 TrustLevel::Certain,
-            );
+            ) {
+                log::error!("Could not store our profile key: {:?}", e);
+            }
 
             client.send(RefreshProfileAttributes).await.unwrap();
             client.send(MultideviceSyncProfile).await.unwrap();
@@ -0,0 +1,527 @@
+//! Sending to groups with sender keys.
+//!
+//! Instead of encrypting a group message for every device of every member, the message is
+//! encrypted once with our sender key for the group, and the server hands it to every device
+//! from a single multi-recipient request.  Devices that do not have our key yet get it first, in
+//! a sender key distribution message.  See [crate::store::Storage::fetch_or_insert_distribution_id]
+//! for how the keys are kept.
+//!
+//! This only works with sealed sender and existing sessions.  Members without unidentified
+//! access or without a session are sent to one by one, and so is the whole group when the
+//! multi-recipient request fails.
+
+use super::*;
+use libsignal_service::proto::{envelope, WebSocketRequestMessage};
+use libsignal_service::push_service::DEFAULT_DEVICE_ID;
+use libsignal_service::session_store::SessionStoreExt;
+use std::time::SystemTime;
+
+/// Below this many members, sending one by one costs about the same.
+pub(super) const MIN_SENDER_KEY_RECIPIENTS: usize = 2;
+
+/// The access key to send to `recipient` with our sender key, if that is possible.
+///
+/// A wrong access key makes the server refuse the message for the whole group, so members whose
+/// key was never confirmed are left out.
+pub(super) fn sender_key_access(
+    certs: &UnidentifiedCertificates,
+    recipient: &orm::Recipient,
+    for_story: bool,
+) -> Option<[u8; 16]> {
+    match recipient.unidentified_access_mode {
+        UnidentifiedAccessMode::Enabled | UnidentifiedAccessMode::Unrestricted => {
+            certs.access_key_for(recipient, for_story)
+        }
+        UnidentifiedAccessMode::Unknown | UnidentifiedAccessMode::Disabled => None,
+    }
+}
+
+/// Pads `plaintext` like the other Signal clients do, so that its length tells little.
+pub(super) fn pad(mut plaintext: Vec<u8>) -> Vec<u8> {
+    const BLOCK: usize = 160;
+    let padded_len = (plaintext.len() + 2 + BLOCK - 1) / BLOCK * BLOCK - 1;
+    plaintext.push(0x80);
+    plaintext.resize(padded_len, 0);
+    plaintext
+}
+
+/// A member that can get the message with our sender key.
+struct Target {
+    address: ServiceAddress,
+    access_key: [u8; 16],
+    /// Every device of the member, with its session.
+    devices: Vec<(ProtocolAddress, SessionRecord)>,
+}
+
+impl Target {
+    /// Looks up the sessions with the devices of `address`.  None when there is no session yet,
+    /// which sending one by one sets up.
+    async fn load(
+        storage: &Storage,
+        address: ServiceAddress,
+        access_key: [u8; 16],
+    ) -> anyhow::Result<Option<Self>> {
+        let mut device_ids = vec![DEFAULT_DEVICE_ID];
+        device_ids.extend(storage.get_sub_device_sessions(&address).await?);
+
+        let mut devices = Vec::with_capacity(device_ids.len());
+        for device_id in device_ids {
+            let device = ProtocolAddress::new(address.uuid.to_string(), device_id.into());
+            match storage.load_session(&device, None).await? {
+                Some(session) if session.remote_registration_id().is_ok() => {
+                    devices.push((device, session))
+                }
+                _ => return Ok(None),
+            }
+        }
+        Ok(Some(Self {
+            address,
+            access_key,
+            devices,
+        }))
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct OutgoingPushMessage {
+    pub r#type: u32,
+    pub destination_device_id: u32,
+    pub destination_registration_id: u32,
+    pub content: String,
+}
+
+#[derive(serde::Serialize)]
+struct OutgoingPushMessages {
+    destination: String,
+    timestamp: u64,
+    messages: Vec<OutgoingPushMessage>,
+    online: bool,
+    urgent: bool,
+}
+
+#[derive(serde::Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct DeviceMismatch {
+    missing_devices: Vec<u32>,
+    extra_devices: Vec<u32>,
+    stale_devices: Vec<u32>,
+}
+
+/// A [DeviceMismatch] in the answer to a multi-recipient request.
+#[derive(serde::Deserialize)]
+struct RecipientMismatch {
+    uuid: String,
+    devices: DeviceMismatch,
+}
+
+#[derive(serde::Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct MultiRecipientResponse {
+    uuids404: Vec<String>,
+}
+
+/// Drops the sessions the server says are outdated, and forgets that the devices of `name` have
+/// our sender key, such that the next attempt starts over with them.
+async fn forget_devices(storage: &Storage, name: &str, devices: &DeviceMismatch) {
+    log::info!(
+        "Devices of {} changed: {} missing, {} extra, {} stale",
+        name,
+        devices.missing_devices.len(),
+        devices.extra_devices.len(),
+        devices.stale_devices.len()
+    );
+    for device_id in devices.extra_devices.iter().chain(&devices.stale_devices) {
+        let device = ProtocolAddress::new(name.to_owned(), (*device_id).into());
+        if let Err(e) = storage.delete_session(&device).await {
+            log::debug!("Deleting session with {}: {}", device, e);
+        }
+    }
+    storage.clear_sender_key_shared_with(name);
+}
+
+/// Sends `content` as a sealed sender message to every device of `target`, encrypting it with
+/// our sessions.
+async fn send_sealed(
+    storage: &Storage,
+    ws: &mut SignalWebSocket,
+    certificate: &SenderCertificate,
+    target: &Target,
+    content: &[u8],
+    timestamp: u64,
+) -> anyhow::Result<()> {
+    let mut messages = Vec::with_capacity(target.devices.len());
+    for (device, session) in &target.devices {
+        let ciphertext = sealed_sender_encrypt(
+            device,
+            certificate,
+            content,
+            &mut storage.clone(),
+            &mut storage.clone(),
+            SystemTime::now(),
+            None,
+            &mut rand::thread_rng(),
+        )
+        .await?;
+        messages.push(OutgoingPushMessage {
+            r#type: envelope::Type::UnidentifiedSender as u32,
+            destination_device_id: u32::from(device.device_id()),
+            destination_registration_id: session.remote_registration_id()?,
+            content: base64::encode(ciphertext),
+        });
+    }
+
+    put_messages(
+        storage,
+        ws,
+        target.address.uuid,
+        Some(target.access_key),
+        messages,
+        timestamp,
+        true,
+    )
+    .await
+}
+
+/// Encrypts `content` for each of `devices` with our sessions with them, sealed when there is a
+/// `certificate`.
+pub(super) async fn encrypt_for_devices(
+    storage: &Storage,
+    certificate: Option<&SenderCertificate>,
+    devices: &[ProtocolAddress],
+    content: &[u8],
+) -> anyhow::Result<Vec<OutgoingPushMessage>> {
+    let mut messages = Vec::with_capacity(devices.len());
+    for device in devices {
+        let session = storage
+            .load_session(device, None)
+            .await?
+            .with_context(|| format!("No session with {}", device))?;
+        let (r#type, ciphertext) = match certificate {
+            Some(certificate) => {
+                let ciphertext = sealed_sender_encrypt(
+                    device,
+                    certificate,
+                    content,
+                    &mut storage.clone(),
+                    &mut storage.clone(),
+                    SystemTime::now(),
+                    None,
+                    &mut rand::thread_rng(),
+                )
+                .await?;
+                (envelope::Type::UnidentifiedSender, ciphertext)
+            }
+            None => {
+                let message = message_encrypt(
+                    content,
+                    device,
+                    &mut storage.clone(),
+                    &mut storage.clone(),
+                    SystemTime::now(),
+                    None,
+                )
+                .await?;
+                let r#type = match message.message_type() {
+                    CiphertextMessageType::PreKey => envelope::Type::PrekeyBundle,
+                    _ => envelope::Type::Ciphertext,
+                };
+                (r#type, message.serialize().to_vec())
+            }
+        };
+        messages.push(OutgoingPushMessage {
+            r#type: r#type as u32,
+            destination_device_id: u32::from(device.device_id()),
+            destination_registration_id: session.remote_registration_id()?,
+            content: base64::encode(ciphertext),
+        });
+    }
+    Ok(messages)
+}
+
+/// Sends `messages` to the devices of `destination` in a single request; sealed sender messages
+/// need the `access_key` of the destination.
+pub(super) async fn put_messages(
+    storage: &Storage,
+    ws: &mut SignalWebSocket,
+    destination: Uuid,
+    access_key: Option<[u8; 16]>,
+    messages: Vec<OutgoingPushMessage>,
+    timestamp: u64,
+    urgent: bool,
+) -> anyhow::Result<()> {
+    let body = serde_json::to_vec(&OutgoingPushMessages {
+        destination: destination.to_string(),
+        timestamp,
+        messages,
+        online: false,
+        urgent,
+    })?;
+    let mut headers = vec!["content-type:application/json".to_owned()];
+    if let Some(access_key) = access_key {
+        headers.push(format!(
+            "unidentified-access-key:{}",
+            base64::encode(access_key)
+        ));
+    }
+    let request = WebSocketRequestMessage {
+        verb: Some("PUT".into()),
+        path: Some(format!("/v1/messages/{}", destination)),
+        headers,
+        body: Some(body),
+        ..Default::default()
+    };
+    let response = ws.request(request).await?;
+    match response.status() as u16 {
+        200 => Ok(()),
+        status @ 409 | status @ 410 => {
+            let name = destination.to_string();
+            match serde_json::from_slice(response.body()) {
+                Ok(devices) => forget_devices(storage, &name, &devices).await,
+                Err(e) => log::warn!("Could not parse device mismatch ({}): {}", status, e),
+            }
+            anyhow::bail!("devices of {} changed", name)
+        }
+        status => Err(ServiceError::UnhandledResponseCode { http_code: status }.into()),
+    }
+}
+
+/// Makes sure every device of `targets` has our sender key for `distribution_id`.
+///
+/// Returns the targets that have it now; the others have to be sent to one by one.
+async fn distribute_sender_key(
+    storage: &Storage,
+    ws: &mut SignalWebSocket,
+    certificate: &SenderCertificate,
+    local: &ProtocolAddress,
+    distribution_id: Uuid,
+    targets: Vec<Target>,
+) -> anyhow::Result<(Vec<Target>, Vec<Target>)> {
+    let shared: HashSet<ProtocolAddress> = storage
+        .fetch_sender_key_shared(distribution_id)
+        .into_iter()
+        .collect();
+    let (ready, missing): (Vec<_>, Vec<_>) = targets.into_iter().partition(|target| {
+        target
+            .devices
+            .iter()
+            .all(|(device, _)| shared.contains(device))
+    });
+    if missing.is_empty() {
+        return Ok((ready, Vec::new()));
+    }
+
+    log::debug!(
+        "Distributing sender key {} to {} member(s)",
+        distribution_id,
+        missing.len()
+    );
+    let skdm = create_sender_key_distribution_message(
+        local,
+        distribution_id,
+        &mut storage.clone(),
+        &mut rand::thread_rng(),
+        None,
+    )
+    .await?;
+    let content = pad(libsignal_service::proto::Content {
+        sender_key_distribution_message: Some(skdm.serialized().to_vec()),
+        ..Default::default()
+    }
+    .encode_to_vec());
+    let timestamp = Utc::now().timestamp_millis() as u64;
+
+    let mut ready = ready;
+    let mut failed = Vec::new();
+    for target in missing {
+        match send_sealed(storage, ws, certificate, &target, &content, timestamp).await {
+            Ok(()) => {
+                let devices: Vec<_> = target.devices.iter().map(|(d, _)| d.clone()).collect();
+                storage.mark_sender_key_shared(distribution_id, &devices);
+                ready.push(target);
+            }
+            Err(e) => {
+                log::warn!(
+                    "Could not send sender key to {}: {}",
+                    target.address.uuid,
+                    e
+                );
+                failed.push(target);
+            }
+        }
+    }
+    Ok((ready, failed))
+}
+
+/// Sends `content` to the members of the group `group_id` (hex) that can get it with our sender
+/// key.
+///
+/// Returns their results, and the members that still have to be sent to one by one.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn send_with_sender_key(
+    storage: Storage,
+    mut ws: SignalWebSocket,
+    certificate: SenderCertificate,
+    local: ProtocolAddress,
+    group_id: &str,
+    members: Vec<(ServiceAddress, [u8; 16])>,
+    content: &ContentBody,
+    timestamp: u64,
+    online: bool,
+) -> anyhow::Result<(Vec<SendMessageResult>, Vec<ServiceAddress>)> {
+    let distribution_id = storage.fetch_or_insert_distribution_id(group_id);
+
+    let mut targets = Vec::with_capacity(members.len());
+    let mut remaining = Vec::new();
+    for (address, access_key) in members {
+        match Target::load(&storage, address, access_key).await? {
+            Some(target) => targets.push(target),
+            None => remaining.push(address),
+        }
+    }
+
+    let (targets, failed) = distribute_sender_key(
+        &storage,
+        &mut ws,
+        &certificate,
+        &local,
+        distribution_id,
+        targets,
+    )
+    .await?;
+    remaining.extend(failed.into_iter().map(|target| target.address));
+    if targets.len() < MIN_SENDER_KEY_RECIPIENTS {
+        remaining.extend(targets.into_iter().map(|target| target.address));
+        return Ok((Vec::new(), remaining));
+    }
+
+    let plaintext = pad(content.clone().into_proto().encode_to_vec());
+    let message = group_encrypt(
+        &mut storage.clone(),
+        &local,
+        distribution_id,
+        &plaintext,
+        &mut rand::thread_rng(),
+        None,
+    )
+    .await?;
+    let usmc = UnidentifiedSenderMessageContent::new(
+        CiphertextMessageType::SenderKey,
+        certificate,
+        message.serialized().to_vec(),
+        ContentHint::Resendable,
+        Some(hex::decode(group_id)?),
+    )?;
+
+    let (destinations, sessions): (Vec<_>, Vec<_>) = targets
+        .iter()
+        .flat_map(|target| target.devices.iter())
+        .map(|(device, session)| (device, session))
+        .unzip();
+    let body = sealed_sender_multi_recipient_encrypt(
+        &destinations,
+        &sessions,
+        &usmc,
+        &mut storage.clone(),
+        None,
+        &mut rand::thread_rng(),
+    )
+    .await?;
+
+    // The server checks the access keys of all members at once.
+    let access_key = targets.iter().fold([0u8; 16], |mut combined, target| {
+        for (c, k) in combined.iter_mut().zip(&target.access_key) {
+            *c ^= k;
+        }
+        combined
+    });
+    let request = WebSocketRequestMessage {
+        verb: Some("PUT".into()),
+        path: Some(format!(
+            "/v1/messages/multi_recipient?ts={}&online={}&urgent=true&story=false",
+            timestamp, online
+        )),
+        headers: vec![
+            "content-type:application/vnd.signal-messenger.mrm".into(),
+            format!("unidentified-access-key:{}", base64::encode(access_key)),
+        ],
+        body: Some(body),
+        ..Default::default()
+    };
+    let response = ws.request(request).await?;
+    let status = response.status() as u16;
+    match status {
+        200 => {}
+        409 | 410 => {
+            let mismatches: Vec<RecipientMismatch> = serde_json::from_slice(response.body())?;
+            for mismatch in mismatches {
+                forget_devices(&storage, &mismatch.uuid, &mismatch.devices).await;
+            }
+            anyhow::bail!("devices changed in group {}", group_id);
+        }
+        _ => return Err(ServiceError::UnhandledResponseCode { http_code: status }.into()),
+    }
+
+    let response: MultiRecipientResponse = serde_json::from_slice(response.body())?;
+    let not_found: HashSet<String> = response.uuids404.into_iter().collect();
+    let results = targets
+        .into_iter()
+        .map(|target| {
+            let uuid = target.address.uuid;
+            if not_found.contains(&uuid.to_string()) {
+                Err(MessageSenderError::NotFound { uuid })
+            } else {
+                Ok(SentMessage {
+                    recipient: target.address,
+                    unidentified: true,
+                    needs_sync: false,
+                })
+            }
+        })
+        .collect();
+    Ok((results, remaining))
+}
+
+/// Tells our other devices about a group message we sent, partly or wholly with our sender key,
+/// which only reached the members.  `results` covers the members that got it one by one too.
+pub(super) fn sent_transcript(
+    message: &DataMessage,
+    timestamp: u64,
+    results: &[SendMessageResult],
+) -> SyncMessage {
+    SyncMessage {
+        sent: Some(Sent {
+            timestamp: Some(timestamp),
+            message: Some(message.clone()),
+            expiration_start_timestamp: message.expire_timer.map(|_| timestamp),
+            unidentified_status: results
+                .iter()
+                .filter_map(|result| result.as_ref().ok())
+                .map(|sent| sync_message::sent::UnidentifiedDeliveryStatus {
+                    destination_uuid: Some(sent.recipient.uuid.to_string()),
+                    unidentified: Some(sent.unidentified),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padding() {
+        assert_eq!(pad(vec![]).len(), 159);
+        assert_eq!(pad(vec![1; 157]).len(), 159);
+        assert_eq!(pad(vec![1; 158]).len(), 159);
+        assert_eq!(pad(vec![1; 159]).len(), 319);
+
+        let padded = pad(vec![1, 2, 3]);
+        assert_eq!(&padded[..4], &[1, 2, 3, 0x80]);
+        assert!(padded[4..].iter().all(|b| *b == 0));
+    }
+}
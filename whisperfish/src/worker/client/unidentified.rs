@@ -1,11 +1,18 @@
 use std::collections::HashMap;
+use std::time::Duration;
 
 use crate::store::orm::{self, UnidentifiedAccessMode};
+use crate::store::{millis_to_naive_chrono, Storage};
 
 use super::ClientActor;
 use actix::prelude::*;
 use libsignal_service::{prelude::*, protocol, unidentified_access::UnidentifiedAccess};
 
+/// Certificates are replaced when they expire within this margin.
+const ROTATION_MARGIN: Duration = Duration::from_secs(24 * 60 * 60);
+/// How often to check whether the certificates need replacing.
+pub const ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
 #[derive(Hash, Eq, PartialEq, Clone, Copy)]
 pub enum CertType {
     Complete,
@@ -16,9 +23,21 @@ impl CertType {
     fn all() -> impl Iterator<Item = Self> {
         vec![Self::Complete, Self::UuidOnly].into_iter()
     }
+
+    /// The key under which the certificate is stored.
+    fn kind(self) -> &'static str {
+        match self {
+            Self::Complete => "complete",
+            Self::UuidOnly => "uuid_only",
+        }
+    }
+
+    fn from_kind(kind: &str) -> Option<Self> {
+        Self::all().find(|cert_type| cert_type.kind() == kind)
+    }
 }
 
-// XXX In principle, these can be persisted, and don't need to be fetched on every start.
+/// The sealed sender certificates, which are kept in the database until they expire.
 #[derive(Default, Clone)]
 pub struct UnidentifiedCertificates {
     certs: HashMap<CertType, protocol::SenderCertificate>,
@@ -26,9 +45,58 @@ pub struct UnidentifiedCertificates {
 
 const UNRESTRICTED_ACCESS_KEY: [u8; 16] = [0u8; 16];
 
+/// Whether `cert` is still valid `margin` from now.
+fn valid_for(cert: &protocol::SenderCertificate, margin: Duration) -> bool {
+    let deadline = std::time::SystemTime::now() + margin;
+    let deadline = deadline
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("time after epoch")
+        .as_millis() as u64;
+    match cert.expiration() {
+        Ok(expiration) => expiration > deadline,
+        Err(e) => {
+            log::warn!("Sender certificate without expiration: {}", e);
+            false
+        }
+    }
+}
+
 impl UnidentifiedCertificates {
+    /// Loads the certificates that have not expired yet.
+    pub fn load(storage: &Storage) -> Self {
+        let mut certs = HashMap::new();
+        for stored in storage.fetch_sender_certificates() {
+            let cert_type = match CertType::from_kind(&stored.kind) {
+                Some(cert_type) => cert_type,
+                None => {
+                    log::warn!("Unknown sender certificate {}", stored);
+                    continue;
+                }
+            };
+            match protocol::SenderCertificate::deserialize(&stored.certificate) {
+                Ok(cert) => {
+                    certs.insert(cert_type, cert);
+                }
+                Err(e) => log::warn!("Could not load {}: {}", stored, e),
+            }
+        }
+        log::debug!("Loaded {} sender certificates", certs.len());
+        Self { certs }
+    }
+
+    /// The certificate of type `cert`, if it has not expired.
     pub fn get(&self, cert: CertType) -> Option<&protocol::SenderCertificate> {
-        self.certs.get(&cert)
+        self.certs
+            .get(&cert)
+            .filter(|cert| valid_for(cert, Duration::from_secs(0)))
+    }
+
+    /// Whether the certificate of type `cert` is missing or about to expire.
+    fn needs_rotation(&self, cert: CertType) -> bool {
+        self.certs
+            .get(&cert)
+            .map(|cert| !valid_for(cert, ROTATION_MARGIN))
+            .unwrap_or(true)
     }
 
     /// Look up the correct access key for a certain recipient.
@@ -72,6 +140,7 @@ impl UnidentifiedCertificates {
 
 #[derive(Message)]
 #[rtype(result = "()")]
+/// Fetch the sealed sender certificates that are missing or about to expire.
 pub struct RotateUnidentifiedCertificates;
 
 impl Handler<RotateUnidentifiedCertificates> for ClientActor {
@@ -82,40 +151,49 @@ impl Handler<RotateUnidentifiedCertificates> for ClientActor {
         _: RotateUnidentifiedCertificates,
         _ctx: &mut Self::Context,
     ) -> Self::Result {
-        let mut service = self.authenticated_service();
+        let rotate: Vec<_> = CertType::all()
+            .filter(|cert_type| self.unidentified_certificates.needs_rotation(*cert_type))
+            .collect();
         // Short cut
-        let all_certs_available =
-            CertType::all().all(|t| self.unidentified_certificates.certs.contains_key(&t));
+        if rotate.is_empty() {
+            return Box::pin(fut::ready(()));
+        }
+
+        let mut service = self.authenticated_service();
+        let storage = self.storage.clone().unwrap();
         Box::pin(
             async move {
-                let mut certs = HashMap::<_, protocol::SenderCertificate>::default();
-                if !all_certs_available {
-                    for cert_type in CertType::all() {
-                        let cert = match cert_type {
-                            CertType::Complete => service.get_sender_certificate().await?,
-                            CertType::UuidOnly => {
-                                service.get_uuid_only_sender_certificate().await?
-                            }
-                        };
-                        certs.insert(cert_type, cert);
-                    }
+                let mut certs = Vec::new();
+                for cert_type in rotate {
+                    let cert = match cert_type {
+                        CertType::Complete => service.get_sender_certificate().await?,
+                        CertType::UuidOnly => service.get_uuid_only_sender_certificate().await?,
+                    };
+                    certs.push((cert_type, cert));
                 }
                 Result::<_, ServiceError>::Ok(certs)
             }
             .into_actor(self)
-            .map(move |certs, act, _ctx| {
-                if all_certs_available {
-                    return;
-                }
-                match certs {
-                    Ok(certs) => {
-                        log::debug!("Fetched {} sender certificates", certs.len());
-                        act.unidentified_certificates.certs = certs;
-                    }
-                    Err(e) => {
-                        log::error!("Error fetching sender certificates: {}", e);
+            .map(move |certs, act, _ctx| match certs {
+                Ok(certs) => {
+                    log::debug!("Fetched {} sender certificates", certs.len());
+                    for (cert_type, cert) in certs {
+                        match (cert.serialized(), cert.expiration()) {
+                            (Ok(serialized), Ok(expiration)) => storage.save_sender_certificate(
+                                cert_type.kind(),
+                                serialized,
+                                millis_to_naive_chrono(expiration),
+                            ),
+                            _ => log::warn!("Could not store sender certificate"),
+                        }
+                        act.unidentified_certificates.certs.insert(cert_type, cert);
                     }
                 }
+                Err(e) => {
+                    // The stored certificates are used while they last, after that messages are
+                    // sent without sealed sender.
+                    log::error!("Error fetching sender certificates: {}", e);
+                }
             }),
         )
     }
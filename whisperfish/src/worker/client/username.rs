@@ -0,0 +1,359 @@
+//! Signal usernames: `nickname.discriminator`, e.g. `alice.42`.
+//!
+//! The server only sees hashes of usernames.  Setting our own username takes two steps: a few
+//! candidates with random discriminators are offered to the server, which reserves one of them.
+//! The reservation is then confirmed with a zero-knowledge proof that we know the username
+//! behind the hash.
+
+use super::*;
+use libsignal_service::configuration::Endpoint;
+use libsignal_service::push_service::{HttpAuthOverride, PushService};
+use qmeta_async::with_executor;
+use rand::Rng;
+use usernames::Username;
+
+const USERNAME_LINK_PREFIX: &str = "https://signal.me/#u/";
+/// How many usernames are offered when reserving one.
+const USERNAME_CANDIDATES: usize = 10;
+
+fn parse_username(username: &str) -> Result<Username, anyhow::Error> {
+    Username::new(username.trim())
+        .map_err(|e| anyhow::anyhow!("Invalid username {:?}: {:?}", username, e))
+}
+
+fn encode_hash(hash: &[u8]) -> String {
+    base64::encode_config(hash, base64::URL_SAFE_NO_PAD)
+}
+
+/// Usernames with random discriminators, shorter ones first.
+fn username_candidates(nickname: &str) -> Vec<Username> {
+    let mut rng = rand::thread_rng();
+    (0..USERNAME_CANDIDATES)
+        .filter_map(|i| {
+            let discriminator = if i < USERNAME_CANDIDATES / 2 {
+                rng.gen_range(10, 100)
+            } else {
+                rng.gen_range(100, 1000)
+            };
+            Username::new(&format!("{}.{}", nickname.trim(), discriminator)).ok()
+        })
+        .collect()
+}
+
+/// The link through which others can find `username`.
+pub fn username_link(username: &str) -> Result<String, anyhow::Error> {
+    let hash = parse_username(username)?.hash();
+    Ok(format!("{}{}", USERNAME_LINK_PREFIX, encode_hash(&hash)))
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReserveUsernameRequest {
+    username_hashes: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConfirmUsernameRequest {
+    username_hash: String,
+    zk_proof: String,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsernameHashResponse {
+    username_hash: String,
+}
+
+#[derive(serde::Deserialize)]
+struct UsernameLookupResponse {
+    uuid: Uuid,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+/// Reserve a username for `nickname`, to be confirmed with [ConfirmUsername].
+pub struct ReserveUsername {
+    pub nickname: String,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+/// Make the reserved username ours.
+pub struct ConfirmUsername;
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DeleteUsername;
+
+#[derive(Message)]
+#[rtype(result = "()")]
+/// Find the account behind a username, and add it to the recipients.
+pub struct LookupUsername {
+    pub username: String,
+}
+
+// methods called from Qt
+impl ClientWorker {
+    #[with_executor]
+    pub fn reserve_username(&self, nickname: String) {
+        let actor = self.actor.clone().unwrap();
+        actix::spawn(async move {
+            if let Err(e) = actor.send(ReserveUsername { nickname }).await {
+                log::error!("{:?} in reserve_username()", e);
+            }
+        });
+    }
+
+    #[with_executor]
+    pub fn confirm_username(&self) {
+        let actor = self.actor.clone().unwrap();
+        actix::spawn(async move {
+            if let Err(e) = actor.send(ConfirmUsername).await {
+                log::error!("{:?} in confirm_username()", e);
+            }
+        });
+    }
+
+    #[with_executor]
+    pub fn delete_username(&self) {
+        let actor = self.actor.clone().unwrap();
+        actix::spawn(async move {
+            if let Err(e) = actor.send(DeleteUsername).await {
+                log::error!("{:?} in delete_username()", e);
+            }
+        });
+    }
+
+    #[with_executor]
+    pub fn find_username(&self, username: String) {
+        let actor = self.actor.clone().unwrap();
+        actix::spawn(async move {
+            if let Err(e) = actor.send(LookupUsername { username }).await {
+                log::error!("{:?} in find_username()", e);
+            }
+        });
+    }
+
+    /// Returns an empty string for an invalid username.
+    pub fn username_link(&self, username: String) -> QString {
+        username_link(&username).unwrap_or_default().into()
+    }
+
+    /// The link of `username` as QR code, see [crate::model::qr_code_data_uri].
+    pub fn username_qr(&self, username: String) -> QString {
+        username_link(&username)
+            .map(|link| crate::model::qr_code_data_uri(&link))
+            .unwrap_or_default()
+            .into()
+    }
+}
+
+impl Handler<ReserveUsername> for ClientActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: ReserveUsername, _ctx: &mut Self::Context) -> Self::Result {
+        log::trace!("handle(ReserveUsername)");
+        let mut service = self.authenticated_service();
+        let candidates = username_candidates(&msg.nickname);
+
+        Box::pin(
+            async move {
+                anyhow::ensure!(!candidates.is_empty(), "Invalid nickname {}", msg.nickname);
+                let request = ReserveUsernameRequest {
+                    username_hashes: candidates.iter().map(|c| encode_hash(&c.hash())).collect(),
+                };
+                let response: UsernameHashResponse = service
+                    .put_json(
+                        Endpoint::Service,
+                        "/v1/accounts/username_hash/reserve",
+                        &[],
+                        HttpAuthOverride::NoOverride,
+                        request,
+                    )
+                    .await?;
+                candidates
+                    .into_iter()
+                    .find(|c| encode_hash(&c.hash()) == response.username_hash)
+                    .map(|c| c.to_string())
+                    .context("The server reserved a username that was not offered")
+            }
+            .into_actor(self)
+            .map(|result, act, _ctx| match result {
+                Ok(username) => {
+                    log::info!("Reserved username {}", username);
+                    act.events.username_reserved(&username);
+                    act.reserved_username = Some(username);
+                }
+                Err(e) => {
+                    log::error!("Reserving a username failed: {:?}", e);
+                    act.events.username_failed();
+                }
+            }),
+        )
+    }
+}
+
+impl Handler<ConfirmUsername> for ClientActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, _: ConfirmUsername, _ctx: &mut Self::Context) -> Self::Result {
+        log::trace!("handle(ConfirmUsername)");
+        let mut service = self.authenticated_service();
+        let storage = self.storage.clone().unwrap();
+        let reserved = self.reserved_username.clone();
+
+        Box::pin(
+            async move {
+                let reserved = reserved.context("No username reserved")?;
+                let username = parse_username(&reserved)?;
+                let mut randomness = [0u8; 32];
+                rand::thread_rng().fill(&mut randomness);
+                let proof = username
+                    .proof(&randomness)
+                    .map_err(|e| anyhow::anyhow!("Proving username: {:?}", e))?;
+                let request = ConfirmUsernameRequest {
+                    username_hash: encode_hash(&username.hash()),
+                    zk_proof: encode_hash(&proof),
+                };
+                let _: serde_json::Value = service
+                    .put_json(
+                        Endpoint::Service,
+                        "/v1/accounts/username_hash/confirm",
+                        &[],
+                        HttpAuthOverride::NoOverride,
+                        request,
+                    )
+                    .await?;
+
+                let self_recipient = storage
+                    .fetch_self_recipient()
+                    .context("No self recipient")?;
+                storage.set_recipient_username(self_recipient.id, Some(&reserved));
+                Ok(reserved)
+            }
+            .into_actor(self)
+            .map(
+                |result: Result<String, anyhow::Error>, act, _ctx| match result {
+                    Ok(username) => {
+                        log::info!("Username is now {}", username);
+                        act.reserved_username = None;
+                        act.events.username_changed(Some(&username));
+                    }
+                    Err(e) => {
+                        log::error!("Confirming the username failed: {:?}", e);
+                        act.events.username_failed();
+                    }
+                },
+            ),
+        )
+    }
+}
+
+impl Handler<DeleteUsername> for ClientActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, _: DeleteUsername, _ctx: &mut Self::Context) -> Self::Result {
+        log::trace!("handle(DeleteUsername)");
+        let mut service = self.authenticated_service();
+        let storage = self.storage.clone().unwrap();
+
+        Box::pin(
+            async move {
+                let _: serde_json::Value = service
+                    .delete_json(Endpoint::Service, "/v1/accounts/username_hash", &[])
+                    .await?;
+                if let Some(self_recipient) = storage.fetch_self_recipient() {
+                    storage.set_recipient_username(self_recipient.id, None);
+                }
+                Result::<_, anyhow::Error>::Ok(())
+            }
+            .into_actor(self)
+            .map(|result, act, _ctx| match result {
+                Ok(()) => {
+                    log::info!("Username deleted");
+                    act.events.username_changed(None);
+                }
+                Err(e) => {
+                    log::error!("Deleting the username failed: {:?}", e);
+                    act.events.username_failed();
+                }
+            }),
+        )
+    }
+}
+
+impl Handler<LookupUsername> for ClientActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: LookupUsername, _ctx: &mut Self::Context) -> Self::Result {
+        log::trace!("handle(LookupUsername)");
+        let mut service = self.authenticated_service();
+        let storage = self.storage.clone().unwrap();
+        let username = msg.username.trim().to_lowercase();
+
+        Box::pin(
+            async move {
+                let hash = encode_hash(&parse_username(&username)?.hash());
+                let response: Result<UsernameLookupResponse, _> = service
+                    .get_json(
+                        Endpoint::Service,
+                        &format!("/v1/accounts/username_hash/{}", hash),
+                        &[],
+                        HttpAuthOverride::NoOverride,
+                    )
+                    .await;
+                let uuid = match response {
+                    Ok(response) => response.uuid,
+                    Err(ServiceError::NotFoundError) => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                };
+
+                let recipient = storage.fetch_or_insert_recipient_by_uuid(uuid);
+                storage.set_recipient_username(recipient.id, Some(&username));
+                Result::<_, anyhow::Error>::Ok(Some(uuid))
+            }
+            .into_actor(self)
+            .map(move |result, act, _ctx| match result {
+                Ok(Some(uuid)) => act.events.username_found(&msg.username, uuid),
+                Ok(None) => {
+                    log::info!("Username {} not found", msg.username);
+                    act.events.username_not_found(&msg.username);
+                }
+                Err(e) => {
+                    log::error!("Looking up username {} failed: {:?}", msg.username, e);
+                    act.events.username_not_found(&msg.username);
+                }
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidates_keep_the_nickname() {
+        let candidates = username_candidates("alice");
+        assert_eq!(candidates.len(), USERNAME_CANDIDATES);
+        for candidate in candidates {
+            let candidate = candidate.to_string();
+            let (nickname, discriminator) = candidate.split_once('.').unwrap();
+            assert_eq!(nickname, "alice");
+            assert!(discriminator.len() == 2 || discriminator.len() == 3);
+        }
+        assert!(username_candidates("a").is_empty());
+    }
+
+    #[test]
+    fn links_hash_the_username() {
+        let link = username_link("alice.42").unwrap();
+        let hash = link.strip_prefix(USERNAME_LINK_PREFIX).unwrap();
+        assert_eq!(
+            base64::decode_config(hash, base64::URL_SAFE_NO_PAD).unwrap(),
+            Username::new("alice.42").unwrap().hash()
+        );
+        assert!(username_link("alice").is_err());
+    }
+}
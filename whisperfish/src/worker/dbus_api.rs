@@ -0,0 +1,248 @@
+#![allow(non_snake_case)]
+
+//! Backend of the `be.rubdos.whisperfish.api` D-Bus interface.
+//!
+//! The adaptor itself is declared in QML, next to the `be.rubdos.whisperfish.app` interface.  It
+//! forwards calls to this object, which resolves recipients and hands out sessions and messages as
+//! JSON.  See `doc/dbus-api.md` for the interface as seen by other applications.
+
+use crate::actor::{MarkSessionRead, SessionActor};
+use crate::store::{orm, Storage};
+use crate::worker::{ClientActor, IsConnected, QueueMessage};
+use actix::prelude::*;
+use anyhow::Context;
+use futures::prelude::*;
+use qmeta_async::with_executor;
+use qmetaobject::prelude::*;
+use serde_json::json;
+
+#[derive(QObject, Default)]
+pub struct DBusApi {
+    base: qt_base_class!(trait QObject),
+    pub storage: Option<Storage>,
+    pub client_actor: Option<Addr<ClientActor>>,
+    pub session_actor: Option<Addr<SessionActor>>,
+
+    sendMessage: qt_method!(fn(&self, recipient: QString, message: QString) -> i32),
+    sendSessionMessage: qt_method!(fn(&self, sid: i32, message: QString) -> bool),
+    listSessions: qt_method!(fn(&self) -> QString),
+    getMessage: qt_method!(fn(&self, mid: i32) -> QString),
+    markRead: qt_method!(fn(&self, sid: i32) -> bool),
+}
+
+impl DBusApi {
+    pub fn new(client_actor: Addr<ClientActor>, session_actor: Addr<SessionActor>) -> Self {
+        Self {
+            client_actor: Some(client_actor),
+            session_actor: Some(session_actor),
+            ..Default::default()
+        }
+    }
+
+    /// Queues a message to a phone number, a UUID or a group ID.
+    ///
+    /// The session is created when there is none with the recipient yet.  Returns the session ID,
+    /// or -1 when the recipient could not be resolved.
+    #[with_executor]
+    fn sendMessage(&self, recipient: QString, message: QString) -> i32 {
+        let storage = match &self.storage {
+            Some(storage) => storage,
+            None => {
+                log::warn!("D-Bus sendMessage() called before the storage was opened");
+                return -1;
+            }
+        };
+
+        let session = match resolve_session(storage, recipient.to_string().trim()) {
+            Ok(session) => session,
+            Err(e) => {
+                log::warn!("D-Bus sendMessage(): {:#}", e);
+                return -1;
+            }
+        };
+
+        self.queue(session.id, message.to_string());
+        session.id
+    }
+
+    /// Queues a message to an existing session.
+    #[with_executor]
+    fn sendSessionMessage(&self, sid: i32, message: QString) -> bool {
+        let exists = self
+            .storage
+            .as_ref()
+            .map(|storage| storage.fetch_session_by_id(sid).is_some())
+            .unwrap_or(false);
+        if !exists {
+            log::warn!("D-Bus sendSessionMessage(): no session {}", sid);
+            return false;
+        }
+
+        self.queue(sid, message.to_string());
+        true
+    }
+
+    fn queue(&self, session_id: i32, message: String) {
+        let client = self.client_actor.clone().unwrap();
+        actix::spawn(async move {
+            let mid = match client
+                .send(QueueMessage {
+                    session_id,
+                    message,
+                    attachments: Vec::new(),
+                    quote: -1,
+                })
+                .await
+                .unwrap()
+            {
+                Ok(mid) => mid,
+                Err(e) => {
+                    log::error!("Could not queue message from D-Bus: {:#}", e);
+                    return;
+                }
+            };
+            if !client.send(IsConnected).await.unwrap() {
+                log::info!("Message {} from D-Bus is queued until connected", mid);
+            }
+        });
+        log::trace!(
+            "Dispatched QueueMessage for session {} over D-Bus",
+            session_id
+        );
+    }
+
+    /// Returns a JSON array of all sessions, most recently active first.
+    #[with_executor]
+    fn listSessions(&self) -> QString {
+        let storage = match &self.storage {
+            Some(storage) => storage,
+            None => return "[]".into(),
+        };
+
+        let mut sessions = storage.fetch_all_sessions_augmented();
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.timestamp()));
+
+        let sessions: Vec<_> = sessions
+            .iter()
+            .map(|session| session_to_json(storage, session))
+            .collect();
+        serde_json::Value::from(sessions).to_string().into()
+    }
+
+    /// Returns a message as JSON object, or an empty string if it does not exist.
+    #[with_executor]
+    fn getMessage(&self, mid: i32) -> QString {
+        let storage = match &self.storage {
+            Some(storage) => storage,
+            None => return QString::default(),
+        };
+
+        match storage.fetch_augmented_message(mid) {
+            Some(message) => message_to_json(storage, &message).to_string().into(),
+            None => QString::default(),
+        }
+    }
+
+    #[with_executor]
+    fn markRead(&self, sid: i32) -> bool {
+        let exists = self
+            .storage
+            .as_ref()
+            .map(|storage| storage.fetch_session_by_id(sid).is_some())
+            .unwrap_or(false);
+        if !exists {
+            log::warn!("D-Bus markRead(): no session {}", sid);
+            return false;
+        }
+
+        actix::spawn(
+            self.session_actor
+                .as_ref()
+                .unwrap()
+                .send(MarkSessionRead { sid })
+                .map(Result::unwrap),
+        );
+        true
+    }
+}
+
+/// Finds or creates the session with a phone number (`+32...`), a UUID, or a group (its hex ID).
+pub(crate) fn resolve_session(
+    storage: &Storage,
+    recipient: &str,
+) -> Result<orm::Session, anyhow::Error> {
+    if let Ok(uuid) = uuid::Uuid::parse_str(recipient) {
+        let recipient = storage.fetch_or_insert_recipient_by_uuid(uuid);
+        return Ok(storage.fetch_or_insert_session_by_recipient_id(recipient.id));
+    }
+
+    // Group IDs are 16 (v1) or 32 (v2) bytes in hex, which is longer than any phone number.
+    let is_group_id = (recipient.len() == 32 || recipient.len() == 64)
+        && recipient.chars().all(|c| c.is_ascii_hexdigit());
+
+    if !is_group_id {
+        // A phone number, possibly without its leading +
+        let international = if recipient.starts_with('+') {
+            recipient.to_string()
+        } else {
+            format!("+{}", recipient)
+        };
+        let phonenumber = phonenumber::parse(None, &international)
+            .ok()
+            .filter(phonenumber::is_valid)
+            .with_context(|| {
+                format!(
+                    "{:?} is neither a phone number, a UUID nor a group ID",
+                    recipient
+                )
+            })?;
+        return Ok(storage.fetch_or_insert_session_by_phonenumber(&phonenumber));
+    }
+
+    let group_id = recipient.to_ascii_lowercase();
+    storage
+        .fetch_session_by_group_v2_id(&group_id)
+        .or_else(|| storage.fetch_session_by_group_v1_id(&group_id))
+        .with_context(|| format!("Unknown group {}", recipient))
+}
+
+fn session_to_json(storage: &Storage, session: &orm::AugmentedSession) -> serde_json::Value {
+    let name = match &session.r#type {
+        orm::SessionType::GroupV1(group) => group.name.clone(),
+        orm::SessionType::GroupV2(group) => group.name.clone(),
+        orm::SessionType::DirectMessage(recipient) => recipient.name().into_owned(),
+    };
+
+    json!({
+        "id": session.id,
+        "name": name,
+        "isGroup": session.group_id().is_some(),
+        "groupId": session.group_id(),
+        "e164": session.recipient_e164(),
+        "uuid": session.recipient_uuid(),
+        "unread": storage.unread_count(session.id),
+        "isMuted": session.is_muted,
+        "isArchived": session.is_archived,
+        "isPinned": session.is_pinned,
+        "lastMessage": session.last_message.as_ref().and_then(|m| m.text.as_deref()),
+        "timestamp": session.timestamp().map(|t| t.timestamp_millis()),
+    })
+}
+
+fn message_to_json(storage: &Storage, message: &orm::AugmentedMessage) -> serde_json::Value {
+    let sender = message
+        .sender_recipient_id
+        .and_then(|id| storage.fetch_recipient_by_id(id));
+
+    json!({
+        "id": message.id,
+        "sessionId": message.session_id,
+        "sender": sender.as_ref().map(orm::Recipient::e164_or_uuid),
+        "senderName": sender.as_ref().map(|r| r.name().into_owned()),
+        "message": message.text,
+        "isOutbound": message.is_outbound,
+        "isRead": message.is_read,
+        "attachments": message.attachments,
+        "timestamp": message.server_timestamp.timestamp_millis(),
+    })
+}
@@ -6,6 +6,7 @@ use libsignal_service::push_service::{
 };
 use phonenumber::PhoneNumber;
 use qmetaobject::prelude::*;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::Arc;
 use uuid::Uuid;
@@ -182,6 +183,35 @@ impl SetupWorker {
             .context("No password code provided")?
             .into();
 
+        if let Some(backup) = SetupWorker::pending_backup(&config) {
+            log::info!("Found backup at {}", backup.display());
+            loop {
+                let passphrase: String = app
+                    .prompt
+                    .pinned()
+                    .borrow_mut()
+                    .ask_backup_passphrase()
+                    .await
+                    .context("No backup passphrase provided")?
+                    .into();
+                if passphrase.is_empty() {
+                    log::info!("Not restoring the backup, registering instead");
+                    break;
+                }
+                if Storage::check_backup_passphrase(&backup, &passphrase).await? {
+                    return SetupWorker::restore(
+                        app,
+                        config,
+                        &backup,
+                        &passphrase,
+                        storage_password,
+                    )
+                    .await;
+                }
+                log::warn!("Wrong backup passphrase, asking again");
+            }
+        }
+
         let is_primary: bool = app
             .prompt
             .pinned()
@@ -248,8 +278,54 @@ impl SetupWorker {
                 Some(reg.service_ids.pni),
                 &profile_key,
                 TrustLevel::Certain,
-            );
+            )?;
+        }
+
+        *app.app_state.pinned().borrow().storage.borrow_mut() = Some(storage);
+
+        Ok(())
+    }
+
+    /// Returns the backup of the account to offer for restoring, if the user has put one in place.
+    fn pending_backup(config: &crate::config::SignalConfig) -> Option<PathBuf> {
+        let backup =
+            dirs::download_dir()?.join(crate::store::account_backup_file_name(config.account()));
+        if backup.is_file() {
+            Some(backup)
+        } else {
+            None
         }
+    }
+
+    /// Sets up the storage from a backup, which makes registering unnecessary.
+    async fn restore(
+        app: Rc<WhisperfishApp>,
+        config: Arc<crate::config::SignalConfig>,
+        backup: &Path,
+        passphrase: &str,
+        storage_password: String,
+    ) -> Result<(), anyhow::Error> {
+        let storage_password = if storage_password.is_empty() {
+            None
+        } else {
+            Some(storage_password)
+        };
+
+        let storage = Storage::restore_backup(
+            config.clone(),
+            &config.get_share_dir().to_owned().into(),
+            backup,
+            passphrase,
+            storage_password.as_deref(),
+        )
+        .await
+        .context("Restoring backup")?;
+
+        let this = app.setup_worker.pinned();
+        let mut this = this.borrow_mut();
+        this.phonenumber = config.get_tel();
+        this.uuid = config.get_uuid();
+        this.deviceId = config.get_device_id().into();
 
         *app.app_state.pinned().borrow().storage.borrow_mut() = Some(storage);
 